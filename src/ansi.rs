@@ -1,49 +1,77 @@
+use std::str::FromStr;
+
+use anyhow::{Error, Result};
+
 pub struct AnsiStyle {
     bg: Option<AnsiColor>,
     fg: Option<AnsiColor>,
+    attributes: Vec<AnsiAttribute>,
 }
 
 #[allow(dead_code)]
 impl AnsiStyle {
     pub fn new(fg: Option<AnsiColor>, bg: Option<AnsiColor>) -> Self {
-        Self { bg, fg }
+        Self {
+            bg,
+            fg,
+            attributes: Vec::new(),
+        }
+    }
+
+    pub fn bold(self) -> Self {
+        self.with(AnsiAttribute::Bold)
+    }
+
+    pub fn underline(self) -> Self {
+        self.with(AnsiAttribute::Underline)
+    }
+
+    pub fn reverse(self) -> Self {
+        self.with(AnsiAttribute::Reverse)
+    }
+
+    pub fn blink(self) -> Self {
+        self.with(AnsiAttribute::Blink)
     }
+
+    fn with(mut self, attribute: AnsiAttribute) -> Self {
+        if !self.attributes.contains(&attribute) {
+            self.attributes.push(attribute);
+        }
+
+        self
+    }
+
     pub fn apply(&self, text: &str) -> String {
-        let fg = match &self.fg {
-            Some(fg) => match fg {
-                AnsiColor::Black => 30,
-                AnsiColor::Red => 31,
-                AnsiColor::Green => 32,
-                AnsiColor::Yellow => 33,
-                AnsiColor::Blue => 34,
-                AnsiColor::Magenta => 35,
-                AnsiColor::Cyan => 36,
-                AnsiColor::White => 37,
-                AnsiColor::Default => 39,
-            },
-            None => 39,
-        };
-
-        let bg = match &self.bg {
-            Some(bg) => match bg {
-                AnsiColor::Black => 40,
-                AnsiColor::Red => 41,
-                AnsiColor::Green => 42,
-                AnsiColor::Yellow => 43,
-                AnsiColor::Blue => 44,
-                AnsiColor::Magenta => 45,
-                AnsiColor::Cyan => 46,
-                AnsiColor::White => 47,
-                AnsiColor::Default => 49,
-            },
-            None => 49,
-        };
-
-        format!("\u{001b}[{};{}m{}\u{001b}[{};{}m", fg, bg, text, 37, 40)
+        let fg = self.fg.unwrap_or(AnsiColor::Default).code(30);
+        let bg = self.bg.unwrap_or(AnsiColor::Default).code(40);
+
+        let attributes: String = self
+            .attributes
+            .iter()
+            .map(|attribute| match attribute {
+                AnsiAttribute::Bold => "1;",
+                AnsiAttribute::Underline => "4;",
+                AnsiAttribute::Blink => "5;",
+                AnsiAttribute::Reverse => "7;",
+            })
+            .collect();
+
+        // The reset clears attributes along with colors.
+        format!("\u{001b}[{attributes}{fg};{bg}m{text}\u{001b}[0m")
     }
 }
 
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AnsiAttribute {
+    Bold,
+    Underline,
+    Reverse,
+    Blink,
+}
+
 #[allow(dead_code)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum AnsiColor {
     Black,
     Red,
@@ -54,4 +82,88 @@ pub enum AnsiColor {
     Cyan,
     White,
     Default,
+    /// One of the 256 colors of the xterm palette.
+    Indexed(u8),
+    /// A 24-bit truecolor value.
+    Rgb(u8, u8, u8),
+}
+
+impl AnsiColor {
+    /// SGR fragment for this color, where `base` is 30 for foreground or 40 for background.
+    fn code(self, base: u8) -> String {
+        let extended = base + 8;
+
+        match self {
+            Self::Black => base.to_string(),
+            Self::Red => (base + 1).to_string(),
+            Self::Green => (base + 2).to_string(),
+            Self::Yellow => (base + 3).to_string(),
+            Self::Blue => (base + 4).to_string(),
+            Self::Magenta => (base + 5).to_string(),
+            Self::Cyan => (base + 6).to_string(),
+            Self::White => (base + 7).to_string(),
+            Self::Default => (base + 9).to_string(),
+            Self::Indexed(n) => format!("{extended};5;{n}"),
+            Self::Rgb(r, g, b) => format!("{extended};2;{r};{g};{b}"),
+        }
+    }
+}
+
+impl FromStr for AnsiColor {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "black" => Ok(Self::Black),
+            "red" => Ok(Self::Red),
+            "green" => Ok(Self::Green),
+            "yellow" => Ok(Self::Yellow),
+            "blue" => Ok(Self::Blue),
+            "magenta" => Ok(Self::Magenta),
+            "cyan" => Ok(Self::Cyan),
+            "white" => Ok(Self::White),
+            "default" => Ok(Self::Default),
+            hex if hex.len() == 7 && hex.starts_with('#') => {
+                let channel = |i: usize| {
+                    hex.get(i..i + 2)
+                        .and_then(|digits| u8::from_str_radix(digits, 16).ok())
+                        .ok_or_else(|| Error::msg(format!("Unknown color {s}")))
+                };
+
+                Ok(Self::Rgb(channel(1)?, channel(3)?, channel(5)?))
+            }
+            index if index.parse::<u8>().is_ok() => Ok(Self::Indexed(index.parse()?)),
+            _ => Err(Error::msg(format!("Unknown color {s}"))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn styles_end_with_a_full_reset() {
+        let styled = AnsiStyle::new(Some(AnsiColor::Red), Some(AnsiColor::Black))
+            .bold()
+            .apply("alert");
+
+        assert!(styled.starts_with("\u{001b}[1;31;40m"), "{styled:?}");
+        assert!(styled.ends_with("alert\u{001b}[0m"), "{styled:?}");
+    }
+
+    #[test]
+    fn palette_and_truecolors_parse_and_apply() {
+        let fg: AnsiColor = "208".parse().unwrap();
+        let bg: AnsiColor = "#ff8800".parse().unwrap();
+
+        assert_eq!(fg, AnsiColor::Indexed(208));
+        assert_eq!(bg, AnsiColor::Rgb(255, 136, 0));
+        assert_eq!(
+            AnsiStyle::new(Some(fg), Some(bg)).apply("x"),
+            "\u{001b}[38;5;208;48;2;255;136;0mx\u{001b}[0m"
+        );
+        assert!("#ff88zz".parse::<AnsiColor>().is_err());
+        assert!("256".parse::<AnsiColor>().is_err());
+    }
 }
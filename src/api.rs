@@ -0,0 +1,34 @@
+use serde::{Deserialize, Serialize};
+
+/// One command sent by a client of the JSON port, one per line. Answers to the
+/// questions a command asks, like login's username and password, go in `input`.
+#[derive(Deserialize)]
+pub struct Request {
+    pub cmd: String,
+    #[serde(default)]
+    pub args: Vec<String>,
+    #[serde(default)]
+    pub input: Vec<String>,
+}
+
+/// What running a request produced, written back as a single line.
+#[derive(Serialize)]
+pub struct Response {
+    pub ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    pub output: Vec<String>,
+    /// The user logged on once the command ran, if any.
+    pub user: Option<String>,
+}
+
+impl Response {
+    pub fn error(error: impl Into<String>) -> Self {
+        Self {
+            ok: false,
+            error: Some(error.into()),
+            output: Vec::new(),
+            user: None,
+        }
+    }
+}
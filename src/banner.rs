@@ -0,0 +1,109 @@
+use serde::{Deserialize, Serialize};
+
+/// DOS end of file marker, anything after it is metadata rather than art.
+const EOF_MARKER: u8 = 0x1a;
+const SAUCE_ID: &[u8] = b"SAUCE";
+const SAUCE_LEN: usize = 128;
+/// Offset of the number of comment lines within a SAUCE record.
+const SAUCE_COMMENTS: usize = 104;
+const COMMENT_ID: &[u8] = b"COMNT";
+const COMMENT_LINE_LEN: usize = 64;
+
+/// Characters of the upper half of code page 437, the lower half matches ASCII.
+const CP437_HIGH: [char; 128] = [
+    'Ç', 'ü', 'é', 'â', 'ä', 'à', 'å', 'ç', 'ê', 'ë', 'è', 'ï', 'î', 'ì', 'Ä', 'Å', //
+    'É', 'æ', 'Æ', 'ô', 'ö', 'ò', 'û', 'ù', 'ÿ', 'Ö', 'Ü', '¢', '£', '¥', '₧', 'ƒ', //
+    'á', 'í', 'ó', 'ú', 'ñ', 'Ñ', 'ª', 'º', '¿', '⌐', '¬', '½', '¼', '¡', '«', '»', //
+    '░', '▒', '▓', '│', '┤', '╡', '╢', '╖', '╕', '╣', '║', '╗', '╝', '╜', '╛', '┐', //
+    '└', '┴', '┬', '├', '─', '┼', '╞', '╟', '╚', '╔', '╩', '╦', '╠', '═', '╬', '╧', //
+    '╨', '╤', '╥', '╙', '╘', '╒', '╓', '╫', '╪', '┘', '┌', '█', '▄', '▌', '▐', '▀', //
+    'α', 'ß', 'Γ', 'π', 'Σ', 'σ', 'µ', 'τ', 'Φ', 'Θ', 'Ω', 'δ', '∞', 'φ', 'ε', '∩', //
+    '≡', '±', '≥', '≤', '⌠', '⌡', '÷', '≈', '°', '∙', '·', '√', 'ⁿ', '²', '■', '\u{a0}',
+];
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BannerEncoding {
+    #[default]
+    Utf8,
+    Cp437,
+}
+
+/// Turns a banner file into text, dropping its SAUCE record and transcoding it.
+pub fn decode(data: &[u8], encoding: BannerEncoding) -> String {
+    let art = strip_sauce(data);
+
+    match encoding {
+        BannerEncoding::Utf8 => String::from_utf8_lossy(art).into_owned(),
+        BannerEncoding::Cp437 => art.iter().map(|&byte| cp437(byte)).collect(),
+    }
+}
+
+/// The art without the trailing SAUCE record, its comment block and the EOF marker
+/// in front of them.
+fn strip_sauce(data: &[u8]) -> &[u8] {
+    let mut art = data;
+
+    if let Some(start) = art.len().checked_sub(SAUCE_LEN)
+        && art[start..].starts_with(SAUCE_ID)
+    {
+        let comment_len = match art[start + SAUCE_COMMENTS] as usize {
+            0 => 0,
+            lines => COMMENT_ID.len() + lines * COMMENT_LINE_LEN,
+        };
+
+        art = &art[..start];
+
+        if let Some(comments) = art.len().checked_sub(comment_len)
+            && art[comments..].starts_with(COMMENT_ID)
+        {
+            art = &art[..comments];
+        }
+    }
+
+    match art.iter().position(|&byte| byte == EOF_MARKER) {
+        Some(end) => &art[..end],
+        None => art,
+    }
+}
+
+fn cp437(byte: u8) -> char {
+    match byte {
+        0x00..=0x7f => byte as char,
+        _ => CP437_HIGH[byte as usize - 0x80],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sauce(comment_lines: u8) -> Vec<u8> {
+        let mut record = vec![b' '; SAUCE_LEN];
+
+        record[..SAUCE_ID.len()].copy_from_slice(SAUCE_ID);
+        record[SAUCE_COMMENTS] = comment_lines;
+        record
+    }
+
+    #[test]
+    fn sauce_records_and_comments_are_dropped() {
+        let mut data = b"art\r\n".to_vec();
+
+        data.push(EOF_MARKER);
+        data.extend_from_slice(COMMENT_ID);
+        data.extend_from_slice(&[b'c'; COMMENT_LINE_LEN]);
+        data.extend(sauce(1));
+
+        assert_eq!(decode(&data, BannerEncoding::Utf8), "art\r\n");
+        assert_eq!(decode(b"plain", BannerEncoding::Utf8), "plain");
+    }
+
+    #[test]
+    fn cp437_maps_the_upper_half_to_unicode() {
+        assert_eq!(
+            decode(&[b'A', 0xb0, 0xdb, 0x80], BannerEncoding::Cp437),
+            "A░█Ç"
+        );
+    }
+}
@@ -1,16 +1,32 @@
-use std::{collections::HashMap, sync::Arc};
+use std::{collections::HashMap, mem::take, sync::Arc, time::Duration};
 
-use anyhow::{Context, Error, Result};
+use anyhow::{Context, Result};
 use async_trait::async_trait;
-use bcrypt::DEFAULT_COST;
-use tokio::io::AsyncWriteExt;
+use base64::{Engine, engine::general_purpose::STANDARD};
+use serde::Serialize;
+use tokio::fs::read_to_string;
+use tracing::{Instrument, debug, info, info_span, warn};
 
-use crate::session::{AppStateKind, Message, Session, SessionStatus, User};
+use crate::{
+    config::Config,
+    error::UserError,
+    glyphs::Glyph,
+    markup,
+    session::{
+        ANONYMOUS_AUTHOR, Board, BoardStats, ChangeSet, DEFAULT_BOARD, DisconnectReason,
+        MAX_PAGE_SIZE, Message, MessageFilter, OnlineUser, PrivateMessage, RESERVED_USERNAMES,
+        Role, Session, SessionMode, SessionStatus, User,
+    },
+    theme::{Scheme, Theme, paint},
+    time, wrap,
+};
 
 #[derive(Clone)]
 pub struct CommandHandler {
     pub welcome_commands: HashMap<&'static str, Arc<dyn Command + Send + Sync>>,
     pub message_commands: HashMap<&'static str, Arc<dyn Command + Send + Sync>>,
+    /// All the names of a command, keyed by its canonical (first) name.
+    aliases: HashMap<&'static str, &'static [&'static str]>,
 }
 
 impl CommandHandler {
@@ -18,6 +34,7 @@ impl CommandHandler {
         Self {
             welcome_commands: HashMap::default(),
             message_commands: HashMap::default(),
+            aliases: HashMap::default(),
         }
     }
 
@@ -25,6 +42,7 @@ impl CommandHandler {
     where
         C: Command + Send + Sync + 'static,
     {
+        self.add_aliases::<C>();
         Self::add_cmd(command, &mut self.message_commands);
     }
 
@@ -32,9 +50,51 @@ impl CommandHandler {
     where
         C: Command + Send + Sync + 'static,
     {
+        self.add_aliases::<C>();
         Self::add_cmd(command, &mut self.welcome_commands);
     }
 
+    fn add_aliases<C: Command>(&mut self) {
+        if let Some(name) = C::names().first() {
+            self.aliases.insert(name, C::names());
+        }
+    }
+
+    /// Describes every registered command so external tools can build completion
+    /// and documentation from it.
+    pub fn manifest(&self) -> Vec<CommandInfo> {
+        let mut manifest: Vec<CommandInfo> = self
+            .aliases
+            .iter()
+            .filter_map(|(name, aliases)| {
+                let welcome = self.welcome_commands.get(name);
+                let message = self.message_commands.get(name);
+                let command = welcome.or(message)?;
+                let mut states = Vec::new();
+
+                if welcome.is_some() {
+                    states.push("logged_off");
+                }
+
+                if message.is_some() {
+                    states.push("logged_on");
+                }
+
+                Some(CommandInfo {
+                    name,
+                    aliases: aliases[1..].to_vec(),
+                    states,
+                    sysop_only: command.sysop_only(),
+                    help: command.help(),
+                })
+            })
+            .collect();
+
+        manifest.sort_by_key(|c| c.name);
+
+        manifest
+    }
+
     fn add_cmd<C>(command: C, map: &mut HashMap<&'static str, Arc<dyn Command + Send + Sync>>)
     where
         C: Command + Send + Sync + 'static,
@@ -50,29 +110,84 @@ impl CommandHandler {
 
     pub async fn handle(&self, raw_command: &str, session: &mut Session) -> Result<()> {
         let mut parts = raw_command.split_whitespace();
-        let name = parts.next().context("Invalid command")?;
+        let name = parts.next().context(UserError::new("Invalid command"))?;
         let args: Vec<&str> = parts.collect();
 
-        match session.status {
+        self.dispatch(name, &args, session).await
+    }
+
+    /// Runs the command `name` with already split `args`.
+    pub async fn dispatch(&self, name: &str, args: &[&str], session: &mut Session) -> Result<()> {
+        // Command names are registered in lowercase, macros keep the case they were saved with.
+        let command_name = name.to_lowercase();
+
+        self.run(&command_name, name, args, session)
+            .instrument(info_span!("command", name = %command_name))
+            .await
+    }
+
+    async fn run(
+        &self,
+        command_name: &str,
+        name: &str,
+        args: &[&str],
+        session: &mut Session,
+    ) -> Result<()> {
+        debug!("Running");
+
+        let outcome = match session.status {
             SessionStatus::LoggedOff => {
                 self.welcome_commands
-                    .get(name)
-                    .context("Unknown command")?
-                    .execute(session, if args.is_empty() { None } else { Some(&args) })
-                    .await
-            }
-            SessionStatus::LoggedOn(_) => {
-                self.message_commands
-                    .get(name)
-                    .context("Unknown command")?
-                    .execute(session, if args.is_empty() { None } else { Some(&args) })
-                    .await
+                    .get(command_name)
+                    .context(UserError::new("Unknown command"))?
+                    .outcome(session, if args.is_empty() { None } else { Some(args) })
+                    .await?
             }
-            SessionStatus::Disconnected => session
-                .stream
-                .shutdown()
-                .await
-                .context("Could not disconnect"),
+            SessionStatus::LoggedOn(_) => match self.message_commands.get(command_name) {
+                Some(command) => {
+                    command
+                        .outcome(session, if args.is_empty() { None } else { Some(args) })
+                        .await?
+                }
+                // A name that isn't a command may be one of the user's macros.
+                None if args.is_empty() && user_macro(session, name).await.is_some() => {
+                    self.message_commands
+                        .get("macro")
+                        .context(UserError::new("Unknown command"))?
+                        .outcome(session, Some(&["run", name]))
+                        .await?
+                }
+                None => anyhow::bail!(UserError::new("Unknown command")),
+            },
+            // The connection is closed once the session is dropped.
+            SessionStatus::Disconnected(_) => return Ok(()),
+        };
+
+        session.render(outcome).await
+    }
+}
+
+#[derive(Serialize)]
+pub struct CommandInfo {
+    pub name: &'static str,
+    pub aliases: Vec<&'static str>,
+    pub states: Vec<&'static str>,
+    pub sysop_only: bool,
+    pub help: String,
+}
+
+/// Structured result of running a command, rendered by the session afterwards.
+#[derive(Debug, Default)]
+pub struct CommandOutcome {
+    pub lines: Vec<String>,
+    pub status: Option<SessionStatus>,
+}
+
+impl CommandOutcome {
+    pub fn line(text: impl Into<String>) -> Self {
+        Self {
+            lines: vec![text.into()],
+            status: None,
         }
     }
 }
@@ -85,6 +200,22 @@ pub trait Command {
         Self: Sized;
     async fn execute(&self, session: &mut Session, args: Option<&[&str]>) -> Result<()>;
     fn help(&self) -> String;
+
+    fn sysop_only(&self) -> bool {
+        false
+    }
+
+    /// Runs the command and returns what it produced instead of writing it out.
+    /// Commands that still write straight to the session get an empty outcome.
+    async fn outcome(
+        &self,
+        session: &mut Session,
+        args: Option<&[&str]>,
+    ) -> Result<CommandOutcome> {
+        self.execute(session, args).await?;
+
+        Ok(CommandOutcome::default())
+    }
 }
 
 pub struct LoginCmd;
@@ -96,37 +227,80 @@ impl Command for LoginCmd {
     }
 
     async fn execute(&self, session: &mut Session, _: Option<&[&str]>) -> Result<()> {
-        loop {
-            let username = session.prompt("Username: ", None).await?;
-            let password = session.prompt("Password: ", None).await?;
+        let max_attempts = session.config.login_attempts();
 
-            let valid_password = {
-                let users = session.app_state.users.read().await;
-                let user: &User = users
-                    .iter()
-                    .filter(|u| u.username == username)
-                    .collect::<Vec<&User>>()
-                    .first()
-                    .context("Could not find user")?;
+        for attempt in 1..=max_attempts {
+            let username = session.prompt("Username (empty to cancel): ", None).await?;
+
+            if username.is_empty() || username.eq_ignore_ascii_case("cancel") {
+                return session.writeln("Login cancelled", None).await;
+            }
+
+            if let Some(remaining) = session.app_state.login_lockout(&username).await {
+                warn!("Login to locked account {username}");
+
+                return session
+                    .writeln(
+                        &format!(
+                            "Account temporarily locked, try again in {} seconds",
+                            remaining.as_secs() + 1
+                        ),
+                        session.theme.error(),
+                    )
+                    .await;
+            }
+
+            let password = session.prompt_secret("Password: ", None).await?;
 
-                bcrypt::verify(password, &user.password).context("Invalid password")?
+            // Unknown users fail the same way as wrong passwords.
+            let hash = session.app_state.password_hash(&username).await;
+            let valid_password = match &hash {
+                Some(hash) => bcrypt::verify(password, hash).context("Invalid password")?,
+                None => false,
             };
 
-            if !valid_password {
-                session.status = SessionStatus::LoggedOff;
-                session.writeln("Login failed", None).await?;
-            } else {
+            if hash.is_some() && !valid_password {
+                session
+                    .app_state
+                    .record_failed_login(
+                        &username,
+                        session.config.lockout_attempts(),
+                        Duration::from_secs(session.config.login_lockout_secs),
+                    )
+                    .await;
+            }
+
+            if valid_password {
+                session.app_state.clear_failed_logins(&username).await;
+                info!(user = %username, "Login succeeded");
                 session.status = SessionStatus::LoggedOn(username);
-                session.writeln("Login successful", None).await?;
-                break;
+                session
+                    .writeln("Login successful", session.theme.success())
+                    .await?;
+                greet(session).await?;
+                onboard(session).await?;
+                load_preferences(session).await?;
+
+                return Ok(());
             }
+
+            warn!("Login failed for {username}");
+            session.status = SessionStatus::LoggedOff;
+            session
+                .writeln(
+                    &format!("Login failed, {} attempt(s) left", max_attempts - attempt),
+                    session.theme.error(),
+                )
+                .await?;
         }
 
-        Ok(())
+        anyhow::bail!(UserError::new("Too many failed login attempts"))
     }
 
     fn help(&self) -> String {
-        String::from("This command allows a user to login.")
+        String::from(
+            "This command logs you on, asking for your username and password. Usage: login",
+        )
     }
 }
 
@@ -134,10 +308,10 @@ impl Command for LoginCmd {
 pub struct RegisterCmd;
 
 impl RegisterCmd {
-    async fn generate_id(&self, session: &mut Session) -> Option<i64> {
-        let users = &*session.app_state.users.read().await;
-
-        Some(users.last()?.id + 1)
+    /// Picks the id of a new user, under the same lock that adds it.
+    fn generate_id(users: &[User]) -> i64 {
+        // Users can be removed, so the last one doesn't necessarily have the highest id.
+        users.iter().map(|u| u.id + 1).max().unwrap_or_default()
     }
 }
 
@@ -149,182 +323,5007 @@ impl Command for RegisterCmd {
 
     async fn execute(&self, session: &mut Session, _: Option<&[&str]>) -> Result<()> {
         let username = session.prompt("Choose a username: ", None).await?;
-        let password = session.prompt("Choose a password: ", None).await?;
+        let password = session.prompt_secret("Choose a password: ", None).await?;
+
+        if RESERVED_USERNAMES
+            .iter()
+            .any(|reserved| reserved.eq_ignore_ascii_case(&username))
+        {
+            return session.writeln("That username is reserved", None).await;
+        }
+
+        let password = bcrypt::hash(password, session.config.hash_cost())
+            .context("Could not register user")?;
 
         let user = User {
-            id: self.generate_id(session).await.unwrap_or_default(),
             username: username.to_owned(),
-            password: bcrypt::hash(password, DEFAULT_COST).context("Could not register user")?,
+            password,
+            last_login: Some(time::now()),
+            ..Default::default()
         };
 
-        session.app_state.users.write().await.push(user);
-        session.app_state.save(AppStateKind::Users).await?;
+        // Checked under the users lock so two concurrent registrations can't both pass.
+        let registered = session
+            .app_state
+            .update_users(|users| {
+                let taken = users
+                    .iter()
+                    .any(|u| u.username.to_lowercase() == user.username.to_lowercase());
+
+                if !taken {
+                    users.push(User {
+                        id: Self::generate_id(users),
+                        ..user
+                    });
+                }
+
+                !taken
+            })
+            .await?;
+
+        if !registered {
+            return session
+                .writeln("Username already taken", session.theme.error())
+                .await;
+        }
+
         session.status = SessionStatus::LoggedOn(username);
-        session.writeln("Registration successful", None).await?;
-        session.writeln("Login successful", None).await?;
+        session
+            .writeln("Registration successful", session.theme.success())
+            .await?;
+        session
+            .writeln("Login successful", session.theme.success())
+            .await?;
+        onboard(session).await?;
+        load_preferences(session).await?;
 
         Ok(())
     }
 
     fn help(&self) -> String {
-        String::from("This command allows a user to register his/her username.")
+        String::from(
+            "This command creates an account, asking for a username and a password. Usage: register",
+        )
+    }
+}
+
+/// Tells a user who just logged on when they last did, recording this login.
+async fn greet(session: &mut Session) -> Result<()> {
+    let username = session.username()?;
+    let greeting = match session.app_state.record_login(&username).await? {
+        Some(last_login) => format!("Last login: {}", time::format(last_login)),
+        None => String::from("This is your first login"),
+    };
+
+    session.writeln(&greeting, None).await
+}
+
+/// Shows the onboarding text to a user who hasn't seen it yet and remembers that
+/// they have.
+async fn onboard(session: &mut Session) -> Result<()> {
+    let username = session.username()?;
+    let Some(onboarding_file) = session.config.onboarding_file.clone() else {
+        return Ok(());
+    };
+
+    let onboarded = session
+        .app_state
+        .users
+        .read()
+        .await
+        .iter()
+        .any(|u| u.username == username && u.onboarded);
+
+    if onboarded {
+        return Ok(());
+    }
+
+    match read_to_string(&onboarding_file).await {
+        Ok(text) => {
+            session.writeln("", None).await?;
+
+            for line in text.lines() {
+                session.writeln(line, None).await?;
+            }
+
+            session.writeln("", None).await?;
+        }
+        Err(e) => {
+            warn!("Skipping onboarding {}: {e}", onboarding_file.display());
+            return Ok(());
+        }
+    }
+
+    session
+        .app_state
+        .update_user(&username, |user| user.onboarded = true)
+        .await?;
+
+    Ok(())
+}
+
+/// Applies a freshly logged in user's saved preferences to the session. They land
+/// in their own default board, or the configured one, as long as it still exists.
+async fn load_preferences(session: &mut Session) -> Result<()> {
+    let username = session.username()?;
+    let (user_board, scheme, page_size, role) = session
+        .app_state
+        .users
+        .read()
+        .await
+        .iter()
+        .find(|u| u.username == username)
+        .map(|u| (u.default_board.clone(), u.scheme, u.page_size, u.role))
+        .unwrap_or_default();
+
+    session.theme.scheme = scheme;
+    session.admin = role == Role::Admin;
+
+    if let Some(page_size) = page_size {
+        session.page_size = page_size;
+    }
+
+    for board in [user_board, session.config.default_board.clone()]
+        .into_iter()
+        .flatten()
+    {
+        if session.board_exists(&board).await {
+            session.current_board = board;
+            break;
+        }
+    }
+
+    Ok(())
+}
+
+/// `body` with every line prefixed by "> ", as quoted in a reply.
+fn quote(body: &str) -> String {
+    body.lines().map(|line| format!("> {line}\r\n")).collect()
+}
+
+fn message_id(arg: &str) -> Result<i64> {
+    arg.parse().context(UserError::new("Invalid message id"))
+}
+
+/// One message in a listing, starred when `unread`, with its id and author colored.
+fn list_line(message: &Message, replies: Option<&usize>, unread: bool, theme: &Theme) -> String {
+    let marker = if unread { "*" } else { " " };
+    let date = match message.created_at {
+        0 => String::from("----------"),
+        created_at => time::format_date(created_at),
+    };
+    let replies = match replies {
+        Some(count) if message.reply_to.is_none() => format!(" [{count} replies]"),
+        _ => String::new(),
+    };
+
+    // Colored parts end in a reset, so the plain ones in between get the default
+    // style again.
+    format!(
+        "{marker}{}{}{}{}",
+        paint(&message.id.to_string(), theme.id()),
+        paint(&format!(" {date} "), theme.default_style()),
+        paint(&message.username, theme.author()),
+        paint(
+            &format!(" {}{replies}", message.subject),
+            theme.default_style()
+        ),
+    )
+}
+
+/// Reads a message body line by line until a "." on its own. Once the next line would
+/// take it past `max_message_bytes` the body is cut there, and the lines up to the "."
+/// are dropped rather than run as commands.
+async fn read_body(session: &mut Session) -> Result<String> {
+    read_body_from(session, String::new()).await
+}
+
+/// Like `read_body`, but with the body started off as `body`, which is shown first so
+/// the user writes on after it.
+async fn read_body_from(session: &mut Session, mut body: String) -> Result<String> {
+    session
+        .write(
+            "\r\nWrite your message. Type \".\" on a line by its own to finish.\r\n\r\n",
+            None,
+        )
+        .await?;
+
+    if !body.is_empty() {
+        session.write(&body, None).await?;
+    }
+
+    loop {
+        let line = session.prompt("", None).await?;
+
+        if line.trim() == "." {
+            return Ok(body);
+        }
+
+        if body.len() + line.len() + 2 > session.config.max_message_bytes {
+            session
+                .writeln(
+                    "Message too long, ignoring the rest up to \".\"",
+                    session.theme.error(),
+                )
+                .await?;
+            skip_to_end(session).await?;
+
+            return Ok(body);
+        }
+
+        body = format!("{}{}\r\n", body, line);
     }
 }
 
+/// Reads and drops lines up to a "." on its own, so the rest of a block that was cut
+/// short isn't taken for commands.
+async fn skip_to_end(session: &mut Session) -> Result<()> {
+    while session.prompt("", None).await?.trim() != "." {}
+
+    Ok(())
+}
+
+/// Characters per line of a base64 download, as in MIME.
+const BASE64_LINE_LENGTH: usize = 76;
+
 #[derive(Clone)]
 pub struct MessageCmd;
 
 impl MessageCmd {
-    async fn generate_id(&self, session: &mut Session) -> Option<i64> {
-        let messages = &*session.app_state.messages.read().await;
+    /// Picks the id of a new message, under the same lock that adds it.
+    fn generate_id(messages: &[Message]) -> i64 {
+        // Purging can remove the newest message, so the last id isn't necessarily taken.
+        messages.iter().map(|m| m.id + 1).max().unwrap_or_default()
+    }
+
+    async fn list(&self, session: &mut Session, after: Option<i64>) -> Result<CommandOutcome> {
+        let page = session
+            .app_state
+            .messages_page(&session.current_board, after, session.page_size)
+            .await;
+        let reply_counts = session.app_state.reply_counts(&session.current_board).await;
+        let username = session.username()?;
+        let read = session.app_state.read_marks(&username).await;
+        let mut lines: Vec<String> = page
+            .messages
+            .iter()
+            .map(|m| {
+                let unread = m.unread_by(&username, &read);
 
-        Some(messages.last()?.id + 1)
+                list_line(m, reply_counts.get(&m.id), unread, &session.theme)
+            })
+            .collect();
+
+        if let Some(next) = page.next {
+            lines.push(format!("More: message list {next}"));
+        }
+
+        Ok(CommandOutcome {
+            lines,
+            status: None,
+        })
     }
-}
 
-#[async_trait]
-impl Command for MessageCmd {
-    fn names() -> &'static [&'static str] {
-        &["message", "messages", "msg"]
+    /// Messages on the current board the user neither read nor wrote.
+    async fn unread(&self, session: &mut Session) -> Result<CommandOutcome> {
+        let username = session.username()?;
+        let read = session.app_state.read_marks(&username).await;
+        let reply_counts = session.app_state.reply_counts(&session.current_board).await;
+        let lines: Vec<String> = session
+            .app_state
+            .messages
+            .read()
+            .await
+            .iter()
+            .filter(|m| {
+                m.board == session.current_board && !m.deleted && m.unread_by(&username, &read)
+            })
+            .map(|m| list_line(m, reply_counts.get(&m.id), true, &session.theme))
+            .collect();
+
+        if lines.is_empty() {
+            return Ok(CommandOutcome::line("No unread messages"));
+        }
+
+        Ok(CommandOutcome {
+            lines,
+            status: None,
+        })
     }
 
-    async fn execute(&self, session: &mut Session, args: Option<&[&str]>) -> Result<()> {
-        match args {
-            None => session.writeln("No sub commands", None).await,
-            Some([sub_command]) => match *sub_command {
-                "list" => {
-                    let messages = {
-                        let guard = session.app_state.messages.read().await;
-                        guard.clone()
+    async fn thread(&self, session: &mut Session, id: i64) -> Result<CommandOutcome> {
+        let thread = session
+            .app_state
+            .thread(id, session.config.max_thread_depth)
+            .await;
+
+        if thread.is_empty() {
+            return Ok(CommandOutcome::line("Invalid message id"));
+        }
+
+        let branch = session.glyph(Glyph::Branch);
+
+        Ok(CommandOutcome {
+            lines: thread
+                .iter()
+                .map(|(depth, m)| {
+                    let indent = match depth {
+                        0 => String::new(),
+                        _ => format!("{}{branch}", "  ".repeat(depth - 1)),
                     };
 
-                    for message in messages {
-                        session
-                            .writeln(
-                                &format!("{} {} {}", message.id, message.username, message.subject),
-                                None,
-                            )
-                            .await?;
+                    if m.deleted {
+                        format!("{indent}{} [deleted]", m.id)
+                    } else {
+                        format!("{indent}{} {} {}", m.id, m.username, m.subject)
                     }
+                })
+                .collect(),
+            status: None,
+        })
+    }
+
+    /// Prompts for and posts a message to `board`. Replies record their parent and
+    /// suggest its subject.
+    async fn post(
+        &self,
+        session: &mut Session,
+        board: String,
+        parent: Option<&Message>,
+    ) -> Result<CommandOutcome> {
+        if session.app_state.board_archived(&board).await {
+            return Ok(CommandOutcome::line("Board is archived"));
+        }
+
+        let suggested = parent.map(|parent| {
+            if parent.subject.starts_with("Re: ") {
+                parent.subject.clone()
+            } else {
+                format!("Re: {}", parent.subject)
+            }
+        });
+        let mut subject = match &suggested {
+            Some(suggested) => {
+                let answer = session
+                    .prompt(&format!("Subject [{suggested}]: "), None)
+                    .await?;
 
-                    Ok(())
+                if answer.is_empty() {
+                    suggested.clone()
+                } else {
+                    answer
                 }
-                "new" => {
-                    let subject = session.prompt("Subject: ", None).await?;
-                    let mut body = String::new();
+            }
+            None => session.prompt("Subject: ", None).await?,
+        };
+        let body = match parent {
+            Some(parent) => read_body_from(session, quote(&parent.body)).await?,
+            None => read_body(session).await?,
+        };
+        let username = session.username()?;
 
-                    session
-                        .write("\r\nWrite your message. Type \".\" on a line by its own to finish.\r\n\r\n", None)
-                        .await?;
+        if session.config.normalize_subjects {
+            subject = normalize_subject(&subject);
+        }
 
-                    while let Ok(line) = session.prompt("", None).await {
-                        if line.trim() != "." {
-                            body = format!("{}{}\r\n", body, line);
-                        } else {
-                            break;
-                        }
-                    }
+        let window = session.config.duplicate_post_window_secs as i64;
+        let duplicate = session
+            .app_state
+            .messages
+            .read()
+            .await
+            .iter()
+            .rev()
+            .find(|m| m.author() == username && !m.deleted)
+            .is_some_and(|m| {
+                m.created_at >= time::now() - window && m.subject == subject && m.body == body
+            });
 
-                    let username = match &session.status {
-                        SessionStatus::LoggedOn(username) => username.to_owned(),
-                        SessionStatus::LoggedOff => {
-                            return Err(Error::msg("User is not logged in"));
-                        }
-                        SessionStatus::Disconnected => {
-                            return Err(Error::msg("User is disconnected"));
-                        }
-                    };
+        if duplicate {
+            return Ok(CommandOutcome::line("Duplicate post ignored"));
+        }
 
-                    let message = Message {
-                        id: self.generate_id(session).await.unwrap_or_default(),
-                        username,
-                        subject,
-                        body,
-                    };
+        let anonymous = session.app_state.board_anonymous(&board).await;
+        let (username, real_author) = if anonymous {
+            (ANONYMOUS_AUTHOR.to_owned(), Some(username))
+        } else {
+            (username, None)
+        };
+        let message = Message {
+            username,
+            board,
+            subject,
+            body,
+            created_at: time::now(),
+            reply_to: parent.map(|parent| parent.id),
+            real_author,
+            ..Default::default()
+        };
+        let id = session
+            .app_state
+            .update_messages(|messages| {
+                let id = Self::generate_id(messages);
 
-                    session.app_state.messages.write().await.push(message);
-                    session.app_state.save(AppStateKind::Messages).await?;
+                messages.push(Message { id, ..message });
+                id
+            })
+            .await?;
+        session.messages_posted += 1;
 
-                    Ok(())
-                }
-                _ => session.writeln("Unknown sub command", None).await,
-            },
-            Some([sub_command, sub_arg]) => match *sub_command {
-                "read" => {
-                    let message = {
-                        let messages = &*session.app_state.messages.read().await;
-                        let index: i64 = sub_arg.parse()?;
+        if anonymous {
+            session
+                .app_state
+                .audit(&session.username()?, &format!("anonymous post {id}"))
+                .await?;
+        }
 
-                        messages
-                            .get(index as usize)
-                            .context("Invalid message id")?
-                            .to_owned()
-                    };
+        Ok(CommandOutcome::default())
+    }
 
-                    session
-                        .writeln(
-                            &format!("Subject: {}\r\n\r\n{}", message.subject, message.body),
-                            None,
-                        )
-                        .await
+    /// Lets the author rewrite a message's body and optionally its subject. On anonymous
+    /// boards only sysops may edit.
+    async fn edit(&self, session: &mut Session, id: i64) -> Result<CommandOutcome> {
+        let username = session.username()?;
+        let (message, group) = {
+            let messages = session.app_state.messages.read().await;
+            let message = messages
+                .iter()
+                .find(|m| m.id == id && !m.deleted)
+                .context(UserError::new("Invalid message id"))?;
+
+            (message.clone(), crosspost_copies(&messages, message))
+        };
+
+        // Edits on anonymous boards could give their hidden authors away, so only
+        // sysops may make them.
+        if session.app_state.board_anonymous(&message.board).await {
+            if !session.is_sysop() {
+                return Ok(CommandOutcome::line(
+                    "Messages on anonymous boards can only be edited by a sysop",
+                ));
+            }
+        } else if message.author() != username {
+            return Ok(CommandOutcome::line("You can only edit your own messages"));
+        }
+
+        if session.app_state.board_archived(&message.board).await {
+            return Ok(CommandOutcome::line("Board is archived"));
+        }
+
+        let mut ids = vec![id];
+
+        if !group.is_empty() {
+            let answer = session
+                .prompt(
+                    &format!("Also edit its {} cross-posted copies? (y/n) ", group.len()),
+                    None,
+                )
+                .await?;
+
+            if answer.eq_ignore_ascii_case("y") {
+                ids.extend(group);
+            }
+        }
+
+        session
+            .writeln("Current message:", session.theme.header())
+            .await?;
+        session.write(&message.body, None).await?;
+
+        let answer = session
+            .prompt(&format!("\r\nSubject [{}]: ", message.subject), None)
+            .await?;
+        let mut subject = if answer.is_empty() {
+            message.subject
+        } else {
+            answer
+        };
+
+        if session.config.normalize_subjects {
+            subject = normalize_subject(&subject);
+        }
+
+        let body = read_body(session).await?;
+
+        // An empty body keeps the old one, so only the subject can be changed.
+        let body = if body.is_empty() { message.body } else { body };
+
+        session
+            .app_state
+            .update_messages(|messages| {
+                for message in messages.iter_mut().filter(|m| ids.contains(&m.id)) {
+                    message.subject = subject.clone();
+                    message.body = body.clone();
+                    message.edited_at = Some(time::now());
                 }
-                _ => session.writeln("Unknown sub command", None).await,
-            },
-            Some(&[]) | Some(&[_, _, _, ..]) => session.writeln("Show usage", None).await,
+            })
+            .await?;
+
+        match ids.len() {
+            1 => Ok(CommandOutcome::line(format!("Message {id} edited"))),
+            edited => Ok(CommandOutcome::line(format!("{edited} message(s) edited"))),
         }
     }
 
-    fn help(&self) -> String {
-        todo!()
-    }
-}
+    /// Hides a message from listings. It stays on disk until purged so a sysop can
+    /// still restore it. Authors can delete their own messages, including anonymous ones.
+    async fn delete(&self, session: &mut Session, id: i64) -> Result<CommandOutcome> {
+        let username = session.username()?;
+        let sysop = session.is_sysop();
 
-pub struct HelpCmd {
-    pub command_handler: CommandHandler,
-}
+        let (owner, group) = {
+            let messages = session.app_state.messages.read().await;
+            let message = messages
+                .iter()
+                .find(|m| m.id == id && !m.deleted)
+                .context(UserError::new("Invalid message id"))?;
 
-#[async_trait]
-impl Command for HelpCmd {
-    fn names() -> &'static [&'static str] {
-        &["help", "commands"]
-    }
+            (
+                message.author().to_owned(),
+                crosspost_copies(&messages, message),
+            )
+        };
 
-    async fn execute(&self, session: &mut Session, args: Option<&[&str]>) -> Result<()> {
-        match args {
-            None => session.writeln(&self.help(), None).await,
-            Some(args) => {
-                let message = self
-                    .command_handler
-                    .welcome_commands
-                    .get(args.get(0).context("Unknown command")?)
-                    .context("Unknown command")?
-                    .help();
+        if owner != username && !sysop {
+            return Ok(CommandOutcome::line(
+                "You can only delete your own messages",
+            ));
+        }
+
+        let mut ids = vec![id];
 
-                session.writeln(&message, None).await
+        if !group.is_empty() {
+            let answer = session
+                .prompt(
+                    &format!(
+                        "Also delete its {} cross-posted copies? (y/n) ",
+                        group.len()
+                    ),
+                    None,
+                )
+                .await?;
+
+            if answer.eq_ignore_ascii_case("y") {
+                ids.extend(group);
             }
         }
+
+        session
+            .app_state
+            .update_messages(|messages| {
+                for message in messages.iter_mut().filter(|m| ids.contains(&m.id)) {
+                    message.deleted = true;
+                    message.deleted_at = Some(time::now());
+                }
+            })
+            .await?;
+
+        Ok(CommandOutcome::line(format!(
+            "{} message(s) deleted",
+            ids.len()
+        )))
     }
 
-    fn help(&self) -> String {
-        String::from("This command shows a message help for each command.")
+    /// Cross-posting copies a message into another board. Every copy, including the
+    /// original, shares a `crosspost_group` (the original's id) so the group can be
+    /// deleted together.
+    async fn crosspost(
+        &self,
+        session: &mut Session,
+        id: i64,
+        board: &str,
+    ) -> Result<CommandOutcome> {
+        if !session.is_sysop() {
+            return Ok(CommandOutcome::line("Permission denied"));
+        }
+
+        if !session.board_exists(board).await {
+            return Ok(CommandOutcome::line("Unknown board"));
+        }
+
+        if session.app_state.board_archived(board).await {
+            return Ok(CommandOutcome::line("Board is archived"));
+        }
+
+        let new_id = session
+            .app_state
+            .update_messages(|messages| {
+                let new_id = Self::generate_id(messages);
+                let original = messages.iter_mut().find(|m| m.id == id && !m.deleted)?;
+
+                if original.board == board {
+                    return Some(None);
+                }
+
+                let group = *original.crosspost_group.get_or_insert(original.id);
+                let copy = Message {
+                    id: new_id,
+                    board: board.to_owned(),
+                    crosspost_group: Some(group),
+                    ..original.clone()
+                };
+
+                messages.push(copy);
+
+                Some(Some(new_id))
+            })
+            .await?
+            .context(UserError::new("Invalid message id"))?;
+
+        let Some(new_id) = new_id else {
+            return Ok(CommandOutcome::line("Message is already in that board"));
+        };
+
+        Ok(CommandOutcome::line(format!(
+            "Message {id} cross-posted to {board} as {new_id}"
+        )))
     }
-}
 
-pub struct QuitCmd;
+    async fn restore(&self, session: &mut Session, id: i64) -> Result<CommandOutcome> {
+        if !session.is_sysop() {
+            return Ok(CommandOutcome::line("Permission denied"));
+        }
 
-#[async_trait]
-impl Command for QuitCmd {
-    fn names() -> &'static [&'static str] {
-        &["quit", "exit", "bye"]
+        session
+            .app_state
+            .update_message(id, |message| {
+                let deleted = message.deleted;
+
+                message.deleted = false;
+                message.deleted_at = None;
+
+                deleted
+            })
+            .await?
+            .filter(|deleted| *deleted)
+            .context(UserError::new("Invalid message id"))?;
+        session
+            .app_state
+            .audit(&session.username()?, &format!("restore message {id}"))
+            .await?;
+
+        Ok(CommandOutcome::line(format!("Message {id} restored")))
     }
 
-    async fn execute(&self, session: &mut Session, _: Option<&[&str]>) -> Result<()> {
-        session.status = SessionStatus::Disconnected;
+    /// Reads the attachment contents as base64 lines, so any bytes survive the line
+    /// based protocol, and stores them in the blob store. Only the author or a sysop
+    /// may attach files.
+    async fn attach(
+        &self,
+        session: &mut Session,
+        id: i64,
+        filename: &str,
+    ) -> Result<CommandOutcome> {
+        if !valid_filename(filename) {
+            return Ok(CommandOutcome::line("Invalid file name"));
+        }
 
-        Ok(())
+        let author = session
+            .app_state
+            .messages
+            .read()
+            .await
+            .iter()
+            .find(|m| m.id == id && !m.deleted)
+            .map(|m| m.author().to_owned())
+            .context(UserError::new("Invalid message id"))?;
+
+        if author != session.username()? && !session.is_sysop() {
+            return Ok(CommandOutcome::line(
+                "You can only attach files to your own messages",
+            ));
+        }
+
+        let max_size = session.config.max_attachment_size;
+
+        session
+            .write(
+                "\r\nSend the file base64 encoded. Type \".\" on a line by its own to finish.\r\n\r\n",
+                None,
+            )
+            .await?;
+
+        // Uploads can pause for a while between lines, so they are exempt from idle kicks.
+        session.mode = SessionMode::Transfer;
+        let data = self.receive(session, max_size).await;
+        session.mode = SessionMode::Normal;
+
+        let Some(data) = data? else {
+            return Ok(CommandOutcome::line(format!(
+                "Attachments are limited to {max_size} bytes"
+            )));
+        };
+
+        let attachment = match session
+            .app_state
+            .attach(id, filename, &data, session.config.max_attachments_total)
+            .await
+        {
+            Ok(attachment) => attachment,
+            Err(e) => return Ok(CommandOutcome::line(e.to_string())),
+        };
+
+        Ok(CommandOutcome::line(format!(
+            "Attached {} ({} bytes) to message {id}",
+            attachment.filename, attachment.size
+        )))
+    }
+
+    /// Reads base64 lines up to a "." line and decodes them. Returns None once the
+    /// upload grows past `max_size` bytes, after dropping the rest of it up to the "."
+    /// so it isn't taken for commands.
+    async fn receive(&self, session: &mut Session, max_size: usize) -> Result<Option<Vec<u8>>> {
+        let mut encoded = String::new();
+
+        loop {
+            let line = session.prompt("", None).await?;
+
+            if line.trim() == "." {
+                break;
+            }
+
+            encoded.extend(line.split_whitespace());
+
+            // Every 4 characters decode to 3 bytes, less up to 2 of padding at the end.
+            if (encoded.len() / 4 * 3).saturating_sub(2) > max_size {
+                skip_to_end(session).await?;
+
+                return Ok(None);
+            }
+        }
+
+        let data = STANDARD
+            .decode(&encoded)
+            .map_err(|_| UserError::new("Attachments have to be sent base64 encoded"))?;
+
+        Ok((data.len() <= max_size).then_some(data))
+    }
+
+    async fn download(&self, session: &mut Session, id: i64) -> Result<CommandOutcome> {
+        let attachment = session
+            .app_state
+            .messages
+            .read()
+            .await
+            .iter()
+            .find(|m| m.id == id && !m.deleted)
+            .context(UserError::new("Invalid message id"))?
+            .attachment
+            .clone();
+        let Some(attachment) = attachment else {
+            return Ok(CommandOutcome::line("Message has no attachment"));
+        };
+        let data = session.app_state.read_attachment(&attachment).await?;
+
+        session
+            .writeln(
+                &format!("{} ({} bytes)", attachment.filename, attachment.size),
+                session.theme.header(),
+            )
+            .await?;
+
+        // Sent back the way it came in: base64 lines up to a "." line.
+        for line in STANDARD.encode(&data).as_bytes().chunks(BASE64_LINE_LENGTH) {
+            session.writeln(std::str::from_utf8(line)?, None).await?;
+        }
+
+        session.writeln(".", None).await?;
+
+        Ok(CommandOutcome::default())
+    }
+
+    async fn show(&self, message: &Message, session: &Session) -> String {
+        let attachment = match &message.attachment {
+            Some(attachment) => format!(
+                "\r\nAttachment: {} ({} bytes)",
+                attachment.filename, attachment.size
+            ),
+            None => String::new(),
+        };
+        let real_author = match &message.real_author {
+            Some(real_author) if session.is_sysop() => format!("Posted by: {real_author}\r\n"),
+            _ => String::new(),
+        };
+
+        // Messages from before timestamps were recorded have a created_at of 0.
+        let date = match message.created_at {
+            0 => String::from("unknown"),
+            created_at => time::format(created_at),
+        };
+
+        // Anonymous messages are shown under a name no user has, so they get none.
+        let signature = match signature(session, &message.username).await {
+            Some(signature) => format!("\r\n-- \r\n{signature}"),
+            None => String::new(),
+        };
+        let edited = if message.edited_at.is_some() {
+            " (edited)"
+        } else {
+            ""
+        };
+
+        format!(
+            "{real_author}Date: {date}{edited}\r\nSubject: {}\r\n\r\n{}{signature}{attachment}",
+            message.subject,
+            wrap::wrap(
+                &markup::render(&message.body, session.config.color),
+                session.wrap_width()
+            )
+        )
+    }
+}
+
+#[async_trait]
+impl Command for MessageCmd {
+    fn names() -> &'static [&'static str] {
+        &["message", "messages", "msg"]
+    }
+
+    async fn execute(&self, session: &mut Session, args: Option<&[&str]>) -> Result<()> {
+        let outcome = self.outcome(session, args).await?;
+
+        session.render(outcome).await
+    }
+
+    async fn outcome(
+        &self,
+        session: &mut Session,
+        args: Option<&[&str]>,
+    ) -> Result<CommandOutcome> {
+        match args {
+            None => Ok(CommandOutcome::line("No sub commands")),
+            Some([sub_command]) => match *sub_command {
+                "list" => self.list(session, None).await,
+                "unread" => self.unread(session).await,
+                "new" => {
+                    let board = session.current_board.clone();
+
+                    self.post(session, board, None).await
+                }
+                "next" => {
+                    let key = (session.username()?, session.current_board.clone());
+                    let message = session
+                        .app_state
+                        .query_messages(MessageFilter {
+                            board: Some(key.1.clone()),
+                            unread_for: Some(key.0.clone()),
+                            limit: Some(1),
+                            ..Default::default()
+                        })
+                        .await
+                        .pop();
+
+                    match message {
+                        Some(message) => {
+                            session
+                                .app_state
+                                .mark_read(&key.0, &key.1, message.id)
+                                .await?;
+                            session.messages_read += 1;
+
+                            Ok(CommandOutcome::line(self.show(&message, session).await))
+                        }
+                        None => Ok(CommandOutcome::line("No more messages")),
+                    }
+                }
+                _ => Ok(CommandOutcome::line("Unknown sub command")),
+            },
+            Some([sub_command, sub_arg]) => match *sub_command {
+                "read" => {
+                    let message = {
+                        let messages = &*session.app_state.messages.read().await;
+                        let id = message_id(sub_arg)?;
+
+                        messages
+                            .iter()
+                            .find(|m| m.id == id && !m.deleted)
+                            .context(UserError::new("Invalid message id"))?
+                            .to_owned()
+                    };
+
+                    session
+                        .app_state
+                        .mark_read(&session.username()?, &message.board, message.id)
+                        .await?;
+                    session.messages_read += 1;
+
+                    Ok(CommandOutcome::line(self.show(&message, session).await))
+                }
+                "list" => {
+                    let after = sub_arg.parse().context(UserError::new("Invalid page"))?;
+
+                    self.list(session, Some(after)).await
+                }
+                "search" => {
+                    let messages = session
+                        .app_state
+                        .query_messages(MessageFilter {
+                            keyword: Some(sub_arg.to_string()),
+                            ..Default::default()
+                        })
+                        .await;
+
+                    if messages.is_empty() {
+                        return Ok(CommandOutcome::line("No messages found"));
+                    }
+
+                    Ok(CommandOutcome {
+                        lines: messages
+                            .iter()
+                            .map(|m| format!("{} {} {} {}", m.id, m.board, m.username, m.subject))
+                            .collect(),
+                        status: None,
+                    })
+                }
+                "reply" => {
+                    let id = message_id(sub_arg)?;
+                    let parent = session
+                        .app_state
+                        .messages
+                        .read()
+                        .await
+                        .iter()
+                        .find(|m| m.id == id && !m.deleted)
+                        .cloned()
+                        .context(UserError::new("Invalid message id"))?;
+
+                    self.post(session, parent.board.clone(), Some(&parent))
+                        .await
+                }
+                "thread" => self.thread(session, message_id(sub_arg)?).await,
+                "edit" => self.edit(session, message_id(sub_arg)?).await,
+                "delete" => self.delete(session, message_id(sub_arg)?).await,
+                "restore" => self.restore(session, message_id(sub_arg)?).await,
+                "download" => self.download(session, message_id(sub_arg)?).await,
+                _ => Ok(CommandOutcome::line("Unknown sub command")),
+            },
+            Some([sub_command, sub_arg, sub_arg2]) => match *sub_command {
+                "crosspost" => {
+                    self.crosspost(session, message_id(sub_arg)?, sub_arg2)
+                        .await
+                }
+                "attach" => self.attach(session, message_id(sub_arg)?, sub_arg2).await,
+                "chown" => {
+                    if !session.is_sysop() {
+                        return Ok(CommandOutcome::line("Permission denied"));
+                    }
+
+                    let id = message_id(sub_arg)?;
+                    let new_owner = sub_arg2.to_string();
+
+                    if !session
+                        .app_state
+                        .users
+                        .read()
+                        .await
+                        .iter()
+                        .any(|u| u.username == new_owner)
+                    {
+                        return Ok(CommandOutcome::line("Could not find user"));
+                    }
+
+                    let old_owner = session
+                        .app_state
+                        .update_messages(|messages| {
+                            let message = messages.iter_mut().find(|m| m.id == id)?;
+                            let old_owner = message.author().to_owned();
+
+                            // Anonymous posts stay anonymous, only their hidden author moves.
+                            match &mut message.real_author {
+                                Some(real_author) => *real_author = new_owner.clone(),
+                                None => message.username = new_owner.clone(),
+                            }
+
+                            Some(old_owner)
+                        })
+                        .await?
+                        .context(UserError::new("Invalid message id"))?;
+
+                    session
+                        .app_state
+                        .audit(
+                            &session.username()?,
+                            &format!("chown message {id} from {old_owner} to {new_owner}"),
+                        )
+                        .await?;
+
+                    Ok(CommandOutcome::line(format!(
+                        "Message {id} now belongs to {new_owner}"
+                    )))
+                }
+                _ => Ok(CommandOutcome::line("Unknown sub command")),
+            },
+            Some(&[]) | Some(&[_, _, _, _, ..]) => Ok(CommandOutcome::line("Show usage")),
+        }
+    }
+
+    fn help(&self) -> String {
+        String::from(
+            "This command lists, reads and posts messages on the current board. Usage: message list [page] | unread | new | next | read <id> | reply <id> | edit <id> | thread <id> | search <keyword> | delete <id> | restore <id> | download <id> | crosspost <id> <board> | attach <id> <file>",
+        )
+    }
+}
+
+/// Lists a change set, prefixed to make clear whether it was applied.
+async fn report_changes(session: &mut Session, changes: ChangeSet, dry_run: bool) -> Result<()> {
+    if dry_run {
+        session
+            .writeln("Dry run, nothing was changed:", session.theme.header())
+            .await?;
+    }
+
+    for change in changes.changes {
+        session.writeln(&format!("  {change}"), None).await?;
+    }
+
+    Ok(())
+}
+
+/// Ids of the visible cross-posted copies of `message`, leaving itself out.
+fn crosspost_copies(messages: &[Message], message: &Message) -> Vec<i64> {
+    match message.crosspost_group {
+        Some(group) => messages
+            .iter()
+            .filter(|m| m.crosspost_group == Some(group) && m.id != message.id && !m.deleted)
+            .map(|m| m.id)
+            .collect(),
+        None => Vec::new(),
+    }
+}
+
+/// Trims a subject, collapses runs of whitespace and capitalizes its first letter.
+fn normalize_subject(subject: &str) -> String {
+    let subject = subject.split_whitespace().collect::<Vec<&str>>().join(" ");
+    let mut chars = subject.chars();
+
+    match chars.next() {
+        Some(first) => first.to_uppercase().chain(chars).collect(),
+        None => subject,
+    }
+}
+
+pub struct MergeUserCmd;
+
+#[async_trait]
+impl Command for MergeUserCmd {
+    fn names() -> &'static [&'static str] {
+        &["mergeuser"]
+    }
+
+    async fn execute(&self, session: &mut Session, args: Option<&[&str]>) -> Result<()> {
+        if !session.is_sysop() {
+            return session
+                .writeln("Permission denied", session.theme.error())
+                .await;
+        }
+
+        let (from, into, dry_run) = match args {
+            Some([from, into]) => (from, into, false),
+            Some([from, into, "dryrun"]) => (from, into, true),
+            _ => {
+                return session
+                    .writeln("Usage: mergeuser <from> <into> [dryrun]", None)
+                    .await;
+            }
+        };
+
+        if from == into {
+            return session
+                .writeln("Cannot merge a user into itself", None)
+                .await;
+        }
+
+        let Some(changes) = session.app_state.merge_user(from, into, dry_run).await? else {
+            return session.writeln("Could not find user", None).await;
+        };
+
+        if dry_run {
+            return report_changes(session, changes, true).await;
+        }
+
+        session
+            .app_state
+            .audit(
+                &session.username()?,
+                &format!("mergeuser {from} into {into}"),
+            )
+            .await?;
+        session
+            .writeln(&format!("Merged {from} into {into}"), None)
+            .await?;
+
+        report_changes(session, changes, false).await
+    }
+
+    fn help(&self) -> String {
+        String::from(
+            "This command merges a duplicate account into another one. Add dryrun to only show what would change.",
+        )
+    }
+
+    fn sysop_only(&self) -> bool {
+        true
+    }
+}
+
+pub struct ForgetCmd;
+
+#[async_trait]
+impl Command for ForgetCmd {
+    fn names() -> &'static [&'static str] {
+        &["forget"]
+    }
+
+    async fn execute(&self, session: &mut Session, args: Option<&[&str]>) -> Result<()> {
+        if !session.is_sysop() {
+            return session
+                .writeln("Permission denied", session.theme.error())
+                .await;
+        }
+
+        let (username, dry_run) = match args {
+            Some([username]) => (username, false),
+            Some([username, "dryrun"]) => (username, true),
+            _ => {
+                return session.writeln("Usage: forget <user> [dryrun]", None).await;
+            }
+        };
+
+        if *username == session.username()? {
+            return session.writeln("Cannot forget yourself", None).await;
+        }
+
+        let Some(id) = session
+            .app_state
+            .users
+            .read()
+            .await
+            .iter()
+            .find(|u| u.username == *username)
+            .map(|u| u.id)
+        else {
+            return session.writeln("Could not find user", None).await;
+        };
+
+        // Their session would keep writing their name to the stores and logs.
+        if session
+            .app_state
+            .online
+            .read()
+            .await
+            .values()
+            .any(|u| u.username == *username)
+        {
+            return session
+                .writeln(&format!("{username} is online, kick them first"), None)
+                .await;
+        }
+
+        if !dry_run {
+            let answer = session
+                .prompt(
+                    &format!("Permanently remove all data about {username}? (y/n) "),
+                    None,
+                )
+                .await?;
+
+            if !answer.eq_ignore_ascii_case("y") {
+                return Ok(());
+            }
+        }
+
+        let remove_messages = session.config.forget_removes_messages;
+        let Some(changes) = session
+            .app_state
+            .forget(username, remove_messages, dry_run)
+            .await?
+        else {
+            return session.writeln("Could not find user", None).await;
+        };
+
+        if !dry_run {
+            session
+                .app_state
+                // Only the id is logged, the name is among what was forgotten.
+                .audit(&session.username()?, &format!("forget user {id}"))
+                .await?;
+            session
+                .writeln(&format!("Forgot {username}"), session.theme.success())
+                .await?;
+        }
+
+        report_changes(session, changes, dry_run).await
+    }
+
+    fn help(&self) -> String {
+        String::from(
+            "This command permanently removes a user's account and personal data. Their messages are kept anonymously or removed, depending on the configuration. Add dryrun to only show what would change.",
+        )
+    }
+
+    fn sysop_only(&self) -> bool {
+        true
+    }
+}
+
+pub struct BoardsCmd;
+
+impl BoardsCmd {
+    async fn show(&self, session: &mut Session, board: BoardStats) -> Result<()> {
+        let last_post = match board.last_post {
+            Some(last_post) => time::format(last_post),
+            None => String::from("never"),
+        };
+
+        session
+            .writeln(
+                &format!(
+                    "{}{} {} message(s), last post {last_post}",
+                    if board.unread { "*" } else { " " },
+                    board.name,
+                    board.messages,
+                ),
+                None,
+            )
+            .await
+    }
+}
+
+#[async_trait]
+impl Command for BoardsCmd {
+    fn names() -> &'static [&'static str] {
+        &["boards"]
+    }
+
+    async fn execute(&self, session: &mut Session, _: Option<&[&str]>) -> Result<()> {
+        let boards = session
+            .app_state
+            .board_stats(&session.username()?, &session.config.boards)
+            .await;
+        let separator = session.separator(40);
+
+        let (archived, active): (Vec<_>, Vec<_>) = boards.into_iter().partition(|b| b.archived);
+
+        session.writeln("Boards", session.theme.header()).await?;
+        session.writeln(&separator, None).await?;
+
+        for board in active {
+            self.show(session, board).await?;
+        }
+
+        if !archived.is_empty() {
+            session.writeln("", None).await?;
+            session.writeln("Archived", session.theme.header()).await?;
+            session.writeln(&separator, None).await?;
+
+            for board in archived {
+                self.show(session, board).await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn help(&self) -> String {
+        String::from(
+            "This command lists the boards, marking those with unread messages. Archived boards are read-only and listed separately.",
+        )
+    }
+}
+
+pub struct FingerCmd;
+
+#[async_trait]
+impl Command for FingerCmd {
+    fn names() -> &'static [&'static str] {
+        &["finger"]
+    }
+
+    async fn execute(&self, session: &mut Session, args: Option<&[&str]>) -> Result<()> {
+        let Some([username]) = args else {
+            return session.writeln("Usage: finger <user>", None).await;
+        };
+
+        let last_seen = session
+            .app_state
+            .users
+            .read()
+            .await
+            .iter()
+            .find(|u| u.username == *username)
+            .map(|u| u.last_seen);
+        let online = session
+            .app_state
+            .online
+            .read()
+            .await
+            .values()
+            .any(|u| u.username == *username);
+
+        let status = match last_seen {
+            None => String::from("Could not find user"),
+            Some(_) if online => format!("{username}: online now"),
+            Some(Some(last_seen)) => format!("{username}: last seen {}", time::format(last_seen)),
+            Some(None) => format!("{username}: never seen"),
+        };
+
+        session.writeln(&status, None).await
+    }
+
+    fn help(&self) -> String {
+        String::from("This command shows whether a user is online or when they were last seen.")
+    }
+}
+
+/// Longest about text or signature a user can set.
+const MAX_PROFILE_LENGTH: usize = 256;
+
+async fn signature(session: &Session, username: &str) -> Option<String> {
+    session
+        .app_state
+        .users
+        .read()
+        .await
+        .iter()
+        .find(|u| u.username == username)?
+        .signature
+        .clone()
+}
+
+pub struct ProfileCmd;
+
+#[async_trait]
+impl Command for ProfileCmd {
+    fn names() -> &'static [&'static str] {
+        &["profile"]
+    }
+
+    async fn execute(&self, session: &mut Session, args: Option<&[&str]>) -> Result<()> {
+        let username = match args {
+            None => session.username()?,
+            Some([username]) => username.to_string(),
+            Some(_) => return session.writeln("Usage: profile [user]", None).await,
+        };
+        let profile = session
+            .app_state
+            .users
+            .read()
+            .await
+            .iter()
+            .find(|u| u.username == username)
+            .map(|u| (u.about.clone(), u.signature.clone()));
+
+        let Some((about, signature)) = profile else {
+            return session
+                .writeln("Could not find user", session.theme.error())
+                .await;
+        };
+
+        session
+            .writeln(&format!("Profile of {username}"), session.theme.header())
+            .await?;
+        session.writeln(&session.separator(9), None).await?;
+        session
+            .writeln(&format!("About: {}", about.as_deref().unwrap_or("-")), None)
+            .await?;
+        session
+            .writeln(
+                &format!("Signature: {}", signature.as_deref().unwrap_or("-")),
+                None,
+            )
+            .await
+    }
+
+    fn help(&self) -> String {
+        String::from("This command shows a user's about text and signature. Usage: profile [user]")
+    }
+}
+
+pub struct SetProfileCmd;
+
+#[async_trait]
+impl Command for SetProfileCmd {
+    fn names() -> &'static [&'static str] {
+        &["setprofile"]
+    }
+
+    async fn execute(&self, session: &mut Session, args: Option<&[&str]>) -> Result<()> {
+        let Some([field @ ("about" | "signature"), text @ ..]) = args else {
+            return session
+                .writeln("Usage: setprofile about|signature [text]", None)
+                .await;
+        };
+
+        let text = text.join(" ");
+
+        if text.chars().count() > MAX_PROFILE_LENGTH {
+            return session
+                .writeln(
+                    &format!("Keep it under {MAX_PROFILE_LENGTH} characters"),
+                    session.theme.error(),
+                )
+                .await;
+        }
+
+        let username = session.username()?;
+        let value = Some(text).filter(|text| !text.is_empty());
+        let cleared = value.is_none();
+
+        session
+            .app_state
+            .update_user(&username, |user| match *field {
+                "about" => user.about = value,
+                _ => user.signature = value,
+            })
+            .await?;
+
+        let message = match cleared {
+            true => format!("Cleared your {field}"),
+            false => format!("Updated your {field}"),
+        };
+
+        session.writeln(&message, session.theme.success()).await
     }
 
     fn help(&self) -> String {
-        String::from("This command terminates your session.")
+        String::from(
+            "This command sets your about text or the signature shown under your messages, leave the text out to clear it. Usage: setprofile about|signature [text]",
+        )
+    }
+}
+
+pub struct RecordCmd;
+
+#[async_trait]
+impl Command for RecordCmd {
+    fn names() -> &'static [&'static str] {
+        &["record"]
+    }
+
+    async fn execute(&self, session: &mut Session, args: Option<&[&str]>) -> Result<()> {
+        if !session.is_sysop() {
+            return session
+                .writeln("Permission denied", session.theme.error())
+                .await;
+        }
+
+        let Some([username]) = args else {
+            return session.writeln("Usage: record <user>", None).await;
+        };
+
+        let recording = {
+            let mut recorded = session.app_state.recorded.write().await;
+
+            if recorded.remove(*username) {
+                false
+            } else {
+                recorded.insert(username.to_string())
+            }
+        };
+
+        session
+            .app_state
+            .audit(
+                &session.username()?,
+                &format!("record {username} {}", if recording { "on" } else { "off" }),
+            )
+            .await?;
+        session
+            .writeln(
+                &format!(
+                    "Recording {} for {username}",
+                    if recording { "enabled" } else { "disabled" }
+                ),
+                None,
+            )
+            .await
+    }
+
+    fn help(&self) -> String {
+        String::from("This command toggles recording a transcript of a user's sessions.")
+    }
+
+    fn sysop_only(&self) -> bool {
+        true
+    }
+}
+
+pub struct ReloadCmd;
+
+impl ReloadCmd {
+    async fn reload_config(&self, session: &mut Session) -> Result<()> {
+        let fresh = Config::from_file().await?;
+
+        for warning in fresh.validate().await {
+            session.writeln(&warning, session.theme.error()).await?;
+        }
+
+        let (config, changed, needs_restart) = session.config.reloaded(fresh)?;
+
+        if changed.is_empty() && needs_restart.is_empty() {
+            return session.writeln("Configuration unchanged", None).await;
+        }
+
+        crate::set_log_filter(&config);
+        session.app_state.set_config(config).await;
+        session
+            .app_state
+            .audit(
+                &session.username()?,
+                &format!("reload config {}", changed.join(",")),
+            )
+            .await?;
+
+        if !changed.is_empty() {
+            session
+                .writeln(&format!("Changed: {}", changed.join(", ")), None)
+                .await?;
+        }
+
+        if !needs_restart.is_empty() {
+            session
+                .writeln(
+                    &format!("Restart required for: {}", needs_restart.join(", ")),
+                    session.theme.error(),
+                )
+                .await?;
+        }
+
+        session.writeln("Configuration reloaded", None).await
+    }
+}
+
+#[async_trait]
+impl Command for ReloadCmd {
+    fn names() -> &'static [&'static str] {
+        &["reload"]
+    }
+
+    async fn execute(&self, session: &mut Session, args: Option<&[&str]>) -> Result<()> {
+        if !session.is_sysop() {
+            return session
+                .writeln("Permission denied", session.theme.error())
+                .await;
+        }
+
+        match args {
+            None => (),
+            Some(["config"]) => return self.reload_config(session).await,
+            Some(_) => return session.writeln("Usage: reload [config]", None).await,
+        }
+
+        // Every change is saved as it happens, so there is nothing pending to flush.
+        // Anything only in memory is the result of a failed save.
+        let answer = session
+            .prompt(
+                "Replace the in-memory state with the files on disk? (y/n) ",
+                None,
+            )
+            .await?;
+
+        if !answer.eq_ignore_ascii_case("y") {
+            return session.writeln("Reload cancelled", None).await;
+        }
+
+        session.app_state.reload().await?;
+        session
+            .app_state
+            .audit(&session.username()?, "reload state")
+            .await?;
+        session.writeln("State reloaded from disk", None).await
+    }
+
+    fn help(&self) -> String {
+        String::from(
+            "This command reloads users and messages from disk. Usage: reload [config], where config rereads the configuration file instead.",
+        )
+    }
+
+    fn sysop_only(&self) -> bool {
+        true
+    }
+}
+
+pub struct SchemeCmd;
+
+#[async_trait]
+impl Command for SchemeCmd {
+    fn names() -> &'static [&'static str] {
+        &["scheme"]
+    }
+
+    async fn execute(&self, session: &mut Session, args: Option<&[&str]>) -> Result<()> {
+        let Some([scheme]) = args else {
+            return session.writeln("Usage: scheme dark|light", None).await;
+        };
+
+        let scheme: Scheme = scheme.parse()?;
+        let username = session.username()?;
+
+        session
+            .app_state
+            .update_user(&username, |user| user.scheme = Some(scheme))
+            .await?;
+        session.theme.scheme = Some(scheme);
+        session
+            .writeln(&format!("Color scheme set to {scheme:?}"), None)
+            .await
+    }
+
+    fn help(&self) -> String {
+        String::from("This command picks a dark or light color scheme.")
+    }
+}
+
+const MIN_PAGE_SIZE: usize = 5;
+
+pub struct PageSizeCmd;
+
+#[async_trait]
+impl Command for PageSizeCmd {
+    fn names() -> &'static [&'static str] {
+        &["pagesize"]
+    }
+
+    async fn execute(&self, session: &mut Session, args: Option<&[&str]>) -> Result<()> {
+        let Some([size]) = args else {
+            return session
+                .writeln(&format!("Page size is {}", session.page_size), None)
+                .await;
+        };
+
+        let size = match size.parse::<usize>() {
+            Ok(size) if (MIN_PAGE_SIZE..=MAX_PAGE_SIZE).contains(&size) => size,
+            _ => {
+                return session
+                    .writeln(
+                        &format!("Page size must be between {MIN_PAGE_SIZE} and {MAX_PAGE_SIZE}"),
+                        None,
+                    )
+                    .await;
+            }
+        };
+        let username = session.username()?;
+
+        session
+            .app_state
+            .update_user(&username, |user| user.page_size = Some(size))
+            .await?;
+        session.page_size = size;
+        session
+            .writeln(&format!("Page size set to {size}"), None)
+            .await
+    }
+
+    fn help(&self) -> String {
+        String::from("This command shows or sets how many messages are listed per page.")
+    }
+}
+
+pub struct JoinCmd;
+
+#[async_trait]
+impl Command for JoinCmd {
+    fn names() -> &'static [&'static str] {
+        &["join"]
+    }
+
+    async fn execute(&self, session: &mut Session, args: Option<&[&str]>) -> Result<()> {
+        let Some([board]) = args else {
+            return session.writeln("Usage: join <board>", None).await;
+        };
+
+        if !session.board_exists(board).await {
+            return session.writeln("Unknown board", None).await;
+        }
+
+        session.current_board = board.to_string();
+        session
+            .writeln(&format!("Now on board {board}"), None)
+            .await
+    }
+
+    fn help(&self) -> String {
+        String::from("This command switches the board messages are listed from and posted to.")
+    }
+}
+
+pub struct SetBoardCmd;
+
+#[async_trait]
+impl Command for SetBoardCmd {
+    fn names() -> &'static [&'static str] {
+        &["setboard"]
+    }
+
+    async fn execute(&self, session: &mut Session, args: Option<&[&str]>) -> Result<()> {
+        let Some([board]) = args else {
+            return session.writeln("Usage: setboard <board>", None).await;
+        };
+
+        if !session.board_exists(board).await {
+            return session.writeln("Unknown board", None).await;
+        }
+
+        let username = session.username()?;
+
+        session
+            .app_state
+            .update_user(&username, |user| {
+                user.default_board = Some(board.to_string())
+            })
+            .await?;
+        session
+            .writeln(&format!("Default board set to {board}"), None)
+            .await
+    }
+
+    fn help(&self) -> String {
+        String::from("This command sets the board you land in after logging in.")
+    }
+}
+
+pub struct CreateCmd;
+
+#[async_trait]
+impl Command for CreateCmd {
+    fn names() -> &'static [&'static str] {
+        &["create"]
+    }
+
+    async fn execute(&self, session: &mut Session, args: Option<&[&str]>) -> Result<()> {
+        let Some([board]) = args else {
+            return session.writeln("Usage: create <board>", None).await;
+        };
+
+        let sysop = session.is_sysop();
+
+        if !sysop && !session.config.allow_user_boards {
+            return session.writeln("Only sysops can create boards", None).await;
+        }
+
+        if !valid_board_name(board) {
+            return session
+                .writeln(
+                    "Board names may only contain letters, digits, - and _",
+                    None,
+                )
+                .await;
+        }
+
+        if session.board_exists(board).await {
+            return session.writeln("Board already exists", None).await;
+        }
+
+        let username = session.username()?;
+        let limit = session.config.max_boards_per_user;
+        // The limit is checked under the same lock that adds the board, so two sessions
+        // of the same user can't both squeeze in the last slot.
+        let reply = session
+            .app_state
+            .update_boards(|boards| {
+                let owned = boards
+                    .iter()
+                    .filter(|b| b.creator.as_deref() == Some(username.as_str()))
+                    .count();
+
+                if boards.iter().any(|b| b.name == *board) {
+                    return String::from("Board already exists");
+                }
+
+                if !sysop && owned >= limit {
+                    return String::from("Board creation limit reached");
+                }
+
+                boards.push(Board {
+                    name: board.to_string(),
+                    creator: Some(username.clone()),
+                    created_at: time::now(),
+                    archived: false,
+                    anonymous: false,
+                });
+
+                format!("Board {board} created")
+            })
+            .await?;
+
+        session.writeln(&reply, None).await
+    }
+
+    fn help(&self) -> String {
+        String::from(
+            "This command creates a new board. Unless you are a sysop, the number of boards you can create is limited.",
+        )
+    }
+}
+
+pub struct UnarchiveCmd;
+
+#[async_trait]
+impl Command for UnarchiveCmd {
+    fn names() -> &'static [&'static str] {
+        &["unarchive"]
+    }
+
+    async fn execute(&self, session: &mut Session, args: Option<&[&str]>) -> Result<()> {
+        if !session.is_sysop() {
+            return session
+                .writeln("Permission denied", session.theme.error())
+                .await;
+        }
+
+        let Some([board]) = args else {
+            return session.writeln("Usage: unarchive <board>", None).await;
+        };
+
+        let unarchived = session
+            .app_state
+            .update_boards(|boards| {
+                boards
+                    .iter_mut()
+                    .find(|b| b.name == *board && b.archived)
+                    .map(|b| {
+                        b.archived = false;
+                        // Counts as activity, or the next archiving pass would undo this.
+                        b.created_at = time::now();
+                    })
+                    .is_some()
+            })
+            .await?;
+
+        if !unarchived {
+            return session.writeln("Board is not archived", None).await;
+        }
+
+        session
+            .app_state
+            .audit(&session.username()?, &format!("unarchive {board}"))
+            .await?;
+        session
+            .writeln(&format!("Board {board} unarchived"), None)
+            .await
+    }
+
+    fn help(&self) -> String {
+        String::from("This command makes an archived board writable again.")
+    }
+
+    fn sysop_only(&self) -> bool {
+        true
+    }
+}
+
+pub struct BoardCmd;
+
+impl BoardCmd {
+    async fn rename(&self, session: &mut Session, old: &str, new: &str) -> Result<()> {
+        if !valid_board_name(new) {
+            return session
+                .writeln(
+                    "Board names may only contain letters, digits, - and _",
+                    None,
+                )
+                .await;
+        }
+
+        if old == DEFAULT_BOARD || session.config.default_board.as_deref() == Some(old) {
+            return session
+                .writeln("The default board can't be renamed", None)
+                .await;
+        }
+
+        if !session.board_exists(old).await {
+            return session.writeln("Unknown board", None).await;
+        }
+
+        if session.board_exists(new).await {
+            return session.writeln("Board already exists", None).await;
+        }
+
+        let moved = session
+            .app_state
+            .update_stores(|stores| {
+                // Checked again under the locks, in case another sysop got in between.
+                if stores.messages.iter().any(|m| m.board == new)
+                    || stores.boards.iter().any(|b| b.name == new)
+                {
+                    return None;
+                }
+
+                let mut moved = 0;
+
+                for board in stores.boards.iter_mut().filter(|b| b.name == old) {
+                    board.name = new.to_owned();
+                }
+
+                for message in stores.messages.iter_mut().filter(|m| m.board == old) {
+                    message.board = new.to_owned();
+                    moved += 1;
+                }
+
+                for user in stores
+                    .users
+                    .iter_mut()
+                    .filter(|u| u.default_board.as_deref() == Some(old))
+                {
+                    user.default_board = Some(new.to_owned());
+                }
+
+                stores.last_read = take(&mut stores.last_read)
+                    .into_iter()
+                    .map(|((username, board), id)| {
+                        let board = if board == old { new.to_owned() } else { board };
+
+                        ((username, board), id)
+                    })
+                    .collect();
+
+                Some(moved)
+            })
+            .await?;
+
+        let Some(moved) = moved else {
+            return session.writeln("Could not rename board", None).await;
+        };
+
+        session
+            .app_state
+            .audit(&session.username()?, &format!("board rename {old} {new}"))
+            .await?;
+
+        if session.current_board == old {
+            session.current_board = new.to_owned();
+        }
+
+        session
+            .writeln(
+                &format!("Board {old} renamed to {new}, {moved} message(s) moved"),
+                None,
+            )
+            .await
+    }
+}
+
+#[async_trait]
+impl Command for BoardCmd {
+    fn names() -> &'static [&'static str] {
+        &["board"]
+    }
+
+    async fn execute(&self, session: &mut Session, args: Option<&[&str]>) -> Result<()> {
+        if !session.is_sysop() {
+            return session
+                .writeln("Permission denied", session.theme.error())
+                .await;
+        }
+
+        match args {
+            Some(["rename", old, new]) => self.rename(session, old, new).await,
+            Some(["anonymous", board, setting @ ("on" | "off")]) => {
+                if !session.board_exists(board).await {
+                    return session.writeln("Unknown board", None).await;
+                }
+
+                session
+                    .app_state
+                    .set_board_anonymous(board, *setting == "on")
+                    .await?;
+                session
+                    .app_state
+                    .audit(
+                        &session.username()?,
+                        &format!("board anonymous {board} {setting}"),
+                    )
+                    .await?;
+                session
+                    .writeln(
+                        &format!("Anonymous posting on {board} turned {setting}"),
+                        None,
+                    )
+                    .await
+            }
+            _ => {
+                session
+                    .writeln(
+                        "Usage: board rename <old> <new> | board anonymous <board> on|off",
+                        None,
+                    )
+                    .await
+            }
+        }
+    }
+
+    fn help(&self) -> String {
+        String::from("This command manages boards.")
+    }
+
+    fn sysop_only(&self) -> bool {
+        true
+    }
+}
+
+/// Attachment names are only ever shown, never used as paths, but are still kept to
+/// plain names so they can't suggest one to a client saving them.
+fn valid_filename(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= 64
+        && !name.starts_with('.')
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.')
+}
+
+fn valid_board_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= 32
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+pub struct CharsetCmd;
+
+#[async_trait]
+impl Command for CharsetCmd {
+    fn names() -> &'static [&'static str] {
+        &["charset"]
+    }
+
+    async fn execute(&self, session: &mut Session, args: Option<&[&str]>) -> Result<()> {
+        match args {
+            Some(["ascii"]) => session.unicode = false,
+            Some(["unicode"]) => session.unicode = true,
+            _ => return session.writeln("Usage: charset ascii|unicode", None).await,
+        }
+
+        session
+            .writeln(
+                &format!(
+                    "Charset set to {}",
+                    if session.unicode { "unicode" } else { "ascii" }
+                ),
+                None,
+            )
+            .await
+    }
+
+    fn help(&self) -> String {
+        String::from("This command switches UI glyphs between unicode and plain ascii.")
+    }
+}
+
+pub struct ConfigCmd;
+
+#[async_trait]
+impl Command for ConfigCmd {
+    fn names() -> &'static [&'static str] {
+        &["config"]
+    }
+
+    async fn execute(&self, session: &mut Session, _: Option<&[&str]>) -> Result<()> {
+        if !session.is_sysop() {
+            return session
+                .writeln("Permission denied", session.theme.error())
+                .await;
+        }
+
+        let config = session.config.redacted()?;
+
+        for line in config.lines() {
+            session.writeln(line, None).await?;
+        }
+
+        Ok(())
+    }
+
+    fn help(&self) -> String {
+        String::from("This command shows the active configuration.")
+    }
+
+    fn sysop_only(&self) -> bool {
+        true
+    }
+}
+
+pub struct HelpCmd {
+    pub command_handler: CommandHandler,
+}
+
+#[async_trait]
+impl Command for HelpCmd {
+    fn names() -> &'static [&'static str] {
+        &["help", "commands"]
+    }
+
+    async fn execute(&self, session: &mut Session, args: Option<&[&str]>) -> Result<()> {
+        let commands = match session.status {
+            SessionStatus::LoggedOn(_) => &self.command_handler.message_commands,
+            _ => &self.command_handler.welcome_commands,
+        };
+
+        match args {
+            None => {
+                // The handler was copied before help was added, so it lists itself.
+                let mut names: Vec<&str> = commands
+                    .iter()
+                    .filter(|(_, command)| !command.sysop_only() || session.is_sysop())
+                    .map(|(name, _)| *name)
+                    .chain(Self::names().iter().copied())
+                    .collect();
+
+                names.sort_unstable();
+
+                let separator = format!(" {} ", session.glyph(Glyph::VerticalLine));
+
+                session.writeln("Commands:", session.theme.header()).await?;
+                session.writeln(&session.separator(9), None).await?;
+                session.writeln(&names.join(&separator), None).await?;
+                session
+                    .writeln("Type help <command> to learn more about one.", None)
+                    .await
+            }
+            Some([name]) if Self::names().contains(&name.to_lowercase().as_str()) => {
+                session.writeln(&self.help(), None).await
+            }
+            Some([name]) => match commands.get(name.to_lowercase().as_str()) {
+                Some(command) => session.writeln(&command.help(), None).await,
+                None => {
+                    session
+                        .writeln(
+                            &format!("Unknown command {name}, type help for a list"),
+                            session.theme.error(),
+                        )
+                        .await
+                }
+            },
+            Some(_) => session.writeln("Usage: help [command]", None).await,
+        }
+    }
+
+    fn help(&self) -> String {
+        String::from(
+            "This command lists the available commands, or describes one. Usage: help [command]",
+        )
+    }
+}
+
+const MAX_MACROS: usize = 20;
+const MAX_MACRO_LENGTH: usize = 256;
+
+/// Macros are typed like commands, so their names are single words starting with a
+/// letter.
+fn valid_macro_name(name: &str) -> bool {
+    name.len() <= 32
+        && name.starts_with(|c: char| c.is_ascii_alphabetic())
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+async fn user_macro(session: &Session, name: &str) -> Option<String> {
+    let username = session.username().ok()?;
+
+    session
+        .app_state
+        .users
+        .read()
+        .await
+        .iter()
+        .find(|u| u.username == username)?
+        .macros
+        .get(name)
+        .cloned()
+}
+
+/// Runs stored command sequences. It dispatches through a copy of the handler taken
+/// before it was registered itself, so a macro can never run `macro` or another
+/// macro, and can't recurse.
+pub struct MacroCmd {
+    pub command_handler: CommandHandler,
+}
+
+impl MacroCmd {
+    /// Whether `name` is taken by a command, as a macro of that name could never run.
+    fn is_command(&self, name: &str) -> bool {
+        let name = name.to_lowercase();
+
+        self.command_handler
+            .message_commands
+            .contains_key(name.as_str())
+            || Self::names().contains(&name.as_str())
+            || HelpCmd::names().contains(&name.as_str())
+    }
+
+    async fn set(&self, session: &mut Session, name: &str, commands: &[&str]) -> Result<()> {
+        let commands = commands.join(" ");
+
+        if !valid_macro_name(name) || self.is_command(name) {
+            return session.writeln("Invalid macro name", None).await;
+        }
+
+        if commands.len() > MAX_MACRO_LENGTH {
+            return session
+                .writeln(
+                    &format!("Macros are limited to {MAX_MACRO_LENGTH} characters"),
+                    None,
+                )
+                .await;
+        }
+
+        let username = session.username()?;
+        let steps: Vec<&str> = commands.split(';').map(str::trim).collect();
+
+        for step in &steps {
+            let Some(command) = step.split_whitespace().next() else {
+                return session.writeln("Macros can't have empty steps", None).await;
+            };
+
+            if command == name
+                || Self::names().contains(&command.to_lowercase().as_str())
+                || user_macro(session, command).await.is_some()
+            {
+                return session.writeln("Macros can't run other macros", None).await;
+            }
+        }
+
+        let commands = steps.join("; ");
+        let stored = session
+            .app_state
+            .update_users(|users| {
+                let user = users.iter_mut().find(|u| u.username == username)?;
+
+                if !user.macros.contains_key(name) && user.macros.len() >= MAX_MACROS {
+                    return None;
+                }
+
+                user.macros.insert(name.to_owned(), commands);
+
+                Some(())
+            })
+            .await?;
+
+        match stored {
+            Some(()) => session.writeln(&format!("Macro {name} saved"), None).await,
+            None => {
+                session
+                    .writeln(&format!("You can have at most {MAX_MACROS} macros"), None)
+                    .await
+            }
+        }
+    }
+
+    async fn run(&self, session: &mut Session, name: &str) -> Result<()> {
+        let commands = user_macro(session, name)
+            .await
+            .context(UserError::new("Unknown macro"))?;
+
+        for command in commands.split(';').map(str::trim) {
+            self.command_handler
+                .handle(command, session)
+                .await
+                .with_context(|| format!("Macro {name} stopped at \"{command}\""))?;
+
+            if matches!(session.status, SessionStatus::Disconnected(_)) {
+                break;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Command for MacroCmd {
+    fn names() -> &'static [&'static str] {
+        &["macro", "macros"]
+    }
+
+    async fn execute(&self, session: &mut Session, args: Option<&[&str]>) -> Result<()> {
+        match args {
+            None | Some(["list"]) => {
+                let username = session.username()?;
+                let macros = session
+                    .app_state
+                    .users
+                    .read()
+                    .await
+                    .iter()
+                    .find(|u| u.username == username)
+                    .map(|u| u.macros.clone())
+                    .unwrap_or_default();
+
+                if macros.is_empty() {
+                    return session.writeln("No macros", None).await;
+                }
+
+                for (name, commands) in macros {
+                    session
+                        .writeln(&format!("{name}: {commands}"), None)
+                        .await?;
+                }
+
+                Ok(())
+            }
+            Some(["run", name]) => self.run(session, name).await,
+            Some(["delete", name]) => {
+                let username = session.username()?;
+                let deleted = session
+                    .app_state
+                    .update_users(|users| {
+                        users
+                            .iter_mut()
+                            .find(|u| u.username == username)
+                            .and_then(|u| u.macros.remove(*name))
+                            .is_some()
+                    })
+                    .await?;
+
+                if !deleted {
+                    return session.writeln("Unknown macro", None).await;
+                }
+
+                session
+                    .writeln(&format!("Macro {name} deleted"), None)
+                    .await
+            }
+            Some(["set", name, commands @ ..]) if !commands.is_empty() => {
+                self.set(session, name, commands).await
+            }
+            _ => session
+                .writeln(
+                    "Usage: macro list|run <name>|delete <name>|set <name> <command>; <command>...",
+                    None,
+                )
+                .await,
+        }
+    }
+
+    fn help(&self) -> String {
+        String::from(
+            "This command manages macros, sequences of commands separated by ; that run with macro run <name> or just <name>.",
+        )
+    }
+}
+
+pub struct DiagCmd;
+
+#[async_trait]
+impl Command for DiagCmd {
+    fn names() -> &'static [&'static str] {
+        &["diag"]
+    }
+
+    async fn execute(&self, session: &mut Session, _: Option<&[&str]>) -> Result<()> {
+        if !session.is_sysop() {
+            return session
+                .writeln("Permission denied", session.theme.error())
+                .await;
+        }
+
+        for line in session.app_state.diagnostics().await {
+            session.writeln(&line, None).await?;
+        }
+
+        Ok(())
+    }
+
+    fn help(&self) -> String {
+        String::from("This command shows sessions, lock contention and background tasks.")
+    }
+
+    fn sysop_only(&self) -> bool {
+        true
+    }
+}
+
+pub struct MailCmd;
+
+impl MailCmd {
+    async fn send(&self, session: &mut Session, to: &str, text: &[&str]) -> Result<()> {
+        let from = session.username()?;
+        let blocked = session
+            .app_state
+            .users
+            .read()
+            .await
+            .iter()
+            .find(|u| u.username == to)
+            .map(|recipient| recipient.blocked.contains(&from));
+        let Some(blocked) = blocked else {
+            return session.writeln("Could not find user", None).await;
+        };
+
+        if let Some(max_per_min) = session.config.max_dms_per_min
+            && !session.app_state.allow_dm(&from, max_per_min).await
+        {
+            return session
+                .writeln("You're sending messages too fast", session.theme.error())
+                .await;
+        }
+
+        // Blocked senders aren't told, so they can't tell they were blocked.
+        if !blocked {
+            let message = PrivateMessage {
+                from,
+                to: to.to_owned(),
+                body: text.join(" "),
+                timestamp: time::now(),
+                read: false,
+            };
+
+            session
+                .app_state
+                .update_private_messages(|messages| messages.push(message))
+                .await?;
+        }
+
+        session
+            .writeln(&format!("Message sent to {to}"), None)
+            .await
+    }
+
+    async fn inbox(&self, session: &mut Session) -> Result<()> {
+        let username = session.username()?;
+        let inbox = session
+            .app_state
+            .update_private_messages(|messages| {
+                messages
+                    .iter_mut()
+                    .filter(|m| m.to == username)
+                    .map(|m| {
+                        let line = format!(
+                            "{}{} {}: {}",
+                            if m.read { " " } else { "*" },
+                            time::format(m.timestamp),
+                            m.from,
+                            m.body
+                        );
+
+                        m.read = true;
+
+                        line
+                    })
+                    .collect::<Vec<String>>()
+            })
+            .await?;
+
+        if inbox.is_empty() {
+            return session.writeln("No messages", None).await;
+        }
+
+        for line in inbox {
+            session.writeln(&line, None).await?;
+        }
+
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Command for MailCmd {
+    fn names() -> &'static [&'static str] {
+        &["mail", "pm"]
+    }
+
+    async fn execute(&self, session: &mut Session, args: Option<&[&str]>) -> Result<()> {
+        match args {
+            None | Some(["inbox"]) => self.inbox(session).await,
+            Some(["send", to, text @ ..]) if !text.is_empty() => self.send(session, to, text).await,
+            _ => {
+                session
+                    .writeln("Usage: pm inbox | pm send <user> <text>", None)
+                    .await
+            }
+        }
+    }
+
+    fn help(&self) -> String {
+        String::from("This command sends private messages to other users and shows yours.")
+    }
+}
+
+pub struct BlockCmd;
+
+#[async_trait]
+impl Command for BlockCmd {
+    fn names() -> &'static [&'static str] {
+        &["block"]
+    }
+
+    async fn execute(&self, session: &mut Session, args: Option<&[&str]>) -> Result<()> {
+        let username = session.username()?;
+
+        let Some([blocked]) = args else {
+            let blocked = session
+                .app_state
+                .users
+                .read()
+                .await
+                .iter()
+                .find(|u| u.username == username)
+                .map(|u| u.blocked.join(", "))
+                .unwrap_or_default();
+
+            return match blocked.is_empty() {
+                true => session.writeln("You haven't blocked anyone", None).await,
+                false => session.writeln(&format!("Blocked: {blocked}"), None).await,
+            };
+        };
+
+        if *blocked == username {
+            return session.writeln("Cannot block yourself", None).await;
+        }
+
+        if !session
+            .app_state
+            .users
+            .read()
+            .await
+            .iter()
+            .any(|u| u.username == *blocked)
+        {
+            return session.writeln("Could not find user", None).await;
+        }
+
+        session
+            .app_state
+            .update_users(|users| {
+                if let Some(user) = users.iter_mut().find(|u| u.username == username)
+                    && !user.blocked.iter().any(|b| b == blocked)
+                {
+                    user.blocked.push(blocked.to_string());
+                }
+            })
+            .await?;
+        session
+            .writeln(
+                &format!("Private messages from {blocked} will be dropped"),
+                None,
+            )
+            .await
+    }
+
+    fn help(&self) -> String {
+        String::from("This command blocks private messages from a user, or lists who you blocked.")
+    }
+}
+
+pub struct UnblockCmd;
+
+#[async_trait]
+impl Command for UnblockCmd {
+    fn names() -> &'static [&'static str] {
+        &["unblock"]
+    }
+
+    async fn execute(&self, session: &mut Session, args: Option<&[&str]>) -> Result<()> {
+        let Some([blocked]) = args else {
+            return session.writeln("Usage: unblock <user>", None).await;
+        };
+
+        let username = session.username()?;
+        let unblocked = session
+            .app_state
+            .update_users(|users| {
+                let user = users.iter_mut().find(|u| u.username == username)?;
+                let index = user.blocked.iter().position(|b| b == blocked)?;
+
+                Some(user.blocked.remove(index))
+            })
+            .await?;
+
+        match unblocked {
+            Some(_) => session.writeln(&format!("Unblocked {blocked}"), None).await,
+            None => session.writeln("That user isn't blocked", None).await,
+        }
+    }
+
+    fn help(&self) -> String {
+        String::from("This command lets a blocked user send you private messages again.")
+    }
+}
+
+pub struct MotdCmd;
+
+#[async_trait]
+impl Command for MotdCmd {
+    fn names() -> &'static [&'static str] {
+        &["motd"]
+    }
+
+    async fn execute(&self, session: &mut Session, _: Option<&[&str]>) -> Result<()> {
+        match session.motd().await {
+            Some(motd) => session.writeln(&motd, None).await,
+            None => session.writeln("No message of the day", None).await,
+        }
+    }
+
+    fn help(&self) -> String {
+        String::from("This command shows the message of the day.")
+    }
+}
+
+pub struct LogoutCmd;
+
+#[async_trait]
+impl Command for LogoutCmd {
+    fn names() -> &'static [&'static str] {
+        &["logout"]
+    }
+
+    async fn execute(&self, session: &mut Session, _: Option<&[&str]>) -> Result<()> {
+        session.logout().await;
+        session.writeln("Logged out", None).await?;
+        session.writeln("", None).await?;
+        session.show_welcome_commands().await
+    }
+
+    fn help(&self) -> String {
+        String::from("This command logs you out without disconnecting.")
+    }
+}
+
+pub struct PasswdCmd;
+
+#[async_trait]
+impl Command for PasswdCmd {
+    fn names() -> &'static [&'static str] {
+        &["passwd"]
+    }
+
+    async fn execute(&self, session: &mut Session, _: Option<&[&str]>) -> Result<()> {
+        let username = session.username()?;
+        let current = session.prompt_secret("Current password: ", None).await?;
+        let hash = session
+            .app_state
+            .password_hash(&username)
+            .await
+            .context(UserError::new("Could not find user"))?;
+
+        if !bcrypt::verify(current, &hash).context("Invalid password")? {
+            return session
+                .writeln("Current password is wrong", session.theme.error())
+                .await;
+        }
+
+        let password = session.prompt_secret("New password: ", None).await?;
+        let confirmation = session.prompt_secret("Repeat new password: ", None).await?;
+
+        if password != confirmation {
+            return session
+                .writeln("Passwords don't match", session.theme.error())
+                .await;
+        }
+
+        let hash = bcrypt::hash(password, session.config.hash_cost())
+            .context("Could not change password")?;
+
+        session
+            .app_state
+            .update_user(&username, |user| user.password = hash)
+            .await?;
+        session.app_state.invalidate_login(&username).await;
+        session
+            .writeln("Password changed", session.theme.success())
+            .await
+    }
+
+    fn help(&self) -> String {
+        String::from("This command changes your password.")
+    }
+}
+
+pub struct WhoCmd;
+
+#[async_trait]
+impl Command for WhoCmd {
+    fn names() -> &'static [&'static str] {
+        &["who", "online"]
+    }
+
+    async fn execute(&self, session: &mut Session, _: Option<&[&str]>) -> Result<()> {
+        let mut online: Vec<OnlineUser> = session
+            .app_state
+            .online
+            .read()
+            .await
+            .values()
+            .cloned()
+            .collect();
+
+        online.sort_by_key(|u| u.since);
+
+        session
+            .writeln(
+                &format!("{} user(s) online", online.len()),
+                session.theme.header(),
+            )
+            .await?;
+
+        for user in online {
+            let minutes = (time::now() - user.since) / 60;
+
+            session
+                .writeln(
+                    &format!(
+                        "{} since {} ({minutes}m)",
+                        user.username,
+                        time::format(user.since)
+                    ),
+                    None,
+                )
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    fn help(&self) -> String {
+        String::from("This command lists the users currently online.")
+    }
+}
+
+pub struct KickCmd;
+
+#[async_trait]
+impl Command for KickCmd {
+    fn names() -> &'static [&'static str] {
+        &["kick"]
+    }
+
+    async fn execute(&self, session: &mut Session, args: Option<&[&str]>) -> Result<()> {
+        if !session.is_sysop() {
+            return session
+                .writeln("Permission denied", session.theme.error())
+                .await;
+        }
+
+        let Some([username]) = args else {
+            return session.writeln("Usage: kick <user>", None).await;
+        };
+
+        if *username == session.username()? {
+            return session.writeln("Cannot kick yourself", None).await;
+        }
+
+        match session.app_state.kick(username).await {
+            0 => {
+                session
+                    .writeln(&format!("{username} is not online"), None)
+                    .await
+            }
+            kicked => {
+                session
+                    .app_state
+                    .audit(&session.username()?, &format!("kick {username}"))
+                    .await?;
+                session
+                    .writeln(&format!("Kicked {kicked} session(s) of {username}"), None)
+                    .await
+            }
+        }
+    }
+
+    fn help(&self) -> String {
+        String::from("This command disconnects every session of a user.")
+    }
+
+    fn sysop_only(&self) -> bool {
+        true
+    }
+}
+
+pub struct DelUserCmd;
+
+#[async_trait]
+impl Command for DelUserCmd {
+    fn names() -> &'static [&'static str] {
+        &["deluser"]
+    }
+
+    async fn execute(&self, session: &mut Session, args: Option<&[&str]>) -> Result<()> {
+        if !session.is_sysop() {
+            return session
+                .writeln("Permission denied", session.theme.error())
+                .await;
+        }
+
+        let (username, dry_run) = match args {
+            Some([username]) => (username, false),
+            Some([username, "dryrun"]) => (username, true),
+            _ => {
+                return session
+                    .writeln("Usage: deluser <user> [dryrun]", None)
+                    .await;
+            }
+        };
+
+        if *username == session.username()? {
+            return session.writeln("Cannot delete yourself", None).await;
+        }
+
+        if !session
+            .app_state
+            .users
+            .read()
+            .await
+            .iter()
+            .any(|u| u.username == *username)
+        {
+            return session.writeln("Could not find user", None).await;
+        }
+
+        let mut changes = ChangeSet::default();
+
+        changes.add("users", 1, "account removed");
+
+        if dry_run {
+            let online = session
+                .app_state
+                .online
+                .read()
+                .await
+                .values()
+                .filter(|u| u.username == *username)
+                .count();
+
+            changes.add("sessions", online, "disconnected");
+
+            return report_changes(session, changes, true).await;
+        }
+
+        let answer = session
+            .prompt(&format!("Delete the account of {username}? (y/n) "), None)
+            .await?;
+
+        if !answer.eq_ignore_ascii_case("y") {
+            return Ok(());
+        }
+
+        let deleted = session
+            .app_state
+            .update_users(|users| {
+                let before = users.len();
+
+                users.retain(|u| u.username != *username);
+
+                users.len() < before
+            })
+            .await?;
+
+        if !deleted {
+            return session.writeln("Could not find user", None).await;
+        }
+
+        session.app_state.invalidate_login(username).await;
+        session.app_state.clear_failed_logins(username).await;
+
+        let kicked = session.app_state.kick(username).await;
+
+        changes.add("sessions", kicked, "disconnected");
+        session
+            .app_state
+            .audit(&session.username()?, &format!("deluser {username}"))
+            .await?;
+        session
+            .writeln(&format!("Deleted {username}"), session.theme.success())
+            .await?;
+
+        report_changes(session, changes, false).await
+    }
+
+    fn help(&self) -> String {
+        String::from(
+            "This command deletes a user's account and disconnects them. Their messages stay, use forget to remove their data too. Add dryrun to only show what would change.",
+        )
+    }
+
+    fn sysop_only(&self) -> bool {
+        true
+    }
+}
+
+pub struct BroadcastCmd;
+
+#[async_trait]
+impl Command for BroadcastCmd {
+    fn names() -> &'static [&'static str] {
+        &["broadcast"]
+    }
+
+    async fn execute(&self, session: &mut Session, args: Option<&[&str]>) -> Result<()> {
+        if !session.is_sysop() {
+            return session
+                .writeln("Permission denied", session.theme.error())
+                .await;
+        }
+
+        let Some(text) = args.filter(|text| !text.is_empty()) else {
+            return session.writeln("Usage: broadcast <text>", None).await;
+        };
+
+        let text = format!(
+            "[Broadcast from {}] {}",
+            session.username()?,
+            text.join(" ")
+        );
+        let reached = session.app_state.broadcast(&text).await;
+
+        session
+            .writeln(&format!("Broadcast sent to {reached} session(s)"), None)
+            .await
+    }
+
+    fn help(&self) -> String {
+        String::from("This command sends a message to every connected session.")
+    }
+
+    fn sysop_only(&self) -> bool {
+        true
+    }
+}
+
+pub struct ChatCmd;
+
+#[async_trait]
+impl Command for ChatCmd {
+    fn names() -> &'static [&'static str] {
+        &["chat"]
+    }
+
+    async fn execute(&self, session: &mut Session, _args: Option<&[&str]>) -> Result<()> {
+        session
+            .writeln("Entering chat, type /exit to leave", session.theme.header())
+            .await?;
+        session.chat().await?;
+        session.writeln("Left chat", None).await
+    }
+
+    fn help(&self) -> String {
+        String::from("This command enters a chat with everyone else in chat, type /exit to leave.")
+    }
+}
+
+pub struct HistoryCmd;
+
+#[async_trait]
+impl Command for HistoryCmd {
+    fn names() -> &'static [&'static str] {
+        &["history"]
+    }
+
+    async fn execute(&self, session: &mut Session, args: Option<&[&str]>) -> Result<()> {
+        let count = match args {
+            None => session.history.len(),
+            Some([count]) => count.parse().context(UserError::new("Invalid count"))?,
+            Some(_) => return session.writeln("Usage: history [count]", None).await,
+        };
+        let start = session.history.len().saturating_sub(count);
+        let lines: Vec<String> = session.history[start..]
+            .iter()
+            .enumerate()
+            .map(|(i, command)| format!("{:>3} {command}", start + i + 1))
+            .collect();
+
+        for line in lines {
+            session.writeln(&line, None).await?;
+        }
+
+        Ok(())
+    }
+
+    fn help(&self) -> String {
+        String::from(
+            "This command lists your recent commands, type !<n> to run one again. Usage: history [count]",
+        )
+    }
+}
+
+pub struct QuitCmd;
+
+#[async_trait]
+impl Command for QuitCmd {
+    fn names() -> &'static [&'static str] {
+        &["quit", "exit", "bye"]
+    }
+
+    async fn execute(&self, session: &mut Session, _: Option<&[&str]>) -> Result<()> {
+        if session.config.farewell_stats && session.username().is_ok() {
+            let stats = format!(
+                "You read {} message(s) and posted {} this session",
+                session.messages_read, session.messages_posted
+            );
+
+            session.writeln(&stats, None).await?;
+        }
+
+        if let Some(farewell_msg) = session.config.farewell_msg.clone() {
+            session.writeln(&farewell_msg, None).await?;
+        }
+
+        session.disconnect(DisconnectReason::Quit).await
+    }
+
+    fn help(&self) -> String {
+        String::from("This command terminates your session. Usage: quit")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use tokio::io::AsyncReadExt;
+
+    use super::*;
+    use crate::{
+        session::DEFAULT_BOARD,
+        testing::{Fixture, FlakyStorage, PASSWORD, SYSOP, plain, test_dir},
+    };
+
+    #[tokio::test]
+    async fn message_list_returns_lines_instead_of_writing_them() {
+        let fixture = Fixture::new().await;
+        let first = fixture.add_message("alice", DEFAULT_BOARD, "First").await;
+        let second = fixture.add_message("bob", DEFAULT_BOARD, "Second").await;
+        let (mut session, _client) = fixture.session("alice").await;
+
+        let outcome = MessageCmd
+            .outcome(&mut session, Some(&["list"]))
+            .await
+            .unwrap();
+
+        let mut expected = Vec::new();
+
+        // Only messages written by someone else count as unread.
+        for (marker, id, line) in [(" ", first, "alice First"), ("*", second, "bob Second")] {
+            let created_at = fixture.message(id).await.unwrap().created_at;
+
+            expected.push(format!(
+                "{marker}{id} {} {line}",
+                time::format_date(created_at)
+            ));
+        }
+
+        let lines: Vec<String> = outcome.lines.iter().map(|l| plain(l)).collect();
+
+        assert_eq!(lines, expected);
+        assert!(outcome.status.is_none());
+    }
+
+    #[tokio::test]
+    async fn message_read_outcome_holds_the_body() {
+        let fixture = Fixture::new().await;
+        let id = fixture.add_message("alice", DEFAULT_BOARD, "Hello").await;
+        let (mut session, _client) = fixture.session("bob").await;
+
+        let outcome = MessageCmd
+            .outcome(&mut session, Some(&["read", &id.to_string()]))
+            .await
+            .unwrap();
+
+        assert_eq!(outcome.lines.len(), 1);
+        assert!(outcome.lines[0].contains("Subject: Hello"));
+        assert!(outcome.lines[0].contains("Body of Hello"));
+    }
+
+    #[tokio::test]
+    async fn reading_moves_a_mark_per_board_without_rewriting_users() {
+        let fixture = Fixture::new().await;
+
+        fixture.add_user("alice").await;
+
+        let older = fixture.add_message("bob", DEFAULT_BOARD, "Older").await;
+        let elsewhere = fixture.add_message("bob", "dev", "Elsewhere").await;
+        let newer = fixture.add_message("bob", DEFAULT_BOARD, "Newer").await;
+        let (mut session, _client) = fixture.session("alice").await;
+
+        std::fs::remove_file(fixture.dir.join("users.json")).unwrap();
+
+        MessageCmd
+            .outcome(&mut session, Some(&["read", &newer.to_string()]))
+            .await
+            .unwrap();
+
+        let marks = fixture.app_state.read_marks("alice").await;
+        let messages = fixture.app_state.messages.read().await.clone();
+        let unread: Vec<i64> = messages
+            .iter()
+            .filter(|m| m.unread_by("alice", &marks))
+            .map(|m| m.id)
+            .collect();
+
+        // Everything up to the mark counts as read, other boards keep their own.
+        assert!(!unread.contains(&older));
+        assert_eq!(unread, [elsewhere]);
+        assert!(!fixture.dir.join("users.json").exists());
+
+        // Reading back below the mark doesn't move it or save anything.
+        fixture
+            .app_state
+            .mark_read("alice", DEFAULT_BOARD, older)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            fixture
+                .app_state
+                .read_marks("alice")
+                .await
+                .get(DEFAULT_BOARD),
+            Some(&newer)
+        );
+    }
+
+    #[tokio::test]
+    async fn unknown_sub_command_is_an_outcome_not_an_error() {
+        let fixture = Fixture::new().await;
+        let (mut session, _client) = fixture.session("alice").await;
+
+        let outcome = MessageCmd
+            .outcome(&mut session, Some(&["frobnicate"]))
+            .await
+            .unwrap();
+
+        assert_eq!(outcome.lines, ["Unknown sub command"]);
+    }
+
+    #[tokio::test]
+    async fn message_next_advances_through_the_board() {
+        let fixture = Fixture::new().await;
+
+        for subject in ["One", "Two"] {
+            fixture.add_message("bob", DEFAULT_BOARD, subject).await;
+        }
+
+        fixture.add_message("bob", "other", "Elsewhere").await;
+
+        let last = fixture.add_message("bob", DEFAULT_BOARD, "Three").await;
+        let (mut session, _client) = fixture.session("alice").await;
+
+        for subject in ["One", "Two", "Three"] {
+            let outcome = MessageCmd
+                .outcome(&mut session, Some(&["next"]))
+                .await
+                .unwrap();
+
+            assert!(outcome.lines[0].contains(&format!("Subject: {subject}")));
+        }
+
+        let outcome = MessageCmd
+            .outcome(&mut session, Some(&["next"]))
+            .await
+            .unwrap();
+
+        assert_eq!(outcome.lines, ["No more messages"]);
+
+        let key = ("alice".to_owned(), DEFAULT_BOARD.to_owned());
+        assert_eq!(
+            fixture.app_state.last_read.read().await.get(&key),
+            Some(&last)
+        );
+    }
+
+    #[test]
+    fn subjects_are_trimmed_collapsed_and_capitalized() {
+        assert_eq!(normalize_subject("  hello   world "), "Hello world");
+        assert_eq!(normalize_subject("élan\tvital"), "Élan vital");
+        assert_eq!(normalize_subject("   "), "");
+    }
+
+    #[tokio::test]
+    async fn posted_subjects_are_normalized_when_enabled() {
+        let fixture = Fixture::with_config(|config| config.normalize_subjects = true).await;
+
+        fixture.add_user("alice").await;
+        fixture
+            .run_as("alice", &["message new", "  hello   world ", "Body", "."])
+            .await;
+
+        assert_eq!(fixture.message(0).await.unwrap().subject, "Hello world");
+    }
+
+    #[tokio::test]
+    async fn oversized_bodies_swallow_the_rest_of_the_paste() {
+        let fixture = Fixture::with_config(|config| config.max_message_bytes = 20).await;
+
+        fixture.add_user("alice").await;
+
+        let output = fixture
+            .run_as(
+                "alice",
+                &[
+                    "message new",
+                    "Long",
+                    "first line",
+                    "second line goes past the cap",
+                    "create leaked",
+                    "message new",
+                    ".",
+                    "message list",
+                ],
+            )
+            .await;
+
+        assert!(output.contains("Message too long"), "{output}");
+        assert!(output.contains("Long"), "{output}");
+        assert_eq!(fixture.message(0).await.unwrap().body, "first line\r\n");
+        assert!(fixture.message(1).await.is_none());
+        assert!(
+            !fixture
+                .app_state
+                .boards
+                .read()
+                .await
+                .iter()
+                .any(|b| b.name == "leaked")
+        );
+    }
+
+    #[tokio::test]
+    async fn chown_hands_a_message_to_another_user() {
+        let fixture = Fixture::new().await;
+
+        fixture.add_user(SYSOP).await;
+        fixture.add_user("alice").await;
+        fixture.add_user("bob").await;
+
+        let id = fixture.add_message("alice", DEFAULT_BOARD, "Mine").await;
+        let chown = format!("message chown {id} bob");
+
+        let output = fixture.run_as("alice", &[&chown]).await;
+        assert!(output.contains("Permission denied"), "{output}");
+
+        let output = fixture.run_as(SYSOP, &[&chown]).await;
+        assert!(output.contains(&format!("Message {id} now belongs to bob")));
+        assert_eq!(fixture.message(id).await.unwrap().username, "bob");
+
+        let audit = std::fs::read_to_string(fixture.dir.join("audit.log")).unwrap();
+        assert!(
+            audit.contains(&format!("{SYSOP} chown message {id} from alice to bob")),
+            "{audit}"
+        );
+    }
+
+    #[tokio::test]
+    async fn deleted_messages_stay_hidden_until_restored() {
+        let fixture = Fixture::new().await;
+        let id = fixture.add_message("alice", DEFAULT_BOARD, "Oops").await;
+        let id_arg = id.to_string();
+        let (mut alice, _alice_client) = fixture.session("alice").await;
+        let (mut sysop, _sysop_client) = fixture.session(SYSOP).await;
+
+        MessageCmd
+            .outcome(&mut alice, Some(&["delete", &id_arg]))
+            .await
+            .unwrap();
+
+        let listed = MessageCmd
+            .outcome(&mut alice, Some(&["list"]))
+            .await
+            .unwrap();
+
+        assert!(listed.lines.is_empty(), "{:?}", listed.lines);
+        assert!(
+            MessageCmd
+                .outcome(&mut alice, Some(&["read", &id_arg]))
+                .await
+                .is_err()
+        );
+
+        let refused = MessageCmd
+            .outcome(&mut alice, Some(&["restore", &id_arg]))
+            .await
+            .unwrap();
+
+        assert_eq!(refused.lines, ["Permission denied"]);
+
+        let restored = MessageCmd
+            .outcome(&mut sysop, Some(&["restore", &id_arg]))
+            .await
+            .unwrap();
+
+        assert_eq!(restored.lines, [format!("Message {id} restored")]);
+
+        let read = MessageCmd
+            .outcome(&mut alice, Some(&["read", &id_arg]))
+            .await
+            .unwrap();
+
+        assert!(read.lines[0].contains("Subject: Oops"));
+        assert!(fixture.message(id).await.unwrap().deleted_at.is_none());
+    }
+
+    #[tokio::test]
+    async fn help_lists_what_the_user_can_run() {
+        let fixture = Fixture::new().await;
+
+        fixture.add_user(SYSOP).await;
+        fixture.add_user("alice").await;
+
+        let output = fixture.run_as("alice", &["help"]).await;
+
+        assert!(output.contains("message"), "{output}");
+        assert!(!output.contains("deluser"), "{output}");
+
+        let output = fixture.run_as(SYSOP, &["help"]).await;
+
+        assert!(output.contains("deluser"), "{output}");
+
+        let output = fixture.run_as("alice", &["help quit", "help nope"]).await;
+
+        assert!(output.contains("Usage: quit"), "{output}");
+        assert!(
+            output.contains("Unknown command nope, type help for a list"),
+            "{output}"
+        );
+
+        let mut guest = fixture.connect().await;
+        let output = guest.send("help login").await;
+
+        assert!(output.contains("Usage: login"), "{output}");
+        guest.close().await;
+    }
+
+    #[tokio::test]
+    async fn config_is_shown_to_sysops_only() {
+        let fixture =
+            Fixture::with_config(|config| config.hostname = "bbs.example".to_owned()).await;
+
+        fixture.add_user(SYSOP).await;
+        fixture.add_user("alice").await;
+
+        let output = fixture.run_as(SYSOP, &["config"]).await;
+
+        assert!(output.contains(r#"hostname = "bbs.example""#), "{output}");
+
+        let output = fixture.run_as("alice", &["config"]).await;
+
+        assert!(output.contains("Permission denied"), "{output}");
+        assert!(!output.contains("bbs.example"), "{output}");
+    }
+
+    #[tokio::test]
+    async fn config_never_shows_secrets() {
+        let fixture = Fixture::with_config(|config| {
+            config.tls_cert = Some("/etc/bbs/cert.pem".into());
+            config.tls_key = Some("/etc/bbs/secret-key.pem".into());
+            config.listeners = vec![crate::config::ListenerConfig {
+                hostname: "0.0.0.0".to_owned(),
+                port: 2323,
+                tls_cert: Some("/etc/bbs/cert.pem".into()),
+                tls_key: Some("/etc/bbs/listener-secret-key.pem".into()),
+            }];
+        })
+        .await;
+
+        fixture.add_user(SYSOP).await;
+        fixture.add_user("alice").await;
+
+        let output = fixture.run_as(SYSOP, &["config"]).await;
+
+        assert!(output.contains("/etc/bbs/cert.pem"), "{output}");
+        assert_eq!(output.matches("<redacted>").count(), 2, "{output}");
+        assert!(!output.contains("secret-key"), "{output}");
+
+        let output = fixture.run_as("alice", &["config"]).await;
+
+        assert!(!output.contains("/etc/bbs"), "{output}");
+    }
+
+    #[tokio::test]
+    async fn new_users_see_the_onboarding_text_once() {
+        let fixture = Fixture::with_config(|config| {
+            config.onboarding_file = Some(config.data_dir.join("onboarding.txt"));
+        })
+        .await;
+
+        std::fs::write(fixture.dir.join("onboarding.txt"), "Read the rules first").unwrap();
+
+        let mut client = fixture.connect().await;
+        let output = client.send_all(&["register", "newbie", "pw"]).await;
+
+        assert!(output.contains("Registration successful"), "{output}");
+        assert!(output.contains("Read the rules first"), "{output}");
+        client.close().await;
+
+        let mut client = fixture.connect().await;
+        let output = client.send_all(&["login", "newbie", "pw"]).await;
+
+        assert!(output.contains("Login successful"), "{output}");
+        assert!(!output.contains("Read the rules first"), "{output}");
+        client.close().await;
+    }
+
+    #[tokio::test]
+    async fn boards_count_the_stored_messages_per_board() {
+        let fixture = Fixture::new().await;
+
+        fixture.add_user("alice").await;
+        fixture.add_board("dev").await;
+        fixture.add_board("empty").await;
+
+        for (board, subject) in [
+            (DEFAULT_BOARD, "One"),
+            ("dev", "Two"),
+            (DEFAULT_BOARD, "Three"),
+            ("dev", "Four"),
+            (DEFAULT_BOARD, "Five"),
+        ] {
+            fixture.add_message("bob", board, subject).await;
+        }
+
+        // Deleted messages don't count.
+        fixture
+            .app_state
+            .update_messages(|messages| messages[4].deleted = true)
+            .await
+            .unwrap();
+
+        let output = fixture.run_as("alice", &["boards"]).await;
+
+        for (board, count) in [(DEFAULT_BOARD, 2), ("dev", 2), ("empty", 0)] {
+            let stored = fixture
+                .app_state
+                .messages
+                .read()
+                .await
+                .iter()
+                .filter(|m| m.board == board && !m.deleted)
+                .count();
+
+            assert_eq!(stored, count);
+            assert!(
+                output.contains(&format!("{board} {count} message(s)")),
+                "{output}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn failed_logins_are_capped_and_can_be_cancelled() {
+        let fixture = Fixture::with_config(|config| config.max_login_attempts = 2).await;
+
+        fixture.add_user("alice").await;
+
+        let mut client = fixture.connect().await;
+        let output = client.send_all(&["login", "cancel"]).await;
+
+        assert!(output.contains("Login cancelled"), "{output}");
+
+        // Unknown users fail just like wrong passwords.
+        let output = client
+            .send_all(&["login", "alice", "wrong", "nobody", PASSWORD])
+            .await;
+
+        assert!(
+            output.contains("Login failed, 1 attempt(s) left"),
+            "{output}"
+        );
+        assert!(
+            output.contains("Login failed, 0 attempt(s) left"),
+            "{output}"
+        );
+        assert!(
+            output.contains("Too many failed login attempts"),
+            "{output}"
+        );
+
+        let output = client.send_all(&["login", "alice", PASSWORD]).await;
+
+        assert!(output.contains("Login successful"), "{output}");
+        client.close().await;
+    }
+
+    #[tokio::test]
+    async fn lockouts_count_failed_logins_across_connections() {
+        let fixture = Fixture::with_config(|config| {
+            config.login_lockout_attempts = 2;
+            config.max_login_attempts = 1;
+        })
+        .await;
+
+        fixture.add_user("alice").await;
+
+        for _ in 0..2 {
+            let mut client = fixture.connect().await;
+            let output = client.send_all(&["login", "alice", "wrong"]).await;
+
+            assert!(
+                output.contains("Login failed, 0 attempt(s) left"),
+                "{output}"
+            );
+            client.close().await;
+        }
+
+        let mut client = fixture.connect().await;
+        let output = client.send_all(&["login", "alice"]).await;
+
+        assert!(output.contains("Account temporarily locked"), "{output}");
+        client.close().await;
+    }
+
+    #[tokio::test]
+    async fn login_lands_in_the_default_board() {
+        let fixture =
+            Fixture::with_config(|config| config.default_board = Some("news".to_owned())).await;
+
+        fixture.add_user("alice").await;
+        fixture.add_user("bob").await;
+        fixture.add_board("news").await;
+        fixture.add_board("dev").await;
+
+        fixture.run_as("bob", &["setboard dev"]).await;
+
+        for user in ["alice", "bob"] {
+            fixture
+                .run_as(user, &["message new", user, "Where am I?", "."])
+                .await;
+        }
+
+        let boards: Vec<String> = fixture
+            .app_state
+            .messages
+            .read()
+            .await
+            .iter()
+            .map(|m| m.board.clone())
+            .collect();
+
+        // Alice gets the configured board, bob the one they chose.
+        assert_eq!(boards, ["news", "dev"]);
+    }
+
+    #[tokio::test]
+    async fn joined_boards_scope_listing_and_posting() {
+        let fixture =
+            Fixture::with_config(|config| config.boards = vec!["support".to_owned()]).await;
+
+        fixture.add_user("alice").await;
+        fixture.add_message("bob", DEFAULT_BOARD, "Elsewhere").await;
+
+        let output = fixture
+            .run_as(
+                "alice",
+                &[
+                    "boards",
+                    "join nowhere",
+                    "join support",
+                    "message new",
+                    "Help",
+                    "It broke",
+                    ".",
+                    "message list",
+                ],
+            )
+            .await;
+        let output = plain(&output);
+
+        assert!(output.contains("support 0 message(s)"), "{output}");
+        assert!(output.contains("Unknown board"), "{output}");
+        assert!(output.contains("Now on board support"), "{output}");
+        assert!(output.contains("alice Help"), "{output}");
+        assert!(!output.contains("Elsewhere"), "{output}");
+        assert_eq!(fixture.message(1).await.unwrap().board, "support");
+    }
+
+    #[tokio::test]
+    async fn cross_posts_are_deleted_together() {
+        let fixture = Fixture::new().await;
+
+        fixture.add_user(SYSOP).await;
+        fixture.add_message("bob", "news", "News").await;
+        fixture.add_message(SYSOP, DEFAULT_BOARD, "Hello").await;
+
+        let (mut session, _client) = fixture.session(SYSOP).await;
+        let outcome = MessageCmd
+            .outcome(&mut session, Some(&["crosspost", "1", "news"]))
+            .await
+            .unwrap();
+
+        assert_eq!(outcome.lines, ["Message 1 cross-posted to news as 2"]);
+
+        let copy = fixture.message(2).await.unwrap();
+
+        assert_eq!(
+            (copy.board.as_str(), copy.subject.as_str()),
+            ("news", "Hello")
+        );
+        assert_eq!(copy.crosspost_group, Some(1));
+        assert_eq!(fixture.message(1).await.unwrap().crosspost_group, Some(1));
+
+        let output = plain(
+            &fixture
+                .run_as(SYSOP, &["message list", "join news", "message list"])
+                .await,
+        );
+        let (source, target) = output.split_once("Now on board news").unwrap();
+
+        // The original stays in its board and the copy lists alongside the news.
+        assert!(source.contains(" 1 "), "{output}");
+        assert!(!source.contains(" 2 "), "{output}");
+        assert!(target.contains(" 2 "), "{output}");
+        assert!(target.contains("bob News"), "{output}");
+
+        let output = fixture.run_as(SYSOP, &["message delete 2", "y"]).await;
+
+        assert!(output.contains("2 message(s) deleted"), "{output}");
+        assert!(fixture.message(1).await.unwrap().deleted);
+        assert!(fixture.message(2).await.unwrap().deleted);
+        assert!(!fixture.message(0).await.unwrap().deleted);
+    }
+
+    #[tokio::test]
+    async fn long_reply_chains_render_without_overflow() {
+        const REPLIES: i64 = 50_000;
+
+        let fixture = Fixture::with_config(|config| config.max_thread_depth = 5).await;
+
+        fixture
+            .app_state
+            .messages
+            .write()
+            .await
+            .extend((0..=REPLIES).map(|id| Message {
+                id,
+                username: "alice".to_owned(),
+                board: DEFAULT_BOARD.to_owned(),
+                subject: "Re".to_owned(),
+                reply_to: id.checked_sub(1),
+                ..Default::default()
+            }));
+
+        let (mut session, _client) = fixture.session("bob").await;
+        let outcome = MessageCmd
+            .outcome(&mut session, Some(&["thread", "0"]))
+            .await
+            .unwrap();
+
+        assert_eq!(outcome.lines.len(), REPLIES as usize + 1);
+        assert!(outcome.lines[REPLIES as usize].ends_with(&format!("{REPLIES} alice Re")));
+
+        // Indentation stops growing at the maximum depth.
+        let indent = |line: &String| line.find(|c: char| c.is_ascii_digit()).unwrap();
+        let deepest = outcome.lines.iter().map(indent).max().unwrap();
+
+        assert_eq!(indent(&outcome.lines[5]), deepest);
+        assert_eq!(indent(&outcome.lines[REPLIES as usize]), deepest);
+    }
+
+    #[tokio::test]
+    async fn identical_posts_in_quick_succession_are_rejected() {
+        let fixture = Fixture::new().await;
+
+        fixture.add_user("alice").await;
+
+        let post = ["message new", "Hello", "Same text", "."];
+        let output = fixture
+            .run_as("alice", &[&post[..], &post[..]].concat())
+            .await;
+
+        assert_eq!(
+            output.matches("Duplicate post ignored").count(),
+            1,
+            "{output}"
+        );
+
+        fixture
+            .run_as("alice", &["message new", "Hello", "Other text", "."])
+            .await;
+
+        let bodies: Vec<String> = fixture
+            .app_state
+            .messages
+            .read()
+            .await
+            .iter()
+            .map(|m| m.body.clone())
+            .collect();
+
+        assert_eq!(bodies, ["Same text\r\n", "Other text\r\n"]);
+    }
+
+    #[test]
+    fn manifest_includes_every_registered_command() {
+        let command_handler = crate::command_handler();
+        let manifest = command_handler.manifest();
+        let listed: HashSet<&str> = manifest
+            .iter()
+            .flat_map(|c| std::iter::once(c.name).chain(c.aliases.iter().copied()))
+            .collect();
+        let registered: HashSet<&str> = command_handler
+            .welcome_commands
+            .keys()
+            .chain(command_handler.message_commands.keys())
+            .copied()
+            .collect();
+
+        assert_eq!(listed, registered);
+
+        let login = manifest.iter().find(|c| c.name == "login").unwrap();
+        assert_eq!(login.states, ["logged_off"]);
+
+        let config = manifest.iter().find(|c| c.name == "config").unwrap();
+        assert!(config.sysop_only);
+    }
+
+    #[tokio::test]
+    async fn ascii_mode_emits_only_ascii() {
+        let fixture = Fixture::new().await;
+
+        fixture.add_user("alice").await;
+
+        let root = fixture.add_message("bob", DEFAULT_BOARD, "Root").await;
+        let reply = fixture.add_message("bob", DEFAULT_BOARD, "Reply").await;
+
+        fixture.app_state.messages.write().await[1].reply_to = Some(root);
+
+        let thread = format!("message thread {root}");
+        let read = format!("message read {reply}");
+        let screens = ["boards", "message list", &thread, &read, "who", "help"];
+
+        let output = fixture.run_as("alice", &screens).await;
+        assert!(!output.is_ascii(), "Unicode glyphs are the default");
+
+        let output = fixture
+            .run_as("alice", &[&["charset ascii"][..], &screens].concat())
+            .await;
+        assert!(output.contains("+ 1 bob Reply"), "{output}");
+        assert!(output.is_ascii(), "{output}");
+    }
+
+    #[tokio::test]
+    async fn quitting_sums_up_the_session() {
+        let fixture = Fixture::with_config(|config| {
+            config.farewell_stats = true;
+            config.farewell_msg = Some("See you soon".to_owned());
+        })
+        .await;
+
+        fixture.add_user("alice").await;
+
+        for subject in ["One", "Two", "Three"] {
+            fixture.add_message("bob", DEFAULT_BOARD, subject).await;
+        }
+
+        let mut client = fixture.login("alice").await;
+        let output = client
+            .send_all(&[
+                "message read 0",
+                "message next",
+                "message new",
+                "Mine",
+                "Text",
+                ".",
+                "quit",
+            ])
+            .await;
+
+        assert!(
+            output.contains("You read 2 message(s) and posted 1 this session"),
+            "{output}"
+        );
+        assert!(output.contains("See you soon"), "{output}");
+        assert!(client.finished().await);
+    }
+
+    #[tokio::test]
+    async fn failed_saves_leave_memory_as_on_disk() {
+        let storage = Arc::new(FlakyStorage::default());
+        let fixture =
+            Fixture::with_storage(test_dir(), Box::new(Arc::clone(&storage)), |_| ()).await;
+
+        fixture.add_user(SYSOP).await;
+        fixture.add_message(SYSOP, "news", "Elsewhere").await;
+
+        let id = fixture.add_message(SYSOP, DEFAULT_BOARD, "Hello").await;
+        let mut client = fixture.login(SYSOP).await;
+
+        storage
+            .failing
+            .store(true, std::sync::atomic::Ordering::Relaxed);
+
+        for line in [
+            format!("message delete {id}"),
+            format!("message crosspost {id} news"),
+            "message next".to_owned(),
+            "setboard news".to_owned(),
+        ] {
+            let output = client.send(&line).await;
+
+            assert!(output.contains("Changes not persisted"), "{line}: {output}");
+        }
+
+        client.close().await;
+
+        let message = fixture.message(id).await.unwrap();
+
+        assert!(!message.deleted);
+        assert_eq!(message.crosspost_group, None);
+        assert_eq!(fixture.app_state.messages.read().await.len(), 2);
+        assert!(fixture.app_state.last_read.read().await.is_empty());
+        assert_eq!(fixture.user(SYSOP).await.unwrap().default_board, None);
+        assert!(!storage.messages.lock().unwrap()[1].deleted);
+    }
+
+    #[tokio::test]
+    async fn board_renames_are_all_or_nothing() {
+        let storage = Arc::new(FlakyStorage::default());
+        let fixture =
+            Fixture::with_storage(test_dir(), Box::new(Arc::clone(&storage)), |_| ()).await;
+
+        fixture.add_user(SYSOP).await;
+        fixture.add_message(SYSOP, "news", "Hello").await;
+        fixture.run_as(SYSOP, &["setboard news"]).await;
+        fixture
+            .app_state
+            .update_last_read(|last_read| {
+                last_read.insert((SYSOP.to_owned(), "news".to_owned()), 0)
+            })
+            .await
+            .unwrap();
+
+        let mut client = fixture.login(SYSOP).await;
+
+        // The save fails at the read pointers, after the users and messages.
+        *storage.saves_left.lock().unwrap() = Some(2);
+
+        let output = client.send("board rename news headlines").await;
+
+        assert!(output.contains("Changes not persisted"), "{output}");
+        assert_eq!(fixture.message(0).await.unwrap().board, "news");
+        assert!(
+            fixture
+                .app_state
+                .last_read
+                .read()
+                .await
+                .contains_key(&(SYSOP.to_owned(), "news".to_owned()))
+        );
+        assert_eq!(
+            storage.users.lock().unwrap()[0].default_board.as_deref(),
+            Some("news")
+        );
+        assert_eq!(storage.messages.lock().unwrap()[0].board, "news");
+
+        let output = client.send("board rename news headlines").await;
+
+        assert!(output.contains("1 message(s) moved"), "{output}");
+        assert_eq!(fixture.message(0).await.unwrap().board, "headlines");
+        assert_eq!(
+            fixture.user(SYSOP).await.unwrap().default_board.as_deref(),
+            Some("headlines")
+        );
+        assert_eq!(
+            *storage.last_read.lock().unwrap(),
+            HashMap::from([((SYSOP.to_owned(), "headlines".to_owned()), 0)])
+        );
+
+        client.close().await;
+    }
+
+    #[tokio::test]
+    async fn merged_users_drop_out_of_the_login_cache() {
+        let fixture = Fixture::new().await;
+
+        for user in ["alice", "bob", SYSOP] {
+            fixture.add_user(user).await;
+        }
+
+        // Logging on caches alice's hash.
+        fixture.run_as("alice", &[]).await;
+        fixture.run_as(SYSOP, &["mergeuser alice bob"]).await;
+
+        let mut client = fixture.connect().await;
+        let output = client.send_all(&["login", "alice", PASSWORD]).await;
+
+        assert!(!output.contains("Login successful"), "{output}");
+        client.close().await;
+    }
+
+    #[tokio::test]
+    async fn password_changes_invalidate_the_login_cache() {
+        let fixture = Fixture::new().await;
+
+        fixture.add_user("alice").await;
+
+        // Logging on caches the old hash.
+        let output = fixture
+            .run_as("alice", &["passwd", PASSWORD, "newpass", "newpass"])
+            .await;
+        assert!(output.contains("Password changed"), "{output}");
+
+        let mut client = fixture.connect().await;
+        let output = client.send_all(&["login", "alice", PASSWORD]).await;
+        assert!(output.contains("Login failed"), "{output}");
+
+        let output = client.send_all(&["alice", "newpass"]).await;
+        assert!(output.contains("Login successful"), "{output}");
+        client.close().await;
+    }
+
+    #[tokio::test]
+    async fn listed_reply_counts_match_the_thread() {
+        let fixture = Fixture::new().await;
+
+        for subject in ["Root", "Reply", "Nested reply", "Deleted reply", "Alone"] {
+            fixture.add_message("bob", DEFAULT_BOARD, subject).await;
+        }
+
+        fixture
+            .app_state
+            .update_messages(|messages| {
+                messages[1].reply_to = Some(0);
+                messages[2].reply_to = Some(1);
+                messages[3].reply_to = Some(0);
+                messages[3].deleted = true;
+            })
+            .await
+            .unwrap();
+
+        let (mut session, _client) = fixture.session("alice").await;
+        let list = MessageCmd
+            .outcome(&mut session, Some(&["list"]))
+            .await
+            .unwrap();
+        let thread = MessageCmd
+            .outcome(&mut session, Some(&["thread", "0"]))
+            .await
+            .unwrap();
+
+        let list: Vec<String> = list.lines.iter().map(|l| plain(l)).collect();
+
+        assert_eq!(thread.lines.len() - 1, 2);
+        assert!(list[0].ends_with("Root [2 replies]"), "{list:?}");
+        assert!(list.iter().any(|l| l.ends_with(" bob Alone")));
+    }
+
+    #[tokio::test]
+    async fn replies_land_under_their_parent() {
+        let fixture = Fixture::new().await;
+
+        fixture.add_user("alice").await;
+        fixture.add_message("bob", "news", "Launch").await;
+
+        let output = fixture
+            .run_as(
+                "alice",
+                &[
+                    "message reply 0",
+                    "",
+                    "Congrats",
+                    ".",
+                    "message reply 1",
+                    "",
+                ],
+            )
+            .await;
+
+        assert!(output.contains("Subject [Re: Launch]"), "{output}");
+
+        let reply = fixture.message(1).await.unwrap();
+
+        assert_eq!(reply.subject, "Re: Launch");
+        assert_eq!(reply.board, "news");
+        assert_eq!(reply.reply_to, Some(0));
+        // The parent is quoted first, then the typed lines follow.
+        assert_eq!(reply.body, "> Body of Launch\r\nCongrats\r\n");
+        // Replying to a reply keeps a single "Re: ".
+        assert_eq!(
+            output.matches("Subject [Re: Launch]").count(),
+            2,
+            "{output}"
+        );
+    }
+
+    #[tokio::test]
+    async fn quitting_disconnects_without_a_notice() {
+        let fixture = Fixture::new().await;
+        let (mut session, mut client) = fixture.session("alice").await;
+
+        QuitCmd.execute(&mut session, None).await.unwrap();
+
+        assert!(matches!(
+            session.status,
+            SessionStatus::Disconnected(DisconnectReason::Quit)
+        ));
+        drop(session);
+
+        let mut output = String::new();
+
+        client.read_to_string(&mut output).await.unwrap();
+
+        assert!(!output.contains("Disconnected"), "{output}");
+    }
+
+    #[tokio::test]
+    async fn reload_picks_up_edited_files() {
+        let fixture = Fixture::new().await;
+
+        fixture.add_user(SYSOP).await;
+
+        let id = fixture.add_message("alice", DEFAULT_BOARD, "Typo").await;
+        let path = fixture.dir.join("messages.json");
+        let json = std::fs::read_to_string(&path).unwrap();
+
+        std::fs::write(&path, json.replace("Typo", "Fixed by hand")).unwrap();
+        assert_eq!(fixture.message(id).await.unwrap().subject, "Typo");
+
+        let output = fixture.run_as(SYSOP, &["reload", "y"]).await;
+
+        assert!(output.contains("State reloaded from disk"), "{output}");
+        assert_eq!(fixture.message(id).await.unwrap().subject, "Fixed by hand");
+    }
+
+    #[tokio::test]
+    async fn board_creation_stops_at_the_limit() {
+        let fixture = Fixture::with_config(|config| {
+            config.allow_user_boards = true;
+            config.max_boards_per_user = 2;
+        })
+        .await;
+
+        fixture.add_user(SYSOP).await;
+        fixture.add_user("alice").await;
+
+        let output = fixture
+            .run_as("alice", &["create one", "create two", "create three"])
+            .await;
+
+        assert!(output.contains("Board two created"), "{output}");
+        assert!(output.contains("Board creation limit reached"), "{output}");
+        assert!(!output.contains("Board three created"), "{output}");
+
+        // Sysops aren't limited.
+        let output = fixture
+            .run_as(SYSOP, &["create a", "create b", "create c"])
+            .await;
+
+        assert!(output.contains("Board c created"), "{output}");
+    }
+
+    #[tokio::test]
+    async fn attachments_round_trip() {
+        let fixture = Fixture::new().await;
+
+        fixture.add_user("alice").await;
+        fixture.add_user("bob").await;
+
+        // Bytes a line based transfer would mangle: NUL, CR LF, a lone CR and invalid
+        // UTF-8.
+        let bytes = b"\x00first\r\nsecond\rthird\n\xff\xfe";
+        let encoded = STANDARD.encode(bytes);
+        let (start, end) = encoded.split_at(8);
+        let id = fixture.add_message("alice", DEFAULT_BOARD, "Notes").await;
+        let attach = format!("message attach {id} notes.bin");
+        let output = fixture.run_as("alice", &[&attach, start, end, "."]).await;
+
+        assert!(
+            output.contains(&format!("Attached notes.bin (23 bytes) to message {id}")),
+            "{output}"
+        );
+
+        let attachment = fixture.message(id).await.unwrap().attachment.unwrap();
+        let data = fixture
+            .app_state
+            .read_attachment(&attachment)
+            .await
+            .unwrap();
+
+        assert_eq!(data, bytes);
+
+        let download = format!("message download {id}");
+        let output = plain(&fixture.run_as("bob", &[&download]).await);
+
+        assert!(
+            output.contains(&format!("notes.bin (23 bytes)\r\n{encoded}\r\n.\r\n")),
+            "{output}"
+        );
+    }
+
+    #[tokio::test]
+    async fn oversized_uploads_swallow_the_rest_of_the_paste() {
+        let fixture = Fixture::with_config(|config| config.max_attachment_size = 8).await;
+
+        fixture.add_user("alice").await;
+
+        let id = fixture.add_message("alice", DEFAULT_BOARD, "Notes").await;
+        let attach = format!("message attach {id} notes.txt");
+        let output = fixture
+            .run_as(
+                "alice",
+                &[
+                    &attach,
+                    "c2VjcmV0",
+                    "IGFuZCBtb3Jl",
+                    "create leaked",
+                    ".",
+                    "message list",
+                ],
+            )
+            .await;
+
+        assert!(
+            output.contains("Attachments are limited to 8 bytes"),
+            "{output}"
+        );
+        assert!(output.contains("Notes"), "{output}");
+        assert!(fixture.message(id).await.unwrap().attachment.is_none());
+        assert!(
+            !fixture
+                .app_state
+                .boards
+                .read()
+                .await
+                .iter()
+                .any(|b| b.name == "leaked")
+        );
+    }
+
+    /// Every file below `dir`, with its contents.
+    fn files_below(dir: &std::path::Path) -> Vec<(std::path::PathBuf, String)> {
+        let mut files = Vec::new();
+
+        for entry in std::fs::read_dir(dir).unwrap() {
+            let path = entry.unwrap().path();
+
+            if path.is_dir() {
+                files.extend(files_below(&path));
+            } else {
+                let contents = String::from_utf8_lossy(&std::fs::read(&path).unwrap()).into_owned();
+
+                files.push((path, contents));
+            }
+        }
+
+        files
+    }
+
+    #[tokio::test]
+    async fn forgetting_leaves_no_trace_of_the_user() {
+        let fixture = Fixture::with_config(|config| {
+            config.allow_user_boards = true;
+            config.connection_log_file = Some(config.data_dir.join("connections.log"));
+        })
+        .await;
+        let log_file = fixture.dir.join("connections.log");
+
+        for user in [SYSOP, "alice", "bob", "carol"] {
+            fixture.add_user(user).await;
+        }
+
+        fixture.add_message("bob", DEFAULT_BOARD, "Welcome").await;
+        fixture.run_as(SYSOP, &["record alice"]).await;
+        fixture
+            .run_as(
+                "alice",
+                &[
+                    "block carol",
+                    "create lounge",
+                    "message next",
+                    "message new",
+                    "Hello",
+                    "First post",
+                    ".",
+                    "mail send bob Hi there",
+                ],
+            )
+            .await;
+        fixture
+            .run_as("bob", &["mail send alice Hi back", "block alice"])
+            .await;
+        fixture.app_state.post_digests(0).await.unwrap();
+
+        let before = files_below(&fixture.dir);
+
+        assert!(
+            before
+                .iter()
+                .any(|(path, _)| path.to_string_lossy().contains("alice")),
+            "no transcript"
+        );
+        assert!(
+            before
+                .iter()
+                .filter(|(_, contents)| contents.contains("alice"))
+                .count()
+                >= 5,
+            "{before:?}"
+        );
+        assert!(
+            std::fs::read_to_string(&log_file)
+                .unwrap()
+                .contains("alice")
+        );
+
+        let output = fixture.run_as(SYSOP, &["forget alice", "y"]).await;
+
+        assert!(output.contains("Forgot alice"), "{output}");
+        assert!(
+            output.contains("transcripts: 1 transcript(s) deleted"),
+            "{output}"
+        );
+        assert!(
+            output.contains("messages: 1 digest line(s) scrubbed"),
+            "{output}"
+        );
+        assert!(
+            output.contains("connections: 1 log line(s) scrubbed"),
+            "{output}"
+        );
+
+        for (path, contents) in files_below(&fixture.dir) {
+            assert!(
+                !path.to_string_lossy().contains("alice") && !contents.contains("alice"),
+                "{}: {contents}",
+                path.display()
+            );
+        }
+
+        let stores = fixture.app_state.stores().await;
+
+        for store in [
+            serde_json::to_string(&stores.users).unwrap(),
+            serde_json::to_string(&stores.messages).unwrap(),
+            serde_json::to_string(&stores.boards).unwrap(),
+            serde_json::to_string(&stores.private_messages).unwrap(),
+            format!("{:?}", stores.last_read),
+        ] {
+            assert!(!store.contains("alice"), "{store}");
+        }
+
+        let log = std::fs::read_to_string(&log_file).unwrap();
+
+        assert!(!log.contains("alice"), "{log}");
+
+        let output = plain(&fixture.run_as("bob", &["message list"]).await);
+
+        assert!(output.contains("[deleted] Hello"), "{output}");
+    }
+
+    #[tokio::test]
+    async fn dry_runs_report_counts_without_touching_the_files() {
+        let fixture = Fixture::new().await;
+
+        fixture.add_user(SYSOP).await;
+        fixture.add_user("alice").await;
+        fixture.add_user("bob").await;
+        fixture.add_message("alice", DEFAULT_BOARD, "First").await;
+        fixture.add_message("alice", DEFAULT_BOARD, "Second").await;
+        fixture.add_message("bob", DEFAULT_BOARD, "Third").await;
+
+        let snapshot = || async {
+            let mut files = std::collections::BTreeMap::new();
+            let mut entries = tokio::fs::read_dir(&fixture.dir).await.unwrap();
+
+            while let Some(entry) = entries.next_entry().await.unwrap() {
+                if entry.file_type().await.unwrap().is_file() {
+                    files.insert(
+                        entry.file_name(),
+                        tokio::fs::read(entry.path()).await.unwrap(),
+                    );
+                }
+            }
+
+            files
+        };
+        let mut sysop = fixture.login(SYSOP).await;
+        let before = snapshot().await;
+        let output = sysop
+            .send_all(&["forget alice dryrun", "mergeuser bob alice dryrun"])
+            .await;
+
+        assert!(output.contains("Dry run, nothing was changed"), "{output}");
+        assert!(output.contains("messages: 2 anonymized"), "{output}");
+        assert!(output.contains("messages: 1 reassigned"), "{output}");
+        assert!(fixture.user("alice").await.is_some());
+        assert!(fixture.user("bob").await.is_some());
+        assert_eq!(snapshot().await, before);
+
+        let output = sysop.send_all(&["forget alice", "y"]).await;
+
+        assert!(output.contains("messages: 2 anonymized"), "{output}");
+        assert!(fixture.user("alice").await.is_none());
+    }
+
+    #[tokio::test]
+    async fn page_size_changes_how_many_messages_are_listed() {
+        let fixture = Fixture::new().await;
+
+        fixture.add_user("alice").await;
+
+        for n in 0..12 {
+            fixture
+                .add_message("bob", DEFAULT_BOARD, &format!("Message {n}"))
+                .await;
+        }
+
+        let (mut session, _client) = fixture.session("alice").await;
+        let list = MessageCmd
+            .outcome(&mut session, Some(&["list"]))
+            .await
+            .unwrap();
+
+        assert_eq!(list.lines.len(), 12, "{:?}", list.lines);
+
+        PageSizeCmd
+            .execute(&mut session, Some(&["500"]))
+            .await
+            .unwrap();
+        PageSizeCmd
+            .execute(&mut session, Some(&["5"]))
+            .await
+            .unwrap();
+
+        let list = MessageCmd
+            .outcome(&mut session, Some(&["list"]))
+            .await
+            .unwrap();
+
+        assert_eq!(list.lines.len(), 6, "{:?}", list.lines);
+        assert_eq!(list.lines[5], "More: message list 4");
+        assert_eq!(fixture.user("alice").await.unwrap().page_size, Some(5));
+    }
+
+    #[test]
+    fn macro_names_are_single_words_starting_with_a_letter() {
+        assert!(valid_macro_name("news"));
+        assert!(valid_macro_name("catch-up_2"));
+        assert!(!valid_macro_name(""));
+        assert!(!valid_macro_name("2fast"));
+        assert!(!valid_macro_name("-x"));
+        assert!(!valid_macro_name("a;b"));
+        assert!(!valid_macro_name(&"m".repeat(33)));
+    }
+
+    #[tokio::test]
+    async fn macros_run_by_name_and_never_recurse() {
+        let fixture = Fixture::new().await;
+
+        fixture.add_user("alice").await;
+        fixture.add_board("other").await;
+        fixture.add_message("bob", DEFAULT_BOARD, "Welcome").await;
+
+        let output = fixture
+            .run_as("alice", &["macro set news boards; message list"])
+            .await;
+
+        assert!(output.contains("Macro news saved"), "{output}");
+
+        for line in ["macro run news", "news"] {
+            let output = fixture.run_as("alice", &[line]).await;
+
+            assert!(output.contains("other"), "{line}: {output}");
+            assert!(output.contains("Welcome"), "{line}: {output}");
+        }
+
+        for line in [
+            "macro set again news",
+            "macro set news boards; news",
+            "macro set news boards; Macro run news",
+        ] {
+            let output = fixture.run_as("alice", &[line]).await;
+
+            assert!(
+                output.contains("Macros can't run other macros"),
+                "{line}: {output}"
+            );
+        }
+
+        for name in ["help", "Boards", "MACROS"] {
+            let output = fixture
+                .run_as("alice", &[&format!("macro set {name} boards")])
+                .await;
+
+            assert!(output.contains("Invalid macro name"), "{name}: {output}");
+        }
+
+        let output = fixture.run_as("alice", &["help"]).await;
+
+        assert!(output.contains("macro"), "{output}");
+        assert_eq!(
+            fixture
+                .user("alice")
+                .await
+                .unwrap()
+                .macros
+                .keys()
+                .collect::<Vec<_>>(),
+            ["news"]
+        );
+    }
+
+    #[tokio::test]
+    async fn motd_shows_the_current_file() {
+        let fixture = Fixture::with_config(|config| {
+            config.motd_file = Some(config.data_dir.join("motd.txt"));
+            config.welcome_msg = None;
+        })
+        .await;
+        let path = fixture.dir.join("motd.txt");
+
+        std::fs::write(&path, "Board meeting on Friday\n").unwrap();
+
+        let mut client = fixture.connect().await;
+
+        assert!(
+            client
+                .send("motd")
+                .await
+                .contains("Board meeting on Friday"),
+            "logged off sessions can read it too"
+        );
+
+        std::fs::write(&path, "Meeting moved to Monday\n").unwrap();
+
+        let output = client.send("motd").await;
+
+        assert!(output.contains("Meeting moved to Monday"), "{output}");
+
+        std::fs::remove_file(&path).unwrap();
+
+        let output = client.send("motd").await;
+
+        assert!(output.contains("No message of the day"), "{output}");
+        client.close().await;
+    }
+
+    #[tokio::test]
+    async fn anonymous_posts_hide_the_author_but_audit_them() {
+        let fixture = Fixture::new().await;
+
+        fixture.add_user("alice").await;
+        fixture.add_user("bob").await;
+        fixture
+            .app_state
+            .set_board_anonymous(DEFAULT_BOARD, true)
+            .await
+            .unwrap();
+
+        fixture
+            .run_as("alice", &["message new", "Whisper", "Guess who", "."])
+            .await;
+
+        let message = fixture.message(0).await.unwrap();
+        assert_eq!(message.username, ANONYMOUS_AUTHOR);
+        assert_eq!(message.author(), "alice");
+
+        let output = plain(
+            &fixture
+                .run_as("bob", &["message list", "message read 0"])
+                .await,
+        );
+        assert!(
+            output.contains(&format!(
+                "0 {} {ANONYMOUS_AUTHOR} Whisper",
+                time::format_date(message.created_at)
+            )),
+            "{output}"
+        );
+        assert!(!output.contains("alice"), "{output}");
+
+        let audit = std::fs::read_to_string(fixture.dir.join("audit.log")).unwrap();
+        assert!(audit.contains("alice anonymous post 0"), "{audit}");
+    }
+
+    #[tokio::test]
+    async fn chown_moves_the_hidden_author_of_anonymous_posts() {
+        let fixture = Fixture::new().await;
+
+        fixture.add_user(SYSOP).await;
+        fixture.add_user("bob").await;
+
+        let id = fixture
+            .add_message(ANONYMOUS_AUTHOR, DEFAULT_BOARD, "Secret")
+            .await;
+
+        fixture
+            .app_state
+            .update_messages(|messages| messages[0].real_author = Some("alice".to_owned()))
+            .await
+            .unwrap();
+
+        let chown = format!("message chown {id} bob");
+        fixture.run_as(SYSOP, &[&chown]).await;
+
+        let message = fixture.message(id).await.unwrap();
+        assert_eq!(message.username, ANONYMOUS_AUTHOR);
+        assert_eq!(message.author(), "bob");
+
+        let audit = std::fs::read_to_string(fixture.dir.join("audit.log")).unwrap();
+        assert!(
+            audit.contains(&format!("chown message {id} from alice to bob")),
+            "{audit}"
+        );
+    }
+
+    #[tokio::test]
+    async fn anonymous_authors_attach_to_their_own_posts() {
+        let fixture = Fixture::new().await;
+
+        fixture.add_user("alice").await;
+        fixture.add_user("bob").await;
+        fixture
+            .app_state
+            .set_board_anonymous(DEFAULT_BOARD, true)
+            .await
+            .unwrap();
+        fixture
+            .run_as("alice", &["message new", "Whisper", "Guess who", "."])
+            .await;
+
+        let output = fixture.run_as("bob", &["message attach 0 notes.txt"]).await;
+
+        assert!(
+            output.contains("You can only attach files to your own messages"),
+            "{output}"
+        );
+
+        let output = fixture
+            .run_as("alice", &["message attach 0 notes.txt", "c2VjcmV0", "."])
+            .await;
+
+        assert!(
+            output.contains("Attached notes.txt (6 bytes) to message 0"),
+            "{output}"
+        );
+    }
+
+    #[tokio::test]
+    async fn diag_counts_the_active_sessions() {
+        let fixture = Fixture::new().await;
+
+        fixture.add_user(SYSOP).await;
+        fixture.add_user("alice").await;
+
+        let mut sysop = fixture.login(SYSOP).await;
+        let alice = fixture.login("alice").await;
+        let guest = fixture.connect().await;
+
+        let output = sysop.send("diag").await;
+
+        assert!(
+            output.contains("sessions: 3 connected, 2 logged on"),
+            "{output}"
+        );
+
+        alice.close().await;
+        guest.close().await;
+
+        let output = sysop.send("diag").await;
+
+        assert!(
+            output.contains("sessions: 1 connected, 1 logged on"),
+            "{output}"
+        );
+        sysop.close().await;
+    }
+
+    #[tokio::test]
+    async fn messages_are_read_by_id_not_position() {
+        let fixture = Fixture::new().await;
+
+        for subject in ["First", "Second", "Third"] {
+            fixture.add_message("bob", DEFAULT_BOARD, subject).await;
+        }
+
+        fixture
+            .app_state
+            .update_messages(|messages| {
+                messages[0].id = 3;
+                messages[1].id = 10;
+                messages[2].id = 42;
+            })
+            .await
+            .unwrap();
+
+        let (mut session, _client) = fixture.session("alice").await;
+        let read = MessageCmd
+            .outcome(&mut session, Some(&["read", "10"]))
+            .await
+            .unwrap();
+
+        assert!(
+            read.lines.iter().any(|l| l.contains("Body of Second")),
+            "{:?}",
+            read.lines
+        );
+
+        let error = MessageCmd
+            .outcome(&mut session, Some(&["read", "1"]))
+            .await
+            .unwrap_err();
+
+        assert_eq!(error.to_string(), "Invalid message id");
+    }
+
+    #[tokio::test]
+    async fn private_messages_are_rate_limited() {
+        let fixture = Fixture::with_config(|config| config.max_dms_per_min = Some(2)).await;
+
+        fixture.add_user("alice").await;
+        fixture.add_user("bob").await;
+
+        let output = fixture
+            .run_as(
+                "alice",
+                &[
+                    "mail send bob one",
+                    "mail send bob two",
+                    "mail send bob three",
+                ],
+            )
+            .await;
+
+        assert_eq!(output.matches("Message sent to bob").count(), 2, "{output}");
+        assert!(
+            output.contains("You're sending messages too fast"),
+            "{output}"
+        );
+        assert_eq!(fixture.app_state.private_messages.read().await.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn blocked_senders_are_silently_dropped() {
+        let fixture = Fixture::new().await;
+
+        fixture.add_user("alice").await;
+        fixture.add_user("bob").await;
+
+        let output = fixture.run_as("bob", &["block alice"]).await;
+
+        assert!(
+            output.contains("Private messages from alice will be dropped"),
+            "{output}"
+        );
+        assert_eq!(fixture.user("bob").await.unwrap().blocked, ["alice"]);
+
+        let output = fixture.run_as("alice", &["mail send bob hello"]).await;
+
+        assert!(output.contains("Message sent to bob"), "{output}");
+
+        let output = fixture.run_as("bob", &["mail inbox"]).await;
+
+        assert!(output.contains("No messages"), "{output}");
+    }
+
+    #[tokio::test]
+    async fn pm_sends_and_reads_private_messages() {
+        let fixture = Fixture::new().await;
+
+        fixture.add_user("alice").await;
+        fixture.add_user("bob").await;
+
+        let output = fixture
+            .run_as(
+                "alice",
+                &["pm send", "pm send carol Hi", "pm send bob Hi there"],
+            )
+            .await;
+
+        assert!(
+            output.contains("Usage: pm inbox | pm send <user> <text>"),
+            "{output}"
+        );
+        assert!(output.contains("Could not find user"), "{output}");
+        assert!(output.contains("Message sent to bob"), "{output}");
+
+        let output = fixture.run_as("bob", &["pm inbox", "pm"]).await;
+
+        let listed: Vec<&str> = output
+            .lines()
+            .filter(|l| l.contains("alice: Hi there"))
+            .collect();
+
+        // Listed as unread the first time, and as read after that.
+        assert_eq!(listed.len(), 2, "{output}");
+        assert!(listed[0].contains('*'), "{output}");
+        assert!(!listed[1].contains('*'), "{output}");
+        assert!(fixture.app_state.private_messages.read().await[0].read);
+    }
+
+    #[tokio::test]
+    async fn only_authors_delete_their_messages() {
+        let fixture = Fixture::new().await;
+
+        fixture.add_user("alice").await;
+
+        let own = fixture.add_message("alice", DEFAULT_BOARD, "Mine").await;
+        let other = fixture.add_message("bob", DEFAULT_BOARD, "Theirs").await;
+        let (mut session, _client) = fixture.session("alice").await;
+        let refused = MessageCmd
+            .outcome(&mut session, Some(&["delete", &other.to_string()]))
+            .await
+            .unwrap();
+
+        assert_eq!(refused.lines, ["You can only delete your own messages"]);
+        assert!(!fixture.message(other).await.unwrap().deleted);
+
+        let deleted = MessageCmd
+            .outcome(&mut session, Some(&["delete", &own.to_string()]))
+            .await
+            .unwrap();
+
+        assert_eq!(deleted.lines, ["1 message(s) deleted"]);
+        assert!(fixture.message(own).await.unwrap().deleted);
+
+        let saved = std::fs::read_to_string(fixture.dir.join("messages.json")).unwrap();
+
+        assert!(saved.contains("\"deleted\": true"), "{saved}");
+
+        let anonymous = fixture.add_message("alice", DEFAULT_BOARD, "Hidden").await;
+
+        fixture
+            .app_state
+            .update_messages(|messages| {
+                let message = messages.iter_mut().find(|m| m.id == anonymous).unwrap();
+
+                message.real_author = Some("alice".to_owned());
+                message.username = ANONYMOUS_AUTHOR.to_owned();
+            })
+            .await
+            .unwrap();
+
+        let deleted = MessageCmd
+            .outcome(&mut session, Some(&["delete", &anonymous.to_string()]))
+            .await
+            .unwrap();
+
+        assert_eq!(deleted.lines, ["1 message(s) deleted"]);
+    }
+
+    #[tokio::test]
+    async fn who_lists_online_users_oldest_first() {
+        let fixture = Fixture::new().await;
+
+        fixture.add_user("alice").await;
+        fixture.add_user("bob").await;
+
+        let mut alice = fixture.login("alice").await;
+        let bob = fixture.login("bob").await;
+
+        // Logins within the same second keep their order only by chance.
+        for online in fixture.app_state.online.write().await.values_mut() {
+            if online.username == "alice" {
+                online.since -= 60;
+            }
+        }
+
+        let output = alice.send("online").await;
+
+        assert!(output.contains("2 user(s) online"), "{output}");
+        assert!(output.contains("alice since "), "{output}");
+        assert!(output.contains("(1m)"), "{output}");
+        assert!(
+            output.find("alice since") < output.find("bob since"),
+            "{output}"
+        );
+
+        bob.close().await;
+
+        let output = alice.send("who").await;
+
+        assert!(output.contains("1 user(s) online"), "{output}");
+        alice.close().await;
+    }
+
+    #[tokio::test]
+    async fn synthetic_authors_cannot_be_registered() {
+        let fixture = Fixture::new().await;
+
+        for username in ["Anonymous", "DIGEST", "[Deleted]"] {
+            let mut client = fixture.connect().await;
+            let output = client.send_all(&["register", username, "pw"]).await;
+
+            assert!(output.contains("That username is reserved"), "{output}");
+            assert!(fixture.app_state.users.read().await.is_empty());
+            client.close().await;
+        }
+    }
+
+    #[tokio::test]
+    async fn usernames_are_taken_in_any_case() {
+        let fixture = Fixture::new().await;
+        let mut client = fixture.connect().await;
+        let output = client.send_all(&["register", "alice", "pw"]).await;
+
+        assert!(output.contains("Registration successful"), "{output}");
+        client.close().await;
+
+        for username in ["alice", "Alice"] {
+            let mut client = fixture.connect().await;
+            let output = client.send_all(&["register", username, "pw"]).await;
+
+            assert!(output.contains("Username already taken"), "{output}");
+            assert!(!output.contains("Login successful"), "{output}");
+            client.close().await;
+        }
+
+        assert_eq!(fixture.app_state.users.read().await.len(), 1);
+    }
+
+    #[test]
+    fn new_ids_stay_above_every_existing_one() {
+        let mut users: Vec<User> = [0, 1, 2]
+            .map(|id| User {
+                id,
+                ..Default::default()
+            })
+            .into();
+        let mut messages: Vec<Message> = [0, 1, 2]
+            .map(|id| Message {
+                id,
+                ..Default::default()
+            })
+            .into();
+
+        assert_eq!(RegisterCmd::generate_id(&[]), 0);
+        assert_eq!(MessageCmd::generate_id(&[]), 0);
+
+        users.remove(1);
+        messages.remove(1);
+        assert_eq!(RegisterCmd::generate_id(&users), 3);
+        assert_eq!(MessageCmd::generate_id(&messages), 3);
+
+        // The highest id doesn't have to come last.
+        messages.reverse();
+        assert_eq!(MessageCmd::generate_id(&messages), 3);
+    }
+
+    #[tokio::test]
+    async fn admins_kick_and_broadcast_like_sysops() {
+        let fixture = Fixture::new().await;
+
+        fixture.add_user("alice").await;
+        fixture.add_user("bob").await;
+        fixture.add_user("carol").await;
+        fixture.app_state.users.write().await[0].role = Role::Admin;
+
+        let output = fixture.run_as("bob", &["kick carol"]).await;
+
+        assert!(output.contains("Permission denied"), "{output}");
+
+        let mut carol = fixture.login("carol").await;
+        let output = fixture.run_as("alice", &["broadcast back in five"]).await;
+
+        assert!(output.contains("Broadcast sent to"), "{output}");
+        assert!(carol.output().await.contains("back in five"));
+
+        let output = fixture.run_as("alice", &["kick carol"]).await;
+
+        assert!(output.contains("Kicked 1 session(s) of carol"), "{output}");
+        assert!(carol.output().await.contains("kicked by a sysop"));
+        assert!(carol.finished().await);
+    }
+
+    #[tokio::test]
+    async fn deluser_dry_run_changes_nothing() {
+        let fixture = Fixture::new().await;
+
+        fixture.add_user(SYSOP).await;
+        fixture.add_user("alice").await;
+
+        let alice = fixture.login("alice").await;
+        let output = fixture.run_as(SYSOP, &["deluser alice dryrun"]).await;
+
+        assert!(output.contains("Dry run, nothing was changed"), "{output}");
+        assert!(output.contains("users: 1 account removed"), "{output}");
+        assert!(output.contains("sessions: 1 disconnected"), "{output}");
+        assert!(fixture.user("alice").await.is_some());
+
+        let output = fixture.run_as(SYSOP, &["deluser alice", "y"]).await;
+
+        assert!(output.contains("Deleted alice"), "{output}");
+        assert!(output.contains("sessions: 1 disconnected"), "{output}");
+        assert!(fixture.user("alice").await.is_none());
+        assert!(alice.finished().await);
+    }
+
+    #[tokio::test]
+    async fn commands_resolve_in_any_case() {
+        let fixture = Fixture::new().await;
+
+        fixture.add_user("alice").await;
+
+        let mut client = fixture.connect().await;
+        let output = client.send_all(&["LOGIN", "alice", PASSWORD]).await;
+
+        assert!(output.contains("Login successful"), "{output}");
+
+        let output = client.send("BoArDs").await;
+
+        assert!(output.contains("Boards"), "{output}");
+        assert!(!output.contains("Unknown command"), "{output}");
+        client.close().await;
+    }
+
+    #[tokio::test]
+    async fn chown_lets_the_new_owner_edit() {
+        let fixture = Fixture::new().await;
+
+        fixture.add_user(SYSOP).await;
+        fixture.add_user("alice").await;
+        fixture.add_user("bob").await;
+
+        let id = fixture.add_message("alice", DEFAULT_BOARD, "Mine").await;
+        let edit = format!("message edit {id}");
+
+        let output = fixture.run_as("bob", &[&edit]).await;
+        assert!(output.contains("You can only edit your own messages"));
+
+        let chown = format!("message chown {id} bob");
+        let output = fixture.run_as(SYSOP, &[&chown]).await;
+        assert!(output.contains(&format!("Message {id} now belongs to bob")));
+
+        let output = fixture
+            .run_as("bob", &[&edit, "", "Rewritten by bob", "."])
+            .await;
+        assert!(output.contains(&format!("Message {id} edited")), "{output}");
+
+        let message = fixture.message(id).await.unwrap();
+        assert_eq!(message.author(), "bob");
+        assert_eq!(message.body, "Rewritten by bob\r\n");
+    }
+
+    #[tokio::test]
+    async fn only_sysops_edit_on_anonymous_boards() {
+        let fixture = Fixture::new().await;
+
+        fixture.add_user(SYSOP).await;
+        fixture.add_user("alice").await;
+        fixture
+            .app_state
+            .set_board_anonymous(DEFAULT_BOARD, true)
+            .await
+            .unwrap();
+        fixture
+            .run_as("alice", &["message new", "Whisper", "Guess who", "."])
+            .await;
+
+        let output = fixture.run_as("alice", &["message edit 0"]).await;
+        assert!(output.contains("only be edited by a sysop"), "{output}");
+
+        let output = fixture
+            .run_as(SYSOP, &["message edit 0", "", "Moderated", "."])
+            .await;
+        assert!(output.contains("Message 0 edited"), "{output}");
+        assert_eq!(fixture.message(0).await.unwrap().body, "Moderated\r\n");
+    }
+
+    #[tokio::test]
+    async fn edits_can_cover_every_cross_posted_copy() {
+        let fixture = Fixture::new().await;
+
+        fixture.add_user(SYSOP).await;
+        fixture.add_board("news").await;
+        fixture.add_message(SYSOP, DEFAULT_BOARD, "Hello").await;
+        fixture.run_as(SYSOP, &["message crosspost 0 news"]).await;
+
+        let output = fixture
+            .run_as(SYSOP, &["message edit 0", "n", "Only here", "", "."])
+            .await;
+
+        assert!(
+            output.contains("Also edit its 1 cross-posted copies?"),
+            "{output}"
+        );
+        assert!(output.contains("Message 0 edited"), "{output}");
+        assert_eq!(fixture.message(1).await.unwrap().subject, "Hello");
+
+        let output = fixture
+            .run_as(
+                SYSOP,
+                &["message edit 0", "y", "Everywhere", "New body", "."],
+            )
+            .await;
+
+        assert!(output.contains("2 message(s) edited"), "{output}");
+
+        for id in [0, 1] {
+            let message = fixture.message(id).await.unwrap();
+
+            assert_eq!(message.subject, "Everywhere");
+            assert_eq!(message.body, "New body\r\n");
+        }
     }
 }
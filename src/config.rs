@@ -1,15 +1,132 @@
-use std::path::PathBuf;
+use std::{ops::RangeInclusive, path::PathBuf};
 
 use anyhow::{Context, Result};
-use serde::Deserialize;
-use tokio::fs::read_to_string;
+use bcrypt::DEFAULT_COST;
+use serde::{Deserialize, Serialize};
+use tokio::fs::{File, metadata, read_to_string};
 
-#[derive(Deserialize)]
+use tracing::warn;
+use tracing_subscriber::EnvFilter;
+
+use crate::{ansi::AnsiColor, banner::BannerEncoding, storage::StorageKind, theme::ThemeConfig};
+
+/// Costs bcrypt accepts.
+const BCRYPT_COSTS: RangeInclusive<u32> = 4..=31;
+/// Settings that must never be shown back to users, not even sysops.
+const SECRET_FIELDS: &[&str] = &["tls_key"];
+/// Settings only read at startup, a reload keeps the running values.
+const RESTART_FIELDS: &[&str] = &[
+    "archive_after_days",
+    "command_manifest_file",
+    "data_dir",
+    "digest_interval_hours",
+    "hostname",
+    "json_port",
+    "listeners",
+    "max_connections",
+    "port",
+    "purge_deleted_after_days",
+    "storage",
+    "tls_cert",
+    "tls_key",
+];
+
+#[derive(Clone, Deserialize, Serialize)]
 pub struct Config {
+    #[serde(default)]
+    pub allow_user_boards: bool,
+    pub archive_after_days: Option<u64>,
+    #[serde(default)]
+    pub banner_encoding: BannerEncoding,
     pub banner_file: Option<PathBuf>,
+    pub banner_flood_limit: Option<usize>,
+    pub banner_flood_window_secs: Option<u64>,
+    #[serde(default = "default_bcrypt_cost")]
+    pub bcrypt_cost: u32,
+    #[serde(default)]
+    pub boards: Vec<String>,
+    #[serde(default = "default_color")]
+    pub color: bool,
+    pub command_manifest_file: Option<PathBuf>,
+    pub connection_log_file: Option<PathBuf>,
+    #[serde(default = "default_connection_log_max_bytes")]
+    pub connection_log_max_bytes: u64,
+    #[serde(default = "default_data_dir")]
+    pub data_dir: PathBuf,
+    pub default_board: Option<String>,
+    pub digest_interval_hours: Option<u64>,
+    #[serde(default = "default_duplicate_post_window_secs")]
+    pub duplicate_post_window_secs: u64,
+    pub farewell_msg: Option<String>,
+    #[serde(default)]
+    pub farewell_stats: bool,
+    #[serde(default)]
+    pub forget_removes_messages: bool,
     pub hostname: String,
+    #[serde(default = "default_idle_exempt_sysops")]
+    pub idle_exempt_sysops: bool,
+    pub idle_timeout_secs: Option<u64>,
+    pub json_port: Option<u16>,
+    /// Seconds between keepalives sent to idle telnet clients. See `Session::keepalive`
+    /// for how long a vanished client can still take to be noticed.
+    pub keepalive_secs: Option<u64>,
+    /// Addresses to accept telnet connections on, `hostname` and `port` when empty.
+    #[serde(default)]
+    pub listeners: Vec<ListenerConfig>,
+    /// Which events get logged, as a tracing filter such as `info` or
+    /// `info,gluboard::session=debug`.
+    #[serde(default = "default_log_level")]
+    pub log_level: String,
+    /// Failed logins in a row, across connections, before an account is locked.
+    #[serde(default = "default_login_lockout_attempts")]
+    pub login_lockout_attempts: usize,
+    #[serde(default = "default_login_lockout_secs")]
+    pub login_lockout_secs: u64,
+    #[serde(default = "default_max_attachment_size")]
+    pub max_attachment_size: usize,
+    #[serde(default = "default_max_attachments_total")]
+    pub max_attachments_total: usize,
+    #[serde(default = "default_max_boards_per_user")]
+    pub max_boards_per_user: usize,
+    pub max_connections: Option<usize>,
+    pub max_connections_per_min: Option<usize>,
+    pub max_dms_per_min: Option<usize>,
+    #[serde(default = "default_max_line_bytes")]
+    pub max_line_bytes: usize,
+    /// Password attempts a single `login` allows before giving up.
+    #[serde(default = "default_max_login_attempts")]
+    pub max_login_attempts: usize,
+    #[serde(default = "default_max_message_bytes")]
+    pub max_message_bytes: usize,
+    #[serde(default = "default_max_thread_depth")]
+    pub max_thread_depth: usize,
+    pub motd_file: Option<PathBuf>,
+    #[serde(default)]
+    pub normalize_subjects: bool,
+    pub onboarding_file: Option<PathBuf>,
+    #[serde(default = "default_outbound_queue_size")]
+    pub outbound_queue_size: usize,
+    #[serde(default = "default_page_size")]
+    pub page_size: usize,
     pub port: u16,
+    pub purge_deleted_after_days: Option<u64>,
+    #[serde(default)]
+    pub record_sessions: bool,
+    #[serde(default)]
+    pub self_test_on_startup: bool,
+    #[serde(default)]
+    pub storage: StorageKind,
+    #[serde(default)]
+    pub sysops: Vec<String>,
+    #[serde(default = "default_telnet")]
+    pub telnet: bool,
+    #[serde(default)]
+    pub theme: ThemeConfig,
+    pub tls_cert: Option<PathBuf>,
+    pub tls_key: Option<PathBuf>,
     pub welcome_msg: Option<String>,
+    #[serde(default = "default_wrap_width")]
+    pub wrap_width: usize,
 }
 
 impl Config {
@@ -19,7 +136,7 @@ impl Config {
             Err(_) => match read_to_string("config.json").await {
                 Ok(json) => Ok(serde_json::from_str(&json).context("Could not parse config.json")?),
                 Err(e) => {
-                    eprintln!("{e}: Could not access any configuration files, using defaults");
+                    warn!("{e}: Could not access any configuration files, using defaults");
                     Ok(Self::default())
                 }
             },
@@ -27,13 +144,431 @@ impl Config {
     }
 }
 
+impl Config {
+    /// Checks settings that only fail at runtime and returns a warning for each problem.
+    pub async fn validate(&self) -> Vec<String> {
+        let mut warnings = Vec::new();
+
+        if !BCRYPT_COSTS.contains(&self.bcrypt_cost) {
+            warnings.push(format!(
+                "bcrypt_cost {} is outside {}..={}, using {DEFAULT_COST}",
+                self.bcrypt_cost,
+                BCRYPT_COSTS.start(),
+                BCRYPT_COSTS.end()
+            ));
+        }
+
+        if let Err(e) = EnvFilter::try_new(&self.log_level) {
+            warnings.push(format!("log_level {}: {e}, using info", self.log_level));
+        }
+
+        for (name, attempts) in [
+            ("login_lockout_attempts", self.login_lockout_attempts),
+            ("max_login_attempts", self.max_login_attempts),
+        ] {
+            if attempts == 0 {
+                warnings.push(format!("{name} must be at least 1, using 1"));
+            }
+        }
+
+        for (name, file) in [
+            ("banner_file", &self.banner_file),
+            ("motd_file", &self.motd_file),
+        ] {
+            let Some(file) = file else {
+                continue;
+            };
+            let path = file.display();
+
+            match metadata(file).await {
+                Err(e) => warnings.push(format!("{name} {path}: {e}")),
+                Ok(meta) if meta.is_dir() => {
+                    warnings.push(format!("{name} {path}: is a directory"))
+                }
+                Ok(_) => {
+                    if let Err(e) = File::open(file).await {
+                        warnings.push(format!("{name} {path}: {e}"));
+                    }
+                }
+            }
+        }
+
+        for color in [
+            &self.theme.error,
+            &self.theme.success,
+            &self.theme.header,
+            &self.theme.prompt,
+            &self.theme.id,
+            &self.theme.author,
+        ]
+        .into_iter()
+        .flatten()
+        {
+            if let Err(e) = color.parse::<AnsiColor>() {
+                warnings.push(format!("theme: {e}"));
+            }
+        }
+
+        warnings
+    }
+
+    /// The filter for which events get logged, falling back to `info` when `log_level`
+    /// doesn't parse.
+    pub fn log_filter(&self) -> EnvFilter {
+        EnvFilter::try_new(&self.log_level).unwrap_or_else(|_| EnvFilter::new("info"))
+    }
+
+    /// The bcrypt cost to hash new passwords with, falling back to the default when the
+    /// configured one is out of range.
+    pub fn hash_cost(&self) -> u32 {
+        if BCRYPT_COSTS.contains(&self.bcrypt_cost) {
+            self.bcrypt_cost
+        } else {
+            DEFAULT_COST
+        }
+    }
+
+    /// Password attempts per `login`, at least one.
+    pub fn login_attempts(&self) -> usize {
+        self.max_login_attempts.max(1)
+    }
+
+    /// Failed logins that lock an account, at least one.
+    pub fn lockout_attempts(&self) -> usize {
+        self.login_lockout_attempts.max(1)
+    }
+
+    /// Merges a freshly read configuration into this one. Returns the merged config
+    /// along with the fields that changed and those that changed but need a restart.
+    pub fn reloaded(&self, fresh: Config) -> Result<(Config, Vec<String>, Vec<String>)> {
+        let running = toml::Table::try_from(self).context("Could not serialize config")?;
+        let mut merged = toml::Table::try_from(fresh).context("Could not serialize config")?;
+        let mut changed = Vec::new();
+        let mut needs_restart = Vec::new();
+        let mut fields: Vec<String> = running.keys().chain(merged.keys()).cloned().collect();
+
+        fields.sort();
+        fields.dedup();
+
+        for field in fields {
+            if running.get(&field) == merged.get(&field) {
+                continue;
+            }
+
+            if RESTART_FIELDS.contains(&field.as_str()) {
+                match running.get(&field) {
+                    Some(value) => merged.insert(field.clone(), value.clone()),
+                    None => merged.remove(&field),
+                };
+                needs_restart.push(field);
+            } else {
+                changed.push(field);
+            }
+        }
+
+        let merged = merged.try_into().context("Could not merge config")?;
+
+        Ok((merged, changed, needs_restart))
+    }
+
+    /// Renders the effective configuration as TOML with secret values redacted.
+    pub fn redacted(&self) -> Result<String> {
+        let mut table = toml::Table::try_from(self).context("Could not serialize config")?;
+
+        redact(&mut table);
+
+        if let Some(toml::Value::Array(listeners)) = table.get_mut("listeners") {
+            for listener in listeners {
+                if let toml::Value::Table(listener) = listener {
+                    redact(listener);
+                }
+            }
+        }
+
+        toml::to_string(&table).context("Could not serialize config")
+    }
+
+    /// Every address to listen on, falling back to the top level `hostname`, `port`
+    /// and TLS settings when no `listeners` are configured.
+    pub fn listeners(&self) -> Vec<ListenerConfig> {
+        if !self.listeners.is_empty() {
+            return self.listeners.clone();
+        }
+
+        vec![ListenerConfig {
+            hostname: self.hostname.clone(),
+            port: self.port,
+            tls_cert: self.tls_cert.clone(),
+            tls_key: self.tls_key.clone(),
+        }]
+    }
+}
+
 impl Default for Config {
     fn default() -> Self {
         Self {
+            allow_user_boards: false,
+            archive_after_days: None,
+            banner_encoding: BannerEncoding::Utf8,
             banner_file: None,
+            banner_flood_limit: None,
+            banner_flood_window_secs: None,
+            bcrypt_cost: default_bcrypt_cost(),
+            boards: Vec::new(),
+            color: true,
+            command_manifest_file: None,
+            connection_log_file: None,
+            connection_log_max_bytes: default_connection_log_max_bytes(),
+            data_dir: default_data_dir(),
+            default_board: None,
+            digest_interval_hours: None,
+            duplicate_post_window_secs: default_duplicate_post_window_secs(),
+            farewell_msg: None,
+            farewell_stats: false,
+            forget_removes_messages: false,
             hostname: "127.0.0.1".to_string(),
+            idle_exempt_sysops: true,
+            idle_timeout_secs: None,
+            json_port: None,
+            keepalive_secs: None,
+            listeners: Vec::new(),
+            log_level: default_log_level(),
+            login_lockout_attempts: default_login_lockout_attempts(),
+            login_lockout_secs: default_login_lockout_secs(),
+            max_attachment_size: default_max_attachment_size(),
+            max_attachments_total: default_max_attachments_total(),
+            max_boards_per_user: default_max_boards_per_user(),
+            max_connections: None,
+            max_connections_per_min: None,
+            max_dms_per_min: None,
+            max_line_bytes: default_max_line_bytes(),
+            max_login_attempts: default_max_login_attempts(),
+            max_message_bytes: default_max_message_bytes(),
+            max_thread_depth: default_max_thread_depth(),
+            motd_file: None,
+            normalize_subjects: false,
+            onboarding_file: None,
+            outbound_queue_size: default_outbound_queue_size(),
+            page_size: default_page_size(),
             port: 1981,
+            purge_deleted_after_days: None,
+            record_sessions: false,
+            self_test_on_startup: false,
+            storage: StorageKind::Json,
+            sysops: Vec::new(),
+            telnet: true,
+            theme: ThemeConfig::default(),
+            tls_cert: None,
+            tls_key: None,
             welcome_msg: Some("Welcome to this BBS!".to_string()),
+            wrap_width: default_wrap_width(),
+        }
+    }
+}
+
+fn redact(table: &mut toml::Table) {
+    for field in SECRET_FIELDS {
+        if let Some(value) = table.get_mut(*field) {
+            *value = toml::Value::String(String::from("<redacted>"));
+        }
+    }
+}
+
+/// One `[[listeners]]` entry, an address serving telnet in plaintext or over TLS.
+#[derive(Clone, Deserialize, Serialize)]
+pub struct ListenerConfig {
+    pub hostname: String,
+    pub port: u16,
+    pub tls_cert: Option<PathBuf>,
+    pub tls_key: Option<PathBuf>,
+}
+
+fn default_log_level() -> String {
+    String::from("info")
+}
+
+fn default_bcrypt_cost() -> u32 {
+    DEFAULT_COST
+}
+
+fn default_color() -> bool {
+    true
+}
+
+fn default_telnet() -> bool {
+    true
+}
+
+fn default_idle_exempt_sysops() -> bool {
+    true
+}
+
+fn default_connection_log_max_bytes() -> u64 {
+    1024 * 1024
+}
+
+fn default_data_dir() -> PathBuf {
+    PathBuf::from(".")
+}
+
+fn default_duplicate_post_window_secs() -> u64 {
+    60
+}
+
+fn default_outbound_queue_size() -> usize {
+    256
+}
+
+fn default_login_lockout_attempts() -> usize {
+    3
+}
+
+fn default_login_lockout_secs() -> u64 {
+    300
+}
+
+fn default_max_line_bytes() -> usize {
+    4096
+}
+
+fn default_max_login_attempts() -> usize {
+    3
+}
+
+fn default_max_message_bytes() -> usize {
+    64 * 1024
+}
+
+fn default_max_attachment_size() -> usize {
+    64 * 1024
+}
+
+fn default_max_attachments_total() -> usize {
+    16 * 1024 * 1024
+}
+
+fn default_max_boards_per_user() -> usize {
+    3
+}
+
+fn default_page_size() -> usize {
+    20
+}
+
+fn default_max_thread_depth() -> usize {
+    8
+}
+
+fn default_wrap_width() -> usize {
+    80
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::test_dir;
+
+    #[test]
+    fn secret_settings_are_redacted() {
+        let config = Config {
+            tls_key: Some("/etc/bbs/secret-key.pem".into()),
+            ..Config::default()
+        };
+        let table = toml::Table::try_from(&config).unwrap();
+
+        // A misspelled field would silently never be redacted.
+        for field in SECRET_FIELDS {
+            assert!(table.contains_key(*field), "{field}");
         }
+
+        let shown = config.redacted().unwrap();
+
+        assert!(shown.contains("tls_key = \"<redacted>\""), "{shown}");
+        assert!(!shown.contains("secret-key"), "{shown}");
+    }
+
+    #[tokio::test]
+    async fn unusable_banner_files_are_warned_about() {
+        let dir = test_dir();
+        let banner = dir.join("banner.txt");
+
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(&banner, "Hello").unwrap();
+
+        let warnings = |banner_file: PathBuf| {
+            let config = Config {
+                banner_file: Some(banner_file),
+                ..Config::default()
+            };
+
+            async move { config.validate().await }
+        };
+
+        assert!(warnings(banner.clone()).await.is_empty());
+        assert_eq!(
+            warnings(dir.clone()).await,
+            [format!("banner_file {}: is a directory", dir.display())]
+        );
+
+        let missing = warnings(dir.join("missing.txt")).await;
+
+        assert_eq!(missing.len(), 1);
+        assert!(missing[0].starts_with("banner_file "), "{missing:?}");
+
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[tokio::test]
+    async fn out_of_range_bcrypt_costs_fall_back_to_the_default() {
+        let config = Config {
+            bcrypt_cost: 40,
+            ..Config::default()
+        };
+
+        assert_eq!(
+            config.validate().await,
+            [format!(
+                "bcrypt_cost 40 is outside 4..=31, using {DEFAULT_COST}"
+            )]
+        );
+        assert_eq!(config.hash_cost(), DEFAULT_COST);
+    }
+
+    #[tokio::test]
+    async fn login_attempts_are_at_least_one() {
+        let config = Config {
+            login_lockout_attempts: 0,
+            max_login_attempts: 0,
+            ..Config::default()
+        };
+
+        assert_eq!(
+            config.validate().await,
+            [
+                "login_lockout_attempts must be at least 1, using 1",
+                "max_login_attempts must be at least 1, using 1",
+            ]
+        );
+        assert_eq!(config.login_attempts(), 1);
+        assert_eq!(config.lockout_attempts(), 1);
+    }
+
+    #[test]
+    fn reloading_keeps_startup_only_fields() {
+        let running = Config::default();
+        let fresh = Config {
+            port: running.port + 1,
+            page_size: running.page_size + 1,
+            tls_key: Some("key.pem".into()),
+            ..Config::default()
+        };
+
+        let (merged, changed, needs_restart) = running.reloaded(fresh).unwrap();
+
+        assert_eq!(changed, ["page_size"]);
+        assert_eq!(needs_restart, ["port", "tls_key"]);
+        assert_eq!(merged.page_size, running.page_size + 1);
+        assert_eq!(merged.port, running.port);
+        assert_eq!(merged.tls_key, None);
     }
 }
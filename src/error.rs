@@ -0,0 +1,38 @@
+use std::fmt::{self, Display, Formatter};
+
+use anyhow::Error;
+
+/// Shown to users when a command fails for a reason that is not theirs to fix.
+const INTERNAL_ERROR: &str = "Something went wrong, please try again later";
+
+/// An error meant for the user, like a mistyped command or a message id that doesn't
+/// exist. Commands fail with it directly or attach it as context to a lower level
+/// error. Every other error is only logged and the user gets a generic message.
+#[derive(Debug)]
+pub struct UserError(String);
+
+impl UserError {
+    pub fn new(message: impl Into<String>) -> Self {
+        Self(message.into())
+    }
+}
+
+impl Display for UserError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for UserError {}
+
+pub fn is_user_error(error: &Error) -> bool {
+    error.downcast_ref::<UserError>().is_some()
+}
+
+/// What to tell the user about `error`.
+pub fn user_message(error: &Error) -> String {
+    match error.downcast_ref::<UserError>() {
+        Some(user_error) => user_error.to_string(),
+        None => String::from(INTERNAL_ERROR),
+    }
+}
@@ -0,0 +1,20 @@
+/// UI glyphs that have an ASCII fallback for clients without Unicode support.
+#[derive(Clone, Copy)]
+pub enum Glyph {
+    HorizontalLine,
+    VerticalLine,
+    Branch,
+}
+
+impl Glyph {
+    pub fn render(self, unicode: bool) -> &'static str {
+        match (self, unicode) {
+            (Glyph::HorizontalLine, true) => "─",
+            (Glyph::HorizontalLine, false) => "-",
+            (Glyph::VerticalLine, true) => "│",
+            (Glyph::VerticalLine, false) => "|",
+            (Glyph::Branch, true) => "└ ",
+            (Glyph::Branch, false) => "+ ",
+        }
+    }
+}
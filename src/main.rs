@@ -1,77 +1,736 @@
 mod ansi;
+mod api;
+mod banner;
+mod cache;
 mod commands;
 mod config;
+mod error;
+mod glyphs;
+mod markup;
+mod replay;
+mod selftest;
 mod session;
+mod storage;
+mod supervisor;
+mod telnet;
+#[cfg(test)]
+mod testing;
+mod theme;
+mod time;
+mod wrap;
 
-use std::sync::Arc;
+use std::{
+    env,
+    io::{self, IsTerminal},
+    net::SocketAddr,
+    path::Path,
+    process::ExitCode,
+    sync::{Arc, OnceLock},
+    time::Duration,
+};
 
 use anyhow::{Context, Result};
 use session::{AppState, Session};
-use tokio::{net::TcpListener, spawn, sync::Mutex};
+use tokio::{
+    fs::{read_to_string, write},
+    net::{TcpListener, TcpStream},
+    pin, select, signal, spawn,
+    sync::{
+        OwnedSemaphorePermit, Semaphore,
+        mpsc::{Sender, channel},
+    },
+    time::{Instant, interval, interval_at, sleep, timeout},
+};
+use tokio_rustls::{
+    TlsAcceptor,
+    rustls::{
+        ServerConfig,
+        pki_types::{CertificateDer, PrivateKeyDer, pem::PemObject},
+    },
+};
+use tracing::{Instrument, error, field, info, info_span, warn};
+use tracing_subscriber::{
+    EnvFilter,
+    fmt::{
+        Formatter,
+        format::{DefaultFields, Format},
+    },
+    reload::Handle,
+};
 
 use crate::{
-    commands::{CommandHandler, HelpCmd, LoginCmd, MessageCmd, QuitCmd, RegisterCmd},
-    config::Config,
+    commands::{
+        BlockCmd, BoardCmd, BoardsCmd, BroadcastCmd, CharsetCmd, ChatCmd, CommandHandler,
+        ConfigCmd, CreateCmd, DelUserCmd, DiagCmd, FingerCmd, ForgetCmd, HelpCmd, HistoryCmd,
+        JoinCmd, KickCmd, LoginCmd, LogoutCmd, MacroCmd, MailCmd, MergeUserCmd, MessageCmd,
+        MotdCmd, PageSizeCmd, PasswdCmd, ProfileCmd, QuitCmd, RecordCmd, RegisterCmd, ReloadCmd,
+        SchemeCmd, SetBoardCmd, SetProfileCmd, UnarchiveCmd, UnblockCmd, WhoCmd,
+    },
+    config::{Config, ListenerConfig},
+    session::Stream,
+    supervisor::spawn_supervised,
 };
 
+/// How long in-flight sessions get to finish once a shutdown is requested.
+const SHUTDOWN_GRACE: Duration = Duration::from_secs(5);
+/// How long a client gets to complete the TLS handshake.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long the accept loop waits to tell a turned away client why.
+const REJECT_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// How the connections accepted on a listener are served.
+#[derive(Clone)]
+struct Listener {
+    json: bool,
+    tls: Option<Arc<TlsAcceptor>>,
+}
+
+type Accepted = (io::Result<(TcpStream, SocketAddr)>, Listener);
+
+/// The formatter `init_logging` installs, writing to stderr.
+type LogFormatter = Formatter<DefaultFields, Format, fn() -> io::Stderr>;
+
+/// Swaps the filter of the installed subscriber, for reloads that change `log_level`.
+static LOG_FILTER: OnceLock<Handle<EnvFilter, LogFormatter>> = OnceLock::new();
+
 #[tokio::main]
-async fn main() -> Result<()> {
+async fn main() -> Result<ExitCode> {
     let config = Arc::new(Config::from_file().await?);
-    let hostname = &config.hostname;
-    let port = config.port;
 
-    match AppState::from_file().await {
+    init_logging(&config);
+
+    for warning in config.validate().await {
+        warn!("{warning}");
+    }
+
+    if env::args().any(|arg| arg == "--self-test") {
+        return Ok(if self_test(&config).await {
+            ExitCode::SUCCESS
+        } else {
+            ExitCode::FAILURE
+        });
+    }
+
+    if let Some(script) = env::args().skip_while(|arg| arg != "--replay").nth(1) {
+        let lines: Vec<String> = read_to_string(&script)
+            .await
+            .with_context(|| format!("Could not read {script}"))?
+            .lines()
+            .map(str::to_owned)
+            .collect();
+        let (_scratch_dir, config, app_state) = replay::scratch_copy(&config).await?;
+        let output = replay::replay(&lines, config, app_state, Arc::new(command_handler())).await?;
+
+        print!("{output}");
+
+        return Ok(ExitCode::SUCCESS);
+    }
+
+    // Set up before anything else, so a broken certificate stops the server from
+    // starting instead of silently serving plaintext.
+    let mut listeners = Vec::new();
+
+    for listener in config.listeners() {
+        let tls = match tls_acceptor(&listener) {
+            Ok(tls) => tls,
+            Err(e) => {
+                error!("{e:#}");
+
+                return Ok(ExitCode::FAILURE);
+            }
+        };
+
+        listeners.push((listener, tls));
+    }
+
+    if config.self_test_on_startup && !self_test(&config).await {
+        error!("Self-test failed, not starting");
+
+        return Ok(ExitCode::FAILURE);
+    }
+
+    match load_state(&config).await {
         Ok(app_state) => {
             let config = Arc::clone(&config);
             let app_state = Arc::new(app_state);
-            let listener = TcpListener::bind(format!("{hostname}:{port}")).await?;
-            let command_handler = Arc::new(Mutex::new(CommandHandler::new()));
+            let (connections, mut accepted_connections) = channel(1);
 
-            {
-                let mut lock = command_handler.lock().await;
+            for (listener, tls) in listeners {
+                spawn_acceptor(
+                    bind(&listener.hostname, listener.port).await?,
+                    Listener { json: false, tls },
+                    &connections,
+                );
+            }
+
+            if let Some(json_port) = config.json_port {
+                spawn_acceptor(
+                    bind(&config.hostname, json_port).await?,
+                    Listener {
+                        json: true,
+                        tls: None,
+                    },
+                    &connections,
+                );
+            }
+
+            drop(connections);
+            let command_handler = command_handler();
 
-                lock.add_welcome_cmd(LoginCmd);
-                lock.add_welcome_cmd(RegisterCmd);
-                lock.add_welcome_cmd(QuitCmd);
-                lock.add_message_cmd(MessageCmd);
-                lock.add_message_cmd(QuitCmd);
+            if let Some(manifest_file) = &config.command_manifest_file {
+                let manifest = serde_json::to_string_pretty(&command_handler.manifest())?;
 
-                let command_handler_clone = lock.clone();
-                lock.add_welcome_cmd(HelpCmd {
-                    command_handler: command_handler_clone,
+                write(manifest_file, manifest)
+                    .await
+                    .context("Could not write command manifest")?;
+            }
+
+            let command_handler = Arc::new(command_handler);
+
+            if let Some(days) = config.purge_deleted_after_days {
+                let app_state = Arc::clone(&app_state);
+
+                spawn_supervised("purge", move || {
+                    let app_state = Arc::clone(&app_state);
+
+                    async move {
+                        let max_age = Duration::from_secs(days * 24 * 60 * 60);
+                        let mut interval = interval(Duration::from_secs(60 * 60));
+
+                        loop {
+                            interval.tick().await;
+
+                            let purged = app_state.purge_deleted(max_age).await?;
+
+                            if purged > 0 {
+                                info!("Purged {purged} deleted message(s)");
+                            }
+                        }
+                    }
                 });
-                let command_handler_clone = lock.clone();
-                lock.add_message_cmd(HelpCmd {
-                    command_handler: command_handler_clone,
+            }
+
+            if let Some(days) = config.archive_after_days {
+                let app_state = Arc::clone(&app_state);
+                let config = Arc::clone(&config);
+
+                spawn_supervised("archive", move || {
+                    let app_state = Arc::clone(&app_state);
+                    let config = Arc::clone(&config);
+
+                    async move {
+                        let max_age = Duration::from_secs(days * 24 * 60 * 60);
+                        let mut interval = interval(Duration::from_secs(60 * 60));
+
+                        loop {
+                            interval.tick().await;
+
+                            let archived = app_state
+                                .archive_inactive(max_age, config.default_board.as_deref())
+                                .await?;
+
+                            if archived > 0 {
+                                info!("Archived {archived} inactive board(s)");
+                            }
+                        }
+                    }
                 });
             }
 
+            if let Some(hours) = config.digest_interval_hours {
+                let app_state = Arc::clone(&app_state);
+
+                spawn_supervised("digest", move || {
+                    let app_state = Arc::clone(&app_state);
+
+                    async move {
+                        let period = Duration::from_secs(hours * 60 * 60);
+                        let mut interval = interval_at(Instant::now() + period, period);
+                        let mut since = time::now();
+
+                        loop {
+                            interval.tick().await;
+
+                            let now = time::now();
+                            let posted = app_state.post_digests(since).await?;
+
+                            since = now;
+
+                            if posted > 0 {
+                                info!("Posted {posted} board digest(s)");
+                            }
+                        }
+                    }
+                });
+            }
+
+            let connection_limit = config
+                .max_connections
+                .map(|max| Arc::new(Semaphore::new(max)));
+            let shutdown = shutdown_signal();
+            pin!(shutdown);
+
             loop {
-                match listener.accept().await.context("Client connection failed") {
+                let (accepted, listener) = select! {
+                    Some(accepted) = accepted_connections.recv() => accepted,
+                    _ = &mut shutdown => break,
+                };
+
+                match accepted.context("Client connection failed") {
                     Ok((stream, address)) => {
+                        let Some(permit) = admit(
+                            &stream,
+                            address,
+                            &config,
+                            &app_state,
+                            connection_limit.as_ref(),
+                        )
+                        .await
+                        else {
+                            continue;
+                        };
                         let config = Arc::clone(&config);
                         let app_state = Arc::clone(&app_state);
                         let command_handler = Arc::clone(&command_handler);
 
-                        println!("Connection from: {address}");
+                        let span = info_span!("session", peer = %address, user = field::Empty);
+
+                        spawn(
+                            async move {
+                                info!("Connected");
+
+                                let stream: Box<dyn Stream> = match &listener.tls {
+                                    Some(tls) => {
+                                        match timeout(HANDSHAKE_TIMEOUT, tls.accept(stream)).await {
+                                            Ok(Ok(stream)) => Box::new(stream),
+                                            Ok(Err(e)) => {
+                                                warn!("TLS handshake failed: {e}");
+                                                return;
+                                            }
+                                            Err(_) => {
+                                                warn!("TLS handshake timed out");
+                                                return;
+                                            }
+                                        }
+                                    }
+                                    None => Box::new(stream),
+                                };
+                                let telnet = config.telnet;
+                                let mut session = Session::new(
+                                    stream,
+                                    address,
+                                    config,
+                                    app_state,
+                                    command_handler,
+                                );
 
-                        spawn(async move {
-                            let mut session =
-                                Session::new(stream, config, app_state, command_handler);
+                                if listener.json {
+                                    session = session.with_json();
+                                } else if telnet {
+                                    session = session.with_telnet();
+                                }
 
-                            if let Err(e) = session.run().await {
-                                eprintln!("{e}");
+                                if let Err(e) = session.run().await {
+                                    error!("{e}");
+                                }
+
+                                drop(permit);
                             }
-                        });
+                            .instrument(span),
+                        );
                     }
-                    Err(e) => eprintln!("{e}"),
+                    Err(e) => error!("{e}"),
                 }
             }
+
+            info!("Shutting down...");
+
+            app_state.shutdown().await;
+
+            let deadline = Instant::now() + SHUTDOWN_GRACE;
+
+            // Every change is saved as it is made, so once the sessions are gone there
+            // is nothing left to write.
+            while app_state.connected() > 0 && Instant::now() < deadline {
+                sleep(Duration::from_millis(100)).await;
+            }
         }
         Err(e) => {
-            eprintln!("{e}");
+            error!("{e}");
         }
     }
 
-    Ok(())
+    Ok(ExitCode::SUCCESS)
+}
+
+/// Resolves on Ctrl-C, or SIGTERM on Unix.
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        let mut terminate = match signal::unix::signal(signal::unix::SignalKind::terminate()) {
+            Ok(terminate) => terminate,
+            Err(e) => {
+                warn!("Could not listen for SIGTERM: {e}");
+                let _ = signal::ctrl_c().await;
+                return;
+            }
+        };
+
+        select! {
+            _ = signal::ctrl_c() => {}
+            _ = terminate.recv() => {}
+        }
+    }
+
+    #[cfg(not(unix))]
+    let _ = signal::ctrl_c().await;
+}
+
+/// Loads the state kept in the data directory through the configured storage.
+async fn load_state(config: &Arc<Config>) -> Result<AppState> {
+    let storage = storage::open(config.storage, &config.data_dir).await?;
+
+    AppState::load(storage, Arc::clone(config)).await
+}
+
+async fn bind(hostname: &str, port: u16) -> Result<TcpListener> {
+    let listener = TcpListener::bind((hostname, port))
+        .await
+        .with_context(|| format!("Could not bind to {hostname} port {port}"))?;
+
+    info!("Listening on {hostname} port {port}");
+
+    Ok(listener)
+}
+
+/// Writes events to stderr, filtered by `log_level`. Colored only on a terminal, so
+/// redirected logs stay plain text.
+fn init_logging(config: &Config) {
+    let subscriber = tracing_subscriber::fmt()
+        .with_writer(io::stderr as fn() -> io::Stderr)
+        .with_ansi(io::stderr().is_terminal())
+        .with_env_filter(config.log_filter())
+        .with_filter_reloading();
+
+    let _ = LOG_FILTER.set(subscriber.reload_handle());
+
+    subscriber.init();
+}
+
+/// Applies the `log_level` of a reloaded configuration to the running subscriber.
+pub fn set_log_filter(config: &Config) {
+    if let Some(handle) = LOG_FILTER.get()
+        && let Err(e) = handle.reload(config.log_filter())
+    {
+        warn!("Could not change the log filter: {e}");
+    }
+}
+
+/// Loads the certificate and key of a listener serving TLS.
+fn tls_acceptor(listener: &ListenerConfig) -> Result<Option<Arc<TlsAcceptor>>> {
+    match (&listener.tls_cert, &listener.tls_key) {
+        (Some(cert), Some(key)) => Ok(Some(Arc::new(load_tls(cert, key)?))),
+        (None, None) => Ok(None),
+        _ => anyhow::bail!(
+            "TLS on port {} needs both tls_cert and tls_key",
+            listener.port
+        ),
+    }
+}
+
+/// Builds a TLS acceptor from a PEM certificate chain and the private key belonging
+/// to it.
+fn load_tls(cert: &Path, key: &Path) -> Result<TlsAcceptor> {
+    let chain = CertificateDer::pem_file_iter(cert)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .with_context(|| format!("Could not load {}", cert.display()))?;
+    let key_der = PrivateKeyDer::from_pem_file(key)
+        .with_context(|| format!("Could not load {}", key.display()))?;
+    let tls_config = ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(chain, key_der)
+        .with_context(|| format!("{} does not match {}", key.display(), cert.display()))?;
+
+    Ok(TlsAcceptor::from(Arc::new(tls_config)))
+}
+
+/// Accepts connections on `tcp_listener` until the server stops, handing each to the
+/// main loop along with how to serve it.
+fn spawn_acceptor(tcp_listener: TcpListener, listener: Listener, connections: &Sender<Accepted>) {
+    let connections = connections.clone();
+
+    spawn(async move {
+        loop {
+            let accepted = tcp_listener.accept().await;
+
+            if connections
+                .send((accepted, listener.clone()))
+                .await
+                .is_err()
+            {
+                break;
+            }
+        }
+    });
+}
+
+/// Decides whether a new connection gets a session. Clients connecting too often or
+/// finding the server full are told so and turned away. Otherwise returns the slot
+/// under `connection_limit` the session holds until the permit is dropped, if there
+/// is a limit.
+async fn admit(
+    stream: &TcpStream,
+    address: SocketAddr,
+    config: &Config,
+    app_state: &AppState,
+    connection_limit: Option<&Arc<Semaphore>>,
+) -> Option<Option<OwnedSemaphorePermit>> {
+    if let Some(max) = config.max_connections_per_min
+        && !app_state.allow_connection(address.ip(), max).await
+    {
+        warn!("Rejected {address}: too many connections");
+        reject(stream, "Too many connections, try again later").await;
+
+        return None;
+    }
+
+    match connection_limit.map(|limit| Arc::clone(limit).try_acquire_owned()) {
+        None => Some(None),
+        Some(Ok(permit)) => Some(Some(permit)),
+        Some(Err(_)) => {
+            warn!("Rejected {address}: server full");
+            reject(stream, "Server full, try again later").await;
+
+            None
+        }
+    }
+}
+
+/// Tells a client why it is turned away, before the caller drops the connection. This
+/// runs right in the accept loop rather than in a task of its own, so a flood of
+/// rejected clients can't pile up tasks. The reason fits in any socket buffer, and a
+/// client whose socket still isn't writable within `REJECT_TIMEOUT` goes without it.
+async fn reject(stream: &TcpStream, reason: &str) {
+    let line = format!("{reason}\r\n");
+    let _ = timeout(REJECT_TIMEOUT, async {
+        stream.writable().await?;
+        stream.try_write(line.as_bytes())
+    })
+    .await;
+}
+
+/// Runs the startup self-test, printing the outcome of every check.
+async fn self_test(config: &Config) -> bool {
+    let mut passed = true;
+
+    for (name, result) in selftest::run(config).await {
+        match result {
+            Ok(()) => println!("PASS {name}"),
+            Err(e) => {
+                println!("FAIL {name}: {e:#}");
+                passed = false;
+            }
+        }
+    }
+
+    passed
+}
+
+/// Every command the server offers, by login state.
+fn command_handler() -> CommandHandler {
+    let mut command_handler = CommandHandler::new();
+
+    command_handler.add_welcome_cmd(LoginCmd);
+    command_handler.add_welcome_cmd(RegisterCmd);
+    command_handler.add_welcome_cmd(QuitCmd);
+    command_handler.add_welcome_cmd(HistoryCmd);
+    command_handler.add_welcome_cmd(MotdCmd);
+    command_handler.add_welcome_cmd(CharsetCmd);
+    command_handler.add_message_cmd(MessageCmd);
+    command_handler.add_message_cmd(MergeUserCmd);
+    command_handler.add_message_cmd(ConfigCmd);
+    command_handler.add_message_cmd(BoardsCmd);
+    command_handler.add_message_cmd(SetBoardCmd);
+    command_handler.add_message_cmd(FingerCmd);
+    command_handler.add_message_cmd(RecordCmd);
+    command_handler.add_message_cmd(CharsetCmd);
+    command_handler.add_message_cmd(BoardCmd);
+    command_handler.add_message_cmd(SchemeCmd);
+    command_handler.add_message_cmd(ReloadCmd);
+    command_handler.add_message_cmd(CreateCmd);
+    command_handler.add_message_cmd(UnarchiveCmd);
+    command_handler.add_message_cmd(ForgetCmd);
+    command_handler.add_message_cmd(PageSizeCmd);
+    command_handler.add_message_cmd(MotdCmd);
+    command_handler.add_message_cmd(DiagCmd);
+    command_handler.add_message_cmd(MailCmd);
+    command_handler.add_message_cmd(BlockCmd);
+    command_handler.add_message_cmd(UnblockCmd);
+    command_handler.add_message_cmd(LogoutCmd);
+    command_handler.add_message_cmd(PasswdCmd);
+    command_handler.add_message_cmd(WhoCmd);
+    command_handler.add_message_cmd(JoinCmd);
+    command_handler.add_message_cmd(KickCmd);
+    command_handler.add_message_cmd(DelUserCmd);
+    command_handler.add_message_cmd(BroadcastCmd);
+    command_handler.add_message_cmd(ChatCmd);
+    command_handler.add_message_cmd(ProfileCmd);
+    command_handler.add_message_cmd(SetProfileCmd);
+    command_handler.add_message_cmd(HistoryCmd);
+    command_handler.add_message_cmd(QuitCmd);
+
+    let command_handler_clone = command_handler.clone();
+    command_handler.add_message_cmd(MacroCmd {
+        command_handler: command_handler_clone,
+    });
+    let command_handler_clone = command_handler.clone();
+    command_handler.add_welcome_cmd(HelpCmd {
+        command_handler: command_handler_clone,
+    });
+    let command_handler_clone = command_handler.clone();
+    command_handler.add_message_cmd(HelpCmd {
+        command_handler: command_handler_clone,
+    });
+
+    command_handler
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+    use tokio_rustls::{
+        TlsConnector,
+        rustls::{ClientConfig, RootCertStore, pki_types::ServerName},
+    };
+
+    use super::*;
+    use crate::testing::Fixture;
+
+    const CERT: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/testdata/tls-cert.pem");
+    const KEY: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/testdata/tls-key.pem");
+
+    /// A client that trusts only the test certificate.
+    fn test_connector() -> TlsConnector {
+        let mut roots = RootCertStore::empty();
+
+        for cert in CertificateDer::pem_file_iter(CERT).unwrap() {
+            roots.add(cert.unwrap()).unwrap();
+        }
+
+        let client_config = ClientConfig::builder()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+
+        TlsConnector::from(Arc::new(client_config))
+    }
+
+    #[tokio::test]
+    async fn lines_round_trip_over_tls() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let acceptor = load_tls(Path::new(CERT), Path::new(KEY)).unwrap();
+
+        let server = spawn(async move {
+            let (socket, _) = listener.accept().await.unwrap();
+            let stream = timeout(HANDSHAKE_TIMEOUT, acceptor.accept(socket)).await;
+            let mut stream = BufReader::new(stream.unwrap().unwrap());
+            let mut line = String::new();
+
+            stream.read_line(&mut line).await.unwrap();
+            stream
+                .write_all(format!("echo: {line}").as_bytes())
+                .await
+                .unwrap();
+            stream.shutdown().await.unwrap();
+        });
+
+        let socket = TcpStream::connect(addr).await.unwrap();
+        let name = ServerName::try_from("localhost").unwrap();
+        let mut client = BufReader::new(test_connector().connect(name, socket).await.unwrap());
+        let mut reply = String::new();
+
+        client.write_all(b"hello\r\n").await.unwrap();
+        client.read_line(&mut reply).await.unwrap();
+        assert_eq!(reply, "echo: hello\r\n");
+
+        // The server's close_notify reads as the end of the stream.
+        reply.clear();
+        assert_eq!(client.read_line(&mut reply).await.unwrap(), 0);
+
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn plain_text_client_fails_the_handshake() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let acceptor = load_tls(Path::new(CERT), Path::new(KEY)).unwrap();
+
+        let server = spawn(async move {
+            let (socket, _) = listener.accept().await.unwrap();
+
+            timeout(HANDSHAKE_TIMEOUT, acceptor.accept(socket)).await
+        });
+
+        let mut socket = TcpStream::connect(addr).await.unwrap();
+
+        socket.write_all(b"GUEST\r\n\r\n\r\n").await.unwrap();
+        socket.shutdown().await.unwrap();
+
+        assert!(server.await.unwrap().unwrap().is_err());
+    }
+
+    #[test]
+    fn mismatched_tls_files_are_rejected() {
+        let cert = Path::new(CERT);
+
+        assert!(load_tls(cert, cert).is_err());
+        assert!(load_tls(cert, Path::new("/nonexistent/key.pem")).is_err());
+    }
+
+    #[tokio::test]
+    async fn connections_past_the_limits_are_turned_away() {
+        let fixture = Fixture::with_config(|config| config.max_connections_per_min = Some(3)).await;
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let limit = Arc::new(Semaphore::new(2));
+        let mut sessions = Vec::new();
+        let mut clients = Vec::new();
+
+        for _ in 0..4 {
+            clients.push(TcpStream::connect(address).await.unwrap());
+
+            let (stream, peer) = listener.accept().await.unwrap();
+            let admitted = admit(
+                &stream,
+                peer,
+                &fixture.config,
+                &fixture.app_state,
+                Some(&limit),
+            )
+            .await;
+
+            if let Some(permit) = admitted {
+                sessions.push((stream, permit));
+            }
+        }
+
+        assert_eq!(sessions.len(), 2);
+
+        // The rejections were written before `admit` returned, with nothing left
+        // running in the background.
+        for (client, reason) in clients.iter_mut().skip(2).zip([
+            "Server full, try again later\r\n",
+            "Too many connections, try again later\r\n",
+        ]) {
+            let mut rejected = String::new();
+
+            client.read_to_string(&mut rejected).await.unwrap();
+            assert_eq!(rejected, reason);
+        }
+
+        // Ending a session frees its slot for the next client.
+        sessions.pop();
+        assert_eq!(limit.available_permits(), 1);
+    }
 }
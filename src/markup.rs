@@ -0,0 +1,94 @@
+use crate::ansi::{AnsiColor, AnsiStyle};
+
+const BOLD_ON: &str = "\u{001b}[1m";
+const BOLD_OFF: &str = "\u{001b}[22m";
+const UNDERLINE_ON: &str = "\u{001b}[4m";
+const UNDERLINE_OFF: &str = "\u{001b}[24m";
+
+/// Renders the lightweight markup used in message bodies: `*bold*`, `_underline_`
+/// and lines starting with `>` as quotes. With ANSI disabled the body is left as is.
+pub fn render(body: &str, ansi: bool) -> String {
+    if !ansi {
+        return body.to_owned();
+    }
+
+    body.split("\r\n")
+        .map(|line| {
+            if line.starts_with('>') {
+                AnsiStyle::new(Some(AnsiColor::Cyan), None).apply(line)
+            } else {
+                render_inline(line)
+            }
+        })
+        .collect::<Vec<String>>()
+        .join("\r\n")
+}
+
+fn render_inline(line: &str) -> String {
+    let mut output = String::new();
+    let mut rest = line;
+    let mut previous: Option<char> = None;
+
+    while let Some(c) = rest.chars().next() {
+        let after = &rest[c.len_utf8()..];
+        let styles = match c {
+            '*' => Some((BOLD_ON, BOLD_OFF)),
+            '_' => Some((UNDERLINE_ON, UNDERLINE_OFF)),
+            _ => None,
+        };
+
+        if let Some((on, off)) = styles
+            && previous.is_none_or(|p| !p.is_alphanumeric())
+            && let Some(end) = after.find(c)
+            && end > 0
+            && !after.starts_with(char::is_whitespace)
+        {
+            output.push_str(&format!("{on}{}{off}", &after[..end]));
+            previous = Some(c);
+            rest = &after[end + c.len_utf8()..];
+            continue;
+        }
+
+        output.push(c);
+        previous = Some(c);
+        rest = after;
+    }
+
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn markers_render_to_their_escape_sequences() {
+        assert_eq!(
+            render("a *bold* word", true),
+            "a \u{001b}[1mbold\u{001b}[22m word"
+        );
+        assert_eq!(
+            render("an _underlined_ word", true),
+            "an \u{001b}[4munderlined\u{001b}[24m word"
+        );
+        assert_eq!(
+            render("> quoted\r\nplain", true),
+            "\u{001b}[36;49m> quoted\u{001b}[0m\r\nplain"
+        );
+    }
+
+    #[test]
+    fn markers_inside_words_or_unclosed_are_kept() {
+        for body in ["snake_case_name", "2*3*4", "a * b", "*open", "**"] {
+            assert_eq!(render(body, true), body);
+        }
+    }
+
+    #[test]
+    fn without_ansi_the_body_is_untouched() {
+        assert_eq!(
+            render("*bold* _under_\r\n> quote", false),
+            "*bold* _under_\r\n> quote"
+        );
+    }
+}
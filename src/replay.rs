@@ -0,0 +1,244 @@
+use std::{
+    collections::VecDeque,
+    io,
+    net::SocketAddr,
+    path::Path,
+    pin::Pin,
+    sync::Arc,
+    task::{Context as TaskContext, Poll},
+};
+
+use anyhow::{Context, Result};
+use tempfile::TempDir;
+use tokio::{
+    fs::{copy, create_dir_all, read_dir},
+    io::{AsyncRead, AsyncReadExt, ReadBuf, duplex, join},
+    spawn,
+};
+
+use crate::{
+    commands::CommandHandler,
+    config::Config,
+    session::{AppState, Session},
+    storage,
+};
+
+const PIPE_SIZE: usize = 64 * 1024;
+
+/// Drives a session over an in-memory pipe, sending `lines` as if typed by a client,
+/// and returns everything the session wrote back. The session reads the whole script
+/// and then sees the client hang up, so it ends the way any other session does.
+pub async fn replay(
+    lines: &[String],
+    config: Arc<Config>,
+    app_state: Arc<AppState>,
+    command_handler: Arc<CommandHandler>,
+) -> Result<String> {
+    let (mut client, server) = duplex(PIPE_SIZE);
+    let address = SocketAddr::from(([127, 0, 0, 1], 0));
+    let script = Script(
+        lines
+            .iter()
+            .map(|line| format!("{line}\r\n").into_bytes())
+            .collect(),
+    );
+    let session = spawn(async move {
+        Session::new(
+            join(script, server),
+            address,
+            config,
+            app_state,
+            command_handler,
+        )
+        .run()
+        .await
+    });
+    let mut output = Vec::new();
+
+    // The pipe closes once the session is over and its writer flushed everything.
+    client.read_to_end(&mut output).await?;
+    session.await??;
+
+    Ok(String::from_utf8_lossy(&output).into_owned())
+}
+
+/// Copies the stores and attachments of `config`'s data directory into a temporary
+/// one, so a replay can't change the real data. Returns the copy to replay against,
+/// which is deleted when the returned directory is dropped.
+pub async fn scratch_copy(config: &Config) -> Result<(TempDir, Arc<Config>, Arc<AppState>)> {
+    let dir = TempDir::new().context("Could not create a scratch data directory")?;
+    let scratch = Arc::new(Config {
+        data_dir: dir.path().to_owned(),
+        connection_log_file: None,
+        ..config.clone()
+    });
+    let original = AppState::load(
+        storage::open(config.storage, &config.data_dir).await?,
+        Arc::new(config.clone()),
+    )
+    .await?;
+    let storage = storage::open(scratch.storage, &scratch.data_dir).await?;
+
+    storage
+        .save_stores(&original.stores().await)
+        .await
+        .context("Could not copy the stores")?;
+
+    let app_state = AppState::load(storage, Arc::clone(&scratch)).await?;
+
+    copy_files(&original.attachments_dir(), &app_state.attachments_dir())
+        .await
+        .context("Could not copy the attachments")?;
+
+    Ok((dir, scratch, Arc::new(app_state)))
+}
+
+/// Copies the files directly in `from` into `to`. Nothing to copy if `from` is missing.
+async fn copy_files(from: &Path, to: &Path) -> io::Result<()> {
+    let mut entries = match read_dir(from).await {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+
+    create_dir_all(to).await?;
+
+    while let Some(entry) = entries.next_entry().await? {
+        if entry.file_type().await?.is_file() {
+            copy(entry.path(), to.join(entry.file_name())).await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// The lines of a script, handed out one per read like a user typing them. The
+/// session never finds the next line already buffered, so it never takes the script
+/// for a paste. Reads nothing once every line is out, which the session takes as the
+/// client hanging up.
+struct Script(VecDeque<Vec<u8>>);
+
+impl AsyncRead for Script {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        _cx: &mut TaskContext<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        if let Some(line) = self.0.front_mut() {
+            let len = line.len().min(buf.remaining());
+
+            buf.put_slice(&line[..len]);
+            line.drain(..len);
+
+            if line.is_empty() {
+                self.0.pop_front();
+            }
+        }
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        session::DEFAULT_BOARD,
+        testing::{Fixture, plain},
+    };
+
+    #[tokio::test]
+    async fn scripts_replay_a_whole_session() {
+        let fixture = Fixture::new().await;
+        let script = [
+            "register",
+            "carol",
+            "secret",
+            "logout",
+            "login",
+            "carol",
+            "secret",
+            "frobnicate",
+            "message new",
+            "Hello",
+            "First post",
+            ".",
+            "message list",
+        ]
+        .map(String::from);
+
+        let output = replay(
+            &script,
+            Arc::clone(&fixture.config),
+            Arc::clone(&fixture.app_state),
+            Arc::clone(&fixture.command_handler),
+        )
+        .await
+        .unwrap();
+        let output = plain(&output);
+
+        assert!(output.contains("Registration successful"), "{output}");
+        assert_eq!(output.matches("Login successful").count(), 2, "{output}");
+        assert!(!output.contains("Paste detected"), "{output}");
+        assert!(output.contains("carol Hello"), "{output}");
+
+        let messages = fixture.app_state.messages.read().await;
+
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].username, "carol");
+
+        // The session ended on its own rather than being cut off.
+        assert_eq!(fixture.app_state.connected(), 0);
+        assert!(fixture.app_state.online.read().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn replays_leave_the_data_directory_alone() {
+        let fixture = Fixture::new().await;
+        let id = fixture.add_message("alice", DEFAULT_BOARD, "Kept").await;
+
+        let attachment = fixture
+            .app_state
+            .attach(id, "notes.txt", b"notes", usize::MAX)
+            .await
+            .unwrap();
+
+        let before = std::fs::read_to_string(fixture.dir.join("messages.json")).unwrap();
+        let (dir, config, app_state) = scratch_copy(&fixture.config).await.unwrap();
+        let script = [
+            "register",
+            "carol",
+            "secret",
+            "message new",
+            "Scratch",
+            "Body",
+            ".",
+        ]
+        .map(String::from);
+
+        replay(
+            &script,
+            config,
+            Arc::clone(&app_state),
+            Arc::clone(&fixture.command_handler),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(app_state.messages.read().await.len(), 2);
+        assert_eq!(
+            app_state.read_attachment(&attachment).await.unwrap(),
+            b"notes"
+        );
+        assert_eq!(
+            std::fs::read_to_string(fixture.dir.join("messages.json")).unwrap(),
+            before
+        );
+        assert!(fixture.user("carol").await.is_none());
+
+        let path = dir.path().to_owned();
+
+        drop(dir);
+        assert!(!path.exists());
+    }
+}
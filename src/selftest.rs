@@ -0,0 +1,111 @@
+use std::{path::PathBuf, sync::Arc};
+
+use anyhow::{Context, Result};
+use tokio::{
+    fs::{create_dir_all, read, read_to_string, remove_file, write},
+    net::TcpListener,
+};
+
+use crate::{config::Config, session::AppState, storage};
+
+const PROBE_FILE: &str = ".selftest";
+
+/// Checks the environment the server needs before it accepts connections. Returns
+/// every check by name with its result, so all problems are reported at once.
+pub async fn run(config: &Config) -> Vec<(&'static str, Result<()>)> {
+    vec![
+        ("data directory", data_dir(config).await),
+        ("state files", state_files(config).await),
+        ("socket", socket(config).await),
+        ("banner", optional_file(&config.banner_file).await),
+        ("motd", optional_file(&config.motd_file).await),
+    ]
+}
+
+/// Round-trips a small file through the data directory, where state is kept.
+async fn data_dir(config: &Config) -> Result<()> {
+    let probe = b"gluboard";
+    let probe_file = config.data_dir.join(PROBE_FILE);
+
+    create_dir_all(&config.data_dir)
+        .await
+        .context("Could not create data directory")?;
+    write(&probe_file, probe)
+        .await
+        .context("Could not write probe file")?;
+
+    let read_back = read(&probe_file)
+        .await
+        .context("Could not read probe file")?;
+
+    remove_file(&probe_file)
+        .await
+        .context("Could not remove probe file")?;
+
+    anyhow::ensure!(read_back == probe, "Probe file came back different");
+
+    Ok(())
+}
+
+async fn state_files(config: &Config) -> Result<()> {
+    AppState::load(
+        storage::open(config.storage, &config.data_dir).await?,
+        Arc::new(config.clone()),
+    )
+    .await?;
+
+    Ok(())
+}
+
+async fn socket(config: &Config) -> Result<()> {
+    for listener in config.listeners() {
+        TcpListener::bind((listener.hostname.as_str(), 0))
+            .await
+            .with_context(|| format!("Could not bind to {}", listener.hostname))?;
+    }
+
+    Ok(())
+}
+
+async fn optional_file(file: &Option<PathBuf>) -> Result<()> {
+    if let Some(file) = file {
+        read_to_string(file)
+            .await
+            .with_context(|| format!("Could not read {}", file.display()))?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::test_dir;
+
+    #[tokio::test]
+    async fn a_broken_data_dir_fails_the_self_test() {
+        let config = Config {
+            data_dir: test_dir(),
+            ..Config::default()
+        };
+
+        assert!(run(&config).await.iter().all(|(_, result)| result.is_ok()));
+
+        // A file where the data directory should be can't hold any state.
+        let config = Config {
+            data_dir: test_dir(),
+            ..Config::default()
+        };
+
+        std::fs::write(&config.data_dir, "not a directory").unwrap();
+
+        let failed: Vec<&str> = run(&config)
+            .await
+            .into_iter()
+            .filter(|(_, result)| result.is_err())
+            .map(|(name, _)| name)
+            .collect();
+
+        assert!(failed.contains(&"data directory"), "{failed:?}");
+    }
+}
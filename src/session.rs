@@ -1,204 +1,4465 @@
-use std::{path::Path, sync::Arc};
+use std::{
+    cmp::Reverse,
+    collections::{BTreeMap, HashMap, HashSet, VecDeque},
+    fmt::{self, Display, Formatter},
+    io,
+    mem::{replace, take},
+    net::{IpAddr, SocketAddr},
+    path::{Path, PathBuf},
+    sync::{
+        Arc,
+        atomic::{AtomicUsize, Ordering},
+    },
+    time::{Duration, Instant},
+};
 
-use anyhow::{Context, Result};
+use anyhow::{Context, Error, Result};
 use serde::{Deserialize, Serialize};
 use tokio::{
-    fs::{File, read, read_to_string},
-    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
-    net::TcpStream,
-    sync::{Mutex, RwLock},
+    fs::{
+        File, OpenOptions, create_dir_all, metadata, read, read_dir, read_to_string, remove_file,
+        rename, write,
+    },
+    io::{
+        AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt,
+        BufReader, WriteHalf, empty, split,
+    },
+    pin, select, spawn,
+    sync::{
+        Mutex, Notify, RwLock,
+        broadcast::{self, error::RecvError},
+        mpsc::{Sender, channel, error::TrySendError},
+    },
+    time::{Interval, interval_at, timeout},
+};
+
+use tracing::{Span, error, field, info, warn};
+
+use crate::{
+    ansi::AnsiStyle,
+    api, banner,
+    cache::LruCache,
+    commands::{CommandHandler, CommandOutcome},
+    config::Config,
+    error::{self, UserError},
+    glyphs::Glyph,
+    storage::Storage,
+    supervisor,
+    telnet::{self, TelnetReader, Terminal},
+    theme::{Scheme, Theme},
+    time,
 };
 
-use crate::{ansi::AnsiStyle, commands::CommandHandler, config::Config};
+const AUDIT_FILE: &str = "audit.log";
+const TRANSCRIPTS_DIR: &str = "transcripts";
+const ATTACHMENTS_DIR: &str = "attachments";
+/// Holds the next attachment id, inside `ATTACHMENTS_DIR`.
+const ATTACHMENT_COUNTER: &str = "next_id";
+pub const MAX_PAGE_SIZE: usize = 100;
+const LOGIN_CACHE_SIZE: usize = 256;
+const NOT_PERSISTED: &str = "Changes not persisted, could not save to disk";
+const PASTE_WINDOW: Duration = Duration::from_millis(50);
+const TOUCH_INTERVAL: Duration = Duration::from_secs(60);
+/// How long `diagnostics` waits for a lock before reporting it as blocked.
+const DIAG_LOCK_TIMEOUT: Duration = Duration::from_secs(1);
+/// Chat lines kept for sessions that fall behind before they start missing some.
+const CHAT_CAPACITY: usize = 64;
+/// Lines queued for a transcript writer before further ones are dropped.
+const TRANSCRIPT_CAPACITY: usize = 1024;
+/// Commands kept in a session's history.
+const MAX_HISTORY: usize = 50;
+/// Typed in chat mode to go back to the prompt.
+const CHAT_EXIT: &str = "/exit";
 
-const USERS_FILE: &str = "users.json";
-const MESSAGES_FILE: &str = "messages.json";
+pub const DEFAULT_BOARD: &str = "general";
+pub const DIGEST_AUTHOR: &str = "digest";
+/// Author shown on messages posted to anonymous boards.
+pub const ANONYMOUS_AUTHOR: &str = "anonymous";
+/// Author shown on messages kept after their author was forgotten.
+const FORGOTTEN_AUTHOR: &str = "[deleted]";
+/// Authors standing in for real users, which nobody may register as.
+pub const RESERVED_USERNAMES: [&str; 3] = [ANONYMOUS_AUTHOR, DIGEST_AUTHOR, FORGOTTEN_AUTHOR];
+/// Added to the names of files set aside while a user is forgotten.
+const FORGET_SUFFIX: &str = ".forget";
+
+/// Anything a session can talk to a client over, like a TCP connection or, in tests
+/// and replays, an in-memory pipe.
+pub trait Stream: AsyncRead + AsyncWrite + Send + Sync + Unpin {}
+
+impl<T: AsyncRead + AsyncWrite + Send + Sync + Unpin> Stream for T {}
 
 pub struct Session {
-    pub stream: BufReader<TcpStream>,
-    config: Arc<Config>,
+    pub stream: BufReader<Box<dyn AsyncRead + Send + Sync + Unpin>>,
+    /// Queue of bytes for the task writing to the client, see `Session::send`.
+    outbound: Sender<Vec<u8>>,
+    pub address: SocketAddr,
+    pub config: Arc<Config>,
     pub app_state: Arc<AppState>,
     pub status: SessionStatus,
-    command_handler: Arc<Mutex<CommandHandler>>,
+    pub current_board: String,
+    pub theme: Theme,
+    /// Whether the client can display Unicode glyphs, see `charset`.
+    pub unicode: bool,
+    pub messages_read: usize,
+    pub messages_posted: usize,
+    /// Messages per page in `message list`, see `pagesize`.
+    pub page_size: usize,
+    pub mode: SessionMode,
+    command_handler: Arc<CommandHandler>,
+    connected_at: Instant,
+    logged_on: bool,
+    /// Who the session's log span names. The formatter appends every value recorded
+    /// on a span, so it is only recorded when this changes.
+    span_user: Option<String>,
+    last_touch: Option<Instant>,
+    transcript: Option<Transcript>,
+    /// Whether the client speaks telnet, see `with_telnet`.
+    telnet: bool,
+    /// Notified when a sysop kicks this session or the server shuts down.
+    kick: Arc<Kick>,
+    /// Whether the logged on user has the admin role, see `is_sysop`.
+    pub admin: bool,
+    terminal: Arc<Terminal>,
+    /// Recent commands, oldest first, see `history`.
+    pub history: Vec<String>,
+    /// Whether the client speaks the JSON protocol, see `with_json`.
+    json: bool,
+    /// Answers to prompts from the JSON request being run.
+    json_input: VecDeque<String>,
+    /// Output of the JSON request being run, sent back as one response.
+    json_output: String,
 }
 
 impl Session {
     pub fn new(
-        stream: TcpStream,
+        stream: impl Stream + 'static,
+        address: SocketAddr,
         config: Arc<Config>,
         app_state: Arc<AppState>,
-        command_handler: Arc<Mutex<CommandHandler>>,
+        command_handler: Arc<CommandHandler>,
     ) -> Self {
+        let theme = Theme::new(&config.theme, config.color);
+        let (reader, writer) = split(Box::new(stream) as Box<dyn Stream>);
+        let outbound = Self::spawn_writer(writer, config.outbound_queue_size);
+        let page_size = config.page_size;
+
         Self {
-            stream: BufReader::new(stream),
+            stream: BufReader::new(Box::new(reader)),
+            outbound,
+            address,
             config,
             app_state,
             status: SessionStatus::LoggedOff,
+            current_board: DEFAULT_BOARD.to_owned(),
+            theme,
+            unicode: true,
+            messages_read: 0,
+            messages_posted: 0,
+            page_size,
+            mode: SessionMode::Normal,
             command_handler,
+            connected_at: Instant::now(),
+            logged_on: false,
+            span_user: None,
+            last_touch: None,
+            transcript: None,
+            telnet: false,
+            kick: Arc::new(Kick::default()),
+            admin: false,
+            terminal: Arc::new(Terminal::default()),
+            history: Vec::new(),
+            json: false,
+            json_input: VecDeque::new(),
+            json_output: String::new(),
+        }
+    }
+
+    /// Speaks line delimited JSON instead of styled text, see `serve_json`.
+    pub fn with_json(mut self) -> Self {
+        self.theme = Theme::new(&self.config.theme, false);
+        self.json = true;
+
+        self
+    }
+
+    /// Speaks telnet to the client: negotiation is filtered out of the input and the
+    /// client is asked to report its window size.
+    pub fn with_telnet(mut self) -> Self {
+        let reader = replace(&mut self.stream, BufReader::new(Box::new(empty()))).into_inner();
+
+        self.stream = BufReader::new(Box::new(TelnetReader::new(
+            reader,
+            self.outbound.clone(),
+            Arc::clone(&self.terminal),
+        )));
+        let _ = self.outbound.try_send(telnet::OFFER.to_vec());
+        self.telnet = true;
+
+        self
+    }
+
+    /// Columns of the client's terminal, if it reported them.
+    pub fn terminal_width(&self) -> Option<u16> {
+        self.terminal.width()
+    }
+
+    /// Writes to the client from a separate task fed by a bounded queue, so a slow
+    /// client fills its own queue instead of stalling whoever is sending to it.
+    fn spawn_writer(mut writer: WriteHalf<Box<dyn Stream>>, queue_size: usize) -> Sender<Vec<u8>> {
+        let (sender, mut receiver) = channel::<Vec<u8>>(queue_size.max(1));
+
+        spawn(async move {
+            while let Some(data) = receiver.recv().await {
+                if writer.write_all(&data).await.is_err() || writer.flush().await.is_err() {
+                    break;
+                }
+            }
+
+            let _ = writer.shutdown().await;
+        });
+
+        sender
+    }
+
+    pub fn username(&self) -> Result<String> {
+        match &self.status {
+            SessionStatus::LoggedOn(username) => Ok(username.to_owned()),
+            SessionStatus::LoggedOff => Err(UserError::new("You must be logged in").into()),
+            SessionStatus::Disconnected(_) => Err(Error::msg("User is disconnected")),
+        }
+    }
+
+    /// Configured sysops and users with the admin role share the same privileges.
+    pub fn is_sysop(&self) -> bool {
+        match &self.status {
+            SessionStatus::LoggedOn(username) => {
+                self.admin || self.config.sysops.contains(username)
+            }
+            _ => false,
+        }
+    }
+
+    pub fn glyph(&self, glyph: Glyph) -> &'static str {
+        glyph.render(self.unicode)
+    }
+
+    /// A horizontal line `width` columns wide, or narrower if the terminal is.
+    pub fn separator(&self, width: usize) -> String {
+        let width = match self.terminal_width() {
+            Some(columns) => width.min(usize::from(columns)),
+            None => width,
+        };
+
+        self.glyph(Glyph::HorizontalLine).repeat(width)
+    }
+
+    /// Columns text is wrapped at: the terminal's width if it reported one, otherwise
+    /// `wrap_width`.
+    pub fn wrap_width(&self) -> usize {
+        match self.terminal_width() {
+            Some(columns) => usize::from(columns),
+            None => self.config.wrap_width,
         }
     }
 
+    pub async fn board_exists(&self, board: &str) -> bool {
+        board == DEFAULT_BOARD
+            || self.config.default_board.as_deref() == Some(board)
+            || self.config.boards.iter().any(|b| b == board)
+            || self
+                .app_state
+                .messages
+                .read()
+                .await
+                .iter()
+                .any(|m| m.board == board)
+            || self
+                .app_state
+                .boards
+                .read()
+                .await
+                .iter()
+                .any(|b| b.name == board)
+    }
+
     pub async fn prompt(&mut self, text: &str, style: Option<AnsiStyle>) -> Result<String> {
-        let mut answer = String::new();
+        let answer = self.read_answer(text, style).await?;
 
-        self.write(text, style).await?;
-        self.stream.read_line(&mut answer).await?;
+        self.record(&format!("{answer}\r\n"));
 
-        Ok(answer.trim().to_owned())
+        Ok(answer)
     }
 
-    pub async fn run(&mut self) -> Result<()> {
-        if let Some(banner_file) = &self.config.banner_file {
-            if let Ok(banner_data) = read(banner_file).await {
-                self.writeln(&String::from_utf8_lossy(&banner_data), None)
+    /// Like `prompt`, but the answer is never written to the session transcript. Telnet
+    /// clients are also asked not to echo it, clients that refuse keep echoing.
+    pub async fn prompt_secret(&mut self, text: &str, style: Option<AnsiStyle>) -> Result<String> {
+        if self.telnet {
+            self.terminal.set_server_echo(true);
+            let _ = self.outbound.try_send(telnet::WILL_ECHO.to_vec());
+        }
+
+        let answer = self.read_answer(text, style).await;
+
+        if self.telnet {
+            self.terminal.set_server_echo(false);
+            // The client didn't echo the end of line either.
+            let _ = self
+                .outbound
+                .try_send([&telnet::WONT_ECHO[..], b"\r\n"].concat());
+        }
+
+        let answer = answer?;
+
+        self.record("<hidden>\r\n");
+
+        Ok(answer)
+    }
+
+    async fn read_answer(&mut self, text: &str, style: Option<AnsiStyle>) -> Result<String> {
+        // JSON clients answer prompts up front, with the request.
+        if self.json {
+            return self
+                .json_input
+                .pop_front()
+                .context(UserError::new("Command needs more input"));
+        }
+
+        self.write(text, style).await?;
+        self.read_input().await
+    }
+
+    /// Reads a line from the client, disconnecting when it is kicked, idle or gone.
+    async fn read_input(&mut self) -> Result<String> {
+        let mut answer = String::new();
+        let idle_timeout = self.idle_timeout();
+        let max_line_bytes = self.config.max_line_bytes;
+        let kick = Arc::clone(&self.kick);
+        let outbound = self.outbound.clone();
+        let mut keepalive = self.keepalive();
+        let input = {
+            let read = read_line_capped(&mut self.stream, &mut answer, max_line_bytes);
+            let read = async {
+                match idle_timeout {
+                    Some(idle_timeout) => timeout(idle_timeout, read).await.ok(),
+                    None => Some(read.await),
+                }
+            };
+            pin!(read);
+
+            loop {
+                select! {
+                    read = &mut read => break Ok(read),
+                    reason = kick.received() => break Err(reason),
+                    _ = next_tick(&mut keepalive) => {
+                        if !Self::ping(&outbound) {
+                            break Err(DisconnectReason::Error);
+                        }
+                    }
+                }
+            }
+        };
+
+        let input = match input {
+            Ok(input) => input,
+            Err(reason) => {
+                self.disconnect(reason).await?;
+                anyhow::bail!("Disconnected: {reason}");
+            }
+        };
+
+        match input {
+            // Reading nothing means the client closed the connection.
+            Some(Ok((0, _))) => {
+                self.disconnect(DisconnectReason::Closed).await?;
+                anyhow::bail!("Disconnected: {}", DisconnectReason::Closed);
+            }
+            Some(read) => {
+                let (_, truncated) = read?;
+
+                if truncated {
+                    self.writeln(
+                        &format!("Line too long, cut to {max_line_bytes} bytes"),
+                        self.theme.error(),
+                    )
                     .await?;
-                self.writeln("", None).await?;
+                }
+            }
+            None => {
+                self.disconnect(DisconnectReason::Idle).await?;
+                anyhow::bail!("Disconnected: {}", DisconnectReason::Idle);
             }
         }
 
-        if let Some(welcome_msg) = self.config.welcome_msg.clone() {
-            self.writeln(&welcome_msg, None).await?;
-            self.writeln("", None).await?;
-        }
+        Ok(answer.trim().to_owned())
+    }
 
-        self.writeln("", None).await?;
+    /// Relays chat until the user types `/exit`. Lines typed here go to every other
+    /// session in chat, and theirs are shown here as they arrive. Following a chat is
+    /// activity too, so sessions in chat are exempt from idle kicks.
+    pub async fn chat(&mut self) -> Result<()> {
+        self.mode = SessionMode::Chat;
 
-        let commands: Vec<String> = self
-            .command_handler
-            .lock()
-            .await
-            .welcome_commands
-            .keys()
-            .map(|k| k.to_lowercase())
-            .collect();
+        let result = self.relay_chat().await;
 
-        self.writeln("Commands:", None).await?;
-        self.writeln(&commands.join(" | "), None).await?;
-        self.writeln("", None).await?;
+        self.mode = SessionMode::Normal;
+        result
+    }
 
-        let command_handler = Arc::clone(&self.command_handler);
+    async fn relay_chat(&mut self) -> Result<()> {
+        let username = self.username()?;
+        let mut receiver = self.app_state.chat.subscribe();
+        let mut line = Vec::new();
+        let mut keepalive = self.keepalive();
+
+        self.say_in_chat(format!("* {username} joined the chat"));
 
         loop {
-            let raw_command = self.prompt("> ", None).await?;
+            let kick = Arc::clone(&self.kick);
 
-            match command_handler
-                .lock()
-                .await
-                .handle(&raw_command, self)
-                .await
-            {
-                Ok(_) => {
-                    if let SessionStatus::Disconnected = self.status {
+            // read_until keeps partial input in `line` when another branch wins, so a
+            // chat line arriving mid-typing loses nothing. A line reaching the limit is
+            // said as is and the rest of it follows as another.
+            let room = self.config.max_line_bytes.saturating_sub(line.len()) as u64;
+            let mut capped = (&mut self.stream).take(room);
+            let event = select! {
+                read = capped.read_until(b'\n', &mut line) => match read? {
+                    0 if room > 0 => ChatEvent::Disconnect(DisconnectReason::Closed),
+                    _ => ChatEvent::Typed,
+                },
+                heard = receiver.recv() => ChatEvent::Heard(heard),
+                reason = kick.received() => ChatEvent::Disconnect(reason),
+                _ = next_tick(&mut keepalive) => ChatEvent::Keepalive,
+            };
+
+            match event {
+                ChatEvent::Typed => {
+                    let text = String::from_utf8_lossy(&line).trim().to_owned();
+
+                    line.clear();
+                    self.record(&format!("{text}\r\n"));
+
+                    if text == CHAT_EXIT {
                         break;
                     }
+
+                    if !text.is_empty() {
+                        self.say_in_chat(format!("<{username}> {text}"));
+                    }
+                }
+                ChatEvent::Heard(Ok(chat)) if chat.from != self.address => {
+                    self.writeln(&chat.text, None).await?;
+                }
+                ChatEvent::Heard(Ok(_)) => (),
+                ChatEvent::Keepalive if Self::ping(&self.outbound) => (),
+                ChatEvent::Keepalive => {
+                    self.disconnect(DisconnectReason::Error).await?;
+                    anyhow::bail!("Disconnected: {}", DisconnectReason::Error);
+                }
+                ChatEvent::Heard(Err(RecvError::Lagged(missed))) => {
+                    self.writeln(&format!("* Missed {missed} line(s)"), self.theme.error())
+                        .await?;
+                }
+                // AppState holds the sender for as long as sessions exist.
+                ChatEvent::Heard(Err(RecvError::Closed)) => break,
+                ChatEvent::Disconnect(reason) => {
+                    self.disconnect(reason).await?;
+                    anyhow::bail!("Disconnected: {reason}");
                 }
-                Err(e) => self.writeln(&format!("{e}"), None).await?,
             }
         }
 
+        self.say_in_chat(format!("* {username} left the chat"));
+
         Ok(())
     }
 
-    async fn send(&mut self, data: &str, newline: bool) -> Result<()> {
-        self.stream
-            .get_mut()
-            .write_all(format!("{data}{}", if newline { "\r\n" } else { "" }).as_bytes())
+    fn say_in_chat(&self, text: String) {
+        // Nobody else in chat to hear it is not an error.
+        let _ = self.app_state.chat.send(ChatLine {
+            from: self.address,
+            text,
+        });
+    }
+
+    /// How long to wait for input before kicking the session, if at all. Sysops (unless
+    /// configured otherwise) and sessions in the middle of a transfer or in chat are
+    /// exempt.
+    fn idle_timeout(&self) -> Option<Duration> {
+        let exempt =
+            (self.config.idle_exempt_sysops && self.is_sysop()) || self.mode != SessionMode::Normal;
+
+        match self.config.idle_timeout_secs {
+            Some(secs) if !exempt => Some(Duration::from_secs(secs)),
+            _ => None,
+        }
+    }
+
+    /// Ticks at which telnet clients are sent a keepalive, if configured. A keepalive
+    /// only reveals a dead peer once writing to it fails. A client that hung up is
+    /// noticed within two ticks, but for one that vanished without a word the kernel
+    /// keeps buffering and retransmitting until TCP gives up, about 15 minutes with
+    /// Linux defaults (`net.ipv4.tcp_retries2`), however short `keepalive_secs` is.
+    fn keepalive(&self) -> Option<Interval> {
+        let period = Duration::from_secs(self.config.keepalive_secs.filter(|secs| *secs > 0)?);
+
+        self.telnet
+            .then(|| interval_at(tokio::time::Instant::now() + period, period))
+    }
+
+    /// Sends a keepalive, returning false once the writer gave up on the connection.
+    /// A full queue means the client is slow rather than gone, so it counts as alive.
+    fn ping(outbound: &Sender<Vec<u8>>) -> bool {
+        !matches!(
+            outbound.try_send(telnet::KEEPALIVE.to_vec()),
+            Err(TrySendError::Closed(_))
+        )
+    }
+
+    fn record(&self, data: &str) {
+        if let Some(transcript) = &self.transcript {
+            transcript.record(data);
+        }
+    }
+
+    async fn should_record(&self, username: &str) -> bool {
+        self.config.record_sessions || self.app_state.recorded.read().await.contains(username)
+    }
+
+    /// Starts writing everything sent and received by this session to a transcript
+    /// file through a dedicated task, so slow disks never block the session.
+    fn start_transcript(&mut self, username: &str) {
+        let (sender, mut receiver) = channel::<String>(TRANSCRIPT_CAPACITY);
+        let dir = self.app_state.data_dir.join(TRANSCRIPTS_DIR);
+        let path = dir.join(format!("{}-{}.log", time::now(), transcript_name(username)));
+
+        spawn(async move {
+            let result: Result<()> = async {
+                create_dir_all(&dir).await?;
+
+                let mut file = File::create(&path).await?;
+
+                while let Some(data) = receiver.recv().await {
+                    file.write_all(data.as_bytes()).await?;
+                }
+
+                Ok(())
+            }
+            .await;
+
+            if let Err(e) = result {
+                error!("Could not record transcript {}: {e}", path.display());
+            }
+        });
+
+        self.transcript = Some(Transcript {
+            sender,
+            dropped: AtomicUsize::new(0),
+        });
+    }
+
+    pub async fn run(&mut self) -> Result<()> {
+        self.app_state.sessions.fetch_add(1, Ordering::Relaxed);
+        self.app_state.connections.write().await.insert(
+            self.address,
+            Connection {
+                outbound: self.outbound.clone(),
+                kick: Arc::clone(&self.kick),
+                json: self.json,
+            },
+        );
+        self.log_connection(&format!("connect {}", self.address))
+            .await;
+
+        let result = self.serve().await;
+
+        if result.is_err() && !matches!(self.status, SessionStatus::Disconnected(_)) {
+            self.status = SessionStatus::Disconnected(DisconnectReason::Error);
+        }
+
+        self.app_state
+            .record_connection(
+                self.address.ip(),
+                self.connected_at.elapsed(),
+                self.logged_on,
+                &self.config,
+            )
+            .await;
+
+        let username = self
+            .app_state
+            .online
+            .write()
             .await
-            .context("Could not send data to client")?;
+            .remove(&self.address)
+            .map(|online| online.username);
+        let reason = match self.status {
+            SessionStatus::Disconnected(reason) => reason.to_string(),
+            _ => String::from("unknown"),
+        };
+
+        info!("Disconnected: {reason}");
+
+        self.log_connection(&format!(
+            "disconnect {} {} {}s {reason}",
+            self.address,
+            username.as_deref().unwrap_or("-"),
+            self.connected_at.elapsed().as_secs()
+        ))
+        .await;
 
-        self.stream
-            .flush()
+        if let Some(username) = username
+            && let Err(e) = self.app_state.touch_user(&username).await
+        {
+            error!("{e}");
+        }
+
+        self.app_state
+            .connections
+            .write()
             .await
-            .context("Could not send data to client")
+            .remove(&self.address);
+        self.app_state.sessions.fetch_sub(1, Ordering::Relaxed);
+
+        result
     }
 
-    pub async fn write(&mut self, data: &str, style: Option<AnsiStyle>) -> Result<()> {
-        match style {
-            None => self.send(data, false).await,
-            Some(style) => self.send(&style.apply(data), false).await,
+    async fn log_connection(&self, event: &str) {
+        let Some(path) = &self.config.connection_log_file else {
+            return;
+        };
+
+        if let Err(e) = self
+            .app_state
+            .log_connection(path, event, self.config.connection_log_max_bytes)
+            .await
+        {
+            error!("{e}");
         }
     }
 
-    pub async fn writeln(&mut self, data: &str, style: Option<AnsiStyle>) -> Result<()> {
-        match style {
-            None => self.send(data, true).await,
-            Some(style) => self.send(&style.apply(data), true).await,
+    /// Discards a block of pasted lines: everything already buffered plus whatever
+    /// keeps arriving within `PASTE_WINDOW`. Returns how many lines were dropped.
+    async fn drain_paste(&mut self) -> Result<usize> {
+        let mut ignored = 0;
+        let mut line = String::new();
+        let max_line_bytes = self.config.max_line_bytes;
+
+        while let Ok(read) = timeout(
+            PASTE_WINDOW,
+            read_line_capped(&mut self.stream, &mut line, max_line_bytes),
+        )
+        .await
+        {
+            if read?.0 == 0 {
+                break;
+            }
+
+            ignored += 1;
+            line.clear();
+        }
+
+        Ok(ignored)
+    }
+
+    /// The message of the day, read from `motd_file` each time so edits show up without
+    /// a restart, falling back to the welcome message.
+    pub async fn motd(&self) -> Option<String> {
+        if let Some(motd_file) = &self.config.motd_file {
+            match read_to_string(motd_file).await {
+                Ok(motd) if !motd.trim().is_empty() => return Some(motd.trim_end().to_owned()),
+                Ok(_) => (),
+                Err(e) => warn!("Skipping motd {}: {e}", motd_file.display()),
+            }
         }
+
+        self.config.welcome_msg.clone()
     }
-}
 
-pub struct AppState {
-    pub users: RwLock<Vec<User>>,
-    pub messages: RwLock<Vec<Message>>,
-}
+    /// Ends the session for `reason`, telling the client why unless they asked to quit.
+    pub async fn disconnect(&mut self, reason: DisconnectReason) -> Result<()> {
+        self.status = SessionStatus::Disconnected(reason);
 
-impl AppState {
-    pub async fn from_file() -> Result<Self> {
-        let users: Vec<User> = if Path::new(USERS_FILE).exists() {
-            let users_json = read_to_string(USERS_FILE).await?;
-            serde_json::from_str(&users_json).context("Could not read users")?
-        } else {
-            Vec::new()
+        let notice = match reason {
+            // Nobody is left to tell after the client hung up or the connection died.
+            DisconnectReason::Quit | DisconnectReason::Closed | DisconnectReason::Error => {
+                return Ok(());
+            }
+            DisconnectReason::Idle => String::from("Disconnected due to inactivity"),
+            DisconnectReason::Shutdown => String::from("The server is shutting down, goodbye"),
+            _ => format!("Disconnected: {reason}"),
         };
 
-        let messages: Vec<Message> = if Path::new(MESSAGES_FILE).exists() {
-            let messages_json = read_to_string(MESSAGES_FILE).await?;
-            serde_json::from_str(&messages_json).context("Could not read messages")?
-        } else {
-            Vec::new()
-        };
+        self.writeln(&notice, None).await
+    }
 
-        Ok(Self {
-            users: RwLock::new(users),
-            messages: RwLock::new(messages),
-        })
+    /// Refreshes the user's last seen time, at most once per `TOUCH_INTERVAL`.
+    async fn touch(&mut self, username: &str) {
+        if self
+            .last_touch
+            .is_some_and(|t| t.elapsed() < TOUCH_INTERVAL)
+        {
+            return;
+        }
+
+        self.last_touch = Some(Instant::now());
+
+        if let Err(e) = self.app_state.touch_user(username).await {
+            error!("{e}");
+        }
+    }
+
+    pub async fn show_welcome_commands(&mut self) -> Result<()> {
+        let commands: Vec<String> = self
+            .command_handler
+            .welcome_commands
+            .keys()
+            .map(|k| k.to_lowercase())
+            .collect();
+        let separator = format!(" {} ", self.glyph(Glyph::VerticalLine));
+
+        self.writeln("Commands:", self.theme.header()).await?;
+        self.writeln(&self.separator(9), None).await?;
+        self.writeln(&commands.join(&separator), None).await?;
+        self.writeln("", None).await
+    }
+
+    /// Switches to the configuration in `AppState` if it was reloaded. The page size only
+    /// follows the new default when the user didn't pick their own.
+    async fn refresh_config(&mut self) {
+        let config = self.app_state.config().await;
+
+        if Arc::ptr_eq(&config, &self.config) {
+            return;
+        }
+
+        let scheme = self.theme.scheme;
+
+        if self.page_size == self.config.page_size {
+            self.page_size = config.page_size;
+        }
+
+        self.theme = Theme::new(&config.theme, config.color && !self.json);
+        self.theme.scheme = scheme;
+        self.config = config;
+    }
+
+    /// Drops back to the welcome state, forgetting everything that belonged to the
+    /// user who was logged on.
+    pub async fn logout(&mut self) {
+        if let Some(online) = self.app_state.online.write().await.remove(&self.address)
+            && let Err(e) = self.app_state.touch_user(&online.username).await
+        {
+            error!("{e}");
+        }
+
+        self.status = SessionStatus::LoggedOff;
+        self.admin = false;
+        self.current_board = DEFAULT_BOARD.to_owned();
+        self.theme.scheme = None;
+        self.page_size = self.config.page_size;
+        self.messages_read = 0;
+        self.messages_posted = 0;
+        self.mode = SessionMode::Normal;
+        self.last_touch = None;
+        self.transcript = None;
+        self.history.clear();
     }
 
-    pub async fn save(&self, kind: AppStateKind) -> Result<()> {
-        match kind {
-            AppStateKind::Users => {
-                let mut file = File::create(USERS_FILE).await?;
-                let users = &*self.users.read().await; // * gets the inner value of the Lock.
-                let users_json = serde_json::to_string_pretty(users)?;
+    async fn serve(&mut self) -> Result<()> {
+        if self.json {
+            return self.serve_json().await;
+        }
+
+        if self
+            .app_state
+            .banner_flooding(self.address.ip(), &self.config)
+            .await
+        {
+            self.writeln("Too many quick reconnects, skipping banner", None)
+                .await?;
+        } else {
+            if let Some(banner_file) = &self.config.banner_file {
+                match read(banner_file).await {
+                    Ok(banner_data) => {
+                        let banner = banner::decode(&banner_data, self.config.banner_encoding);
+
+                        self.writeln(&banner, None).await?;
+                        self.writeln("", None).await?;
+                    }
+                    Err(e) => warn!("Skipping banner {}: {e}", banner_file.display()),
+                }
+            }
+
+            if let Some(motd) = self.motd().await {
+                self.writeln(&motd, None).await?;
+                self.writeln("", None).await?;
+            }
+        }
+
+        self.writeln("", None).await?;
+        self.show_welcome_commands().await?;
+
+        let command_handler = Arc::clone(&self.command_handler);
+
+        loop {
+            self.refresh_config().await;
+
+            let raw_command = match self.prompt("> ", self.theme.prompt()).await {
+                Ok(raw_command) => raw_command,
+                Err(_) if matches!(self.status, SessionStatus::Disconnected(_)) => break,
+                Err(e) => return Err(e),
+            };
+            let result = match self.recall(&raw_command).await {
+                Ok(raw_command) => command_handler.handle(&raw_command, self).await,
+                Err(e) => Err(e),
+            };
+
+            if let Err(e) = result {
+                if matches!(self.status, SessionStatus::Disconnected(_)) {
+                    break;
+                }
+
+                self.log_failure(&e);
+
+                if self.stream.buffer().is_empty() {
+                    self.writeln(&error::user_message(&e), self.theme.error())
+                        .await?;
+                } else {
+                    let ignored = self.drain_paste().await?;
 
-                file.write_all(users_json.as_bytes()).await?;
+                    self.writeln(
+                        &format!(
+                            "Paste detected, {ignored} line(s) ignored. Are you in the right mode?"
+                        ),
+                        self.theme.error(),
+                    )
+                    .await?;
+                }
             }
-            AppStateKind::Messages => {
-                let mut file = File::create(MESSAGES_FILE).await?;
-                let messages = &*self.messages.read().await; // * gets the inner value of the Lock.
-                let messages_json = serde_json::to_string_pretty(messages)?;
 
-                file.write_all(messages_json.as_bytes()).await?;
+            if self.track_status().await {
+                break;
             }
         }
 
         Ok(())
     }
-}
 
-#[derive(Debug, Deserialize, Serialize)]
-pub struct User {
-    pub id: i64,
-    pub username: String,
-    pub password: String,
-}
+    /// Expands `!<n>` to the nth command in the history and records the command run.
+    async fn recall(&mut self, raw_command: &str) -> Result<String> {
+        let raw_command = match raw_command.strip_prefix('!') {
+            Some(n) => {
+                let command = n
+                    .parse::<usize>()
+                    .ok()
+                    .and_then(|n| self.history.get(n.checked_sub(1)?))
+                    .cloned()
+                    .context(UserError::new("No such history entry"))?;
 
-#[derive(Clone, Deserialize, Serialize)]
-pub struct Message {
-    pub id: i64,
-    pub username: String,
-    pub subject: String,
-    pub body: String,
-}
+                self.writeln(&command, None).await?;
 
-#[derive(Debug)]
-pub enum SessionStatus {
-    LoggedOn(String),
-    LoggedOff,
-    Disconnected,
-}
+                command
+            }
+            None => raw_command.to_owned(),
+        };
+
+        if !raw_command.is_empty() {
+            if self.history.len() == MAX_HISTORY {
+                self.history.remove(0);
+            }
+
+            self.history.push(raw_command.clone());
+        }
+
+        Ok(raw_command)
+    }
+
+    /// Answers one JSON request per line until the client quits or hangs up. Prompts
+    /// are answered from the request's `input` and everything a command writes is
+    /// collected into its response, one entry per line.
+    async fn serve_json(&mut self) -> Result<()> {
+        let command_handler = Arc::clone(&self.command_handler);
 
-pub enum AppStateKind {
-    Users,
-    Messages,
+        loop {
+            self.refresh_config().await;
+
+            let line = match self.read_input().await {
+                Ok(line) => line,
+                Err(_) if matches!(self.status, SessionStatus::Disconnected(_)) => break,
+                Err(e) => return Err(e),
+            };
+
+            if line.is_empty() {
+                continue;
+            }
+
+            let response = match serde_json::from_str::<api::Request>(&line) {
+                Ok(request) => {
+                    let args: Vec<&str> = request.args.iter().map(String::as_str).collect();
+
+                    self.json_input = request.input.into();
+                    self.json_output.clear();
+
+                    let result = command_handler.dispatch(&request.cmd, &args, self).await;
+                    let output = take(&mut self.json_output);
+
+                    if let Err(e) = &result {
+                        self.log_failure(e);
+                    }
+
+                    api::Response {
+                        ok: result.is_ok(),
+                        error: result.err().map(|e| error::user_message(&e)),
+                        output: output.lines().map(str::to_owned).collect(),
+                        user: self.username().ok(),
+                    }
+                }
+                Err(e) => api::Response {
+                    user: self.username().ok(),
+                    ..api::Response::error(format!("Invalid request: {e}"))
+                },
+            };
+            let mut data = serde_json::to_vec(&response)?;
+
+            data.push(b'\n');
+
+            if self.outbound.try_send(data).is_err() {
+                self.status = SessionStatus::Disconnected(DisconnectReason::TooSlow);
+            }
+
+            if self.track_status().await {
+                break;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Logs why a command failed. Mistakes of the user are routine, anything else is
+    /// logged with its full chain of causes since the user only saw a generic message.
+    fn log_failure(&self, e: &Error) {
+        if error::is_user_error(e) {
+            info!("Command failed: {e}");
+        } else {
+            error!("Command failed: {e:#}");
+        }
+    }
+
+    /// Keeps the online registry, last seen time and transcript in line with who is
+    /// logged on after a command. Returns true once the session is disconnected.
+    async fn track_status(&mut self) -> bool {
+        match &self.status {
+            SessionStatus::LoggedOn(username) => {
+                let username = username.clone();
+
+                self.logged_on = true;
+
+                if self.span_user.as_ref() != Some(&username) {
+                    Span::current().record("user", field::display(&username));
+                    self.span_user = Some(username.clone());
+                }
+
+                self.app_state
+                    .online
+                    .write()
+                    .await
+                    .entry(self.address)
+                    .or_insert_with(|| OnlineUser {
+                        username: username.clone(),
+                        since: time::now(),
+                    });
+                self.touch(&username).await;
+
+                match (
+                    self.transcript.is_some(),
+                    self.should_record(&username).await,
+                ) {
+                    (false, true) => self.start_transcript(&username),
+                    (true, false) => self.transcript = None,
+                    _ => (),
+                }
+
+                false
+            }
+            SessionStatus::Disconnected(_) => true,
+            SessionStatus::LoggedOff => {
+                if self.span_user.take().is_some() {
+                    Span::current().record("user", field::display("-"));
+                }
+
+                false
+            }
+        }
+    }
+
+    pub async fn render(&mut self, outcome: CommandOutcome) -> Result<()> {
+        for line in outcome.lines {
+            self.writeln(&line, None).await?;
+        }
+
+        if let Some(status) = outcome.status {
+            self.status = status;
+        }
+
+        Ok(())
+    }
+
+    async fn send(&mut self, data: &str, newline: bool) -> Result<()> {
+        let data = format!("{data}{}", if newline { "\r\n" } else { "" });
+
+        self.record(&data);
+
+        if self.json {
+            self.json_output.push_str(&data);
+
+            return Ok(());
+        }
+
+        match self.outbound.try_send(data.into_bytes()) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full(_)) => {
+                self.status = SessionStatus::Disconnected(DisconnectReason::TooSlow);
+
+                Err(Error::msg("Client is too slow, dropping session"))
+            }
+            Err(TrySendError::Closed(_)) => {
+                self.status = SessionStatus::Disconnected(DisconnectReason::Error);
+
+                Err(Error::msg("Could not send data to client"))
+            }
+        }
+    }
+
+    pub async fn write(&mut self, data: &str, style: Option<AnsiStyle>) -> Result<()> {
+        match style.or_else(|| self.theme.default_style()) {
+            None => self.send(data, false).await,
+            Some(style) => self.send(&style.apply(data), false).await,
+        }
+    }
+
+    pub async fn writeln(&mut self, data: &str, style: Option<AnsiStyle>) -> Result<()> {
+        match style.or_else(|| self.theme.default_style()) {
+            None => self.send(data, true).await,
+            Some(style) => self.send(&style.apply(data), true).await,
+        }
+    }
+}
+
+pub struct AppState {
+    /// Where audit logs, transcripts and attachments are kept next to the stores.
+    data_dir: PathBuf,
+    storage: Box<dyn Storage>,
+    pub users: RwLock<Vec<User>>,
+    pub messages: RwLock<Vec<Message>>,
+    /// Id of the last message each (user, board) pair has read with `message next`.
+    pub last_read: RwLock<HashMap<(String, String), i64>>,
+    /// Boards created explicitly with `create`, along with who created them.
+    pub boards: RwLock<Vec<Board>>,
+    pub private_messages: RwLock<Vec<PrivateMessage>>,
+    users_save: Mutex<()>,
+    messages_save: Mutex<()>,
+    last_read_save: Mutex<()>,
+    boards_save: Mutex<()>,
+    private_messages_save: Mutex<()>,
+    audit_save: Mutex<()>,
+    attachments_save: Mutex<()>,
+    connection_log_save: Mutex<()>,
+    quick_disconnects: RwLock<HashMap<IpAddr, Vec<Instant>>>,
+    /// When each user recently sent private messages, for rate limiting.
+    dm_times: Mutex<HashMap<String, Vec<Instant>>>,
+    /// When each IP recently connected, for rate limiting.
+    connection_times: Mutex<HashMap<IpAddr, Vec<Instant>>>,
+    /// Consecutive failed logins per username, and until when the account is locked.
+    failed_logins: Mutex<HashMap<String, (usize, Option<Instant>)>>,
+    /// Password hashes of recently logged in users, by username.
+    login_cache: Mutex<LruCache<String, String>>,
+    /// Users whose sessions a sysop asked to record.
+    pub recorded: RwLock<HashSet<String>>,
+    /// Users of the sessions currently logged on, by peer address.
+    pub online: RwLock<HashMap<SocketAddr, OnlineUser>>,
+    /// Sessions currently connected, logged on or not.
+    sessions: AtomicUsize,
+    /// Every connected session, by peer address.
+    connections: RwLock<HashMap<SocketAddr, Connection>>,
+    /// Lines said in chat, heard by every session in chat mode.
+    pub chat: broadcast::Sender<ChatLine>,
+    /// The current configuration, replaced as a whole when it is reloaded.
+    config: RwLock<Arc<Config>>,
+}
+
+impl AppState {
+    pub async fn load(storage: Box<dyn Storage>, config: Arc<Config>) -> Result<Self> {
+        let stores = load_stores(&*storage).await?;
+
+        Ok(Self {
+            data_dir: config.data_dir.clone(),
+            storage,
+            users: RwLock::new(stores.users),
+            messages: RwLock::new(stores.messages),
+            last_read: RwLock::new(stores.last_read),
+            boards: RwLock::new(stores.boards),
+            private_messages: RwLock::new(stores.private_messages),
+            users_save: Mutex::new(()),
+            messages_save: Mutex::new(()),
+            last_read_save: Mutex::new(()),
+            boards_save: Mutex::new(()),
+            private_messages_save: Mutex::new(()),
+            audit_save: Mutex::new(()),
+            attachments_save: Mutex::new(()),
+            connection_log_save: Mutex::new(()),
+            quick_disconnects: RwLock::new(HashMap::new()),
+            dm_times: Mutex::new(HashMap::new()),
+            connection_times: Mutex::new(HashMap::new()),
+            failed_logins: Mutex::new(HashMap::new()),
+            login_cache: Mutex::new(LruCache::new(LOGIN_CACHE_SIZE)),
+            recorded: RwLock::new(HashSet::new()),
+            online: RwLock::new(HashMap::new()),
+            sessions: AtomicUsize::new(0),
+            connections: RwLock::new(HashMap::new()),
+            chat: broadcast::channel(CHAT_CAPACITY).0,
+            config: RwLock::new(config),
+        })
+    }
+
+    /// Replaces the persisted stores with what is currently in storage, e.g. after an
+    /// operator edited the files by hand. Every store is swapped while holding all of
+    /// their write locks, so readers never see a mix of old and new state.
+    pub async fn reload(&self) -> Result<()> {
+        let fresh = load_stores(&*self.storage).await?;
+        let _users_save = self.users_save.lock().await;
+        let _messages_save = self.messages_save.lock().await;
+        let _last_read_save = self.last_read_save.lock().await;
+        let _boards_save = self.boards_save.lock().await;
+        let _private_messages_save = self.private_messages_save.lock().await;
+        let mut users = self.users.write().await;
+        let mut messages = self.messages.write().await;
+        let mut last_read = self.last_read.write().await;
+        let mut boards = self.boards.write().await;
+        let mut private_messages = self.private_messages.write().await;
+
+        *users = fresh.users;
+        *messages = fresh.messages;
+        *last_read = fresh.last_read;
+        *boards = fresh.boards;
+        *private_messages = fresh.private_messages;
+        self.login_cache.lock().await.clear();
+
+        Ok(())
+    }
+
+    /// Whether an IP has recently connected and left without logging in often enough
+    /// to only deserve a terse greeting instead of the full banner.
+    pub async fn banner_flooding(&self, ip: IpAddr, config: &Config) -> bool {
+        let (Some(limit), Some(window)) =
+            (config.banner_flood_limit, config.banner_flood_window_secs)
+        else {
+            return false;
+        };
+        let window = Duration::from_secs(window);
+
+        let mut quick_disconnects = self.quick_disconnects.write().await;
+
+        match quick_disconnects.get_mut(&ip) {
+            Some(times) => {
+                times.retain(|t| t.elapsed() < window);
+                times.len() >= limit
+            }
+            None => false,
+        }
+    }
+
+    /// Records how a connection from `ip` ended. Short sessions without a login count
+    /// towards the banner flood limit, while a login clears the IP's history.
+    pub async fn record_connection(
+        &self,
+        ip: IpAddr,
+        duration: Duration,
+        logged_on: bool,
+        config: &Config,
+    ) {
+        let Some(window) = config.banner_flood_window_secs else {
+            return;
+        };
+        let window = Duration::from_secs(window);
+
+        let mut quick_disconnects = self.quick_disconnects.write().await;
+
+        if logged_on {
+            quick_disconnects.remove(&ip);
+        } else if duration < window {
+            // Drop IPs whose quick disconnects all fell out of the window, or every
+            // host that ever connected and left would stay here.
+            quick_disconnects.retain(|_, times| {
+                times.retain(|t| t.elapsed() < window);
+                !times.is_empty()
+            });
+            quick_disconnects
+                .entry(ip)
+                .or_default()
+                .push(Instant::now());
+        }
+    }
+
+    /// Looks up a user's password hash, going through the login cache first.
+    pub async fn password_hash(&self, username: &str) -> Option<String> {
+        let mut cache = self.login_cache.lock().await;
+
+        if let Some(hash) = cache.get(&username.to_owned()) {
+            return Some(hash);
+        }
+
+        let hash = self
+            .users
+            .read()
+            .await
+            .iter()
+            .find(|u| u.username == username)?
+            .password
+            .clone();
+
+        cache.insert(username.to_owned(), hash.clone());
+
+        Some(hash)
+    }
+
+    /// Drops a user from the login cache. Must be called whenever a password
+    /// changes or an account goes away.
+    pub async fn invalidate_login(&self, username: &str) {
+        self.login_cache.lock().await.remove(&username.to_owned());
+    }
+
+    pub async fn touch_user(&self, username: &str) -> Result<()> {
+        self.update_user(username, |user| user.last_seen = Some(time::now()))
+            .await?;
+
+        Ok(())
+    }
+
+    /// Stamps a successful login of `username` and returns when the previous one was,
+    /// if it was ever recorded.
+    pub async fn record_login(&self, username: &str) -> Result<Option<i64>> {
+        Ok(self
+            .update_user(username, |user| user.last_login.replace(time::now()))
+            .await?
+            .flatten())
+    }
+
+    /// Returns the visible messages matching every field set in `filter`, oldest first,
+    /// all under a single read lock.
+    pub async fn query_messages(&self, filter: MessageFilter) -> Vec<Message> {
+        let messages = self.messages.read().await;
+        let last_read = self.last_read.read().await;
+        let keyword = filter.keyword.map(|k| k.to_lowercase());
+
+        messages
+            .iter()
+            .filter(|m| !m.deleted)
+            .filter(|m| filter.board.as_ref().is_none_or(|b| &m.board == b))
+            .filter(|m| filter.author.as_ref().is_none_or(|a| &m.username == a))
+            .filter(|m| {
+                keyword.as_ref().is_none_or(|k| {
+                    m.subject.to_lowercase().contains(k) || m.body.to_lowercase().contains(k)
+                })
+            })
+            .filter(|m| {
+                filter.unread_for.as_ref().is_none_or(|u| {
+                    last_read
+                        .get(&(u.clone(), m.board.clone()))
+                        .is_none_or(|id| m.id > *id)
+                })
+            })
+            .take(filter.limit.unwrap_or(usize::MAX))
+            .cloned()
+            .collect()
+    }
+
+    /// Posts a digest message to every board with new activity since `since`,
+    /// listing the new messages. Returns the number of digests posted.
+    pub async fn post_digests(&self, since: i64) -> Result<usize> {
+        let mut digests: BTreeMap<String, Vec<String>> = BTreeMap::new();
+
+        for message in self
+            .messages
+            .read()
+            .await
+            .iter()
+            .filter(|m| !m.deleted && m.created_at > since && m.username != DIGEST_AUTHOR)
+        {
+            digests
+                .entry(message.board.clone())
+                .or_default()
+                .push(format!(
+                    "{} {} {}",
+                    message.id, message.username, message.subject
+                ));
+        }
+
+        if digests.is_empty() {
+            return Ok(0);
+        }
+
+        let now = time::now();
+
+        self.update_messages(|messages| {
+            for (board, lines) in &digests {
+                let id = messages.iter().map(|m| m.id + 1).max().unwrap_or_default();
+
+                messages.push(Message {
+                    id,
+                    username: DIGEST_AUTHOR.to_owned(),
+                    board: board.clone(),
+                    subject: format!("Digest for {}", time::format(now)),
+                    body: format!("{}\r\n", lines.join("\r\n")),
+                    created_at: now,
+                    ..Default::default()
+                });
+            }
+        })
+        .await?;
+
+        Ok(digests.len())
+    }
+
+    /// Counts the visible replies, direct or nested, under each thread root on
+    /// `board`. Deleted messages in between don't hide their replies, like in `thread`.
+    pub async fn reply_counts(&self, board: &str) -> HashMap<i64, usize> {
+        let messages = self.messages.read().await;
+        let mut on_board: Vec<&Message> = messages.iter().filter(|m| m.board == board).collect();
+        let mut below: HashMap<i64, usize> = HashMap::new();
+        let mut counts = HashMap::new();
+
+        // Replies always get later ids than what they answer, so going newest first
+        // hands every message's total up to its parent before the parent is reached.
+        on_board.sort_unstable_by_key(|m| Reverse(m.id));
+
+        for message in on_board {
+            let replies = below.remove(&message.id).unwrap_or_default();
+
+            match message.reply_to {
+                Some(parent) => {
+                    *below.entry(parent).or_default() += replies + usize::from(!message.deleted)
+                }
+                None if replies > 0 => {
+                    counts.insert(message.id, replies);
+                }
+                None => (),
+            }
+        }
+
+        counts
+    }
+
+    /// Returns the messages of the thread rooted at `id` in reading order, each with its
+    /// depth clamped to `max_depth`. Deleted messages with visible replies below them
+    /// are kept as placeholders without author or content, so those replies stay in
+    /// place. The tree is walked with an explicit stack so pathological reply chains
+    /// can't overflow the call stack.
+    pub async fn thread(&self, id: i64, max_depth: usize) -> Vec<(usize, Message)> {
+        let messages = self.messages.read().await;
+        let mut children: HashMap<i64, Vec<&Message>> = HashMap::new();
+
+        for message in messages.iter() {
+            if let Some(parent) = message.reply_to {
+                children.entry(parent).or_default().push(message);
+            }
+        }
+
+        // Each entry holds its depth, the index of its parent's entry and the message.
+        let mut entries: Vec<(usize, Option<usize>, &Message)> = Vec::new();
+        let mut visited = HashSet::new();
+        let mut stack: Vec<(usize, Option<usize>, &Message)> = messages
+            .iter()
+            .filter(|m| m.id == id)
+            .map(|m| (0, None, m))
+            .collect();
+
+        while let Some((depth, parent, message)) = stack.pop() {
+            // Hand edited files could contain reply cycles.
+            if !visited.insert(message.id) {
+                continue;
+            }
+
+            let index = entries.len();
+
+            entries.push((depth, parent, message));
+
+            if let Some(replies) = children.get(&message.id) {
+                // Pushed in reverse so the oldest reply is visited first.
+                stack.extend(replies.iter().rev().map(|m| (depth + 1, Some(index), *m)));
+            }
+        }
+
+        let mut keep = vec![false; entries.len()];
+
+        for (index, (_, _, message)) in entries.iter().enumerate() {
+            if message.deleted {
+                continue;
+            }
+
+            let mut next = Some(index);
+
+            while let Some(index) = next
+                && !keep[index]
+            {
+                keep[index] = true;
+                next = entries[index].1;
+            }
+        }
+
+        entries
+            .into_iter()
+            .zip(keep)
+            .filter(|(_, keep)| *keep)
+            .map(|((depth, _, message), _)| {
+                let message = if message.deleted {
+                    Message {
+                        id: message.id,
+                        board: message.board.clone(),
+                        reply_to: message.reply_to,
+                        created_at: message.created_at,
+                        deleted: true,
+                        ..Default::default()
+                    }
+                } else {
+                    message.clone()
+                };
+
+                (depth.min(max_depth), message)
+            })
+            .collect()
+    }
+
+    /// Returns up to `limit` visible messages of `board` with an id greater than `after`,
+    /// plus the cursor to pass as `after` for the following page, if there is one.
+    pub async fn messages_page(&self, board: &str, after: Option<i64>, limit: usize) -> Page {
+        let limit = limit.clamp(1, MAX_PAGE_SIZE);
+        let messages = self.messages.read().await;
+        // Messages are appended with increasing ids, so the vector is sorted by id.
+        let start = after.map_or(0, |after| messages.partition_point(|m| m.id <= after));
+        let mut visible = messages[start..]
+            .iter()
+            .filter(|m| !m.deleted && m.board == board);
+        let page: Vec<Message> = visible.by_ref().take(limit).cloned().collect();
+        let next = match visible.next() {
+            Some(_) => page.last().map(|m| m.id),
+            None => None,
+        };
+
+        Page {
+            messages: page,
+            next,
+        }
+    }
+
+    /// Summarizes every board that has messages, plus the default, configured and
+    /// created boards, from the point of view of `username`.
+    pub async fn board_stats(&self, username: &str, configured: &[String]) -> Vec<BoardStats> {
+        let read = self.read_marks(username).await;
+        let messages = self.messages.read().await;
+        let created = self.boards.read().await;
+        let mut boards: BTreeMap<&str, BoardStats> = BTreeMap::new();
+
+        boards.insert(DEFAULT_BOARD, BoardStats::new(DEFAULT_BOARD));
+
+        for board in configured {
+            boards.insert(board, BoardStats::new(board));
+        }
+
+        for board in created.iter() {
+            let stats = boards
+                .entry(&board.name)
+                .or_insert_with(|| BoardStats::new(&board.name));
+
+            stats.archived = board.archived;
+        }
+
+        for message in messages.iter().filter(|m| !m.deleted) {
+            let stats = boards
+                .entry(&message.board)
+                .or_insert_with(|| BoardStats::new(&message.board));
+
+            stats.messages += 1;
+            stats.last_post = stats.last_post.max(Some(message.created_at));
+            stats.unread |= message.unread_by(username, &read);
+        }
+
+        boards.into_values().collect()
+    }
+
+    /// Permanently removes soft deleted messages older than `max_age`. Replies to a
+    /// purged message move up to its closest remaining ancestor. Returns how many were
+    /// purged.
+    pub async fn purge_deleted(&self, max_age: Duration) -> Result<usize> {
+        let cutoff = time::now() - max_age.as_secs() as i64;
+        let expired = |m: &Message| m.deleted && m.deleted_at.is_none_or(|at| at <= cutoff);
+
+        if !self.messages.read().await.iter().any(expired) {
+            return Ok(0);
+        }
+
+        let (purged, orphans) = self
+            .update_messages(|messages| {
+                let purged = remove_messages(messages, expired);
+                // Cross-posted copies share their attachment, so only drop blobs nothing
+                // else points to anymore.
+                let orphans: HashSet<i64> = purged
+                    .iter()
+                    .filter_map(|m| m.attachment.as_ref())
+                    .map(|a| a.id)
+                    .filter(|id| {
+                        !messages
+                            .iter()
+                            .any(|m| m.attachment.as_ref().is_some_and(|a| a.id == *id))
+                    })
+                    .collect();
+
+                (purged.len(), orphans)
+            })
+            .await?;
+
+        for id in orphans {
+            if let Err(e) = remove_file(self.attachment_path(id)).await {
+                warn!("Could not remove attachment {id}: {e}");
+            }
+        }
+
+        Ok(purged)
+    }
+
+    pub async fn board_anonymous(&self, board: &str) -> bool {
+        self.boards
+            .read()
+            .await
+            .iter()
+            .any(|b| b.name == board && b.anonymous)
+    }
+
+    /// Turns anonymous posting on or off for a board, registering it if it only
+    /// existed through its messages so far.
+    pub async fn set_board_anonymous(&self, board: &str, anonymous: bool) -> Result<()> {
+        self.update_boards(|boards| match boards.iter_mut().find(|b| b.name == board) {
+            Some(board) => board.anonymous = anonymous,
+            None => boards.push(Board {
+                name: board.to_owned(),
+                creator: None,
+                created_at: time::now(),
+                archived: false,
+                anonymous,
+            }),
+        })
+        .await
+    }
+
+    pub async fn board_archived(&self, board: &str) -> bool {
+        self.boards
+            .read()
+            .await
+            .iter()
+            .any(|b| b.name == board && b.archived)
+    }
+
+    /// Archives every board without activity for `max_age`, except the default boards.
+    /// A board's activity is its last post or its creation, which unarchiving bumps,
+    /// whichever is later. Boards that only exist through their messages are added to
+    /// the registry so the flag has somewhere to live. Nothing is saved unless a board
+    /// is archived. Returns how many were.
+    pub async fn archive_inactive(
+        &self,
+        max_age: Duration,
+        default_board: Option<&str>,
+    ) -> Result<usize> {
+        let cutoff = time::now() - max_age.as_secs() as i64;
+        let mut last_post: HashMap<String, i64> = HashMap::new();
+
+        for message in self.messages.read().await.iter().filter(|m| !m.deleted) {
+            let last = last_post.entry(message.board.clone()).or_default();
+
+            *last = (*last).max(message.created_at);
+        }
+
+        let stale = |name: &str, created_at: i64| {
+            let last = last_post.get(name).copied().unwrap_or_default();
+
+            name != DEFAULT_BOARD && default_board != Some(name) && last.max(created_at) < cutoff
+        };
+        let archive = |boards: &mut Vec<Board>| {
+            let mut archived = 0;
+
+            for board in boards
+                .iter_mut()
+                .filter(|b| !b.archived && stale(&b.name, b.created_at))
+            {
+                board.archived = true;
+                archived += 1;
+            }
+
+            for (name, at) in &last_post {
+                if !boards.iter().any(|b| b.name == *name) && stale(name, *at) {
+                    boards.push(Board {
+                        name: name.clone(),
+                        creator: None,
+                        created_at: *at,
+                        archived: true,
+                        anonymous: false,
+                    });
+                    archived += 1;
+                }
+            }
+
+            archived
+        };
+
+        // Most passes find nothing to do, and those shouldn't rewrite the boards.
+        if archive(&mut self.boards.read().await.clone()) == 0 {
+            return Ok(0);
+        }
+
+        self.update_boards(archive).await
+    }
+
+    /// Attaches `data` to a message, storing the bytes in the blob store under a new
+    /// attachment id. Fails without touching anything if the message already has an
+    /// attachment or the store would grow past `max_total` bytes. The blob is written
+    /// before the message points to it, and removed again if that can't be saved. Ids
+    /// come from a counter kept next to the blobs, so a purged attachment's id is never
+    /// handed out again.
+    pub async fn attach(
+        &self,
+        message_id: i64,
+        filename: &str,
+        data: &[u8],
+        max_total: usize,
+    ) -> Result<Attachment> {
+        // Ids are only handed out under this lock, so nobody else writes the same blob.
+        let _guard = self.attachments_save.lock().await;
+        let counted: i64 = read_to_string(self.attachments_dir().join(ATTACHMENT_COUNTER))
+            .await
+            .ok()
+            .and_then(|id| id.trim().parse().ok())
+            .unwrap_or_default();
+        let attachment = {
+            let messages = self.messages.read().await;
+            let total: usize = messages
+                .iter()
+                .filter_map(|m| m.attachment.as_ref())
+                .map(|a| (a.id, a.size))
+                .collect::<HashMap<_, _>>()
+                .values()
+                .sum();
+
+            attachable(&messages, message_id)?;
+
+            if total + data.len() > max_total {
+                anyhow::bail!(UserError::new("Attachment storage is full"));
+            }
+
+            // Stores from before the counter start after the highest id in use.
+            let in_use = messages
+                .iter()
+                .filter_map(|m| m.attachment.as_ref())
+                .map(|a| a.id + 1)
+                .max()
+                .unwrap_or_default();
+
+            Attachment {
+                id: in_use.max(counted),
+                filename: filename.to_owned(),
+                size: data.len(),
+            }
+        };
+
+        // The id is used up before anything refers to it, so even a failed attach
+        // can't have it handed out twice.
+        create_dir_all(self.attachments_dir()).await?;
+        write(
+            self.attachments_dir().join(ATTACHMENT_COUNTER),
+            (attachment.id + 1).to_string(),
+        )
+        .await?;
+
+        let path = self.attachment_path(attachment.id);
+        let result = async {
+            let mut file = File::create(&path).await?;
+
+            file.write_all(data).await?;
+            file.sync_all().await?;
+
+            // Checked again, the message could have been deleted in the meantime.
+            self.update_messages(|messages| {
+                let index = attachable(messages, message_id)?;
+
+                messages[index].attachment = Some(attachment.clone());
+
+                Ok(())
+            })
+            .await?
+        }
+        .await;
+
+        if let Err(e) = result {
+            if let Err(e) = remove_file(&path).await {
+                warn!("Could not remove attachment {}: {e}", attachment.id);
+            }
+
+            return Err(e);
+        }
+
+        Ok(attachment)
+    }
+
+    /// Blobs are named after their attachment id only, so user supplied file names
+    /// never reach the file system.
+    fn attachment_path(&self, id: i64) -> PathBuf {
+        self.attachments_dir().join(id.to_string())
+    }
+
+    pub fn attachments_dir(&self) -> PathBuf {
+        self.data_dir.join(ATTACHMENTS_DIR)
+    }
+
+    pub async fn read_attachment(&self, attachment: &Attachment) -> Result<Vec<u8>> {
+        read(self.attachment_path(attachment.id))
+            .await
+            .context("Could not read attachment")
+    }
+
+    /// Removes everything stored about a user: the account with its profile, macros and
+    /// read messages, read pointers, board ownership, private mail, block list entries,
+    /// recording flags and transcripts. Their messages are either removed, with
+    /// `remove_messages`, or attributed to `FORGOTTEN_AUTHOR`, and so are their lines in
+    /// digests, the audit log and the connection log. Free text mentioning them, like
+    /// message bodies or other users' transcripts, is left alone.
+    ///
+    /// The stores change through `update_stores`. Transcripts and rewritten logs are
+    /// set aside first and only deleted or swapped in once the stores are saved, so a
+    /// failure leaves everything as it was. With `dry_run` nothing is changed and only
+    /// the change set is returned. Returns None for unknown users.
+    pub async fn forget(
+        &self,
+        username: &str,
+        remove_messages: bool,
+        dry_run: bool,
+    ) -> Result<Option<ChangeSet>> {
+        // Held throughout, so no lines get appended to the logs while they are rewritten.
+        let _audit_save = self.audit_save.lock().await;
+        let _connection_log_save = self.connection_log_save.lock().await;
+
+        if !self
+            .users
+            .read()
+            .await
+            .iter()
+            .any(|u| u.username == username)
+        {
+            return Ok(None);
+        }
+
+        let mut transcripts = Vec::new();
+
+        if let Ok(mut entries) = read_dir(self.data_dir.join(TRANSCRIPTS_DIR)).await {
+            while let Some(entry) = entries.next_entry().await? {
+                if is_transcript_of(&entry.file_name().to_string_lossy(), username) {
+                    transcripts.push(entry.path());
+                }
+            }
+        }
+
+        let mut logs = vec![("audit", self.data_dir.join(AUDIT_FILE))];
+
+        if let Some(path) = &self.config().await.connection_log_file {
+            logs.push(("connections", path.clone()));
+            logs.push(("connections", with_suffix(path, ".1")));
+        }
+
+        // Each log mentioning the user, with its scrubbed contents.
+        let mut scrubbed = Vec::new();
+
+        for (name, path) in logs {
+            let contents = match read_to_string(&path).await {
+                Ok(contents) => contents,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e).context(format!("Could not read {}", path.display())),
+            };
+            let (contents, lines) = scrub_username(&contents, username);
+
+            if lines > 0 {
+                scrubbed.push((name, path, contents, lines));
+            }
+        }
+
+        let add_file_changes = |changes: &mut ChangeSet| {
+            changes.add("transcripts", transcripts.len(), "transcript(s) deleted");
+
+            for (name, _, _, lines) in &scrubbed {
+                changes.add(name, *lines, "log line(s) scrubbed");
+            }
+        };
+
+        if dry_run {
+            let forgotten = forget_user(&mut self.stores().await, username, remove_messages);
+
+            return Ok(forgotten.map(|(mut changes, _)| {
+                add_file_changes(&mut changes);
+                changes
+            }));
+        }
+
+        let mut set_aside = Vec::new();
+        let staged: Result<()> = async {
+            for transcript in &transcripts {
+                let aside = with_suffix(transcript, FORGET_SUFFIX);
+
+                rename(transcript, &aside).await?;
+                set_aside.push((transcript.clone(), aside));
+            }
+
+            for (_, path, contents, _) in &scrubbed {
+                write(with_suffix(path, FORGET_SUFFIX), contents).await?;
+            }
+
+            Ok(())
+        }
+        .await;
+        let forgotten = match staged {
+            Ok(()) => {
+                self.update_stores(|stores| forget_user(stores, username, remove_messages))
+                    .await
+            }
+            Err(e) => Err(e.context(UserError::new(NOT_PERSISTED))),
+        };
+        let (mut changes, orphans) = match forgotten {
+            Ok(Some(forgotten)) => forgotten,
+            not_forgotten => {
+                for (transcript, aside) in &set_aside {
+                    if let Err(e) = rename(aside, transcript).await {
+                        error!("Could not restore {}: {e}", transcript.display());
+                    }
+                }
+
+                for (_, path, _, _) in &scrubbed {
+                    let _ = remove_file(with_suffix(path, FORGET_SUFFIX)).await;
+                }
+
+                return not_forgotten.map(|_| None);
+            }
+        };
+
+        for (_, path, _, _) in &scrubbed {
+            if let Err(e) = rename(with_suffix(path, FORGET_SUFFIX), path).await {
+                warn!("Could not replace {}: {e}", path.display());
+            }
+        }
+
+        for (_, aside) in &set_aside {
+            if let Err(e) = remove_file(aside).await {
+                warn!("Could not remove {}: {e}", aside.display());
+            }
+        }
+
+        for id in orphans {
+            if let Err(e) = remove_file(self.attachment_path(id)).await {
+                warn!("Could not remove attachment {id}: {e}");
+            }
+        }
+
+        self.dm_times.lock().await.remove(username);
+        self.failed_logins.lock().await.remove(username);
+        self.recorded.write().await.remove(username);
+        self.invalidate_login(username).await;
+        add_file_changes(&mut changes);
+
+        Ok(Some(changes))
+    }
+
+    /// Describes the runtime state for operators investigating a stuck server: session
+    /// counts, how long each store's lock takes to acquire right now, saves in progress
+    /// and supervised tasks. Locks held longer than `DIAG_LOCK_TIMEOUT` are reported as
+    /// blocked rather than waited for, since a stuck lock is what this is for.
+    pub async fn diagnostics(&self) -> Vec<String> {
+        let blocked = format!("blocked >{}s", DIAG_LOCK_TIMEOUT.as_secs());
+        let logged_on = match timeout(DIAG_LOCK_TIMEOUT, self.online.read()).await {
+            Ok(online) => online.len().to_string(),
+            Err(_) => blocked.clone(),
+        };
+        let mut lines = vec![format!(
+            "sessions: {} connected, {logged_on} logged on",
+            self.connected(),
+        )];
+        let stores = [
+            ("users", lock_wait(&self.users).await, &self.users_save),
+            (
+                "messages",
+                lock_wait(&self.messages).await,
+                &self.messages_save,
+            ),
+            (
+                "last_read",
+                lock_wait(&self.last_read).await,
+                &self.last_read_save,
+            ),
+            ("boards", lock_wait(&self.boards).await, &self.boards_save),
+            (
+                "private_messages",
+                lock_wait(&self.private_messages).await,
+                &self.private_messages_save,
+            ),
+        ];
+
+        for (name, wait, save) in stores {
+            let wait = match wait {
+                Some(wait) => format!("{:.3}ms", wait.as_secs_f64() * 1000.0),
+                None => blocked.clone(),
+            };
+
+            lines.push(format!(
+                "{name}: lock wait {wait}{}",
+                if save.try_lock().is_err() {
+                    ", saving"
+                } else {
+                    ""
+                }
+            ));
+        }
+
+        for (name, stats) in supervisor::task_stats() {
+            lines.push(format!(
+                "task {name}: {}, {} restart(s)",
+                if stats.running { "running" } else { "stopped" },
+                stats.restarts
+            ));
+        }
+
+        lines
+    }
+
+    /// Appends a line describing a privileged action to the audit log.
+    pub async fn audit(&self, username: &str, action: &str) -> Result<()> {
+        let _guard = self.audit_save.lock().await;
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.data_dir.join(AUDIT_FILE))
+            .await
+            .context("Could not open audit log")?;
+
+        file.write_all(format!("{} {username} {action}\n", time::now()).as_bytes())
+            .await
+            .context("Could not write audit log")?;
+        // Written before the lock is released, so `forget` never misses a line.
+        file.flush().await.context("Could not write audit log")
+    }
+
+    /// Merges the account of `from` into `into`: their messages, read pointers and read
+    /// messages, private mail, block lists, boards and macros all move over and `from` is
+    /// removed. Everything is saved at once or not at all. With `dry_run` nothing is
+    /// changed and only the change set is returned. Returns None if either user is
+    /// unknown.
+    pub async fn merge_user(
+        &self,
+        from: &str,
+        into: &str,
+        dry_run: bool,
+    ) -> Result<Option<ChangeSet>> {
+        if dry_run {
+            return Ok(merge_user(&mut self.stores().await, from, into));
+        }
+
+        let changes = self
+            .update_stores(|stores| merge_user(stores, from, into))
+            .await?;
+
+        if changes.is_some() {
+            self.invalidate_login(from).await;
+        }
+
+        Ok(changes)
+    }
+
+    /// A copy of every store, taken under all their locks so it is consistent.
+    pub async fn stores(&self) -> Stores {
+        let users = self.users.read().await;
+        let messages = self.messages.read().await;
+        let last_read = self.last_read.read().await;
+        let boards = self.boards.read().await;
+        let private_messages = self.private_messages.read().await;
+
+        Stores {
+            users: users.clone(),
+            messages: messages.clone(),
+            last_read: last_read.clone(),
+            boards: boards.clone(),
+            private_messages: private_messages.clone(),
+        }
+    }
+
+    /// Applies a change spanning every store. Other writers are locked out, `change`
+    /// gets copies to work on and nothing is saved if it returns None. The copies are
+    /// saved together through `Storage::save_stores`, so a failed save leaves memory and
+    /// storage untouched, and readers only wait for the final swap, never for the disk.
+    pub async fn update_stores<R>(
+        &self,
+        change: impl FnOnce(&mut Stores) -> Option<R>,
+    ) -> Result<Option<R>> {
+        let _users_save = self.users_save.lock().await;
+        let _messages_save = self.messages_save.lock().await;
+        let _last_read_save = self.last_read_save.lock().await;
+        let _boards_save = self.boards_save.lock().await;
+        let _private_messages_save = self.private_messages_save.lock().await;
+        let mut updated = self.stores().await;
+        let Some(result) = change(&mut updated) else {
+            return Ok(None);
+        };
+
+        self.storage
+            .save_stores(&updated)
+            .await
+            .context(UserError::new(NOT_PERSISTED))?;
+
+        let mut users = self.users.write().await;
+        let mut messages = self.messages.write().await;
+        let mut last_read = self.last_read.write().await;
+        let mut boards = self.boards.write().await;
+        let mut private_messages = self.private_messages.write().await;
+
+        *users = updated.users;
+        *messages = updated.messages;
+        *last_read = updated.last_read;
+        *boards = updated.boards;
+        *private_messages = updated.private_messages;
+
+        Ok(Some(result))
+    }
+
+    /// Appends a timestamped connection event to the connection log, rotating the file
+    /// to `<path>.1` once it grows past `max_bytes`.
+    pub async fn log_connection(&self, path: &Path, event: &str, max_bytes: u64) -> Result<()> {
+        let _guard = self.connection_log_save.lock().await;
+
+        if metadata(path).await.is_ok_and(|m| m.len() >= max_bytes) {
+            rename(path, with_suffix(path, ".1"))
+                .await
+                .context("Could not rotate connection log")?;
+        }
+
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .await
+            .context("Could not open connection log")?;
+
+        file.write_all(format!("{} {event}\n", time::now()).as_bytes())
+            .await
+            .context("Could not write connection log")?;
+        file.flush().await.context("Could not write connection log")
+    }
+
+    /// Applies `change` to a copy of the users and only swaps it in once it has been
+    /// saved, so a failed save leaves memory matching what is stored. The save lock
+    /// keeps writers in line, so readers only wait for the swap and never for the disk.
+    pub async fn update_users<R>(&self, change: impl FnOnce(&mut Vec<User>) -> R) -> Result<R> {
+        let _guard = self.users_save.lock().await;
+        let mut updated = self.users.read().await.clone();
+        let result = change(&mut updated);
+
+        self.storage
+            .save_users(&updated)
+            .await
+            .context(UserError::new(NOT_PERSISTED))?;
+        *self.users.write().await = updated;
+
+        Ok(result)
+    }
+
+    /// Like `update_users`, for a change to the single user `username`, which is the
+    /// only record saved. Returns `None` when there is no such user.
+    pub async fn update_user<R>(
+        &self,
+        username: &str,
+        change: impl FnOnce(&mut User) -> R,
+    ) -> Result<Option<R>> {
+        let _guard = self.users_save.lock().await;
+        let Some(mut updated) = self
+            .users
+            .read()
+            .await
+            .iter()
+            .find(|u| u.username == username)
+            .cloned()
+        else {
+            return Ok(None);
+        };
+        let result = change(&mut updated);
+
+        self.storage
+            .save_user(&updated)
+            .await
+            .context(UserError::new(NOT_PERSISTED))?;
+
+        if let Some(user) = self
+            .users
+            .write()
+            .await
+            .iter_mut()
+            .find(|u| u.id == updated.id)
+        {
+            *user = updated;
+        }
+
+        Ok(Some(result))
+    }
+
+    /// Records that `username` read message `id` on `board`. Read state is a mark per
+    /// board: everything up to the highest id read there counts as read. Only saves
+    /// when the mark moves forward.
+    pub async fn mark_read(&self, username: &str, board: &str, id: i64) -> Result<()> {
+        let key = (username.to_owned(), board.to_owned());
+
+        if self
+            .last_read
+            .read()
+            .await
+            .get(&key)
+            .is_some_and(|m| *m >= id)
+        {
+            return Ok(());
+        }
+
+        self.update_last_read(|last_read| {
+            let mark = last_read.entry(key).or_insert(id);
+
+            *mark = (*mark).max(id);
+        })
+        .await
+    }
+
+    /// The highest message id `username` has read, by board.
+    pub async fn read_marks(&self, username: &str) -> HashMap<String, i64> {
+        self.last_read
+            .read()
+            .await
+            .iter()
+            .filter(|((user, _), _)| user == username)
+            .map(|((_, board), id)| (board.clone(), *id))
+            .collect()
+    }
+
+    /// Same as `update_users`, for messages.
+    pub async fn update_messages<R>(
+        &self,
+        change: impl FnOnce(&mut Vec<Message>) -> R,
+    ) -> Result<R> {
+        let _guard = self.messages_save.lock().await;
+        let mut updated = self.messages.read().await.clone();
+        let result = change(&mut updated);
+
+        self.storage
+            .save_messages(&updated)
+            .await
+            .context(UserError::new(NOT_PERSISTED))?;
+        *self.messages.write().await = updated;
+
+        Ok(result)
+    }
+
+    /// Like `update_user`, for the single message `id`.
+    pub async fn update_message<R>(
+        &self,
+        id: i64,
+        change: impl FnOnce(&mut Message) -> R,
+    ) -> Result<Option<R>> {
+        let _guard = self.messages_save.lock().await;
+        let Some(mut updated) = self
+            .messages
+            .read()
+            .await
+            .iter()
+            .find(|m| m.id == id)
+            .cloned()
+        else {
+            return Ok(None);
+        };
+        let result = change(&mut updated);
+
+        self.storage
+            .save_message(&updated)
+            .await
+            .context(UserError::new(NOT_PERSISTED))?;
+
+        if let Some(message) = self.messages.write().await.iter_mut().find(|m| m.id == id) {
+            *message = updated;
+        }
+
+        Ok(Some(result))
+    }
+
+    /// Same as `update_users`, for read pointers.
+    pub async fn update_last_read<R>(
+        &self,
+        change: impl FnOnce(&mut HashMap<(String, String), i64>) -> R,
+    ) -> Result<R> {
+        let _guard = self.last_read_save.lock().await;
+        let mut updated = self.last_read.read().await.clone();
+        let result = change(&mut updated);
+
+        self.storage
+            .save_last_read(&updated)
+            .await
+            .context(UserError::new(NOT_PERSISTED))?;
+        *self.last_read.write().await = updated;
+
+        Ok(result)
+    }
+
+    /// Same as `update_users`, for private messages.
+    pub async fn update_private_messages<R>(
+        &self,
+        change: impl FnOnce(&mut Vec<PrivateMessage>) -> R,
+    ) -> Result<R> {
+        let _guard = self.private_messages_save.lock().await;
+        let mut updated = self.private_messages.read().await.clone();
+        let result = change(&mut updated);
+
+        self.storage
+            .save_private_messages(&updated)
+            .await
+            .context(UserError::new(NOT_PERSISTED))?;
+        *self.private_messages.write().await = updated;
+
+        Ok(result)
+    }
+
+    /// Records a private message sent by `sender` unless they already sent
+    /// `max_per_min` in the last minute, in which case it returns false.
+    pub async fn allow_dm(&self, sender: &str, max_per_min: usize) -> bool {
+        let mut dm_times = self.dm_times.lock().await;
+
+        // Senders quiet for a minute have nothing left to count.
+        dm_times.retain(|_, times| {
+            times.retain(|t| t.elapsed() < Duration::from_secs(60));
+            !times.is_empty()
+        });
+
+        let times = dm_times.entry(sender.to_owned()).or_default();
+
+        if times.len() >= max_per_min {
+            return false;
+        }
+
+        times.push(Instant::now());
+
+        true
+    }
+
+    /// Records a connection from `ip` unless it already connected `max_per_min` times
+    /// in the last minute, in which case it returns false.
+    pub async fn allow_connection(&self, ip: IpAddr, max_per_min: usize) -> bool {
+        let mut connection_times = self.connection_times.lock().await;
+
+        // Forget IPs that went quiet, so the map doesn't grow with every host ever seen.
+        connection_times.retain(|_, times| {
+            times.retain(|t| t.elapsed() < Duration::from_secs(60));
+            !times.is_empty()
+        });
+
+        let times = connection_times.entry(ip).or_default();
+
+        if times.len() >= max_per_min {
+            return false;
+        }
+
+        times.push(Instant::now());
+
+        true
+    }
+
+    /// How much longer `username` stays locked out after too many failed logins.
+    pub async fn login_lockout(&self, username: &str) -> Option<Duration> {
+        let failed_logins = self.failed_logins.lock().await;
+        let (_, locked_until) = failed_logins.get(username)?;
+
+        (*locked_until)?.checked_duration_since(Instant::now())
+    }
+
+    /// Counts a failed login for `username`, locking the account for `lockout` once
+    /// `max_attempts` failures in a row were recorded.
+    pub async fn record_failed_login(
+        &self,
+        username: &str,
+        max_attempts: usize,
+        lockout: Duration,
+    ) {
+        let mut failed_logins = self.failed_logins.lock().await;
+        let (count, locked_until) = failed_logins.entry(username.to_owned()).or_default();
+
+        *count += 1;
+
+        if *count >= max_attempts {
+            *count = 0;
+            *locked_until = Some(Instant::now() + lockout);
+        }
+    }
+
+    pub async fn clear_failed_logins(&self, username: &str) {
+        self.failed_logins.lock().await.remove(username);
+    }
+
+    /// Same as `update_users`, for created boards.
+    pub async fn update_boards<R>(&self, change: impl FnOnce(&mut Vec<Board>) -> R) -> Result<R> {
+        let _guard = self.boards_save.lock().await;
+        let mut updated = self.boards.read().await.clone();
+        let result = change(&mut updated);
+
+        self.storage
+            .save_boards(&updated)
+            .await
+            .context(UserError::new(NOT_PERSISTED))?;
+        *self.boards.write().await = updated;
+
+        Ok(result)
+    }
+
+    /// Disconnects every session `username` is logged on with. Returns how many.
+    pub async fn kick(&self, username: &str) -> usize {
+        let online = self.online.read().await;
+        let connections = self.connections.read().await;
+        let mut kicked = 0;
+
+        for (address, _) in online.iter().filter(|(_, u)| u.username == username) {
+            if let Some(connection) = connections.get(address) {
+                connection.kick.send(DisconnectReason::Kicked);
+                kicked += 1;
+            }
+        }
+
+        kicked
+    }
+
+    /// Disconnects every session, telling them the server is going down. Returns how
+    /// many there were.
+    pub async fn shutdown(&self) -> usize {
+        let connections = self.connections.read().await;
+
+        for connection in connections.values() {
+            connection.kick.send(DisconnectReason::Shutdown);
+        }
+
+        connections.len()
+    }
+
+    /// Sends `text` to every connected session, logged on or not. Returns how many got
+    /// it, sessions whose queue is full miss out. JSON clients are left out, as anything
+    /// but a response would break their protocol.
+    pub async fn broadcast(&self, text: &str) -> usize {
+        let data = format!("\r\n{text}\r\n").into_bytes();
+
+        self.connections
+            .read()
+            .await
+            .values()
+            .filter(|connection| !connection.json)
+            .filter(|connection| connection.outbound.try_send(data.clone()).is_ok())
+            .count()
+    }
+
+    pub async fn config(&self) -> Arc<Config> {
+        Arc::clone(&*self.config.read().await)
+    }
+
+    /// Swaps in a new configuration, picked up by each session before its next prompt.
+    pub async fn set_config(&self, config: Config) {
+        *self.config.write().await = Arc::new(config);
+    }
+
+    /// Number of sessions currently connected, logged on or not.
+    pub fn connected(&self) -> usize {
+        self.sessions.load(Ordering::Relaxed)
+    }
+}
+
+/// Removes everything of `username` from `stores`, see `AppState::forget`. Also returns
+/// the attachments no message points to anymore.
+fn forget_user(stores: &mut Stores, username: &str, remove: bool) -> Option<(ChangeSet, Vec<i64>)> {
+    let index = stores.users.iter().position(|u| u.username == username)?;
+    let mut changes = ChangeSet::default();
+
+    stores.users.remove(index);
+    changes.add("users", 1, "account removed");
+
+    let attachments: HashSet<i64> = stores
+        .messages
+        .iter()
+        .filter_map(|m| m.attachment.as_ref())
+        .map(|a| a.id)
+        .collect();
+
+    if remove {
+        let removed = remove_messages(&mut stores.messages, |m| m.author() == username);
+
+        changes.add("messages", removed.len(), "removed");
+    } else {
+        let mut anonymized = 0;
+
+        for message in stores
+            .messages
+            .iter_mut()
+            .filter(|m| m.author() == username)
+        {
+            if message.real_author.take().is_none() {
+                message.username = FORGOTTEN_AUTHOR.to_owned();
+            }
+
+            anonymized += 1;
+        }
+
+        changes.add("messages", anonymized, "anonymized");
+    }
+
+    let mut digest_lines = 0;
+
+    for digest in stores
+        .messages
+        .iter_mut()
+        .filter(|m| m.username == DIGEST_AUTHOR)
+    {
+        // Digest lines are "<id> <author> <subject>".
+        let mut scrubbed = 0;
+        let lines: Vec<String> = digest
+            .body
+            .lines()
+            .filter_map(|line| {
+                if line.split(' ').nth(1) != Some(username) {
+                    return Some(line.to_owned());
+                }
+
+                scrubbed += 1;
+                (!remove).then(|| {
+                    line.replacen(&format!(" {username}"), &format!(" {FORGOTTEN_AUTHOR}"), 1)
+                })
+            })
+            .collect();
+
+        if scrubbed > 0 {
+            digest.body = lines.iter().map(|line| format!("{line}\r\n")).collect();
+            digest_lines += scrubbed;
+        }
+    }
+
+    changes.add("messages", digest_lines, "digest line(s) scrubbed");
+
+    let pointers = stores.last_read.len();
+
+    stores.last_read.retain(|(user, _), _| user != username);
+    changes.add(
+        "last_read",
+        pointers - stores.last_read.len(),
+        "read pointer(s) removed",
+    );
+
+    let mut boards = 0;
+
+    for board in stores
+        .boards
+        .iter_mut()
+        .filter(|b| b.creator.as_deref() == Some(username))
+    {
+        board.creator = None;
+        boards += 1;
+    }
+
+    changes.add("boards", boards, "board(s) disowned");
+
+    let mail = stores.private_messages.len();
+
+    stores
+        .private_messages
+        .retain(|m| m.from != username && m.to != username);
+    changes.add(
+        "private_messages",
+        mail - stores.private_messages.len(),
+        "removed",
+    );
+
+    let mut block_lists = 0;
+
+    for user in stores.users.iter_mut() {
+        if user.blocked.iter().any(|b| b == username) {
+            user.blocked.retain(|b| b != username);
+            block_lists += 1;
+        }
+    }
+
+    changes.add("users", block_lists, "block list(s) cleaned");
+
+    let orphans = attachments
+        .into_iter()
+        .filter(|id| {
+            !stores
+                .messages
+                .iter()
+                .any(|m| m.attachment.as_ref().is_some_and(|a| a.id == *id))
+        })
+        .collect();
+
+    Some((changes, orphans))
+}
+
+/// Moves everything of `from` over to `into` within `stores`, see `AppState::merge_user`.
+fn merge_user(stores: &mut Stores, from: &str, into: &str) -> Option<ChangeSet> {
+    let index = stores.users.iter().position(|u| u.username == from)?;
+
+    if !stores.users.iter().any(|u| u.username == into) {
+        return None;
+    }
+
+    let merged = stores.users.remove(index);
+    let mut changes = ChangeSet::default();
+
+    changes.add("users", 1, "account removed");
+
+    let mut block_lists = 0;
+
+    for user in stores.users.iter_mut() {
+        if user.blocked.iter().any(|b| b == from) {
+            user.blocked.retain(|b| b != from);
+
+            if user.username != into && !user.blocked.iter().any(|b| b == into) {
+                user.blocked.push(into.to_owned());
+            }
+
+            block_lists += 1;
+        }
+
+        if user.username == into {
+            let macros: Vec<(String, String)> = merged
+                .macros
+                .iter()
+                .filter(|(name, _)| !user.macros.contains_key(*name))
+                .map(|(name, text)| (name.clone(), text.clone()))
+                .collect();
+
+            changes.add("users", macros.len(), "macro(s) merged");
+            user.macros.extend(macros);
+
+            for blocked in &merged.blocked {
+                if blocked != into && !user.blocked.contains(blocked) {
+                    user.blocked.push(blocked.clone());
+                }
+            }
+        }
+    }
+
+    changes.add("users", block_lists, "block list(s) updated");
+
+    let mut reassigned = 0;
+
+    for message in stores.messages.iter_mut().filter(|m| m.author() == from) {
+        match &mut message.real_author {
+            Some(real_author) => *real_author = into.to_owned(),
+            None => message.username = into.to_owned(),
+        }
+
+        reassigned += 1;
+    }
+
+    changes.add("messages", reassigned, "reassigned");
+
+    let pointers: Vec<(String, String)> = stores
+        .last_read
+        .keys()
+        .filter(|(username, _)| username == from)
+        .cloned()
+        .collect();
+
+    changes.add("last_read", pointers.len(), "read pointer(s) merged");
+
+    for key in pointers {
+        if let Some(id) = stores.last_read.remove(&key) {
+            let pointer = stores
+                .last_read
+                .entry((into.to_owned(), key.1))
+                .or_insert(id);
+
+            *pointer = (*pointer).max(id);
+        }
+    }
+
+    let mut mail = 0;
+
+    for message in stores.private_messages.iter_mut() {
+        if message.from == from || message.to == from {
+            mail += 1;
+        }
+
+        if message.from == from {
+            message.from = into.to_owned();
+        }
+
+        if message.to == from {
+            message.to = into.to_owned();
+        }
+    }
+
+    changes.add("private_messages", mail, "reassigned");
+
+    let mut boards = 0;
+
+    for board in stores
+        .boards
+        .iter_mut()
+        .filter(|b| b.creator.as_deref() == Some(from))
+    {
+        board.creator = Some(into.to_owned());
+        boards += 1;
+    }
+
+    changes.add("boards", boards, "creator(s) changed");
+
+    Some(changes)
+}
+
+/// The position of the message `id` if a file can be attached to it.
+fn attachable(messages: &[Message], id: i64) -> Result<usize> {
+    let index = messages
+        .iter()
+        .position(|m| m.id == id && !m.deleted)
+        .context(UserError::new("Invalid message id"))?;
+
+    if messages[index].attachment.is_some() {
+        anyhow::bail!(UserError::new("Message already has an attachment"));
+    }
+
+    Ok(index)
+}
+
+/// `username` as it appears in the file names of transcripts.
+fn transcript_name(username: &str) -> String {
+    username
+        .chars()
+        .map(|c| {
+            if c.is_alphanumeric() || c == '-' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+/// Whether `file_name` is a transcript of `username`, named `<timestamp>-<name>.log`.
+fn is_transcript_of(file_name: &str, username: &str) -> bool {
+    file_name
+        .strip_suffix(".log")
+        .and_then(|stem| stem.split_once('-'))
+        .is_some_and(|(timestamp, name)| {
+            !timestamp.is_empty()
+                && timestamp.chars().all(|c| c.is_ascii_digit())
+                && name == transcript_name(username)
+        })
+}
+
+/// `path` with `suffix` added to its file name.
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+
+    path.push(suffix);
+    path.into()
+}
+
+/// `text` with every word that is exactly `username` replaced by `FORGOTTEN_AUTHOR`,
+/// and how many lines that changed.
+fn scrub_username(text: &str, username: &str) -> (String, usize) {
+    let mut scrubbed = 0;
+    let text = text
+        .split_inclusive('\n')
+        .map(|line| {
+            if !line.split_whitespace().any(|word| word == username) {
+                return line.to_owned();
+            }
+
+            scrubbed += 1;
+            line.split(' ')
+                .map(|word| {
+                    if word.trim_end() == username {
+                        word.replacen(username, FORGOTTEN_AUTHOR, 1)
+                    } else {
+                        word.to_owned()
+                    }
+                })
+                .collect::<Vec<_>>()
+                .join(" ")
+        })
+        .collect();
+
+    (text, scrubbed)
+}
+
+/// Removes the messages matching `remove` and returns them. Replies to a removed
+/// message are handed to its closest remaining ancestor, so no `reply_to` is left
+/// pointing at a message that no longer exists.
+fn remove_messages(messages: &mut Vec<Message>, remove: impl Fn(&Message) -> bool) -> Vec<Message> {
+    let (removed, mut kept): (Vec<Message>, Vec<Message>) =
+        messages.drain(..).partition(|m| remove(m));
+    let parents: HashMap<i64, Option<i64>> = removed.iter().map(|m| (m.id, m.reply_to)).collect();
+
+    for message in kept.iter_mut() {
+        let mut steps = 0;
+
+        while let Some(parent) = message.reply_to.and_then(|id| parents.get(&id)) {
+            // Hand edited files could contain reply cycles among the removed messages.
+            if steps == parents.len() {
+                message.reply_to = None;
+                break;
+            }
+
+            message.reply_to = *parent;
+            steps += 1;
+        }
+    }
+
+    *messages = kept;
+
+    removed
+}
+
+/// Resolves at the next tick of `interval`, or never without one.
+async fn next_tick(interval: &mut Option<Interval>) {
+    match interval {
+        Some(interval) => {
+            interval.tick().await;
+        }
+        None => std::future::pending().await,
+    }
+}
+
+/// Reads a line of at most `max` bytes into `line`, discarding the rest of a longer
+/// one so a client can't make us buffer without bound. Returns the bytes kept and
+/// whether the line was cut.
+async fn read_line_capped<R: AsyncBufRead + Unpin>(
+    reader: &mut R,
+    line: &mut String,
+    max: usize,
+) -> io::Result<(usize, bool)> {
+    let mut bytes = Vec::new();
+    let read = (&mut *reader)
+        .take(max as u64)
+        .read_until(b'\n', &mut bytes)
+        .await?;
+    let truncated = read == max && !bytes.ends_with(b"\n");
+
+    if truncated {
+        let mut rest = Vec::new();
+
+        loop {
+            rest.clear();
+
+            let skipped = (&mut *reader)
+                .take(max as u64)
+                .read_until(b'\n', &mut rest)
+                .await?;
+
+            if skipped == 0 || rest.ends_with(b"\n") {
+                break;
+            }
+        }
+    }
+
+    // A cut can split a character, which decoding lossily tolerates.
+    line.push_str(&String::from_utf8_lossy(&bytes));
+
+    Ok((read, truncated))
+}
+
+/// How long taking `lock` for reading takes, or None if it is still held after
+/// `DIAG_LOCK_TIMEOUT`.
+async fn lock_wait<T>(lock: &RwLock<T>) -> Option<Duration> {
+    let start = Instant::now();
+
+    drop(timeout(DIAG_LOCK_TIMEOUT, lock.read()).await.ok()?);
+
+    Some(start.elapsed())
+}
+
+async fn load_stores(storage: &dyn Storage) -> Result<Stores> {
+    Ok(Stores {
+        users: storage.load_users().await.context("Could not read users")?,
+        messages: storage
+            .load_messages()
+            .await
+            .context("Could not read messages")?,
+        last_read: storage
+            .load_last_read()
+            .await
+            .context("Could not read last read pointers")?,
+        boards: storage
+            .load_boards()
+            .await
+            .context("Could not read boards")?,
+        private_messages: storage
+            .load_private_messages()
+            .await
+            .context("Could not read private messages")?,
+    })
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct User {
+    pub id: i64,
+    pub username: String,
+    pub password: String,
+    #[serde(default)]
+    pub onboarded: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub default_board: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_login: Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_seen: Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub scheme: Option<Scheme>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub page_size: Option<usize>,
+    /// Users whose private messages are dropped.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub blocked: Vec<String>,
+    /// Semicolon separated command sequences, by macro name.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub macros: BTreeMap<String, String>,
+    #[serde(default, skip_serializing_if = "Role::is_user")]
+    pub role: Role,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signature: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub about: Option<String>,
+}
+
+/// Admins have the same privileges as the sysops named in the configuration.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    #[default]
+    User,
+    Admin,
+}
+
+impl Role {
+    fn is_user(&self) -> bool {
+        *self == Role::User
+    }
+}
+
+#[derive(Clone, Default, Deserialize, Serialize)]
+pub struct Message {
+    pub id: i64,
+    pub username: String,
+    #[serde(default = "default_board")]
+    pub board: String,
+    pub subject: String,
+    pub body: String,
+    #[serde(default)]
+    pub created_at: i64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub edited_at: Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub crosspost_group: Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reply_to: Option<i64>,
+    #[serde(default)]
+    pub deleted: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub deleted_at: Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub attachment: Option<Attachment>,
+    /// Who actually posted an anonymous message, only ever shown to sysops.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub real_author: Option<String>,
+}
+
+impl Message {
+    /// The account that wrote the message, even when it was posted anonymously.
+    pub fn author(&self) -> &str {
+        self.real_author.as_deref().unwrap_or(&self.username)
+    }
+
+    /// Whether `username` has yet to read the message, given their read marks by board.
+    /// Their own posts never count as unread.
+    pub fn unread_by(&self, username: &str, marks: &HashMap<String, i64>) -> bool {
+        self.author() != username && marks.get(&self.board).is_none_or(|m| self.id > *m)
+    }
+}
+
+/// What other sessions need to reach a connected session.
+struct Connection {
+    outbound: Sender<Vec<u8>>,
+    kick: Arc<Kick>,
+    /// Speaks the JSON protocol, so it can't take unsolicited text.
+    json: bool,
+}
+
+/// Wakes a session up to disconnect it, along with the reason why.
+#[derive(Default)]
+struct Kick {
+    notify: Notify,
+    reason: std::sync::Mutex<Option<DisconnectReason>>,
+}
+
+impl Kick {
+    fn send(&self, reason: DisconnectReason) {
+        *self.reason.lock().unwrap() = Some(reason);
+        self.notify.notify_one();
+    }
+
+    /// Resolves once the session was kicked. Like `Notify`, a kick sent while nobody
+    /// waited is kept for the next call.
+    async fn received(&self) -> DisconnectReason {
+        self.notify.notified().await;
+        self.reason
+            .lock()
+            .unwrap()
+            .take()
+            .unwrap_or(DisconnectReason::Kicked)
+    }
+}
+
+/// The session's end of a transcript writer task.
+struct Transcript {
+    sender: Sender<String>,
+    /// Lines dropped since the writer last had room, see `record`.
+    dropped: AtomicUsize,
+}
+
+impl Transcript {
+    /// Queues `data` for the file. Like `Session::send` the queue is bounded, but a
+    /// slow disk is no reason to drop the session: lines that don't fit are counted
+    /// instead, and the file notes the gap once the writer catches up.
+    fn record(&self, data: &str) {
+        let dropped = self.dropped.load(Ordering::Relaxed);
+
+        if dropped > 0 {
+            let note = format!("[{dropped} line(s) missing, transcript fell behind]\r\n");
+
+            if self.sender.try_send(note).is_err() {
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+                return;
+            }
+
+            self.dropped.store(0, Ordering::Relaxed);
+        }
+
+        // A closed queue means the file failed, which the writer task reports.
+        if let Err(TrySendError::Full(_)) = self.sender.try_send(data.to_owned()) {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// A line said in chat and the session that said it, which doesn't hear it back.
+#[derive(Clone)]
+pub struct ChatLine {
+    pub from: SocketAddr,
+    pub text: String,
+}
+
+enum ChatEvent {
+    Typed,
+    Heard(Result<ChatLine, RecvError>),
+    Keepalive,
+    Disconnect(DisconnectReason),
+}
+
+/// A logged on user and when they logged on.
+#[derive(Clone)]
+pub struct OnlineUser {
+    pub username: String,
+    pub since: i64,
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct PrivateMessage {
+    pub from: String,
+    pub to: String,
+    pub body: String,
+    pub timestamp: i64,
+    #[serde(default)]
+    pub read: bool,
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct Attachment {
+    pub id: i64,
+    pub filename: String,
+    pub size: usize,
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct Board {
+    pub name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub creator: Option<String>,
+    pub created_at: i64,
+    #[serde(default)]
+    pub archived: bool,
+    /// Posts hide their author behind `ANONYMOUS_AUTHOR`.
+    #[serde(default)]
+    pub anonymous: bool,
+}
+
+#[derive(Default)]
+pub struct MessageFilter {
+    pub board: Option<String>,
+    pub author: Option<String>,
+    pub keyword: Option<String>,
+    /// Only messages past the user's last read pointer in their board.
+    pub unread_for: Option<String>,
+    pub limit: Option<usize>,
+}
+
+pub struct BoardStats {
+    pub name: String,
+    pub messages: usize,
+    pub last_post: Option<i64>,
+    pub unread: bool,
+    pub archived: bool,
+}
+
+impl BoardStats {
+    fn new(name: &str) -> Self {
+        Self {
+            name: name.to_owned(),
+            messages: 0,
+            last_post: None,
+            unread: false,
+            archived: false,
+        }
+    }
+}
+
+/// What a destructive operation changes, one line per store touched. Used to show
+/// sysops the effect of a command before (or after) applying it.
+#[derive(Default)]
+pub struct ChangeSet {
+    pub changes: Vec<String>,
+}
+
+impl ChangeSet {
+    pub fn add(&mut self, store: &str, count: usize, what: &str) {
+        if count > 0 {
+            self.changes.push(format!("{store}: {count} {what}"));
+        }
+    }
+}
+
+pub struct Page {
+    pub messages: Vec<Message>,
+    pub next: Option<i64>,
+}
+
+/// A copy of every store, for changes that have to land in several of them at once.
+#[derive(Clone, Default)]
+pub struct Stores {
+    pub users: Vec<User>,
+    pub messages: Vec<Message>,
+    pub last_read: HashMap<(String, String), i64>,
+    pub boards: Vec<Board>,
+    pub private_messages: Vec<PrivateMessage>,
+}
+
+#[derive(Debug)]
+pub enum SessionStatus {
+    LoggedOn(String),
+    LoggedOff,
+    Disconnected(DisconnectReason),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DisconnectReason {
+    Quit,
+    Error,
+    TooSlow,
+    Idle,
+    Kicked,
+    Shutdown,
+    Closed,
+}
+
+/// What a session is busy with, for the parts of the server that treat some
+/// activities differently, like idle kicks.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SessionMode {
+    Normal,
+    Transfer,
+    Chat,
+}
+
+impl Display for DisconnectReason {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            DisconnectReason::Quit => write!(f, "quit"),
+            DisconnectReason::Error => write!(f, "connection error"),
+            DisconnectReason::TooSlow => write!(f, "client too slow"),
+            DisconnectReason::Idle => write!(f, "idle timeout"),
+            DisconnectReason::Kicked => write!(f, "kicked by a sysop"),
+            DisconnectReason::Shutdown => write!(f, "server shutdown"),
+            DisconnectReason::Closed => write!(f, "closed by client"),
+        }
+    }
+}
+
+fn default_board() -> String {
+    DEFAULT_BOARD.to_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use tracing::{Instrument, info_span};
+
+    use super::*;
+    use crate::testing::{Client, Fixture, FlakyStorage, Logs, PASSWORD, SYSOP, test_dir};
+
+    #[tokio::test]
+    async fn concurrent_posts_all_reach_the_file() {
+        const POSTERS: usize = 16;
+
+        let fixture = Fixture::new().await;
+        let mut clients = Vec::new();
+
+        // Logging on is slow on purpose, so only the posts race.
+        for n in 0..POSTERS {
+            fixture.add_user(&format!("user{n}")).await;
+            clients.push(fixture.login(&format!("user{n}")).await);
+        }
+
+        let posters: Vec<_> = clients
+            .into_iter()
+            .enumerate()
+            .map(|(n, mut client)| {
+                tokio::spawn(async move {
+                    let subject = format!("Post {n}");
+
+                    client
+                        .send_all(&["message new", &subject, "Racing", "."])
+                        .await;
+                    client.close().await;
+                })
+            })
+            .collect();
+
+        for poster in posters {
+            poster.await.unwrap();
+        }
+
+        let json = std::fs::read_to_string(fixture.dir.join("messages.json")).unwrap();
+        let saved: Vec<Message> = serde_json::from_str(&json).unwrap();
+        let mut ids: Vec<i64> = saved.iter().map(|m| m.id).collect();
+
+        ids.sort();
+        ids.dedup();
+        assert_eq!(saved.len(), POSTERS);
+        assert_eq!(ids.len(), POSTERS);
+
+        for n in 0..POSTERS {
+            assert!(saved.iter().any(|m| m.subject == format!("Post {n}")));
+        }
+    }
+
+    #[tokio::test]
+    async fn quick_reconnects_get_a_terse_greeting() {
+        let fixture = Fixture::with_config(|config| {
+            config.banner_file = Some(config.data_dir.join("banner.txt"));
+            config.banner_flood_limit = Some(3);
+            config.banner_flood_window_secs = Some(60);
+        })
+        .await;
+
+        std::fs::write(fixture.dir.join("banner.txt"), "THE BANNER").unwrap();
+        fixture.add_user("alice").await;
+
+        for _ in 0..3 {
+            let mut client = fixture.spawn_client(false);
+
+            assert!(client.output().await.contains("THE BANNER"));
+            client.close().await;
+        }
+
+        let mut client = fixture.spawn_client(false);
+        let output = client.output().await;
+
+        assert!(output.contains("Too many quick reconnects"), "{output}");
+        assert!(!output.contains("THE BANNER"), "{output}");
+
+        // Logging on shows the IP is a person after all.
+        client.send_all(&["login", "alice", PASSWORD]).await;
+        client.close().await;
+
+        let mut client = fixture.spawn_client(false);
+
+        assert!(client.output().await.contains("THE BANNER"));
+        client.close().await;
+    }
+
+    async fn merge_fixture(fixture: &Fixture) {
+        fixture.add_user("alice").await;
+        fixture.add_user("bob").await;
+        fixture.add_message("alice", DEFAULT_BOARD, "Mine").await;
+        fixture
+            .app_state
+            .update_users(|users| users[0].macros.insert("hi".to_owned(), "boards".to_owned()))
+            .await
+            .unwrap();
+        fixture
+            .app_state
+            .update_last_read(|last_read| {
+                last_read.insert(("alice".to_owned(), DEFAULT_BOARD.to_owned()), 0)
+            })
+            .await
+            .unwrap();
+        fixture
+            .app_state
+            .update_boards(|boards| {
+                boards.push(Board {
+                    name: "alices".to_owned(),
+                    creator: Some("alice".to_owned()),
+                    created_at: 0,
+                    archived: false,
+                    anonymous: false,
+                })
+            })
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn merging_moves_everything_a_user_owns() {
+        let fixture = Fixture::new().await;
+
+        merge_fixture(&fixture).await;
+
+        let changes = fixture
+            .app_state
+            .merge_user("alice", "bob", false)
+            .await
+            .unwrap()
+            .unwrap();
+        let stores = fixture.app_state.stores().await;
+        let bob = fixture.user("bob").await.unwrap();
+
+        assert!(fixture.user("alice").await.is_none());
+        assert_eq!(bob.macros.get("hi").map(String::as_str), Some("boards"));
+        assert_eq!(fixture.message(0).await.unwrap().username, "bob");
+        assert_eq!(
+            stores
+                .last_read
+                .get(&("bob".to_owned(), DEFAULT_BOARD.to_owned())),
+            Some(&0)
+        );
+        assert_eq!(stores.boards[0].creator.as_deref(), Some("bob"));
+        assert_eq!(changes.changes.len(), 5, "{:?}", changes.changes);
+        assert!(
+            fixture
+                .app_state
+                .merge_user("alice", "bob", false)
+                .await
+                .unwrap()
+                .is_none()
+        );
+    }
+
+    #[tokio::test]
+    async fn failed_attachments_leave_no_blob_behind() {
+        let storage = Arc::new(FlakyStorage::default());
+        let fixture =
+            Fixture::with_storage(test_dir(), Box::new(Arc::clone(&storage)), |_| ()).await;
+        let id = fixture.add_message("alice", DEFAULT_BOARD, "Notes").await;
+
+        storage.failing.store(true, Ordering::Relaxed);
+        assert!(
+            fixture
+                .app_state
+                .attach(id, "notes.txt", b"data", 1024)
+                .await
+                .is_err()
+        );
+        assert!(fixture.message(id).await.unwrap().attachment.is_none());
+        assert!(!fixture.dir.join(ATTACHMENTS_DIR).join("0").exists());
+
+        storage.failing.store(false, Ordering::Relaxed);
+
+        let attachment = fixture
+            .app_state
+            .attach(id, "notes.txt", b"data", 1024)
+            .await
+            .unwrap();
+
+        // The failed attempt used up id 0.
+        assert_eq!(attachment.id, 1);
+        assert_eq!(
+            fixture
+                .app_state
+                .read_attachment(&attachment)
+                .await
+                .unwrap(),
+            b"data"
+        );
+    }
+
+    #[tokio::test]
+    async fn purged_attachment_ids_are_not_handed_out_again() {
+        let fixture = Fixture::new().await;
+        let first = fixture.add_message("alice", DEFAULT_BOARD, "First").await;
+        let second = fixture.add_message("alice", DEFAULT_BOARD, "Second").await;
+        let attachment = fixture
+            .app_state
+            .attach(first, "old.txt", b"old", 1024)
+            .await
+            .unwrap();
+
+        fixture
+            .app_state
+            .update_message(first, |message| message.deleted = true)
+            .await
+            .unwrap();
+        fixture
+            .app_state
+            .purge_deleted(Duration::ZERO)
+            .await
+            .unwrap();
+        assert!(
+            fixture
+                .app_state
+                .read_attachment(&attachment)
+                .await
+                .is_err()
+        );
+
+        let attachment = fixture
+            .app_state
+            .attach(second, "new.txt", b"new", 1024)
+            .await
+            .unwrap();
+
+        assert_eq!(attachment.id, 1);
+    }
+
+    #[tokio::test]
+    async fn failed_merges_change_nothing() {
+        let storage = Arc::new(FlakyStorage::default());
+        let fixture =
+            Fixture::with_storage(test_dir(), Box::new(Arc::clone(&storage)), |_| ()).await;
+
+        merge_fixture(&fixture).await;
+
+        // The save fails at the read pointers, after the users and messages.
+        *storage.saves_left.lock().unwrap() = Some(2);
+
+        assert!(
+            fixture
+                .app_state
+                .merge_user("alice", "bob", false)
+                .await
+                .is_err()
+        );
+        assert!(fixture.user("alice").await.is_some());
+        assert_eq!(fixture.message(0).await.unwrap().username, "alice");
+        assert!(
+            storage
+                .users
+                .lock()
+                .unwrap()
+                .iter()
+                .any(|u| u.username == "alice")
+        );
+        assert_eq!(storage.messages.lock().unwrap()[0].username, "alice");
+    }
+
+    #[tokio::test]
+    async fn purging_drops_only_expired_deleted_messages() {
+        let fixture = Fixture::new().await;
+
+        for subject in ["Kept", "Expired", "Recent"] {
+            fixture.add_message("alice", DEFAULT_BOARD, subject).await;
+        }
+
+        {
+            let mut messages = fixture.app_state.messages.write().await;
+
+            messages[1].deleted = true;
+            messages[1].deleted_at = Some(time::now() - 100);
+            messages[2].deleted = true;
+            messages[2].deleted_at = Some(time::now());
+        }
+
+        let purged = fixture
+            .app_state
+            .purge_deleted(Duration::from_secs(50))
+            .await
+            .unwrap();
+        let ids: Vec<i64> = fixture
+            .app_state
+            .messages
+            .read()
+            .await
+            .iter()
+            .map(|m| m.id)
+            .collect();
+
+        assert_eq!(purged, 1);
+        assert_eq!(ids, [0, 2]);
+    }
+
+    #[tokio::test]
+    async fn listings_page_with_cursors() {
+        let fixture = Fixture::new().await;
+
+        for n in 0..6 {
+            fixture
+                .add_message("bob", DEFAULT_BOARD, &format!("Post {n}"))
+                .await;
+        }
+
+        fixture.app_state.messages.write().await[2].deleted = true;
+
+        let subjects = |page: &Page| -> Vec<String> {
+            page.messages.iter().map(|m| m.subject.clone()).collect()
+        };
+
+        let first = fixture
+            .app_state
+            .messages_page(DEFAULT_BOARD, None, 2)
+            .await;
+        assert_eq!(subjects(&first), ["Post 0", "Post 1"]);
+        assert_eq!(first.next, Some(1));
+
+        let middle = fixture
+            .app_state
+            .messages_page(DEFAULT_BOARD, first.next, 2)
+            .await;
+        assert_eq!(subjects(&middle), ["Post 3", "Post 4"]);
+        assert_eq!(middle.next, Some(4));
+
+        let last = fixture
+            .app_state
+            .messages_page(DEFAULT_BOARD, middle.next, 2)
+            .await;
+        assert_eq!(subjects(&last), ["Post 5"]);
+        assert_eq!(last.next, None);
+
+        assert!(
+            fixture
+                .app_state
+                .messages_page(DEFAULT_BOARD, Some(5), 2)
+                .await
+                .messages
+                .is_empty()
+        );
+    }
+
+    #[tokio::test]
+    async fn disconnecting_updates_last_seen() {
+        let fixture = Fixture::new().await;
+
+        fixture.add_user("alice").await;
+        fixture.add_user("bob").await;
+
+        let client = fixture.login("alice").await;
+        let output = fixture.run_as("bob", &["finger alice"]).await;
+
+        assert!(output.contains("alice: online now"), "{output}");
+
+        fixture.app_state.users.write().await[0].last_seen = Some(0);
+        client.close().await;
+
+        let last_seen = fixture.user("alice").await.unwrap().last_seen.unwrap();
+
+        assert!(last_seen >= time::now() - 5, "{last_seen}");
+
+        let output = fixture.run_as("bob", &["finger alice"]).await;
+
+        assert!(output.contains("alice: last seen "), "{output}");
+    }
+
+    #[tokio::test]
+    async fn the_session_span_follows_who_is_logged_on() {
+        let fixture = Fixture::new().await;
+        let logs = Logs::default();
+        let _subscriber = tracing::subscriber::set_default(logs.subscriber());
+        let (mut session, _client) = fixture.session("alice").await;
+
+        fixture.add_user("alice").await;
+
+        async {
+            session.track_status().await;
+            session.track_status().await;
+            info!("Logged on");
+            session.status = SessionStatus::LoggedOff;
+            session.track_status().await;
+            info!("Logged off");
+        }
+        .instrument(info_span!("session", user = field::Empty))
+        .await;
+
+        let lines = logs.lines();
+
+        assert!(lines[0].contains("session{user=alice}:"), "{lines:?}");
+        // Recorded once per change, even though every command re-checks it.
+        assert!(
+            lines[1].contains("session{user=alice user=-}:"),
+            "{lines:?}"
+        );
+    }
+
+    #[tokio::test]
+    async fn transcripts_record_the_session_after_login() {
+        let fixture = Fixture::with_config(|config| config.record_sessions = true).await;
+
+        fixture.add_user("alice").await;
+
+        let output = fixture.run_as("alice", &["boards"]).await;
+
+        assert!(output.contains(DEFAULT_BOARD), "{output}");
+
+        // The transcript is written by its own task, give it a moment to finish.
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let transcripts: Vec<_> = std::fs::read_dir(fixture.dir.join(TRANSCRIPTS_DIR))
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect();
+
+        assert_eq!(transcripts.len(), 1);
+
+        let transcript = std::fs::read_to_string(&transcripts[0]).unwrap();
+
+        assert!(transcript.contains("boards\r\n"), "{transcript}");
+        assert!(transcript.contains(DEFAULT_BOARD), "{transcript}");
+        assert!(
+            !transcript.contains(&format!("{PASSWORD}\r\n")),
+            "{transcript}"
+        );
+    }
+
+    #[tokio::test]
+    async fn transcripts_hide_secret_answers() {
+        let fixture = Fixture::with_config(|config| config.record_sessions = true).await;
+
+        fixture.add_user("alice").await;
+
+        let output = fixture
+            .run_as(
+                "alice",
+                &["boards", "passwd", PASSWORD, "hunter22", "hunter22"],
+            )
+            .await;
+
+        assert!(output.contains("Password changed"), "{output}");
+
+        // The transcript is written by its own task, give it a moment to finish.
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let transcripts: Vec<_> = std::fs::read_dir(fixture.dir.join(TRANSCRIPTS_DIR))
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect();
+
+        assert_eq!(transcripts.len(), 1);
+
+        let transcript = std::fs::read_to_string(&transcripts[0]).unwrap();
+
+        assert!(transcript.contains("boards\r\n"), "{transcript}");
+        assert!(transcript.contains("Current password: "), "{transcript}");
+        assert_eq!(transcript.matches("<hidden>").count(), 3, "{transcript}");
+        assert!(!transcript.contains("hunter22"), "{transcript}");
+        assert!(
+            !transcript.contains(&format!("{PASSWORD}\r\n")),
+            "{transcript}"
+        );
+    }
+
+    #[tokio::test]
+    async fn replies_under_deleted_messages_stay_in_their_thread() {
+        let fixture = Fixture::new().await;
+
+        for subject in ["Root", "Gone", "Reply to gone", "Gone leaf"] {
+            fixture.add_message("alice", DEFAULT_BOARD, subject).await;
+        }
+
+        {
+            let mut messages = fixture.app_state.messages.write().await;
+
+            messages[1].reply_to = Some(0);
+            messages[1].deleted = true;
+            messages[2].reply_to = Some(1);
+            messages[3].reply_to = Some(0);
+            messages[3].deleted = true;
+        }
+
+        let thread = fixture.app_state.thread(0, 10).await;
+        let shown: Vec<(usize, i64, bool)> = thread
+            .iter()
+            .map(|(depth, m)| (*depth, m.id, m.deleted))
+            .collect();
+
+        assert_eq!(shown, [(0, 0, false), (1, 1, true), (2, 2, false)]);
+        assert_eq!(thread[1].1.subject, "");
+        assert_eq!(thread[1].1.body, "");
+        assert_eq!(
+            fixture.app_state.reply_counts(DEFAULT_BOARD).await.get(&0),
+            Some(&1)
+        );
+        assert!(fixture.app_state.reply_counts("news").await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn purged_messages_hand_their_replies_to_the_nearest_ancestor() {
+        let fixture = Fixture::new().await;
+
+        for subject in [
+            "Root",
+            "Purged",
+            "Also purged",
+            "Reply",
+            "Sibling",
+            "Nested",
+        ] {
+            fixture.add_message("alice", DEFAULT_BOARD, subject).await;
+        }
+
+        {
+            let mut messages = fixture.app_state.messages.write().await;
+
+            messages[1].reply_to = Some(0);
+            messages[1].deleted = true;
+            messages[2].reply_to = Some(1);
+            messages[2].deleted = true;
+            messages[3].reply_to = Some(2);
+            messages[4].reply_to = Some(1);
+            messages[5].reply_to = Some(3);
+        }
+
+        assert_eq!(
+            fixture
+                .app_state
+                .purge_deleted(Duration::ZERO)
+                .await
+                .unwrap(),
+            2
+        );
+
+        let parents: Vec<(i64, Option<i64>)> = fixture
+            .app_state
+            .messages
+            .read()
+            .await
+            .iter()
+            .map(|m| (m.id, m.reply_to))
+            .collect();
+
+        assert_eq!(
+            parents,
+            [(0, None), (3, Some(0)), (4, Some(0)), (5, Some(3))]
+        );
+    }
+
+    #[tokio::test]
+    async fn board_stats_agree_with_the_unread_list() {
+        let fixture = Fixture::new().await;
+
+        fixture.add_user("alice").await;
+        fixture.add_user("bob").await;
+        fixture.add_message("alice", "dev", "Mine").await;
+
+        let id = fixture.add_message("bob", DEFAULT_BOARD, "Theirs").await;
+        let unread = |username: &'static str| {
+            let app_state = Arc::clone(&fixture.app_state);
+
+            async move {
+                app_state
+                    .board_stats(username, &[])
+                    .await
+                    .into_iter()
+                    .filter(|b| b.unread)
+                    .map(|b| b.name)
+                    .collect::<Vec<_>>()
+            }
+        };
+
+        assert_eq!(unread("alice").await, [DEFAULT_BOARD]);
+        assert_eq!(unread("bob").await, ["dev"]);
+
+        fixture
+            .app_state
+            .mark_read("alice", DEFAULT_BOARD, id)
+            .await
+            .unwrap();
+
+        assert!(unread("alice").await.is_empty());
+
+        let output = fixture.run_as("alice", &["message unread"]).await;
+
+        assert!(output.contains("No unread messages"), "{output}");
+    }
+
+    #[tokio::test]
+    async fn message_queries_combine_their_filters() {
+        let fixture = Fixture::new().await;
+
+        for (user, board, subject) in [
+            ("alice", DEFAULT_BOARD, "Rust news"),
+            ("bob", DEFAULT_BOARD, "Lunch"),
+            ("alice", "dev", "Tips for rust"),
+            ("bob", "dev", "Gone"),
+            ("alice", DEFAULT_BOARD, "Other"),
+        ] {
+            fixture.add_message(user, board, subject).await;
+        }
+
+        fixture
+            .app_state
+            .update_messages(|messages| messages[3].deleted = true)
+            .await
+            .unwrap();
+        fixture
+            .app_state
+            .update_last_read(|last_read| {
+                last_read.insert(("carol".to_owned(), DEFAULT_BOARD.to_owned()), 1)
+            })
+            .await
+            .unwrap();
+
+        let ids = |filter: MessageFilter| {
+            let app_state = Arc::clone(&fixture.app_state);
+
+            async move {
+                app_state
+                    .query_messages(filter)
+                    .await
+                    .iter()
+                    .map(|m| m.id)
+                    .collect::<Vec<_>>()
+            }
+        };
+        let board = || Some(DEFAULT_BOARD.to_owned());
+
+        assert_eq!(ids(MessageFilter::default()).await, [0, 1, 2, 4]);
+        assert_eq!(
+            ids(MessageFilter {
+                board: board(),
+                author: Some("alice".to_owned()),
+                ..Default::default()
+            })
+            .await,
+            [0, 4]
+        );
+        assert_eq!(
+            ids(MessageFilter {
+                keyword: Some("RUST".to_owned()),
+                ..Default::default()
+            })
+            .await,
+            [0, 2]
+        );
+        assert_eq!(
+            ids(MessageFilter {
+                unread_for: Some("carol".to_owned()),
+                ..Default::default()
+            })
+            .await,
+            [2, 4]
+        );
+        assert!(
+            ids(MessageFilter {
+                board: board(),
+                keyword: Some("lunch".to_owned()),
+                unread_for: Some("carol".to_owned()),
+                ..Default::default()
+            })
+            .await
+            .is_empty()
+        );
+        assert_eq!(
+            ids(MessageFilter {
+                author: Some("alice".to_owned()),
+                limit: Some(2),
+                ..Default::default()
+            })
+            .await,
+            [0, 2]
+        );
+        assert!(
+            ids(MessageFilter {
+                board: Some("dev".to_owned()),
+                author: Some("bob".to_owned()),
+                ..Default::default()
+            })
+            .await
+            .is_empty()
+        );
+    }
+
+    #[tokio::test]
+    async fn digests_list_only_new_visible_messages() {
+        let fixture = Fixture::new().await;
+
+        for (user, board, subject) in [
+            ("alice", DEFAULT_BOARD, "Old news"),
+            ("alice", DEFAULT_BOARD, "Fresh"),
+            ("bob", DEFAULT_BOARD, "Deleted"),
+            ("bob", "dev", "Patch"),
+            (DIGEST_AUTHOR, "dev", "Digest for yesterday"),
+            ("bob", "quiet", "Ancient"),
+        ] {
+            fixture.add_message(user, board, subject).await;
+        }
+
+        fixture
+            .app_state
+            .update_messages(|messages| {
+                messages[0].created_at = 100;
+                messages[2].deleted = true;
+                messages[5].created_at = 100;
+            })
+            .await
+            .unwrap();
+
+        assert_eq!(fixture.app_state.post_digests(1000).await.unwrap(), 2);
+
+        let digests: Vec<Message> = fixture
+            .app_state
+            .messages
+            .read()
+            .await
+            .iter()
+            .skip(6)
+            .cloned()
+            .collect();
+
+        assert_eq!(digests.len(), 2);
+        assert_eq!(digests[0].board, "dev");
+        assert_eq!(digests[0].body, "3 bob Patch\r\n");
+        assert_eq!(digests[1].board, DEFAULT_BOARD);
+        assert_eq!(digests[1].body, "1 alice Fresh\r\n");
+        assert!(digests.iter().all(|d| d.username == DIGEST_AUTHOR));
+
+        // Digests never end up in later digests.
+        fixture
+            .app_state
+            .update_messages(|messages| {
+                for message in messages.iter_mut().filter(|m| m.username != DIGEST_AUTHOR) {
+                    message.created_at = 100;
+                }
+            })
+            .await
+            .unwrap();
+        assert_eq!(fixture.app_state.post_digests(1000).await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn pasted_blocks_produce_one_error() {
+        let fixture = Fixture::new().await;
+
+        fixture.add_user("alice").await;
+
+        let mut alice = fixture.login("alice").await;
+        let paste: String = (0..50)
+            .map(|n| format!("line {n} of an essay\r\n"))
+            .collect();
+        let output = alice.paste(&paste).await;
+
+        assert!(
+            output.contains("Paste detected, 49 line(s) ignored"),
+            "{output}"
+        );
+        assert!(!output.contains("Unknown command"), "{output}");
+        assert!(output.lines().count() < 5, "{output}");
+
+        // The session is still usable afterwards.
+        assert!(alice.send("boards").await.contains("Boards"));
+        alice.close().await;
+    }
+
+    #[tokio::test]
+    async fn keepalives_drop_sessions_whose_client_stopped_reading() {
+        let fixture = Fixture::with_config(|config| {
+            config.keepalive_secs = Some(30);
+            config.connection_log_file = Some(config.data_dir.join("connections.log"));
+        })
+        .await;
+
+        fixture.add_user("alice").await;
+
+        // A pipe for each direction, so the client can stop reading and still not
+        // hang up its side of the input.
+        let (mut input, server_input) = tokio::io::duplex(4096);
+        let (server_output, mut output) = tokio::io::duplex(4096);
+        let address = SocketAddr::from(([127, 0, 0, 1], 1));
+        let mut session = Session::new(
+            tokio::io::join(server_input, server_output),
+            address,
+            Arc::clone(&fixture.config),
+            Arc::clone(&fixture.app_state),
+            Arc::clone(&fixture.command_handler),
+        )
+        .with_telnet();
+        let session = tokio::spawn(async move { session.run().await });
+        let mut seen = Vec::new();
+        let mut buffer = [0; 4096];
+
+        input
+            .write_all(format!("login\r\nalice\r\n{PASSWORD}\r\n").as_bytes())
+            .await
+            .unwrap();
+
+        while !String::from_utf8_lossy(&seen).contains("Login successful") {
+            let read = output.read(&mut buffer).await.unwrap();
+
+            assert!(read > 0, "{}", String::from_utf8_lossy(&seen));
+            seen.extend_from_slice(&buffer[..read]);
+        }
+
+        // The session registers as online once the login command is done.
+        timeout(Duration::from_secs(5), async {
+            while !fixture.app_state.online.read().await.contains_key(&address) {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+
+        // The first keepalive after this finds the writer failing, the next one finds
+        // it gone. The paused clock skips straight to them.
+        drop(output);
+        tokio::time::pause();
+
+        assert!(
+            timeout(Duration::from_secs(300), session)
+                .await
+                .unwrap()
+                .unwrap()
+                .is_ok()
+        );
+        assert!(!fixture.app_state.online.read().await.contains_key(&address));
+
+        let log = std::fs::read_to_string(fixture.dir.join("connections.log")).unwrap();
+
+        assert!(log.trim_end().ends_with("connection error"), "{log}");
+    }
+
+    #[tokio::test]
+    async fn stalled_readers_are_dropped() {
+        let fixture = Fixture::with_config(|config| {
+            config.outbound_queue_size = 8;
+            config.connection_log_file = Some(config.data_dir.join("connections.log"));
+        })
+        .await;
+
+        fixture.add_user("alice").await;
+
+        let id = fixture.add_message("bob", DEFAULT_BOARD, "Huge").await;
+
+        // Far more than the pipe holds, so the writer stalls on the first copy.
+        fixture
+            .app_state
+            .update_messages(|messages| messages[0].body = "word ".repeat(100_000))
+            .await
+            .unwrap();
+
+        let mut alice = fixture.login("alice").await;
+
+        alice
+            .write(&format!("message read {id}\r\n").repeat(20))
+            .await;
+        assert!(alice.finished().await);
+
+        let log = std::fs::read_to_string(fixture.dir.join("connections.log")).unwrap();
+
+        assert!(log.trim_end().ends_with("client too slow"), "{log}");
+    }
+
+    #[tokio::test]
+    async fn only_stale_boards_are_archived() {
+        let fixture = Fixture::new().await;
+
+        for board in ["stale", "fresh", "unused", "news"] {
+            fixture.add_board(board).await;
+        }
+
+        for board in ["stale", "fresh", "news", DEFAULT_BOARD] {
+            fixture.add_message("bob", board, "Hi").await;
+        }
+
+        fixture
+            .app_state
+            .update_stores(|stores| {
+                for board in stores.boards.iter_mut().filter(|b| b.name != "fresh") {
+                    board.created_at = 100;
+                }
+
+                for message in stores.messages.iter_mut().filter(|m| m.board != "fresh") {
+                    message.created_at = 100;
+                }
+
+                Some(())
+            })
+            .await
+            .unwrap();
+
+        let archived = fixture
+            .app_state
+            .archive_inactive(Duration::from_secs(86400), Some("news"))
+            .await
+            .unwrap();
+        let boards: Vec<(String, bool)> = fixture
+            .app_state
+            .boards
+            .read()
+            .await
+            .iter()
+            .map(|b| (b.name.clone(), b.archived))
+            .collect();
+
+        assert_eq!(archived, 2);
+        assert!(boards.contains(&("stale".to_owned(), true)));
+        assert!(boards.contains(&("unused".to_owned(), true)));
+        assert!(boards.contains(&("fresh".to_owned(), false)));
+        // Default boards stay open however quiet they are.
+        assert!(boards.contains(&("news".to_owned(), false)));
+        assert!(!boards.contains(&(DEFAULT_BOARD.to_owned(), true)));
+
+        // Unarchiving counts as activity, however old the posts are.
+        fixture
+            .app_state
+            .update_boards(|boards| {
+                let stale = boards.iter_mut().find(|b| b.name == "stale").unwrap();
+
+                stale.archived = false;
+                stale.created_at = time::now();
+            })
+            .await
+            .unwrap();
+        std::fs::remove_file(fixture.dir.join("boards.json")).unwrap();
+
+        let archived = fixture
+            .app_state
+            .archive_inactive(Duration::from_secs(86400), Some("news"))
+            .await
+            .unwrap();
+
+        assert_eq!(archived, 0);
+        assert!(!fixture.app_state.board_archived("stale").await);
+        // A pass that archives nothing saves nothing either.
+        assert!(!fixture.dir.join("boards.json").exists());
+    }
+
+    #[tokio::test]
+    async fn diagnostics_report_stuck_locks_instead_of_waiting() {
+        let fixture = Fixture::new().await;
+        let _users = fixture.app_state.users.write().await;
+
+        tokio::time::pause();
+
+        let lines = fixture.app_state.diagnostics().await;
+
+        assert!(
+            lines.contains(&"users: lock wait blocked >1s".to_owned()),
+            "{lines:?}"
+        );
+        assert!(
+            lines
+                .iter()
+                .any(|l| l.starts_with("messages: lock wait 0.")),
+            "{lines:?}"
+        );
+    }
+
+    #[test]
+    fn transcripts_match_the_whole_sanitized_name() {
+        assert!(is_transcript_of("1700000000-alice.log", "alice"));
+        assert!(is_transcript_of("1700000000-bob_smith.log", "bob smith"));
+        assert!(is_transcript_of("1700000000-alice-bob.log", "alice-bob"));
+        assert!(!is_transcript_of("1700000000-alice-bob.log", "bob"));
+        assert!(!is_transcript_of("1700000000-alice.log", "ice"));
+        assert!(!is_transcript_of("notes-alice.log", "alice"));
+        assert!(!is_transcript_of("1700000000-alice.txt", "alice"));
+    }
+
+    #[tokio::test]
+    async fn connections_are_logged_on_both_ends() {
+        let fixture = Fixture::with_config(|config| {
+            config.connection_log_file = Some(config.data_dir.join("connections.log"));
+        })
+        .await;
+
+        fixture.add_user("alice").await;
+
+        let mut alice = fixture.login("alice").await;
+        let address = alice.address;
+
+        alice.send("quit").await;
+        assert!(alice.finished().await);
+
+        let log = std::fs::read_to_string(fixture.dir.join("connections.log")).unwrap();
+        let lines: Vec<&str> = log.lines().collect();
+
+        assert_eq!(lines.len(), 2, "{log}");
+        assert!(lines[0].ends_with(&format!(" connect {address}")), "{log}");
+        assert!(
+            lines[1].contains(&format!(" disconnect {address} alice ")),
+            "{log}"
+        );
+        assert!(lines[1].ends_with("s quit"), "{log}");
+    }
+
+    #[tokio::test]
+    async fn sysops_outlast_the_idle_timeout() {
+        let fixture = Fixture::with_config(|config| config.idle_timeout_secs = Some(1)).await;
+
+        fixture.add_user(SYSOP).await;
+        fixture.add_user("alice").await;
+
+        let mut sysop = fixture.login(SYSOP).await;
+        let mut alice = fixture.login("alice").await;
+
+        tokio::time::pause();
+        tokio::time::advance(Duration::from_millis(1500)).await;
+
+        assert!(
+            alice
+                .output()
+                .await
+                .contains("Disconnected due to inactivity")
+        );
+        assert!(alice.finished().await);
+        assert!(sysop.send("boards").await.contains("Boards"));
+        sysop.close().await;
+    }
+
+    #[tokio::test]
+    async fn idle_disconnects_record_the_idle_reason() {
+        let fixture = Fixture::with_config(|config| {
+            config.idle_timeout_secs = Some(1);
+            config.connection_log_file = Some(config.data_dir.join("connections.log"));
+        })
+        .await;
+
+        fixture.add_user("alice").await;
+
+        let mut alice = fixture.login("alice").await;
+
+        tokio::time::pause();
+        tokio::time::advance(Duration::from_millis(1500)).await;
+
+        let output = alice.output().await;
+
+        assert!(
+            output.contains("Disconnected due to inactivity"),
+            "{output}"
+        );
+        assert!(alice.finished().await);
+
+        let log = std::fs::read_to_string(fixture.dir.join("connections.log")).unwrap();
+
+        assert!(log.trim_end().ends_with("idle timeout"), "{log}");
+    }
+
+    #[tokio::test]
+    async fn logging_out_keeps_the_connection_for_another_user() {
+        let fixture = Fixture::new().await;
+
+        fixture.add_user("alice").await;
+        fixture.add_user("bob").await;
+        fixture.add_board("lounge").await;
+
+        let mut client = fixture.login("alice").await;
+        let output = client.send_all(&["setboard lounge", "logout"]).await;
+
+        assert!(output.contains("Logged out"), "{output}");
+        assert!(output.contains("Commands:"), "{output}");
+        assert!(fixture.app_state.online.read().await.is_empty());
+
+        let output = client.send_all(&["login", "bob", PASSWORD]).await;
+
+        assert!(output.contains("Login successful"), "{output}");
+
+        client
+            .send_all(&["message new", "Hello", "Where am I?", "."])
+            .await;
+        client.close().await;
+
+        let message = fixture.message(0).await.unwrap();
+
+        assert_eq!(message.username, "bob");
+        assert_eq!(message.board, DEFAULT_BOARD);
+    }
+
+    #[tokio::test]
+    async fn connections_are_rate_limited_per_ip() {
+        let fixture = Fixture::new().await;
+        let ip = IpAddr::from([192, 0, 2, 1]);
+
+        assert!(fixture.app_state.allow_connection(ip, 2).await);
+        assert!(fixture.app_state.allow_connection(ip, 2).await);
+        assert!(!fixture.app_state.allow_connection(ip, 2).await);
+        assert!(
+            fixture
+                .app_state
+                .allow_connection(IpAddr::from([192, 0, 2, 2]), 2)
+                .await
+        );
+    }
+
+    #[tokio::test]
+    async fn shutdown_disconnects_every_session_with_a_notice() {
+        let fixture = Fixture::new().await;
+
+        fixture.add_user("alice").await;
+
+        let mut alice = fixture.login("alice").await;
+        let mut guest = fixture.connect().await;
+
+        assert_eq!(fixture.app_state.shutdown().await, 2);
+
+        for client in [&mut alice, &mut guest] {
+            let output = client.output().await;
+
+            assert!(output.contains("The server is shutting down"), "{output}");
+        }
+
+        assert!(alice.finished().await);
+        assert!(guest.finished().await);
+        assert_eq!(fixture.app_state.connected(), 0);
+    }
+
+    #[tokio::test]
+    async fn chat_lines_reach_everyone_else_in_chat() {
+        let fixture = Fixture::new().await;
+
+        fixture.add_user("alice").await;
+        fixture.add_user("bob").await;
+
+        let mut alice = fixture.login("alice").await;
+        let mut bob = fixture.login("bob").await;
+
+        alice.send("chat").await;
+        bob.send("chat").await;
+        assert!(alice.output().await.contains("* bob joined the chat"));
+
+        let output = alice.send("hello bob").await;
+
+        assert!(!output.contains("<alice> hello bob"), "{output}");
+        assert!(bob.output().await.contains("<alice> hello bob"));
+
+        bob.send("/exit").await;
+        assert!(alice.output().await.contains("* bob left the chat"));
+
+        alice.close().await;
+        bob.close().await;
+    }
+
+    #[tokio::test]
+    async fn chat_is_exempt_from_idle_kicks() {
+        let fixture = Fixture::with_config(|config| config.idle_timeout_secs = Some(1)).await;
+
+        fixture.add_user("alice").await;
+
+        let mut alice = fixture.login("alice").await;
+
+        alice.send("chat").await;
+        tokio::time::pause();
+        tokio::time::advance(Duration::from_millis(1500)).await;
+
+        let output = alice.send("/exit").await;
+
+        assert!(!output.contains("inactivity"), "{output}");
+        tokio::time::advance(Duration::from_millis(1500)).await;
+        assert!(
+            alice
+                .output()
+                .await
+                .contains("Disconnected due to inactivity")
+        );
+        assert!(alice.finished().await);
+    }
+
+    #[tokio::test]
+    async fn hanging_up_mid_prompt_ends_the_session() {
+        let fixture = Fixture::with_config(|config| {
+            config.connection_log_file = Some(config.data_dir.join("connections.log"));
+        })
+        .await;
+
+        fixture.add_user("alice").await;
+
+        let mut alice = fixture.login("alice").await;
+        let output = alice.send("message new").await;
+
+        assert!(output.contains("Subject"), "{output}");
+        alice.close().await;
+        assert_eq!(fixture.app_state.connected(), 0);
+
+        let log = std::fs::read_to_string(fixture.dir.join("connections.log")).unwrap();
+
+        assert!(log.trim_end().ends_with("closed by client"), "{log}");
+    }
+
+    #[tokio::test]
+    async fn json_requests_get_plain_json_responses() {
+        let fixture = Fixture::new().await;
+
+        fixture.add_user("alice").await;
+
+        let mut client = fixture.connect_json().await;
+        let mut request = async |line: &str| -> serde_json::Value {
+            serde_json::from_str(&client.send(line).await).unwrap()
+        };
+
+        let response = request("not json").await;
+        assert_eq!(response["ok"], false);
+        assert!(
+            response["error"]
+                .as_str()
+                .unwrap()
+                .starts_with("Invalid request")
+        );
+
+        let response = request(r#"{"cmd": "nonsense"}"#).await;
+        assert_eq!(response["ok"], false);
+        assert_eq!(response["error"], "Unknown command");
+
+        let response = request(&format!(
+            r#"{{"cmd": "login", "input": ["alice", "{PASSWORD}"]}}"#
+        ))
+        .await;
+        assert_eq!(response["ok"], true);
+        assert_eq!(response["user"], "alice");
+
+        let response =
+            request(r#"{"cmd": "message", "args": ["new"], "input": ["Hi", "Text", "."]}"#).await;
+        assert_eq!(response["ok"], true, "{response}");
+        assert!(!response.to_string().contains('\u{001b}'), "{response}");
+        assert_eq!(fixture.app_state.messages.read().await[0].subject, "Hi");
+
+        request(r#"{"cmd": "quit"}"#).await;
+        assert!(client.finished().await);
+    }
+
+    #[tokio::test]
+    async fn broadcasts_skip_json_clients() {
+        let fixture = Fixture::new().await;
+
+        fixture.add_user(SYSOP).await;
+        fixture.add_user("alice").await;
+
+        let mut alice = fixture.login("alice").await;
+        let mut json = fixture.connect_json().await;
+        let mut sysop = fixture.login(SYSOP).await;
+        let output = sysop.send("broadcast going down soon").await;
+
+        assert!(
+            output.contains("Broadcast sent to 2 session(s)"),
+            "{output}"
+        );
+        assert!(
+            alice
+                .output()
+                .await
+                .contains("[Broadcast from sysop] going down soon")
+        );
+        assert_eq!(json.output().await, "");
+
+        for client in [alice, json, sysop] {
+            client.close().await;
+        }
+    }
+
+    /// Output of `message list` over the JSON protocol, starting after `after`.
+    async fn list_page(client: &mut Client, after: Option<i64>) -> Vec<String> {
+        let args = match after {
+            Some(after) => format!(r#"["list", "{after}"]"#),
+            None => String::from(r#"["list"]"#),
+        };
+        let request = format!(r#"{{"cmd": "message", "args": {args}}}"#);
+        let response: serde_json::Value =
+            serde_json::from_str(&client.send(&request).await).unwrap();
+
+        serde_json::from_value(response["output"].clone()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn json_listings_page_with_cursors() {
+        let fixture = Fixture::with_config(|config| config.page_size = 2).await;
+
+        fixture.add_user("alice").await;
+
+        for n in 0..5 {
+            fixture
+                .add_message("bob", DEFAULT_BOARD, &format!("Post {n}"))
+                .await;
+        }
+
+        let mut client = fixture.connect_json().await;
+
+        client
+            .send(&format!(
+                r#"{{"cmd": "login", "input": ["alice", "{PASSWORD}"]}}"#
+            ))
+            .await;
+
+        let first = list_page(&mut client, None).await;
+        assert_eq!(first.len(), 3);
+        assert!(first[0].ends_with("Post 0") && first[1].ends_with("Post 1"));
+        assert_eq!(first[2], "More: message list 1");
+
+        let middle = list_page(&mut client, Some(1)).await;
+        assert!(middle[0].ends_with("Post 2") && middle[1].ends_with("Post 3"));
+        assert_eq!(middle[2], "More: message list 3");
+
+        let last = list_page(&mut client, Some(3)).await;
+        assert_eq!(last.len(), 1);
+        assert!(last[0].ends_with("Post 4"));
+
+        assert!(list_page(&mut client, Some(4)).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn history_does_not_outlive_a_logout() {
+        let fixture = Fixture::new().await;
+
+        fixture.add_user("alice").await;
+        fixture.add_user("bob").await;
+
+        let mut client = fixture.login("alice").await;
+
+        client.send_all(&["boards", "logout"]).await;
+        client.send_all(&["login", "bob", PASSWORD]).await;
+
+        let output = client.send("history").await;
+
+        assert!(output.contains("  1 login"), "{output}");
+        assert!(!output.contains("boards"), "{output}");
+        client.close().await;
+    }
+
+    #[test]
+    fn transcripts_note_the_lines_a_slow_disk_missed() {
+        let (sender, mut receiver) = channel(2);
+        let transcript = Transcript {
+            sender,
+            dropped: AtomicUsize::new(0),
+        };
+
+        for line in ["one\r\n", "two\r\n", "three\r\n", "four\r\n"] {
+            transcript.record(line);
+        }
+
+        assert_eq!(receiver.try_recv().unwrap(), "one\r\n");
+        assert_eq!(receiver.try_recv().unwrap(), "two\r\n");
+        assert!(receiver.try_recv().is_err());
+
+        transcript.record("five\r\n");
+
+        assert_eq!(
+            receiver.try_recv().unwrap(),
+            "[2 line(s) missing, transcript fell behind]\r\n"
+        );
+        assert_eq!(receiver.try_recv().unwrap(), "five\r\n");
+    }
 }
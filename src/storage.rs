@@ -0,0 +1,647 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+use sqlx::{
+    SqliteConnection, SqlitePool,
+    sqlite::{SqliteConnectOptions, SqlitePoolOptions},
+};
+use tokio::{
+    fs::{File, create_dir_all, read_to_string, remove_file, rename},
+    io::AsyncWriteExt,
+};
+
+use crate::session::{Board, Message, PrivateMessage, Stores, User};
+
+const USERS_FILE: &str = "users.json";
+const MESSAGES_FILE: &str = "messages.json";
+const LAST_READ_FILE: &str = "last_read.json";
+const BOARDS_FILE: &str = "boards.json";
+const PRIVATE_MESSAGES_FILE: &str = "private_messages.json";
+const DATABASE_FILE: &str = "gluboard.db";
+
+/// Every store but the read pointers is kept as one JSON document per row, keyed by
+/// its id and listed by position. Boards and private messages have no id of their own
+/// and are keyed by their position.
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS users (
+        id INTEGER PRIMARY KEY,
+        position INTEGER NOT NULL,
+        data TEXT NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS messages (
+        id INTEGER PRIMARY KEY,
+        position INTEGER NOT NULL,
+        data TEXT NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS last_read (
+        username TEXT NOT NULL,
+        board TEXT NOT NULL,
+        id INTEGER NOT NULL,
+        PRIMARY KEY (username, board)
+    );
+    CREATE TABLE IF NOT EXISTS boards (
+        id INTEGER PRIMARY KEY,
+        position INTEGER NOT NULL,
+        data TEXT NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS private_messages (
+        id INTEGER PRIMARY KEY,
+        position INTEGER NOT NULL,
+        data TEXT NOT NULL
+    );
+";
+
+/// Where the persisted stores live. The plural saves write a whole store, matching how
+/// `AppState` swaps in a changed copy once it is on disk. `save_user` and
+/// `save_message` write a single record, adding it when its id is new. `save_stores`
+/// writes every store at once, and either all of them land or none does.
+#[async_trait]
+pub trait Storage: Send + Sync {
+    async fn load_users(&self) -> Result<Vec<User>>;
+    async fn save_users(&self, users: &[User]) -> Result<()>;
+    async fn save_user(&self, user: &User) -> Result<()>;
+    async fn load_messages(&self) -> Result<Vec<Message>>;
+    async fn save_messages(&self, messages: &[Message]) -> Result<()>;
+    async fn save_message(&self, message: &Message) -> Result<()>;
+    async fn load_last_read(&self) -> Result<HashMap<(String, String), i64>>;
+    async fn save_last_read(&self, last_read: &HashMap<(String, String), i64>) -> Result<()>;
+    async fn load_boards(&self) -> Result<Vec<Board>>;
+    async fn save_boards(&self, boards: &[Board]) -> Result<()>;
+    async fn load_private_messages(&self) -> Result<Vec<PrivateMessage>>;
+    async fn save_private_messages(&self, private_messages: &[PrivateMessage]) -> Result<()>;
+    async fn save_stores(&self, stores: &Stores) -> Result<()>;
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StorageKind {
+    #[default]
+    Json,
+    Sqlite,
+}
+
+/// Opens the configured storage backend, keeping its files in `dir`.
+pub async fn open(kind: StorageKind, dir: &Path) -> Result<Box<dyn Storage>> {
+    create_dir_all(dir)
+        .await
+        .with_context(|| format!("Could not create data directory {}", dir.display()))?;
+
+    match kind {
+        StorageKind::Json => Ok(Box::new(JsonStorage {
+            dir: dir.to_owned(),
+        })),
+        StorageKind::Sqlite => Ok(Box::new(SqliteStorage::open(dir).await?)),
+    }
+}
+
+/// One pretty printed JSON file per store in its directory.
+pub struct JsonStorage {
+    dir: PathBuf,
+}
+
+#[async_trait]
+impl Storage for JsonStorage {
+    async fn load_users(&self) -> Result<Vec<User>> {
+        load_json(&self.dir.join(USERS_FILE)).await
+    }
+
+    async fn save_users(&self, users: &[User]) -> Result<()> {
+        save_json(&self.dir.join(USERS_FILE), users).await
+    }
+
+    async fn save_user(&self, user: &User) -> Result<()> {
+        let mut users = self.load_users().await?;
+
+        upsert(&mut users, user, |u| u.id == user.id);
+        self.save_users(&users).await
+    }
+
+    async fn load_messages(&self) -> Result<Vec<Message>> {
+        load_json(&self.dir.join(MESSAGES_FILE)).await
+    }
+
+    async fn save_messages(&self, messages: &[Message]) -> Result<()> {
+        save_json(&self.dir.join(MESSAGES_FILE), messages).await
+    }
+
+    async fn save_message(&self, message: &Message) -> Result<()> {
+        let mut messages = self.load_messages().await?;
+
+        upsert(&mut messages, message, |m| m.id == message.id);
+        self.save_messages(&messages).await
+    }
+
+    async fn load_last_read(&self) -> Result<HashMap<(String, String), i64>> {
+        // JSON object keys must be strings, so the map is stored as a list of pairs.
+        let last_read: Vec<((String, String), i64)> =
+            load_json(&self.dir.join(LAST_READ_FILE)).await?;
+
+        Ok(last_read.into_iter().collect())
+    }
+
+    async fn save_last_read(&self, last_read: &HashMap<(String, String), i64>) -> Result<()> {
+        let last_read: Vec<_> = last_read.iter().collect();
+
+        save_json(&self.dir.join(LAST_READ_FILE), &last_read).await
+    }
+
+    async fn load_boards(&self) -> Result<Vec<Board>> {
+        load_json(&self.dir.join(BOARDS_FILE)).await
+    }
+
+    async fn save_boards(&self, boards: &[Board]) -> Result<()> {
+        save_json(&self.dir.join(BOARDS_FILE), boards).await
+    }
+
+    async fn load_private_messages(&self) -> Result<Vec<PrivateMessage>> {
+        load_json(&self.dir.join(PRIVATE_MESSAGES_FILE)).await
+    }
+
+    async fn save_private_messages(&self, private_messages: &[PrivateMessage]) -> Result<()> {
+        save_json(&self.dir.join(PRIVATE_MESSAGES_FILE), private_messages).await
+    }
+
+    async fn save_stores(&self, stores: &Stores) -> Result<()> {
+        let last_read: Vec<_> = stores.last_read.iter().collect();
+        let files = [
+            (USERS_FILE, serde_json::to_string_pretty(&stores.users)?),
+            (
+                MESSAGES_FILE,
+                serde_json::to_string_pretty(&stores.messages)?,
+            ),
+            (LAST_READ_FILE, serde_json::to_string_pretty(&last_read)?),
+            (BOARDS_FILE, serde_json::to_string_pretty(&stores.boards)?),
+            (
+                PRIVATE_MESSAGES_FILE,
+                serde_json::to_string_pretty(&stores.private_messages)?,
+            ),
+        ];
+        let mut written = Vec::new();
+
+        // Every file is written aside before any is renamed into place, so a failed
+        // write leaves all of them as they were.
+        for (file, json) in &files {
+            let path = self.dir.join(file);
+
+            match write_aside(&path, json).await {
+                Ok(tmp_path) => written.push((tmp_path, path)),
+                Err(e) => {
+                    for (tmp_path, _) in &written {
+                        let _ = remove_file(tmp_path).await;
+                    }
+
+                    return Err(e);
+                }
+            }
+        }
+
+        for (tmp_path, path) in &written {
+            rename(tmp_path, path).await?;
+        }
+
+        Ok(())
+    }
+}
+
+/// A single SQLite database in its directory. Whole store saves run in one
+/// transaction that only writes the rows that changed and deletes the ones that are
+/// gone, so a failed save leaves the previous contents in place. `save_stores` does
+/// the same for every store in a single transaction.
+pub struct SqliteStorage {
+    pool: SqlitePool,
+}
+
+impl SqliteStorage {
+    pub async fn open(dir: &Path) -> Result<Self> {
+        let options = SqliteConnectOptions::new()
+            .filename(dir.join(DATABASE_FILE))
+            .create_if_missing(true);
+        // Saves are serialized per store already, one connection keeps them in order.
+        let pool = SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect_with(options)
+            .await
+            .context("Could not open database")?;
+
+        sqlx::raw_sql(SCHEMA)
+            .execute(&pool)
+            .await
+            .context("Could not create tables")?;
+
+        Ok(Self { pool })
+    }
+
+    async fn load_rows<T: DeserializeOwned>(&self, table: &'static str) -> Result<Vec<T>> {
+        let rows: Vec<String> =
+            sqlx::query_scalar(&format!("SELECT data FROM {table} ORDER BY position"))
+                .fetch_all(&self.pool)
+                .await?;
+
+        rows.iter()
+            .map(|row| serde_json::from_str(row).with_context(|| format!("Invalid row in {table}")))
+            .collect()
+    }
+
+    /// Makes `table` hold `rows` in a transaction of its own, see `write_rows`.
+    async fn save_rows<T: Serialize>(
+        &self,
+        table: &'static str,
+        rows: &[T],
+        id: impl Fn(usize, &T) -> i64,
+    ) -> Result<()> {
+        let mut transaction = self.pool.begin().await?;
+
+        write_rows(&mut transaction, table, rows, id).await?;
+        transaction.commit().await?;
+
+        Ok(())
+    }
+
+    /// Writes the single row with `id` in `table`, appending it when it is new.
+    async fn save_row<T: Serialize>(&self, table: &'static str, id: i64, row: &T) -> Result<()> {
+        sqlx::query(&format!(
+            "INSERT INTO {table} (id, position, data)
+             VALUES (?1, (SELECT COALESCE(MAX(position) + 1, 0) FROM {table}), ?2)
+             ON CONFLICT (id) DO UPDATE SET data = excluded.data"
+        ))
+        .bind(id)
+        .bind(serde_json::to_string(row)?)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Storage for SqliteStorage {
+    async fn load_users(&self) -> Result<Vec<User>> {
+        self.load_rows("users").await
+    }
+
+    async fn save_users(&self, users: &[User]) -> Result<()> {
+        self.save_rows("users", users, |_, user| user.id).await
+    }
+
+    async fn save_user(&self, user: &User) -> Result<()> {
+        self.save_row("users", user.id, user).await
+    }
+
+    async fn load_messages(&self) -> Result<Vec<Message>> {
+        self.load_rows("messages").await
+    }
+
+    async fn save_messages(&self, messages: &[Message]) -> Result<()> {
+        self.save_rows("messages", messages, |_, message| message.id)
+            .await
+    }
+
+    async fn save_message(&self, message: &Message) -> Result<()> {
+        self.save_row("messages", message.id, message).await
+    }
+
+    async fn load_last_read(&self) -> Result<HashMap<(String, String), i64>> {
+        let rows: Vec<(String, String, i64)> =
+            sqlx::query_as("SELECT username, board, id FROM last_read")
+                .fetch_all(&self.pool)
+                .await?;
+
+        Ok(rows
+            .into_iter()
+            .map(|(username, board, id)| ((username, board), id))
+            .collect())
+    }
+
+    async fn save_last_read(&self, last_read: &HashMap<(String, String), i64>) -> Result<()> {
+        let mut transaction = self.pool.begin().await?;
+
+        write_last_read(&mut transaction, last_read).await?;
+        transaction.commit().await?;
+
+        Ok(())
+    }
+
+    async fn load_boards(&self) -> Result<Vec<Board>> {
+        self.load_rows("boards").await
+    }
+
+    async fn save_boards(&self, boards: &[Board]) -> Result<()> {
+        self.save_rows("boards", boards, |position, _| position as i64)
+            .await
+    }
+
+    async fn load_private_messages(&self) -> Result<Vec<PrivateMessage>> {
+        self.load_rows("private_messages").await
+    }
+
+    async fn save_private_messages(&self, private_messages: &[PrivateMessage]) -> Result<()> {
+        self.save_rows("private_messages", private_messages, |position, _| {
+            position as i64
+        })
+        .await
+    }
+
+    async fn save_stores(&self, stores: &Stores) -> Result<()> {
+        let mut transaction = self.pool.begin().await?;
+
+        write_rows(&mut transaction, "users", &stores.users, |_, user| user.id).await?;
+        write_rows(
+            &mut transaction,
+            "messages",
+            &stores.messages,
+            |_, message| message.id,
+        )
+        .await?;
+        write_last_read(&mut transaction, &stores.last_read).await?;
+        write_rows(&mut transaction, "boards", &stores.boards, |position, _| {
+            position as i64
+        })
+        .await?;
+        write_rows(
+            &mut transaction,
+            "private_messages",
+            &stores.private_messages,
+            |position, _| position as i64,
+        )
+        .await?;
+        transaction.commit().await?;
+
+        Ok(())
+    }
+}
+
+/// Makes `table` hold `rows`, in order, each keyed by what `id` returns for it and its
+/// position. Runs on `connection` so several tables can share one transaction.
+async fn write_rows<T: Serialize>(
+    connection: &mut SqliteConnection,
+    table: &'static str,
+    rows: &[T],
+    id: impl Fn(usize, &T) -> i64,
+) -> Result<()> {
+    let rows = rows
+        .iter()
+        .enumerate()
+        .map(|(position, row)| {
+            Ok((
+                id(position, row),
+                position as i64,
+                serde_json::to_string(row)?,
+            ))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let ids = serde_json::to_string(&rows.iter().map(|(id, ..)| id).collect::<Vec<_>>())?;
+
+    sqlx::query(&format!(
+        "DELETE FROM {table} WHERE id NOT IN (SELECT value FROM json_each(?1))"
+    ))
+    .bind(ids)
+    .execute(&mut *connection)
+    .await?;
+
+    for (id, position, data) in rows {
+        sqlx::query(&format!(
+            "INSERT INTO {table} (id, position, data) VALUES (?1, ?2, ?3)
+             ON CONFLICT (id) DO UPDATE SET position = excluded.position, data = excluded.data
+             WHERE position != excluded.position OR data != excluded.data"
+        ))
+        .bind(id)
+        .bind(position)
+        .bind(data)
+        .execute(&mut *connection)
+        .await?;
+    }
+
+    Ok(())
+}
+
+/// Like `write_rows`, for the read pointers keyed by user and board.
+async fn write_last_read(
+    connection: &mut SqliteConnection,
+    last_read: &HashMap<(String, String), i64>,
+) -> Result<()> {
+    let keys = serde_json::to_string(&last_read.keys().collect::<Vec<_>>())?;
+
+    sqlx::query(
+        "DELETE FROM last_read WHERE (username, board) NOT IN (
+            SELECT value ->> 0, value ->> 1 FROM json_each(?1)
+        )",
+    )
+    .bind(keys)
+    .execute(&mut *connection)
+    .await?;
+
+    for ((username, board), id) in last_read {
+        sqlx::query(
+            "INSERT INTO last_read (username, board, id) VALUES (?1, ?2, ?3)
+             ON CONFLICT (username, board) DO UPDATE SET id = excluded.id
+             WHERE id != excluded.id",
+        )
+        .bind(username)
+        .bind(board)
+        .bind(id)
+        .execute(&mut *connection)
+        .await?;
+    }
+
+    Ok(())
+}
+
+/// Replaces the row `same` matches with `row`, or appends it when there is none.
+fn upsert<T: Clone>(rows: &mut Vec<T>, row: &T, same: impl Fn(&T) -> bool) {
+    match rows.iter_mut().find(|r| same(r)) {
+        Some(existing) => *existing = row.clone(),
+        None => rows.push(row.clone()),
+    }
+}
+
+async fn load_json<T: DeserializeOwned + Default>(path: &Path) -> Result<T> {
+    if path.exists() {
+        let json = read_to_string(path).await?;
+
+        Ok(serde_json::from_str(&json)?)
+    } else {
+        Ok(T::default())
+    }
+}
+
+/// Writes `value` to a temporary file next to `path` and renames it into place, so a
+/// crash mid-write leaves the previous contents intact.
+async fn save_json<T: Serialize + ?Sized>(path: &Path, value: &T) -> Result<()> {
+    let json = serde_json::to_string_pretty(value)?;
+    let tmp_path = write_aside(path, &json).await?;
+
+    rename(&tmp_path, path).await?;
+
+    Ok(())
+}
+
+/// Writes `json` to a synced temporary file next to `path`, ready to be renamed into
+/// place, and returns where.
+async fn write_aside(path: &Path, json: &str) -> Result<PathBuf> {
+    let tmp_path = path.with_extension("json.tmp");
+    let mut file = File::create(&tmp_path).await?;
+
+    file.write_all(json.as_bytes()).await?;
+    file.flush().await?;
+    file.sync_all().await?;
+
+    Ok(tmp_path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::test_dir;
+
+    async fn round_trip(kind: StorageKind) {
+        let dir = test_dir();
+        let storage = open(kind, &dir).await.unwrap();
+        let users = vec![User {
+            id: 0,
+            username: "alice".to_owned(),
+            ..Default::default()
+        }];
+        let messages = vec![
+            Message {
+                id: 0,
+                subject: "First".to_owned(),
+                ..Default::default()
+            },
+            Message {
+                id: 1,
+                subject: "Second".to_owned(),
+                ..Default::default()
+            },
+        ];
+        let last_read = HashMap::from([(("alice".to_owned(), "general".to_owned()), 1)]);
+
+        assert!(storage.load_users().await.unwrap().is_empty());
+
+        storage.save_users(&users).await.unwrap();
+        storage.save_messages(&messages).await.unwrap();
+        storage.save_last_read(&last_read).await.unwrap();
+        // A later save replaces the rows of an earlier one.
+        storage.save_messages(&messages[1..]).await.unwrap();
+        drop(storage);
+
+        let storage = open(kind, &dir).await.unwrap();
+        let messages = storage.load_messages().await.unwrap();
+
+        assert_eq!(storage.load_users().await.unwrap()[0].username, "alice");
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].subject, "Second");
+        assert_eq!(storage.load_last_read().await.unwrap(), last_read);
+        assert!(storage.load_private_messages().await.unwrap().is_empty());
+
+        // Single records replace the one with their id in place, or are appended.
+        let bob = User {
+            id: 1,
+            username: "bob".to_owned(),
+            ..Default::default()
+        };
+
+        storage.save_user(&bob).await.unwrap();
+        storage
+            .save_user(&User {
+                username: "alicia".to_owned(),
+                ..users[0].clone()
+            })
+            .await
+            .unwrap();
+        storage
+            .save_message(&Message {
+                id: 2,
+                subject: "Third".to_owned(),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+
+        let last_read = HashMap::from([(("bob".to_owned(), "general".to_owned()), 2)]);
+
+        storage.save_last_read(&last_read).await.unwrap();
+        drop(storage);
+
+        let storage = open(kind, &dir).await.unwrap();
+        let usernames: Vec<String> = storage
+            .load_users()
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|u| u.username)
+            .collect();
+        let subjects: Vec<String> = storage
+            .load_messages()
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|m| m.subject)
+            .collect();
+
+        assert_eq!(usernames, ["alicia", "bob"]);
+        assert_eq!(subjects, ["Second", "Third"]);
+        assert_eq!(storage.load_last_read().await.unwrap(), last_read);
+
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[tokio::test]
+    async fn json_storage_keeps_what_was_saved() {
+        round_trip(StorageKind::Json).await;
+    }
+
+    #[tokio::test]
+    async fn sqlite_storage_keeps_what_was_saved() {
+        round_trip(StorageKind::Sqlite).await;
+    }
+
+    #[tokio::test]
+    async fn failed_serialization_leaves_the_file_untouched() {
+        let dir = test_dir();
+        let path = dir.join("last_read.json");
+
+        create_dir_all(&dir).await.unwrap();
+        save_json(&path, &["previous"]).await.unwrap();
+
+        // JSON object keys must be strings, so tuple keys fail to serialize.
+        let unserializable = HashMap::from([(("alice".to_owned(), "general".to_owned()), 1)]);
+
+        assert!(save_json(&path, &unserializable).await.is_err());
+        assert_eq!(read_to_string(&path).await.unwrap(), "[\n  \"previous\"\n]");
+        assert!(!path.with_extension("json.tmp").exists());
+    }
+
+    #[tokio::test]
+    async fn failed_store_saves_leave_every_file_untouched() {
+        let dir = test_dir();
+        let storage = open(StorageKind::Json, &dir).await.unwrap();
+        let alice = User {
+            username: "alice".to_owned(),
+            ..Default::default()
+        };
+
+        storage.save_users(&[alice]).await.unwrap();
+        // A directory in the way of the messages' temporary file fails its write
+        // after the users' one has been written.
+        create_dir_all(dir.join("messages.json.tmp")).await.unwrap();
+
+        let stores = Stores {
+            users: vec![User {
+                username: "bob".to_owned(),
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+
+        assert!(storage.save_stores(&stores).await.is_err());
+        assert_eq!(storage.load_users().await.unwrap()[0].username, "alice");
+        assert!(!dir.join("users.json.tmp").exists());
+
+        let _ = std::fs::remove_dir_all(dir);
+    }
+}
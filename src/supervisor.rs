@@ -0,0 +1,121 @@
+use std::{
+    collections::BTreeMap,
+    future::Future,
+    sync::{LazyLock, Mutex},
+    time::Duration,
+};
+
+use anyhow::Result;
+use tokio::{spawn, task::JoinHandle, time::sleep};
+
+use tracing::{error, warn};
+
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(300);
+
+/// State of every supervised task, by name, for diagnostics.
+static TASKS: LazyLock<Mutex<BTreeMap<&'static str, TaskStats>>> =
+    LazyLock::new(|| Mutex::new(BTreeMap::new()));
+
+#[derive(Clone, Copy, Default)]
+pub struct TaskStats {
+    pub running: bool,
+    pub restarts: usize,
+}
+
+pub fn task_stats() -> Vec<(&'static str, TaskStats)> {
+    TASKS
+        .lock()
+        .map(|tasks| tasks.iter().map(|(name, stats)| (*name, *stats)).collect())
+        .unwrap_or_default()
+}
+
+fn update_stats(name: &'static str, update: impl FnOnce(&mut TaskStats)) {
+    if let Ok(mut tasks) = TASKS.lock() {
+        update(tasks.entry(name).or_default());
+    }
+}
+
+/// Spawns a long-lived task and restarts it with exponential backoff whenever it
+/// fails or panics. A task that returns `Ok` is considered finished and not restarted.
+pub fn spawn_supervised<F, Fut>(name: &'static str, factory: F) -> JoinHandle<()>
+where
+    F: Fn() -> Fut + Send + 'static,
+    Fut: Future<Output = Result<()>> + Send + 'static,
+{
+    spawn(async move {
+        let mut backoff = INITIAL_BACKOFF;
+
+        loop {
+            update_stats(name, |stats| stats.running = true);
+
+            let result = spawn(factory()).await;
+
+            update_stats(name, |stats| stats.running = false);
+
+            match result {
+                Ok(Ok(())) => break,
+                Ok(Err(e)) => error!("Task {name} failed: {e}"),
+                Err(e) => error!("Task {name} panicked: {e}"),
+            }
+
+            update_stats(name, |stats| stats.restarts += 1);
+            warn!("Restarting task {name} in {}s", backoff.as_secs());
+            sleep(backoff).await;
+            backoff = (backoff * 2).min(MAX_BACKOFF);
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        Arc,
+        atomic::{AtomicUsize, Ordering},
+    };
+
+    use super::*;
+
+    #[tokio::test]
+    async fn failed_tasks_are_restarted_until_they_succeed() {
+        tokio::time::pause();
+
+        let started = tokio::time::Instant::now();
+        let attempts = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&attempts);
+
+        let supervisor = spawn_supervised("fails twice", move || {
+            let attempt = counter.fetch_add(1, Ordering::Relaxed);
+
+            async move {
+                match attempt {
+                    0 => anyhow::bail!("first failure"),
+                    1 => panic!("second failure"),
+                    _ => Ok(()),
+                }
+            }
+        });
+
+        // Backs off for one and then two seconds, which the paused clock skips.
+        tokio::time::timeout(Duration::from_secs(10), supervisor)
+            .await
+            .unwrap()
+            .unwrap();
+
+        let waited = started.elapsed();
+
+        assert!(
+            (Duration::from_secs(3)..Duration::from_secs(4)).contains(&waited),
+            "{waited:?}"
+        );
+
+        let (_, stats) = task_stats()
+            .into_iter()
+            .find(|(name, _)| *name == "fails twice")
+            .unwrap();
+
+        assert_eq!(attempts.load(Ordering::Relaxed), 3);
+        assert_eq!(stats.restarts, 2);
+        assert!(!stats.running);
+    }
+}
@@ -0,0 +1,275 @@
+use std::{
+    collections::HashSet,
+    io,
+    pin::Pin,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, AtomicU16, Ordering},
+    },
+    task::{Context, Poll, ready},
+};
+
+use tokio::{
+    io::{AsyncRead, ReadBuf},
+    sync::mpsc::Sender,
+};
+
+const IAC: u8 = 255;
+const DONT: u8 = 254;
+const DO: u8 = 253;
+const WONT: u8 = 252;
+const WILL: u8 = 251;
+const SB: u8 = 250;
+const SE: u8 = 240;
+const NOP: u8 = 241;
+
+const ECHO: u8 = 1;
+const SUPPRESS_GO_AHEAD: u8 = 3;
+const NAWS: u8 = 31;
+
+/// Longest subnegotiation kept, anything longer is cut short.
+const MAX_SUBNEGOTIATION: usize = 64;
+
+/// Sent when a session starts, asking the client to report its window size.
+pub const OFFER: [u8; 3] = [IAC, DO, NAWS];
+/// Tells the client the server echoes, which stops it echoing locally.
+pub const WILL_ECHO: [u8; 3] = [IAC, WILL, ECHO];
+/// Hands echoing back to the client.
+pub const WONT_ECHO: [u8; 3] = [IAC, WONT, ECHO];
+/// Does nothing on the client, only proves the connection still takes writes.
+pub const KEEPALIVE: [u8; 2] = [IAC, NOP];
+
+/// Window size reported by the client through NAWS.
+#[derive(Default)]
+pub struct Terminal {
+    /// Columns, 0 while unknown.
+    width: AtomicU16,
+    /// Whether we offered to echo, so the client agreeing isn't refused.
+    server_echo: AtomicBool,
+}
+
+impl Terminal {
+    pub fn width(&self) -> Option<u16> {
+        Some(self.width.load(Ordering::Relaxed)).filter(|w| *w > 0)
+    }
+
+    pub fn set_server_echo(&self, on: bool) {
+        self.server_echo.store(on, Ordering::Relaxed);
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum State {
+    Data,
+    Iac,
+    Option(u8),
+    Subnegotiation,
+    SubnegotiationIac,
+}
+
+/// Strips telnet commands from the bytes read from a client, answers option
+/// negotiation through the session's outbound queue and records the window size.
+pub struct TelnetReader<R> {
+    inner: R,
+    outbound: Sender<Vec<u8>>,
+    terminal: Arc<Terminal>,
+    state: State,
+    subnegotiation: Vec<u8>,
+    /// Negotiations already answered, so a misbehaving client can't make us loop.
+    answered: HashSet<(u8, u8)>,
+}
+
+impl<R> TelnetReader<R> {
+    pub fn new(inner: R, outbound: Sender<Vec<u8>>, terminal: Arc<Terminal>) -> Self {
+        Self {
+            inner,
+            outbound,
+            terminal,
+            state: State::Data,
+            subnegotiation: Vec::new(),
+            // We asked for NAWS ourselves, the client agreeing needs no answer.
+            answered: HashSet::from([(WILL, NAWS)]),
+        }
+    }
+
+    /// Removes telnet commands from `data` in place and returns how many bytes of
+    /// user input are left at its start.
+    fn filter(&mut self, data: &mut [u8]) -> usize {
+        let mut kept = 0;
+
+        for i in 0..data.len() {
+            let byte = data[i];
+
+            self.state = match (self.state, byte) {
+                (State::Data, IAC) => State::Iac,
+                // Telnet sends CR as CR NUL, the NUL is not input.
+                (State::Data, 0) => State::Data,
+                (State::Data, _) => {
+                    data[kept] = byte;
+                    kept += 1;
+                    State::Data
+                }
+                // An escaped 255 is a data byte.
+                (State::Iac, IAC) => {
+                    data[kept] = byte;
+                    kept += 1;
+                    State::Data
+                }
+                (State::Iac, WILL | WONT | DO | DONT) => State::Option(byte),
+                (State::Iac, SB) => {
+                    self.subnegotiation.clear();
+                    State::Subnegotiation
+                }
+                (State::Iac, _) => State::Data,
+                (State::Option(verb), option) => {
+                    self.negotiate(verb, option);
+                    State::Data
+                }
+                (State::Subnegotiation, IAC) => State::SubnegotiationIac,
+                (State::Subnegotiation, _) => {
+                    if self.subnegotiation.len() < MAX_SUBNEGOTIATION {
+                        self.subnegotiation.push(byte);
+                    }
+                    State::Subnegotiation
+                }
+                (State::SubnegotiationIac, SE) => {
+                    self.subnegotiated();
+                    State::Data
+                }
+                (State::SubnegotiationIac, _) => {
+                    if self.subnegotiation.len() < MAX_SUBNEGOTIATION {
+                        self.subnegotiation.push(byte);
+                    }
+                    State::Subnegotiation
+                }
+            };
+        }
+
+        kept
+    }
+
+    /// Agrees to suppress go-ahead and to the client reporting its window size, and
+    /// refuses everything else. Echo is refused too since clients echo locally, unless
+    /// we offered it to mask a password.
+    fn negotiate(&mut self, verb: u8, option: u8) {
+        let reply = match (verb, option) {
+            (DO, ECHO) if self.terminal.server_echo.load(Ordering::Relaxed) => return,
+            (WILL, NAWS | SUPPRESS_GO_AHEAD) => DO,
+            (WILL, _) => DONT,
+            (DO, SUPPRESS_GO_AHEAD) => WILL,
+            (DO, _) => WONT,
+            // Disabling an option needs no answer, it was never enabled.
+            _ => return,
+        };
+
+        if self.answered.insert((verb, option)) {
+            // Dropping a reply when the queue is full only costs us the option.
+            let _ = self.outbound.try_send(vec![IAC, reply, option]);
+        }
+    }
+
+    fn subnegotiated(&mut self) {
+        // NAWS reports width then height, two bytes each.
+        if let [NAWS, w1, w2, _, _, ..] = self.subnegotiation[..] {
+            let width = u16::from_be_bytes([w1, w2]);
+
+            self.terminal.width.store(width, Ordering::Relaxed);
+        }
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for TelnetReader<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        loop {
+            let start = buf.filled().len();
+
+            ready!(Pin::new(&mut self.inner).poll_read(cx, buf))?;
+
+            let end = buf.filled().len();
+
+            // Nothing read means end of stream.
+            if end == start {
+                return Poll::Ready(Ok(()));
+            }
+
+            let kept = self.filter(&mut buf.filled_mut()[start..end]);
+
+            buf.set_filled(start + kept);
+
+            // Reading only telnet commands must not look like end of stream.
+            if kept > 0 {
+                return Poll::Ready(Ok(()));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::{io::AsyncReadExt, sync::mpsc::channel};
+
+    #[tokio::test]
+    async fn commands_are_stripped_and_answered_once() {
+        let (outbound, mut replies) = channel(8);
+        let terminal = Arc::new(Terminal::default());
+        let input: &[u8] = &[
+            b'h',
+            IAC,
+            DO,
+            1,
+            b'i',
+            IAC,
+            DO,
+            1,
+            IAC,
+            IAC,
+            b'\r',
+            0,
+            IAC,
+            SB,
+            NAWS,
+            0,
+            100,
+            0,
+            40,
+            IAC,
+            SE,
+            IAC,
+            WILL,
+            SUPPRESS_GO_AHEAD,
+            b'\n',
+        ];
+        let mut reader = TelnetReader::new(input, outbound, Arc::clone(&terminal));
+        let mut data = Vec::new();
+
+        reader.read_to_end(&mut data).await.unwrap();
+        drop(reader);
+
+        assert_eq!(data, [b'h', b'i', IAC, b'\r', b'\n']);
+        assert_eq!(terminal.width(), Some(100));
+        assert_eq!(replies.recv().await, Some(vec![IAC, WONT, ECHO]));
+        assert_eq!(replies.recv().await, Some(vec![IAC, DO, SUPPRESS_GO_AHEAD]));
+        assert_eq!(replies.recv().await, None);
+    }
+
+    #[tokio::test]
+    async fn echo_is_accepted_only_while_offered() {
+        let (outbound, mut replies) = channel(8);
+        let terminal = Arc::new(Terminal::default());
+        let input: &[u8] = &[IAC, DO, ECHO];
+
+        terminal.set_server_echo(true);
+
+        let mut reader = TelnetReader::new(input, outbound, Arc::clone(&terminal));
+
+        reader.read_to_end(&mut Vec::new()).await.unwrap();
+        drop(reader);
+
+        assert_eq!(replies.recv().await, None);
+    }
+}
@@ -0,0 +1,532 @@
+//! Fixtures shared by the unit tests: a throwaway data directory with its own
+//! `AppState`, and clients driving real sessions over in-memory pipes.
+
+// Not every test needs every fixture.
+#![allow(dead_code)]
+
+use std::{
+    collections::HashMap,
+    env,
+    fs::{create_dir_all, remove_dir_all},
+    io,
+    net::SocketAddr,
+    path::PathBuf,
+    process,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicBool, AtomicU16, AtomicUsize, Ordering},
+    },
+    time::Duration,
+};
+
+use anyhow::Result;
+use async_trait::async_trait;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt, DuplexStream, ReadHalf, WriteHalf, duplex, split},
+    spawn,
+    task::JoinHandle,
+    time::timeout,
+};
+use tracing::Subscriber;
+
+use crate::{
+    command_handler,
+    commands::CommandHandler,
+    config::Config,
+    session::{AppState, Board, Message, PrivateMessage, Session, SessionStatus, Stores, User},
+    storage::{self, Storage, StorageKind},
+};
+
+/// Password of every user added with `Fixture::add_user`.
+pub const PASSWORD: &str = "pw";
+/// A user listed as sysop in every fixture's configuration.
+pub const SYSOP: &str = "sysop";
+
+const PIPE_SIZE: usize = 256 * 1024;
+/// How long a session must stay silent before its output is considered complete.
+const QUIET_WINDOW: Duration = Duration::from_millis(60);
+/// Longest a session may take to wind down once its client went away.
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+static NEXT_DIR: AtomicUsize = AtomicUsize::new(0);
+static NEXT_PORT: AtomicU16 = AtomicU16::new(1);
+
+/// A data directory unique to one test, removed when the fixture is dropped.
+pub struct Fixture {
+    pub dir: PathBuf,
+    pub config: Arc<Config>,
+    pub app_state: Arc<AppState>,
+    pub command_handler: Arc<CommandHandler>,
+}
+
+impl Fixture {
+    pub async fn new() -> Self {
+        Self::with_config(|_| ()).await
+    }
+
+    /// A fixture whose configuration is first adjusted by `configure`.
+    pub async fn with_config(configure: impl FnOnce(&mut Config)) -> Self {
+        let dir = test_dir();
+        let storage = storage::open(StorageKind::Json, &dir).await.unwrap();
+
+        Self::with_storage(dir, storage, configure).await
+    }
+
+    /// A fixture persisting through `storage`, which must keep its data in `dir`.
+    pub async fn with_storage(
+        dir: PathBuf,
+        storage: Box<dyn Storage>,
+        configure: impl FnOnce(&mut Config),
+    ) -> Self {
+        // Audit logs and transcripts go here whatever the storage.
+        create_dir_all(&dir).unwrap();
+
+        let mut config = Config {
+            bcrypt_cost: 4,
+            data_dir: dir.clone(),
+            sysops: vec![SYSOP.to_owned()],
+            welcome_msg: None,
+            ..Config::default()
+        };
+
+        configure(&mut config);
+
+        let config = Arc::new(config);
+        let app_state = Arc::new(AppState::load(storage, Arc::clone(&config)).await.unwrap());
+
+        Self {
+            dir,
+            config,
+            app_state,
+            command_handler: Arc::new(command_handler()),
+        }
+    }
+
+    /// Adds a user with `PASSWORD` as their password.
+    pub async fn add_user(&self, username: &str) {
+        let user = User {
+            username: username.to_owned(),
+            password: bcrypt::hash(PASSWORD, 4).unwrap(),
+            ..Default::default()
+        };
+
+        self.app_state
+            .update_users(|users| {
+                let id = users.iter().map(|u| u.id + 1).max().unwrap_or_default();
+
+                users.push(User { id, ..user });
+            })
+            .await
+            .unwrap();
+    }
+
+    /// Stores a message from `username` on `board` and returns its id.
+    pub async fn add_message(&self, username: &str, board: &str, subject: &str) -> i64 {
+        self.app_state
+            .update_messages(|messages| {
+                let id = messages.iter().map(|m| m.id + 1).max().unwrap_or_default();
+
+                messages.push(Message {
+                    id,
+                    username: username.to_owned(),
+                    board: board.to_owned(),
+                    subject: subject.to_owned(),
+                    body: format!("Body of {subject}\r\n"),
+                    created_at: crate::time::now(),
+                    ..Default::default()
+                });
+
+                id
+            })
+            .await
+            .unwrap()
+    }
+
+    /// Creates a board owned by nobody.
+    pub async fn add_board(&self, name: &str) {
+        self.app_state
+            .update_boards(|boards| {
+                boards.push(Board {
+                    name: name.to_owned(),
+                    creator: None,
+                    created_at: crate::time::now(),
+                    archived: false,
+                    anonymous: false,
+                })
+            })
+            .await
+            .unwrap();
+    }
+
+    pub async fn message(&self, id: i64) -> Option<Message> {
+        self.app_state
+            .messages
+            .read()
+            .await
+            .iter()
+            .find(|m| m.id == id)
+            .cloned()
+    }
+
+    pub async fn user(&self, username: &str) -> Option<User> {
+        self.app_state
+            .users
+            .read()
+            .await
+            .iter()
+            .find(|u| u.username == username)
+            .cloned()
+    }
+
+    /// A session logged on as `username` that nobody serves, for running commands on
+    /// directly. The client end of its pipe is returned so the session can still write.
+    pub async fn session(&self, username: &str) -> (Session, DuplexStream) {
+        let (client, server) = duplex(PIPE_SIZE);
+        let port = NEXT_PORT.fetch_add(1, Ordering::Relaxed);
+        let mut session = Session::new(
+            server,
+            SocketAddr::from(([127, 0, 0, 1], port)),
+            Arc::clone(&self.config),
+            Arc::clone(&self.app_state),
+            Arc::clone(&self.command_handler),
+        );
+
+        session.status = SessionStatus::LoggedOn(username.to_owned());
+
+        (session, client)
+    }
+
+    /// Connects a new client, returning once the welcome screen was sent.
+    pub async fn connect(&self) -> Client {
+        let mut client = self.spawn_client(false);
+
+        client.output().await;
+        client
+    }
+
+    /// Connects a new client speaking the JSON protocol.
+    pub async fn connect_json(&self) -> Client {
+        let mut client = self.spawn_client(true);
+
+        client.output().await;
+        client
+    }
+
+    /// Connects a new client without waiting for the welcome screen.
+    pub fn spawn_client(&self, json: bool) -> Client {
+        let (client, server) = duplex(PIPE_SIZE);
+        // Every client gets its own address, as sessions are keyed by it.
+        let port = NEXT_PORT.fetch_add(1, Ordering::Relaxed);
+        let address = SocketAddr::from(([127, 0, 0, 1], port));
+        let mut session = Session::new(
+            server,
+            address,
+            Arc::clone(&self.config),
+            Arc::clone(&self.app_state),
+            Arc::clone(&self.command_handler),
+        );
+
+        if json {
+            session = session.with_json();
+        }
+
+        let session = spawn(async move { session.run().await });
+        let (reader, writer) = split(client);
+
+        Client {
+            reader,
+            writer,
+            session,
+            address,
+        }
+    }
+
+    /// Connects and logs on as `username`.
+    pub async fn login(&self, username: &str) -> Client {
+        let mut client = self.connect().await;
+        let output = client.send_all(&["login", username, PASSWORD]).await;
+
+        assert!(output.contains("Login successful"), "{output}");
+
+        client
+    }
+
+    /// Runs `lines` in a session logged on as `username` and returns what it wrote
+    /// after logging on. The session is closed before returning.
+    pub async fn run_as(&self, username: &str, lines: &[&str]) -> String {
+        let mut client = self.login(username).await;
+        let output = client.send_all(lines).await;
+
+        client.close().await;
+        output
+    }
+}
+
+impl Drop for Fixture {
+    fn drop(&mut self) {
+        let _ = remove_dir_all(&self.dir);
+    }
+}
+
+/// A client connected to a session over an in-memory pipe.
+pub struct Client {
+    reader: ReadHalf<DuplexStream>,
+    writer: WriteHalf<DuplexStream>,
+    session: JoinHandle<Result<()>>,
+    pub address: SocketAddr,
+}
+
+impl Client {
+    /// Sends a line as if typed and returns everything the session wrote back.
+    pub async fn send(&mut self, line: &str) -> String {
+        self.writer
+            .write_all(format!("{line}\r\n").as_bytes())
+            .await
+            .unwrap();
+        self.output().await
+    }
+
+    /// Sends `lines` one at a time, waiting for each to be handled.
+    pub async fn send_all(&mut self, lines: &[&str]) -> String {
+        let mut output = String::new();
+
+        for line in lines {
+            output.push_str(&self.send(line).await);
+        }
+
+        output
+    }
+
+    /// Sends raw bytes at once, like a paste.
+    pub async fn paste(&mut self, data: &str) -> String {
+        self.writer.write_all(data.as_bytes()).await.unwrap();
+        self.output().await
+    }
+
+    /// Sends raw bytes without reading anything back, like a client that stalled.
+    pub async fn write(&mut self, data: &str) {
+        self.writer.write_all(data.as_bytes()).await.unwrap();
+    }
+
+    /// Whatever the session wrote until it went quiet.
+    pub async fn output(&mut self) -> String {
+        let mut output = Vec::new();
+        let mut buffer = [0; 4096];
+
+        while let Ok(Ok(read)) = timeout(QUIET_WINDOW, self.reader.read(&mut buffer)).await {
+            if read == 0 {
+                break;
+            }
+
+            output.extend_from_slice(&buffer[..read]);
+        }
+
+        String::from_utf8_lossy(&output).into_owned()
+    }
+
+    /// Hangs up and waits for the session to wind down.
+    pub async fn close(self) {
+        let Client {
+            reader,
+            writer,
+            session,
+            ..
+        } = self;
+
+        drop(reader.unsplit(writer));
+        let _ = timeout(CLOSE_TIMEOUT, session).await;
+    }
+
+    /// Waits for the session to end on its own, e.g. after `quit`.
+    pub async fn finished(self) -> bool {
+        timeout(CLOSE_TIMEOUT, self.session).await.is_ok()
+    }
+}
+
+/// A fresh directory for one test's data, below the system's temporary directory.
+pub fn test_dir() -> PathBuf {
+    let n = NEXT_DIR.fetch_add(1, Ordering::Relaxed);
+
+    env::temp_dir().join(format!("gluboard-test-{}-{n}", process::id()))
+}
+
+/// `text` without its ANSI escape codes, as a user reads it.
+pub fn plain(text: &str) -> String {
+    let mut plain = String::with_capacity(text.len());
+    let mut rest = text;
+
+    while let Some(start) = rest.find("\u{001b}[") {
+        plain.push_str(&rest[..start]);
+
+        let codes = &rest[start + 2..];
+        let end = codes
+            .find(|c: char| !c.is_ascii_digit() && c != ';')
+            .map_or(codes.len(), |end| end + 1);
+
+        rest = &codes[end..];
+    }
+
+    plain.push_str(rest);
+    plain
+}
+
+/// Everything a subscriber from `Logs::subscriber` wrote, for checking log lines.
+#[derive(Clone, Default)]
+pub struct Logs(Arc<Mutex<Vec<u8>>>);
+
+impl Logs {
+    /// A subscriber writing every event into these logs, without colors.
+    pub fn subscriber(&self) -> impl Subscriber + Send + Sync + 'static {
+        let logs = self.clone();
+
+        tracing_subscriber::fmt()
+            .with_writer(move || logs.clone())
+            .with_ansi(false)
+            .finish()
+    }
+
+    pub fn lines(&self) -> Vec<String> {
+        String::from_utf8_lossy(&self.0.lock().unwrap())
+            .lines()
+            .map(str::to_owned)
+            .collect()
+    }
+}
+
+impl io::Write for Logs {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Storage keeping everything in memory that can be told to fail its saves. Shared
+/// through an `Arc` so a test can keep inspecting what was saved.
+#[derive(Default)]
+pub struct FlakyStorage {
+    /// Saves fail while set.
+    pub failing: AtomicBool,
+    /// Saves still allowed to succeed before the next one fails, once, if set.
+    pub saves_left: Mutex<Option<usize>>,
+    pub users: Mutex<Vec<User>>,
+    pub messages: Mutex<Vec<Message>>,
+    pub last_read: Mutex<HashMap<(String, String), i64>>,
+    pub boards: Mutex<Vec<Board>>,
+    pub private_messages: Mutex<Vec<PrivateMessage>>,
+}
+
+impl FlakyStorage {
+    fn check(&self) -> Result<()> {
+        let mut saves_left = self.saves_left.lock().unwrap();
+
+        match saves_left.as_mut() {
+            Some(0) => {
+                *saves_left = None;
+                anyhow::bail!("Injected save failure");
+            }
+            Some(left) => *left -= 1,
+            None => (),
+        }
+
+        if self.failing.load(Ordering::Relaxed) {
+            anyhow::bail!("Injected save failure");
+        }
+
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Storage for Arc<FlakyStorage> {
+    async fn load_users(&self) -> Result<Vec<User>> {
+        Ok(self.users.lock().unwrap().clone())
+    }
+
+    async fn save_users(&self, users: &[User]) -> Result<()> {
+        self.check()?;
+        *self.users.lock().unwrap() = users.to_vec();
+        Ok(())
+    }
+
+    async fn save_user(&self, user: &User) -> Result<()> {
+        self.check()?;
+        let mut users = self.users.lock().unwrap();
+
+        match users.iter_mut().find(|u| u.id == user.id) {
+            Some(existing) => *existing = user.clone(),
+            None => users.push(user.clone()),
+        }
+        Ok(())
+    }
+
+    async fn load_messages(&self) -> Result<Vec<Message>> {
+        Ok(self.messages.lock().unwrap().clone())
+    }
+
+    async fn save_messages(&self, messages: &[Message]) -> Result<()> {
+        self.check()?;
+        *self.messages.lock().unwrap() = messages.to_vec();
+        Ok(())
+    }
+
+    async fn save_message(&self, message: &Message) -> Result<()> {
+        self.check()?;
+        let mut messages = self.messages.lock().unwrap();
+
+        match messages.iter_mut().find(|m| m.id == message.id) {
+            Some(existing) => *existing = message.clone(),
+            None => messages.push(message.clone()),
+        }
+        Ok(())
+    }
+
+    async fn load_last_read(&self) -> Result<HashMap<(String, String), i64>> {
+        Ok(self.last_read.lock().unwrap().clone())
+    }
+
+    async fn save_last_read(&self, last_read: &HashMap<(String, String), i64>) -> Result<()> {
+        self.check()?;
+        *self.last_read.lock().unwrap() = last_read.clone();
+        Ok(())
+    }
+
+    async fn load_boards(&self) -> Result<Vec<Board>> {
+        Ok(self.boards.lock().unwrap().clone())
+    }
+
+    async fn save_boards(&self, boards: &[Board]) -> Result<()> {
+        self.check()?;
+        *self.boards.lock().unwrap() = boards.to_vec();
+        Ok(())
+    }
+
+    async fn load_private_messages(&self) -> Result<Vec<PrivateMessage>> {
+        Ok(self.private_messages.lock().unwrap().clone())
+    }
+
+    async fn save_private_messages(&self, private_messages: &[PrivateMessage]) -> Result<()> {
+        self.check()?;
+        *self.private_messages.lock().unwrap() = private_messages.to_vec();
+        Ok(())
+    }
+
+    async fn save_stores(&self, stores: &Stores) -> Result<()> {
+        // Each store counts as a save, but like a transaction nothing is kept unless
+        // all of them go through.
+        for _ in 0..5 {
+            self.check()?;
+        }
+
+        *self.users.lock().unwrap() = stores.users.clone();
+        *self.messages.lock().unwrap() = stores.messages.clone();
+        *self.last_read.lock().unwrap() = stores.last_read.clone();
+        *self.boards.lock().unwrap() = stores.boards.clone();
+        *self.private_messages.lock().unwrap() = stores.private_messages.clone();
+        Ok(())
+    }
+}
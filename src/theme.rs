@@ -0,0 +1,194 @@
+use std::str::FromStr;
+
+use anyhow::{Error, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    ansi::{AnsiColor, AnsiStyle},
+    error::UserError,
+};
+
+/// Color names overriding the default theme, as set in the `[theme]` config table.
+#[derive(Clone, Default, Deserialize, Serialize)]
+pub struct ThemeConfig {
+    pub error: Option<String>,
+    pub success: Option<String>,
+    pub header: Option<String>,
+    pub prompt: Option<String>,
+    pub id: Option<String>,
+    pub author: Option<String>,
+}
+
+/// Default foreground and background colors picked by a user with `scheme`.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Scheme {
+    Dark,
+    Light,
+}
+
+impl Scheme {
+    fn colors(self) -> (AnsiColor, AnsiColor) {
+        match self {
+            Scheme::Dark => (AnsiColor::White, AnsiColor::Black),
+            Scheme::Light => (AnsiColor::Black, AnsiColor::White),
+        }
+    }
+}
+
+impl FromStr for Scheme {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "dark" => Ok(Scheme::Dark),
+            "light" => Ok(Scheme::Light),
+            _ => Err(UserError::new(format!("Unknown scheme {s}")).into()),
+        }
+    }
+}
+
+/// Named styles used across the UI so messages of the same kind look alike.
+/// Every preset is `None` when color output is disabled.
+pub struct Theme {
+    enabled: bool,
+    pub scheme: Option<Scheme>,
+    error: AnsiColor,
+    success: AnsiColor,
+    header: AnsiColor,
+    prompt: AnsiColor,
+    id: AnsiColor,
+    author: AnsiColor,
+}
+
+impl Theme {
+    pub fn new(config: &ThemeConfig, enabled: bool) -> Self {
+        let color = |name: &Option<String>, default| {
+            name.as_deref()
+                .and_then(|name| name.parse().ok())
+                .unwrap_or(default)
+        };
+
+        Self {
+            enabled,
+            scheme: None,
+            error: color(&config.error, AnsiColor::Red),
+            success: color(&config.success, AnsiColor::Green),
+            header: color(&config.header, AnsiColor::Yellow),
+            prompt: color(&config.prompt, AnsiColor::Cyan),
+            id: color(&config.id, AnsiColor::Cyan),
+            author: color(&config.author, AnsiColor::Green),
+        }
+    }
+
+    pub fn error(&self) -> Option<AnsiStyle> {
+        self.style(self.error)
+    }
+
+    pub fn success(&self) -> Option<AnsiStyle> {
+        self.style(self.success)
+    }
+
+    pub fn header(&self) -> Option<AnsiStyle> {
+        self.style(self.header)
+    }
+
+    pub fn prompt(&self) -> Option<AnsiStyle> {
+        self.style(self.prompt)
+    }
+
+    /// Message ids in listings.
+    pub fn id(&self) -> Option<AnsiStyle> {
+        self.style(self.id)
+    }
+
+    /// Usernames in listings.
+    pub fn author(&self) -> Option<AnsiStyle> {
+        self.style(self.author)
+    }
+
+    /// Style for text written without an explicit one. Only set once a scheme was
+    /// picked, so sessions without one keep the terminal's own colors.
+    pub fn default_style(&self) -> Option<AnsiStyle> {
+        let (fg, bg) = self.scheme?.colors();
+
+        self.enabled.then(|| AnsiStyle::new(Some(fg), Some(bg)))
+    }
+
+    fn style(&self, fg: AnsiColor) -> Option<AnsiStyle> {
+        let bg = self.scheme.map(|scheme| scheme.colors().1);
+
+        self.enabled.then(|| AnsiStyle::new(Some(fg), bg))
+    }
+}
+
+/// `text` in `style`, or as is without one. For coloring part of a line.
+pub fn paint(text: &str, style: Option<AnsiStyle>) -> String {
+    match style {
+        Some(style) => style.apply(text),
+        None => text.to_owned(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn errors_are_red_unless_overridden() {
+        let theme = Theme::new(&ThemeConfig::default(), true);
+
+        assert_eq!(
+            paint("oops", theme.error()),
+            "\u{001b}[31;49moops\u{001b}[0m"
+        );
+
+        let config = ThemeConfig {
+            error: Some("magenta".to_owned()),
+            ..Default::default()
+        };
+        let theme = Theme::new(&config, true);
+
+        assert_eq!(
+            paint("oops", theme.error()),
+            "\u{001b}[35;49moops\u{001b}[0m"
+        );
+        assert!(Theme::new(&config, false).error().is_none());
+    }
+
+    #[test]
+    fn light_scheme_flips_the_default_colors() {
+        let mut theme = Theme::new(&ThemeConfig::default(), true);
+
+        assert!(theme.default_style().is_none());
+
+        theme.scheme = Some(Scheme::Dark);
+        assert_eq!(
+            paint("text", theme.default_style()),
+            "\u{001b}[37;40mtext\u{001b}[0m"
+        );
+
+        theme.scheme = Some(Scheme::Light);
+        assert_eq!(
+            paint("text", theme.default_style()),
+            "\u{001b}[30;47mtext\u{001b}[0m"
+        );
+        // Presets keep their color but sit on the scheme's background.
+        assert_eq!(
+            paint("oops", theme.error()),
+            "\u{001b}[31;47moops\u{001b}[0m"
+        );
+
+        // Whatever the scheme, styled text ends in a full reset rather than in the
+        // scheme's own colors, so they never bleed into what follows.
+        for scheme in [Scheme::Dark, Scheme::Light] {
+            theme.scheme = Some(scheme);
+
+            for style in [theme.default_style(), theme.header(), theme.prompt()] {
+                let painted = paint("text", style);
+
+                assert!(painted.ends_with("text\u{001b}[0m"), "{painted:?}");
+            }
+        }
+    }
+}
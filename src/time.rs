@@ -0,0 +1,43 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Current time as seconds since the Unix epoch.
+pub fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or_default()
+}
+
+/// Formats seconds since the Unix epoch as a UTC date and time.
+pub fn format(epoch: i64) -> String {
+    let (year, month, day) = civil_date(epoch);
+    let seconds = epoch.rem_euclid(86_400);
+
+    format!(
+        "{year:04}-{month:02}-{day:02} {:02}:{:02} UTC",
+        seconds / 3_600,
+        seconds % 3_600 / 60
+    )
+}
+
+/// Formats seconds since the Unix epoch as a UTC date only.
+pub fn format_date(epoch: i64) -> String {
+    let (year, month, day) = civil_date(epoch);
+
+    format!("{year:04}-{month:02}-{day:02}")
+}
+
+/// Converts seconds since the epoch to a civil date (Howard Hinnant's algorithm).
+fn civil_date(epoch: i64) -> (i64, i64, i64) {
+    let z = epoch.div_euclid(86_400) + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1_460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    (year, month, day)
+}
@@ -0,0 +1,121 @@
+use std::mem::take;
+
+const ESC: char = '\u{001b}';
+
+/// Breaks each line of `text` at spaces so none is wider than `width` columns, splitting
+/// words that don't fit on a line of their own. Existing line breaks are kept and ANSI
+/// escape sequences take no room and are never split. A `width` of 0 leaves text as is.
+pub fn wrap(text: &str, width: usize) -> String {
+    if width == 0 {
+        return text.to_owned();
+    }
+
+    text.split("\r\n")
+        .map(|line| wrap_line(line, width))
+        .collect::<Vec<String>>()
+        .join("\r\n")
+}
+
+fn wrap_line(line: &str, width: usize) -> String {
+    let mut lines = Vec::new();
+    let mut current = String::new();
+    let mut current_width = 0;
+    let mut first = true;
+
+    for word in line.split(' ') {
+        let word_width = visible_width(word);
+
+        if !first && current_width + 1 + word_width > width {
+            lines.push(take(&mut current));
+            current_width = 0;
+            first = true;
+        }
+
+        if word_width > width {
+            let mut chunks = split_word(word, width);
+
+            current = chunks.pop().unwrap_or_default();
+            current_width = visible_width(&current);
+            lines.extend(chunks);
+        } else {
+            if !first {
+                current.push(' ');
+                current_width += 1;
+            }
+
+            current.push_str(word);
+            current_width += word_width;
+        }
+
+        first = false;
+    }
+
+    lines.push(current);
+    lines.join("\r\n")
+}
+
+/// Pieces of `word` at most `width` columns wide, with escape sequences kept whole.
+fn split_word(word: &str, width: usize) -> Vec<String> {
+    let mut chunks = Vec::new();
+    let mut chunk = String::new();
+    let mut chunk_width = 0;
+    let mut chars = word.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        if c == ESC {
+            chunk.push(c);
+
+            // A CSI sequence runs until its final byte, anything else is one character.
+            if chars.next_if_eq(&'[').is_some() {
+                chunk.push('[');
+
+                for c in chars.by_ref() {
+                    chunk.push(c);
+
+                    if is_final(c) {
+                        break;
+                    }
+                }
+            } else if let Some(c) = chars.next() {
+                chunk.push(c);
+            }
+
+            continue;
+        }
+
+        if chunk_width == width {
+            chunks.push(take(&mut chunk));
+            chunk_width = 0;
+        }
+
+        chunk.push(c);
+        chunk_width += 1;
+    }
+
+    chunks.push(chunk);
+    chunks
+}
+
+/// Columns `text` takes on screen, leaving out escape sequences.
+fn visible_width(text: &str) -> usize {
+    let mut width = 0;
+    let mut chars = text.chars();
+
+    while let Some(c) = chars.next() {
+        if c != ESC {
+            width += 1;
+            continue;
+        }
+
+        if chars.next() == Some('[') {
+            chars.by_ref().find(|&c| is_final(c));
+        }
+    }
+
+    width
+}
+
+/// Whether `c` ends a CSI sequence.
+fn is_final(c: char) -> bool {
+    ('\u{40}'..='\u{7e}').contains(&c)
+}